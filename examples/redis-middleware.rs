@@ -21,7 +21,7 @@ async fn main() -> anyhow::Result<()> {
         // Let's create our middleware here
         let rate_limiter = {
             // timeout is 10 secs
-            let store = RedisStore::from_client(client, "test-actix-web-rate-limit-2", chrono::Duration::seconds(10));
+            let store = RedisStore::from_client(client, "test-actix-web-rate-limit-2", actix_rl::time::Duration::seconds(10));
 
             // create a default controller which controls request handling.
             let controller = controller::Controller::default()
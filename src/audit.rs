@@ -0,0 +1,287 @@
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use chrono::{SecondsFormat, Utc};
+use sha2::{Digest, Sha256};
+use crate::utils::write_json_string;
+
+/// The default number of rotated files kept by [AuditLog].
+pub const DEFAULT_MAX_FILES: usize = 10;
+
+/// The default number of records waiting to be written by [AuditLog].
+pub const DEFAULT_MAX_PENDING: usize = 65536;
+
+/// [AuditAction] is the decision recorded by [AuditLog].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AuditAction {
+    /// The request was rejected by the limiter.
+    Rejected,
+    /// The key was banned, such as by the application in [Controller::on_first_violation](crate::controller::Controller::on_first_violation).
+    Banned,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Rejected => "rejected",
+            AuditAction::Banned => "banned",
+        }
+    }
+}
+
+/// [AuditLog] appends the rejections and bans as JSON lines to a file, such as
+/// `{"timestamp":"2024-01-01T00:00:00.000Z","action":"rejected","key":"9f86d0…","policy":"api","count":11}`,
+/// for an audit trail independent of the logs of the application,
+/// see [RateLimit::with_audit_log](crate::middleware::RateLimit::with_audit_log).
+///
+/// The keys are hashed with SHA-256 (see [Self::with_salt]), so the trail does not hold
+/// the IP addresses or the user ids. The file is only appended to; when it grows over
+/// `max_bytes`, it is renamed to `{path}.1` (the older files to `{path}.2` and so on),
+/// and the oldest file over [Self::with_max_files] is removed.
+///
+/// The records are written by a background thread, one `write` per record, so recording
+/// never blocks on the file. At most [DEFAULT_MAX_PENDING] records wait to be written
+/// (see [Self::with_max_pending]); the records over it are dropped, and [Self::record]
+/// returns [io::ErrorKind::WouldBlock]. The errors of the thread are returned by the next
+/// [Self::record] or [Self::flush]. Clones share the same thread and file, which are closed
+/// when the last clone is dropped.
+///
+/// ```rust
+/// use actix_rl::audit::{AuditAction, AuditLog};
+///
+/// # let path = std::env::temp_dir().join("actix-rl-audit-doc.jsonl");
+/// let audit = AuditLog::open(&path, 64 * 1024 * 1024).unwrap().with_salt("secret");
+/// audit.record(AuditAction::Banned, "127.0.0.1", Some("login"), 10).unwrap();
+/// audit.flush().unwrap();
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    salt: Vec<u8>,
+    sender: SyncSender<Message>,
+    /// the first error of the writer thread, not returned yet.
+    error: Arc<Mutex<Option<io::Error>>>,
+}
+
+/// [Message] is sent to the writer thread of [AuditLog].
+#[derive(Debug)]
+enum Message {
+    Record(Vec<u8>),
+    MaxFiles(usize),
+    Flush(SyncSender<()>),
+}
+
+#[derive(Debug)]
+struct AuditFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl AuditLog {
+    /// Open (or create) the file at `path` for appending, rotated over `max_bytes`,
+    /// and start the writer thread.
+    pub fn open<P: AsRef<Path>>(path: P, max_bytes: u64) -> io::Result<Self> {
+        Self::open_with_max_pending(path, max_bytes, DEFAULT_MAX_PENDING)
+    }
+
+    /// Like [Self::open], with at most `max_pending` records waiting to be written,
+    /// instead of [DEFAULT_MAX_PENDING].
+    pub fn open_with_max_pending<P: AsRef<Path>>(path: P, max_bytes: u64, max_pending: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let file = AuditFile {
+            path,
+            file,
+            size,
+            max_bytes,
+            max_files: DEFAULT_MAX_FILES,
+        };
+
+        let (sender, receiver) = mpsc::sync_channel(max_pending);
+        let error = Arc::new(Mutex::new(None));
+        let thread_error = error.clone();
+        std::thread::Builder::new()
+            .name("actix-rl-audit".to_string())
+            .spawn(move || file.run(receiver, thread_error))?;
+
+        Ok(Self {
+            salt: Vec::new(),
+            sender,
+            error,
+        })
+    }
+
+    /// Prefix the keys with `salt` before hashing them, so the hashes of
+    /// well-known keys (such as IP addresses) cannot be looked up.
+    pub fn with_salt<S: AsRef<[u8]>>(mut self, salt: S) -> Self {
+        self.salt = salt.as_ref().to_vec();
+        self
+    }
+
+    /// Keep at most `max_files` rotated files, default to [DEFAULT_MAX_FILES].
+    pub fn with_max_files(self, max_files: usize) -> Self {
+        // the thread stops only when the log is dropped.
+        let _ = self.sender.send(Message::MaxFiles(max_files));
+        self
+    }
+
+    /// Queue a record of `action` on `key`, with the name of the policy and the count,
+    /// to be appended by the writer thread.
+    pub fn record<C: Display>(&self, action: AuditAction, key: &str, policy: Option<&str>, count: C) -> io::Result<()> {
+        let mut line = format!(
+            r#"{{"timestamp":"{}","action":"{}","key":"{}","policy":"#,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            action.as_str(),
+            self.hash(key),
+        );
+        match policy {
+            Some(policy) => write_json_string(&mut line, policy),
+            None => line.push_str("null"),
+        }
+        line.push_str(r#","count":"#);
+        let count = count.to_string();
+        if count.parse::<f64>().is_ok_and(f64::is_finite) {
+            line.push_str(&count);
+        } else {
+            write_json_string(&mut line, &count);
+        }
+        line.push_str("}\n");
+
+        self.take_error()?;
+        match self.sender.try_send(Message::Record(line.into_bytes())) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::Error::new(io::ErrorKind::WouldBlock, "too many audit records pending")),
+            Err(TrySendError::Disconnected(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    /// Wait until the records queued before are written, and return the error of the
+    /// writer thread, if any.
+    pub fn flush(&self) -> io::Result<()> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.sender.send(Message::Flush(sender)).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        receiver.recv().map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.take_error()
+    }
+
+    fn take_error(&self) -> io::Result<()> {
+        match self.error.lock().unwrap_or_else(PoisonError::into_inner).take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Return the SHA-256 of the salt and `key`, in lowercase hex.
+    fn hash(&self, key: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.salt);
+        hasher.update(key.as_bytes());
+        hasher.finalize().iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+impl AuditFile {
+    /// Write the records from `receiver` until all the senders are dropped,
+    /// keeping the first error in `error`.
+    fn run(mut self, receiver: Receiver<Message>, error: Arc<Mutex<Option<io::Error>>>) {
+        for message in receiver {
+            let result = match message {
+                Message::Record(line) => self.append(&line),
+                Message::MaxFiles(max_files) => {
+                    self.max_files = max_files;
+                    Ok(())
+                },
+                Message::Flush(done) => {
+                    let _ = done.send(());
+                    Ok(())
+                },
+            };
+            if let Err(e) = result {
+                error.lock().unwrap_or_else(PoisonError::into_inner).get_or_insert(e);
+            }
+        }
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                match std::fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {},
+                }
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_log() {
+        let path = std::env::temp_dir().join(format!("actix-rl-audit-{}.jsonl", std::process::id()));
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+        let audit = AuditLog::open(&path, 400).unwrap().with_max_files(1);
+
+        audit.record(AuditAction::Rejected, "127.0.0.1", Some("api \"v1\""), 11).unwrap();
+        audit.record(AuditAction::Banned, "127.0.0.1", None, 12).unwrap();
+        audit.flush().unwrap();
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = lines.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["action"], "rejected");
+        assert_eq!(records[0]["policy"], "api \"v1\"");
+        assert_eq!(records[0]["count"], 11);
+        assert_eq!(records[0]["key"], audit.hash("127.0.0.1"));
+        assert_ne!(records[0]["key"], AuditLog::open(&path, 400).unwrap().with_salt("salt").hash("127.0.0.1"));
+        assert_eq!(records[1]["action"], "banned");
+        assert!(records[1]["policy"].is_null());
+
+        // rotate over 400 bytes, keeping one file.
+        audit.record(AuditAction::Rejected, "127.0.0.1", None, 13).unwrap();
+        audit.record(AuditAction::Rejected, "127.0.0.1", None, 14).unwrap();
+        audit.record(AuditAction::Rejected, "127.0.0.1", None, 15).unwrap();
+        audit.flush().unwrap();
+        assert!(std::fs::read_to_string(rotated(1)).unwrap().contains(r#""count":14"#));
+        assert!(std::fs::read_to_string(&path).unwrap().contains(r#""count":15"#));
+        assert!(!rotated(2).exists());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(rotated(1)).unwrap();
+    }
+}
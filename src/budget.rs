@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use actix_web::HttpRequest;
+use crate::time::{DateTime, Utc};
+
+/// Classify a request into a client class, such as `"authenticated"`, see [WeightedBudget].
+pub type ClassifyFunc = fn(&HttpRequest) -> &'static str;
+
+/// [WeightedBudget] divides a global budget of `max` requests per window between
+/// client classes by weight (such as 70% for authenticated clients and 30% for
+/// anonymous ones), see [RateLimit::with_weighted_budget](crate::middleware::RateLimit::with_weighted_budget).
+///
+/// Over its share, a class borrows the capacity left, except the unused shares of the
+/// other active classes (the classes with requests in this window or the previous one),
+/// so a busy class cannot starve the others. So an active class can always use its share.
+///
+/// The share of an idle class is lent, so it does not waste capacity: when the class
+/// becomes active, it only gets what is not borrowed yet in this window, and its full
+/// share from the next window. The classes without a weight only borrow.
+///
+/// The budget is counted in this process, with windows aligned to the unix epoch.
+/// Clones share the same counts.
+///
+/// ```rust
+/// use actix_rl::budget::WeightedBudget;
+///
+/// let budget = WeightedBudget::new(1000, actix_rl::time::Duration::seconds(1), |req| {
+///     if req.headers().contains_key("Authorization") { "authenticated" } else { "anonymous" }
+/// })
+///     .with_class("authenticated", 70)
+///     .with_class("anonymous", 30);
+/// ```
+#[derive(Debug, Clone)]
+pub struct WeightedBudget {
+    max: u32,
+    window: crate::time::Duration,
+    classify: ClassifyFunc,
+    /// the classes and their weights.
+    weights: Vec<(&'static str, u32)>,
+    state: Arc<Mutex<BudgetState>>,
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    /// the index of the current window.
+    epoch: i64,
+    total: u32,
+    counts: HashMap<&'static str, u32>,
+    /// the counts of the previous window, to tell the active classes.
+    previous: HashMap<&'static str, u32>,
+}
+
+impl WeightedBudget {
+    /// Create a budget of `max` requests per `window`, for the classes given by `classify`.
+    pub fn new(max: u32, window: crate::time::Duration, classify: ClassifyFunc) -> Self {
+        Self {
+            max,
+            window,
+            classify,
+            weights: Vec::new(),
+            state: Default::default(),
+        }
+    }
+
+    /// Give `class` the share `weight` out of the sum of the weights, replacing the previous one.
+    pub fn with_class(mut self, class: &'static str, weight: u32) -> Self {
+        self.weights.retain(|(other, _)| *other != class);
+        self.weights.push((class, weight));
+        self
+    }
+
+    /// Count `req` in the budget of its class.
+    /// Return the end of the window if the budget of the class is used up.
+    pub fn acquire(&self, req: &HttpRequest) -> Result<(), DateTime<Utc>> {
+        self.acquire_class((self.classify)(req), Utc::now())
+    }
+
+    fn acquire_class(&self, class: &'static str, now: DateTime<Utc>) -> Result<(), DateTime<Utc>> {
+        let window = self.window.num_milliseconds().max(1);
+        let epoch = now.timestamp_millis().div_euclid(window);
+        let until = DateTime::from_timestamp_millis((epoch + 1) * window).unwrap_or(now);
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.epoch != epoch {
+            let counts = std::mem::take(&mut state.counts);
+            state.previous = if state.epoch + 1 == epoch { counts } else { HashMap::new() };
+            state.epoch = epoch;
+            state.total = 0;
+        }
+
+        if state.total >= self.max {
+            return Err(until);
+        }
+
+        let count = state.counts.get(class).copied().unwrap_or_default();
+        if count >= self.share(class) {
+            // borrow the capacity left, keeping the unused shares of the other active classes.
+            let reserved: u32 = self.weights.iter()
+                .filter(|(other, _)| *other != class)
+                .filter(|(other, _)| state.counts.contains_key(other) || state.previous.contains_key(other))
+                .map(|(other, _)| self.share(other).saturating_sub(state.counts.get(other).copied().unwrap_or_default()))
+                .sum();
+            if state.total.saturating_add(reserved) >= self.max {
+                return Err(until);
+            }
+        }
+
+        *state.counts.entry(class).or_default() += 1;
+        state.total += 1;
+        Ok(())
+    }
+
+    /// Return the share of `class` in each window.
+    fn share(&self, class: &str) -> u32 {
+        let total: u64 = self.weights.iter().map(|(_, weight)| *weight as u64).sum();
+        let weight = self.weights.iter()
+            .find(|(other, _)| *other == class)
+            .map_or(0, |(_, weight)| *weight as u64);
+        match total {
+            0 => 0,
+            total => (self.max as u64 * weight / total) as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_budget() {
+        let budget = WeightedBudget::new(10, crate::time::Duration::seconds(10), |_| "unused")
+            .with_class("authenticated", 70)
+            .with_class("anonymous", 30);
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let acquire = |class, times| (0..times).filter(|_| budget.acquire_class(class, now).is_ok()).count();
+
+        // the idle class lends its share.
+        assert_eq!(acquire("anonymous", 12), 10);
+
+        // in the next window, the anonymous class is active and keeps its share,
+        // the authenticated class is idle and lends its share.
+        let now = now + crate::time::Duration::seconds(10);
+        let acquire = |class, times| (0..times).filter(|_| budget.acquire_class(class, now).is_ok()).count();
+        assert_eq!(acquire("authenticated", 12), 7);
+        assert_eq!(acquire("anonymous", 5), 3);
+
+        // the classes without a weight only borrow.
+        let now = now + crate::time::Duration::seconds(10);
+        let acquire = |class, times| (0..times).filter(|_| budget.acquire_class(class, now).is_ok()).count();
+        assert_eq!(acquire("crawler", 12), 0);
+        assert_eq!(acquire("anonymous", 5), 3);
+        assert_eq!(acquire("authenticated", 8), 7);
+        assert!(budget.acquire_class("anonymous", now).is_err_and(|until| until == now + crate::time::Duration::seconds(10)));
+    }
+}
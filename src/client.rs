@@ -0,0 +1,140 @@
+use actix_web::http::header::{HeaderMap, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use crate::time::{DateTime, Utc};
+use crate::controller::{DEFAULT_RATE_LIMITED_UNTIL_HEADER, DEFAULT_RATE_LIMIT_REMAINING_HEADER, DEFAULT_RATE_LIMIT_RESET_HEADER, IETF_RATE_LIMIT_REMAINING_HEADER, IETF_RATE_LIMIT_RESET_HEADER};
+
+/// The wait of the rejected responses without a reset time.
+pub const DEFAULT_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// [ClientBackoff] reads the rate-limit headers of a response of another service using
+/// this crate (or sending `Retry-After`), and tells how long to wait before the next
+/// request, so the services calling each other honor their limits.
+///
+/// The headers are read in order: [DEFAULT_RATE_LIMITED_UNTIL_HEADER], `Retry-After`
+/// (in seconds), then the reset and remaining headers of [SuccessHeaders](crate::controller::SuccessHeaders).
+///
+/// ```rust
+/// use actix_rl::client::ClientBackoff;
+///
+/// # async fn call() {
+/// # let (status, headers) = (actix_web::http::StatusCode::TOO_MANY_REQUESTS, actix_web::http::header::HeaderMap::new());
+/// // with the status and the headers of the response of awc (or any client using `http`):
+/// let backoff = ClientBackoff::from_headers(status, &headers);
+/// if let Some(wait) = backoff.wait() {
+///     tokio::time::sleep(wait).await;
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub struct ClientBackoff {
+    limited: bool,
+    remaining: Option<u64>,
+    reset: Option<DateTime<Utc>>,
+}
+
+impl ClientBackoff {
+    /// Read the status and the headers of a response.
+    pub fn from_headers(status: StatusCode, headers: &HeaderMap) -> Self {
+        Self::from_fn(status.as_u16(), |name| headers.get(name)?.to_str().ok().map(str::to_string))
+    }
+
+    /// Read the status of a response and its headers by `header`, returning the value
+    /// of a header by its name, for clients with other header types.
+    pub fn from_fn<F: Fn(&str) -> Option<String>>(status: u16, header: F) -> Self {
+        let number = |name: &str| header(name)?.trim().parse::<i64>().ok();
+        let now = Utc::now();
+
+        let reset = number(DEFAULT_RATE_LIMITED_UNTIL_HEADER)
+            .and_then(|until| DateTime::from_timestamp(until, 0))
+            .or_else(|| number(RETRY_AFTER.as_str()).map(|secs| now + crate::time::Duration::seconds(secs)))
+            .or_else(|| number(DEFAULT_RATE_LIMIT_RESET_HEADER).and_then(|reset| DateTime::from_timestamp(reset, 0)))
+            .or_else(|| number(IETF_RATE_LIMIT_RESET_HEADER).map(|secs| now + crate::time::Duration::seconds(secs)));
+        let remaining = number(DEFAULT_RATE_LIMIT_REMAINING_HEADER)
+            .or_else(|| number(IETF_RATE_LIMIT_REMAINING_HEADER))
+            .and_then(|remaining| u64::try_from(remaining).ok());
+
+        Self {
+            limited: status == StatusCode::TOO_MANY_REQUESTS.as_u16(),
+            remaining,
+            reset,
+        }
+    }
+
+    /// Check if the request was rejected by the limiter.
+    pub fn is_limited(&self) -> bool {
+        self.limited
+    }
+
+    /// Return the remaining count of the window, if sent.
+    pub fn remaining(&self) -> Option<u64> {
+        self.remaining
+    }
+
+    /// Return the time the window resets, if sent.
+    pub fn reset(&self) -> Option<DateTime<Utc>> {
+        self.reset
+    }
+
+    /// Return how long to wait before the next request: until the reset if the request
+    /// was rejected (or [DEFAULT_BACKOFF] without a reset time) or if no request remains,
+    /// otherwise [None].
+    pub fn wait(&self) -> Option<std::time::Duration> {
+        if !self.limited && self.remaining != Some(0) {
+            return None;
+        }
+        match self.reset {
+            Some(reset) => Some((reset - Utc::now()).to_std().unwrap_or_default()),
+            None if self.limited => Some(DEFAULT_BACKOFF),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_backoff() {
+        let headers = |pairs: &[(&'static str, String)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name.parse().unwrap(), value.parse().unwrap());
+            }
+            headers
+        };
+        let near = |wait: Option<std::time::Duration>, secs: u64| {
+            wait.is_some_and(|wait| wait <= std::time::Duration::from_secs(secs) && wait + std::time::Duration::from_secs(2) > std::time::Duration::from_secs(secs))
+        };
+        let in_secs = |secs: i64| (Utc::now() + crate::time::Duration::seconds(secs)).timestamp().to_string();
+
+        let backoff = ClientBackoff::from_headers(StatusCode::TOO_MANY_REQUESTS, &headers(&[(DEFAULT_RATE_LIMITED_UNTIL_HEADER, in_secs(10))]));
+        assert!(backoff.is_limited());
+        assert!(near(backoff.wait(), 10));
+
+        let backoff = ClientBackoff::from_headers(StatusCode::TOO_MANY_REQUESTS, &headers(&[("Retry-After", "5".to_string())]));
+        assert!(near(backoff.wait(), 5));
+
+        let backoff = ClientBackoff::from_headers(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new());
+        assert_eq!(backoff.wait(), Some(DEFAULT_BACKOFF));
+
+        // the quota is used up, but the request is allowed.
+        let backoff = ClientBackoff::from_headers(StatusCode::OK, &headers(&[
+            (IETF_RATE_LIMIT_REMAINING_HEADER, "0".to_string()),
+            (IETF_RATE_LIMIT_RESET_HEADER, "30".to_string()),
+        ]));
+        assert_eq!(backoff.remaining(), Some(0));
+        assert!(near(backoff.wait(), 30));
+
+        let backoff = ClientBackoff::from_headers(StatusCode::OK, &headers(&[
+            (DEFAULT_RATE_LIMIT_REMAINING_HEADER, "3".to_string()),
+            (DEFAULT_RATE_LIMIT_RESET_HEADER, in_secs(30)),
+        ]));
+        assert_eq!(backoff.remaining(), Some(3));
+        assert_eq!(backoff.wait(), None);
+
+        // a reset in the past does not wait.
+        let backoff = ClientBackoff::from_headers(StatusCode::TOO_MANY_REQUESTS, &headers(&[(DEFAULT_RATE_LIMITED_UNTIL_HEADER, in_secs(-10))]));
+        assert_eq!(backoff.wait(), Some(std::time::Duration::ZERO));
+    }
+}
@@ -1,20 +1,353 @@
-use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
 use actix_web::body::{BoxBody, MessageBody};
-use actix_web::http::StatusCode;
-use crate::error::Error;
-use crate::store::Store;
+use actix_web::http::header::{HeaderMap, ACCEPT_LANGUAGE};
+use actix_web::http::{Method, StatusCode};
+use crate::time::{DateTime, Utc};
+use crate::error::{Error, ErrorClass, StoreError};
+use crate::identifier::{CardinalityGuard, Fnv1a, KeyExtractor, Normalizer};
+use crate::store::atomic::AtomicWindow;
+use crate::store::{Store, Value};
+use crate::utils;
+use crate::utils::insert_header;
+
+pub mod presets;
 
 pub(crate) type FromRequestFunc<I> = fn(&HttpRequest) -> I;
 pub(crate) type FromRequestWithRef<S, V> = fn(&HttpRequest, &S, Option<&V>);
 pub(crate) type FromRequestOnError<E, R> = fn(&HttpRequest, E) -> R;
+pub(crate) type FromRequestResponse<R> = fn(&HttpRequest) -> R;
+pub(crate) type FromRequestThreshold<V> = fn(&HttpRequest, &V);
+pub(crate) type FromRequestFrozen<V> = fn(&HttpRequest, Option<&V>, bool);
+pub(crate) type NormalizeFunc<K> = fn(&Normalizer, K) -> K;
+pub(crate) type GuardFunc<K> = fn(&CardinalityGuard, K) -> K;
+pub(crate) type FromRequestShadow<V> = fn(&HttpRequest, &V);
+
+/// [MessageProvider] returns the message of the rate-limit responses for the request
+/// and its locale (see [preferred_locale]), or [None] for no message.
+/// See [Controller::with_message_provider].
+pub type MessageProvider = fn(&HttpRequest, &str) -> Option<String>;
+
+/// The locale of the requests without an `Accept-Language` header.
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// [RateLimitMessage] is the message of [Controller::with_message_provider] for a rate-limited
+/// request. It is inserted into the extensions of the request before [Controller::on_rate_limit_error]
+/// is called, so a custom response can use the localized message.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RateLimitMessage {
+    /// The [preferred_locale] of the request, or [DEFAULT_LOCALE].
+    pub locale: String,
+    pub message: String,
+}
+
+impl RateLimitMessage {
+    /// Return the message of the rate-limited `req`, or [None] if the provider has none.
+    pub fn from_request(req: &HttpRequest) -> Option<RateLimitMessage> {
+        req.extensions().get::<RateLimitMessage>().cloned()
+    }
+
+    /// Return the message of `provider` for `req`, in its preferred locale.
+    pub(crate) fn provide(req: &HttpRequest, provider: MessageProvider) -> Option<RateLimitMessage> {
+        let locale = preferred_locale(req).unwrap_or_else(|| DEFAULT_LOCALE.to_string());
+        let message = provider(req, &locale)?;
+        Some(Self { locale, message })
+    }
+}
 
-#[derive(Clone)]
 pub struct Controller<T: Store, B: MessageBody = BoxBody> {
     pub(crate) fn_do_rate_limit: Option<FromRequestFunc<bool>>,
     pub(crate) fn_find_identifier: Option<FromRequestFunc<T::Key>>,
     pub(crate) fn_on_rate_limit_error: Option<FromRequestOnError<Error, HttpResponse<B>>>,
     pub(crate) fn_on_store_error: Option<FromRequestOnError<<T as Store>::Error, HttpResponse<B>>>,
+    pub(crate) fn_on_any_store_error: Option<FromRequestOnError<StoreError, HttpResponse<B>>>,
     pub(crate) fn_on_success: Option<FromRequestWithRef<T, T::Value>>,
+    pub(crate) success_sampler: Option<Sampler>,
+    pub(crate) fn_on_store_timeout: Option<FromRequestResponse<HttpResponse<B>>>,
+    pub(crate) fn_on_invalid_signature: Option<FromRequestResponse<HttpResponse<B>>>,
+    pub(crate) store_timeout: Option<std::time::Duration>,
+    pub(crate) deadline_header: Option<&'static str>,
+    pub(crate) failure_policy: FailurePolicy,
+    pub(crate) forward_quota_headers: bool,
+    pub(crate) success_headers: SuccessHeaders,
+    pub(crate) name: Option<String>,
+    pub(crate) ignore_checked: bool,
+    pub(crate) grace: Option<<<T as Store>::Value as Value>::Count>,
+    pub(crate) threshold: Option<Threshold<T>>,
+    pub(crate) first_violation: Option<FirstViolation<T::Key, T::Value>>,
+    pub(crate) fn_on_frozen: Option<FromRequestFrozen<T::Value>>,
+    pub(crate) normalizer: Option<(Normalizer, NormalizeFunc<T::Key>)>,
+    pub(crate) cardinality: Option<(CardinalityGuard, GuardFunc<T::Key>)>,
+    pub(crate) rollout: Option<Rollout<T::Key>>,
+    pub(crate) fn_on_shadow_limited: Option<FromRequestShadow<T::Value>>,
+    pub(crate) tarpit: Option<Tarpit>,
+    pub(crate) message_provider: Option<MessageProvider>,
+    pub(crate) key_extractor: Option<Arc<dyn KeyExtractor<T::Key>>>,
+}
+
+impl<T: Store, B: MessageBody> Clone for Controller<T, B> {
+    fn clone(&self) -> Self {
+        Self {
+            fn_do_rate_limit: self.fn_do_rate_limit,
+            fn_find_identifier: self.fn_find_identifier,
+            fn_on_rate_limit_error: self.fn_on_rate_limit_error,
+            fn_on_store_error: self.fn_on_store_error,
+            fn_on_any_store_error: self.fn_on_any_store_error,
+            fn_on_success: self.fn_on_success,
+            success_sampler: self.success_sampler.clone(),
+            fn_on_store_timeout: self.fn_on_store_timeout,
+            fn_on_invalid_signature: self.fn_on_invalid_signature,
+            store_timeout: self.store_timeout,
+            deadline_header: self.deadline_header,
+            failure_policy: self.failure_policy,
+            forward_quota_headers: self.forward_quota_headers,
+            success_headers: self.success_headers,
+            name: self.name.clone(),
+            ignore_checked: self.ignore_checked,
+            grace: self.grace.clone(),
+            threshold: self.threshold.clone(),
+            first_violation: self.first_violation.clone(),
+            fn_on_frozen: self.fn_on_frozen,
+            normalizer: self.normalizer.clone(),
+            cardinality: self.cardinality.clone(),
+            rollout: self.rollout.clone(),
+            fn_on_shadow_limited: self.fn_on_shadow_limited,
+            tarpit: self.tarpit.clone(),
+            message_provider: self.message_provider,
+            key_extractor: self.key_extractor.clone(),
+        }
+    }
+}
+
+/// [Tarpit] delays the rate-limit responses, see [Controller::with_tarpit].
+#[derive(Debug, Clone)]
+pub(crate) struct Tarpit {
+    pub delay: std::time::Duration,
+    pub jitter: std::time::Duration,
+    pub max_concurrent: usize,
+    /// the number of the responses being delayed, shared by the clones.
+    pub active: Arc<AtomicUsize>,
+}
+
+impl Tarpit {
+    /// Wait for the delay, or return at once if `max_concurrent` responses are being delayed.
+    pub async fn wait(&self) {
+        struct Active<'a>(&'a AtomicUsize);
+
+        impl Drop for Active<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+
+        let active = Active(&self.active);
+        if active.0.fetch_add(1, Ordering::AcqRel) >= self.max_concurrent {
+            return;
+        }
+
+        tokio::time::sleep(self.delay + self.random_jitter()).await;
+    }
+
+    /// Return a pseudo-random duration in `[0, jitter)`.
+    fn random_jitter(&self) -> std::time::Duration {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+
+        let jitter = self.jitter.as_nanos() as u64;
+        if jitter == 0 {
+            return std::time::Duration::ZERO;
+        }
+
+        let seed = (Utc::now().timestamp_nanos_opt(), SEQ.fetch_add(1, Ordering::Relaxed));
+        std::time::Duration::from_nanos(Fnv1a::hash(&seed) % jitter)
+    }
+}
+
+/// [Rollout] enforces the limits for a percentage of keys, see [Controller::with_rollout_percent].
+pub(crate) struct Rollout<K> {
+    pub percent: u8,
+    pub hash: fn(&K) -> u64,
+}
+
+impl<K> Clone for Rollout<K> {
+    fn clone(&self) -> Self {
+        Self {
+            percent: self.percent,
+            hash: self.hash,
+        }
+    }
+}
+
+impl<K> Rollout<K> {
+    /// Check if the limits are enforced for `key`.
+    pub fn enforces(&self, key: &K) -> bool {
+        (self.hash)(key) % 100 < self.percent as u64
+    }
+}
+
+/// [Threshold] is the soft limit set by [Controller::on_threshold].
+#[derive(Clone)]
+pub(crate) struct Threshold<T: Store> {
+    pub fraction: f64,
+    pub hook: FromRequestThreshold<T::Value>,
+    pub to_f64: fn(&<T::Value as Value>::Count) -> f64,
+    pub charge_to_f64: fn(&T::Count) -> f64,
+}
+
+impl<T: Store> Threshold<T> {
+    /// Check if the request charged by `charge` (1 if [None], the default increment of the
+    /// store) moved the count from below the threshold to `count`, at or over it.
+    pub fn crossed(&self, max: &<T::Value as Value>::Count, count: &<T::Value as Value>::Count, charge: Option<&T::Count>) -> bool {
+        let threshold = self.fraction * (self.to_f64)(max);
+        let count = (self.to_f64)(count);
+        let previous = count - charge.map_or(1.0, self.charge_to_f64);
+        previous < threshold && threshold <= count
+    }
+}
+
+/// [Sampler] picks a part of the calls, see [Controller::with_success_sampling].
+/// Clones share the same state.
+///
+/// ```rust
+/// use actix_rl::controller::Sampler;
+///
+/// let sampler = Sampler::one_in(100);
+/// assert_eq!((0..1000).filter(|_| sampler.sample()).count(), 10);
+///
+/// // log at most 5 allowed requests per second.
+/// let controller = actix_rl::controller::Controller::<actix_rl::store::mem_store::MemStore>::default()
+///     .on_success(|req, _, _| println!("allowed: {}", req.path()))
+///     .with_success_sampling(Sampler::per_second(5));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Sampler(SampleMode);
+
+#[derive(Debug, Clone)]
+enum SampleMode {
+    /// `n` and the number of calls.
+    OneIn(u64, Arc<AtomicU64>),
+    /// the rate and the calls in the current second.
+    PerSecond(u32, Arc<AtomicWindow>),
+}
+
+impl Sampler {
+    /// Pick 1 in `n` calls, starting with the first one.
+    /// `0` is treated as `1`, picking all calls.
+    pub fn one_in(n: u64) -> Self {
+        Self(SampleMode::OneIn(n.max(1), Arc::new(AtomicU64::new(0))))
+    }
+
+    /// Pick at most `rate` calls per second (aligned to the unix epoch).
+    pub fn per_second(rate: u32) -> Self {
+        Self(SampleMode::PerSecond(rate, Arc::new(AtomicWindow::default())))
+    }
+
+    /// Check if this call is picked.
+    pub fn sample(&self) -> bool {
+        match &self.0 {
+            SampleMode::OneIn(n, calls) => calls.fetch_add(1, Ordering::Relaxed) % n == 0,
+            SampleMode::PerSecond(rate, window) => window.incr(Utc::now().timestamp() as u32, 1) <= *rate,
+        }
+    }
+}
+
+/// [FirstViolation] is the hook set by [Controller::on_first_violation].
+/// Clones share the keys already notified.
+pub(crate) struct FirstViolation<K, V: Value> {
+    pub hook: FromRequestThreshold<V>,
+    pub hash: fn(&K) -> u64,
+    notified: Arc<Mutex<Notified>>,
+}
+
+/// [Notified] is the end of the window of each key (by hash) already notified.
+#[derive(Default)]
+struct Notified {
+    until: HashMap<u64, DateTime<Utc>>,
+    /// The number of keys at which the ended windows are removed.
+    sweep: usize,
+}
+
+impl<K, V: Value> Clone for FirstViolation<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            hook: self.hook,
+            hash: self.hash,
+            notified: self.notified.clone(),
+        }
+    }
+}
+
+impl<K, V: Value> FirstViolation<K, V> {
+    pub fn new(hook: FromRequestThreshold<V>, hash: fn(&K) -> u64) -> Self {
+        Self {
+            hook,
+            hash,
+            notified: Default::default(),
+        }
+    }
+
+    /// Check if no violation of `key` was notified in the window of `value`, and mark it as notified.
+    /// The violations without a key are notified as one key, and those without a window once.
+    pub fn first(&self, key: Option<&K>, value: &V) -> bool {
+        let now = Utc::now();
+        let hash = key.map_or(0, self.hash);
+        let mut notified = self.notified.lock().unwrap_or_else(PoisonError::into_inner);
+        if notified.until.get(&hash).is_some_and(|until| *until > now) {
+            return false;
+        }
+
+        if notified.until.len() >= notified.sweep {
+            notified.until.retain(|_, until| *until > now);
+            notified.sweep = (notified.until.len() * 2).max(1024);
+        }
+        notified.until.insert(hash, value.expire_date().unwrap_or(DateTime::<Utc>::MAX_UTC));
+        true
+    }
+}
+
+/// [SuccessHeaders] defines which rate-limit headers are inserted
+/// into the responses of allowed requests.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum SuccessHeaders {
+    /// Do not insert any header.
+    #[default]
+    None,
+    /// Insert [DEFAULT_RATE_LIMIT_LIMIT_HEADER], [DEFAULT_RATE_LIMIT_REMAINING_HEADER]
+    /// and [DEFAULT_RATE_LIMIT_RESET_HEADER] (as a unix timestamp).
+    Standard,
+    /// Insert the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+    /// (as seconds until reset) headers, as described by the IETF draft.
+    Ietf,
+}
+
+/// [FailurePolicy] decides what to do with the request when the [Store]
+/// returns an error or does not respond in time.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum FailurePolicy {
+    /// Reject the request, with the response from [Controller::on_store_error]
+    /// or [Controller::on_store_timeout].
+    #[default]
+    Closed,
+    /// Let the request pass, as if it is not checked.
+    Open,
+    /// Let the request pass on timeouts and [ErrorClass::Transient] errors,
+    /// reject the request on [ErrorClass::Fatal] errors.
+    /// See [Store::classify_error].
+    OpenOnTransient,
+}
+
+impl FailurePolicy {
+    /// Check if the request should pass on an error of `class`.
+    pub fn is_open(&self, class: ErrorClass) -> bool {
+        match self {
+            Self::Closed => false,
+            Self::Open => true,
+            Self::OpenOnTransient => class == ErrorClass::Transient,
+        }
+    }
 }
 
 impl<T: Store, B: MessageBody> Controller<T, B> {
@@ -25,7 +358,29 @@ impl<T: Store, B: MessageBody> Controller<T, B> {
             fn_find_identifier: None,
             fn_on_rate_limit_error: None,
             fn_on_store_error: None,
+            fn_on_any_store_error: None,
             fn_on_success: None,
+            success_sampler: None,
+            fn_on_store_timeout: None,
+            fn_on_invalid_signature: None,
+            store_timeout: None,
+            deadline_header: None,
+            failure_policy: FailurePolicy::Closed,
+            forward_quota_headers: false,
+            success_headers: SuccessHeaders::None,
+            name: None,
+            ignore_checked: false,
+            grace: None,
+            threshold: None,
+            first_violation: None,
+            fn_on_frozen: None,
+            normalizer: None,
+            cardinality: None,
+            rollout: None,
+            fn_on_shadow_limited: None,
+            tarpit: None,
+            message_provider: None,
+            key_extractor: None,
         }
     }
 
@@ -36,18 +391,80 @@ impl<T: Store, B: MessageBody> Controller<T, B> {
         self
     }
 
+    /// Skip the infrastructure endpoints ([INFRA_ENDPOINTS], such as `/healthz` and `/metrics`)
+    /// and the `OPTIONS` requests (such as CORS preflights), see [is_infra_request].
+    /// It replaces [Self::with_do_rate_limit].
+    pub fn skip_infra_endpoints(self) -> Self {
+        self.with_do_rate_limit(|req| !is_infra_request(req))
+    }
+
     /// Extract the identifier from the request, such as the IP address or other information.
+    /// It replaces [Self::with_key_extractor].
     pub fn with_find_identifier(mut self, f: FromRequestFunc<T::Key>) -> Self {
         self.fn_find_identifier = Some(f);
+        self.key_extractor = None;
+        self
+    }
+
+    /// Extract the identifier from the request with `extractor`, such as [PeerIpKey](crate::identifier::PeerIpKey),
+    /// [HeaderKey](crate::identifier::HeaderKey) or a closure. The requests without an identifier are not counted.
+    /// It replaces [Self::with_find_identifier].
+    ///
+    /// ```rust
+    /// use actix_rl::controller::Controller;
+    /// use actix_rl::identifier::HeaderKey;
+    /// use actix_rl::store::mem_store::MemStore;
+    ///
+    /// let controller: Controller<MemStore> = Controller::default()
+    ///     .with_key_extractor(HeaderKey::new("X-Api-Key"));
+    /// ```
+    pub fn with_key_extractor<E: KeyExtractor<T::Key> + 'static>(mut self, extractor: E) -> Self {
+        self.key_extractor = Some(Arc::new(extractor));
+        self.fn_find_identifier = None;
         self
     }
 
+    /// Check if the identifier is extracted, by [Self::with_find_identifier] or [Self::with_key_extractor].
+    pub(crate) fn has_identifier(&self) -> bool {
+        self.fn_find_identifier.is_some() || self.key_extractor.is_some()
+    }
+
+    /// Return the identifier of `req`, see [Self::with_key_extractor].
+    pub(crate) fn find_identifier(&self, req: &HttpRequest) -> Option<T::Key> {
+        match &self.key_extractor {
+            Some(extractor) => extractor.extract(req),
+            None => self.fn_find_identifier.map(|f| f(req)),
+        }
+    }
+
     /// Set the [`HttpResponse<B>`] to be returned when a rate-limit error occurs.
     pub fn on_rate_limit_error(mut self, f: FromRequestOnError<Error, HttpResponse<B>>) -> Self {
         self.fn_on_rate_limit_error = Some(f);
         self
     }
 
+    /// Localize the default rate-limit responses with `provider`: the message for the
+    /// [preferred_locale] of the request is returned as the JSON body
+    /// `{"error":"rate_limited","message":"…"}`, with the `Content-Language` header.
+    ///
+    /// With [Self::on_rate_limit_error] (set before or after), the custom response is
+    /// returned, and can read the message with [RateLimitMessage::from_request].
+    ///
+    /// ```rust
+    /// use actix_rl::controller::Controller;
+    /// use actix_rl::store::mem_store::MemStore;
+    ///
+    /// let controller: Controller<MemStore> = Controller::default()
+    ///     .with_message_provider(|_, locale| Some(match locale {
+    ///         "fr" => "Trop de requêtes, réessayez plus tard.",
+    ///         _ => "Too many requests, try again later.",
+    ///     }.to_string()));
+    /// ```
+    pub fn with_message_provider(mut self, provider: MessageProvider) -> Self {
+        self.message_provider = Some(provider);
+        self
+    }
+
     /// Set the [`HttpResponse<B>`] to be returned when an error occurs in the [Store]
     /// (such as Redis or other storage structures).
     pub fn on_store_error(mut self, f: FromRequestOnError<<T as Store>::Error, HttpResponse<B>>) -> Self {
@@ -55,38 +472,350 @@ impl<T: Store, B: MessageBody> Controller<T, B> {
         self
     }
 
+    /// Like [Self::on_store_error], but the error is wrapped in [StoreError],
+    /// so the same handler works for any [Store]. [Self::on_store_error] takes precedence.
+    pub fn on_any_store_error(mut self, f: FromRequestOnError<StoreError, HttpResponse<B>>) -> Self {
+        self.fn_on_any_store_error = Some(f);
+        self
+    }
+
+    /// Set the [`HttpResponse<B>`] to be returned when the [Store] does not respond
+    /// in time (see [Self::with_store_timeout]).
+    /// If not set, `503 Service Unavailable` is returned.
+    pub fn on_store_timeout(mut self, f: FromRequestResponse<HttpResponse<B>>) -> Self {
+        self.fn_on_store_timeout = Some(f);
+        self
+    }
+
+    /// Set the [`HttpResponse<B>`] to be returned when the signature of a request is invalid
+    /// (see [RateLimit::with_signature_check](crate::middleware::RateLimit::with_signature_check)).
+    /// If not set, `401 Unauthorized` is returned.
+    pub fn on_invalid_signature(mut self, f: FromRequestResponse<HttpResponse<B>>) -> Self {
+        self.fn_on_invalid_signature = Some(f);
+        self
+    }
+
+    /// Set the timeout of each [Store] call, so a slow [Store]
+    /// never stalls requests for longer than `timeout`.
+    /// When a timeout occurs, the [FailurePolicy] applies.
+    /// If not set, there is no timeout.
+    pub fn with_store_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.store_timeout = Some(timeout);
+        self
+    }
+
+    /// Bound the [Store] calls by the deadline of the request, read from the `header`
+    /// (such as [DEFAULT_REQUEST_TIMEOUT_HEADER]) as the milliseconds left to the caller
+    /// when the request arrives. Each call times out at the shorter of the time left
+    /// and [Self::with_store_timeout], and the [FailurePolicy] applies, so the limiter
+    /// never makes the request miss its deadline. The wait in the queue of
+    /// [RateLimit::with_queue](crate::middleware::RateLimit::with_queue) ends at the deadline too.
+    /// If not set, or if the header is missing or invalid, only [Self::with_store_timeout] applies.
+    pub fn with_deadline_header(mut self, header: &'static str) -> Self {
+        self.deadline_header = Some(header);
+        self
+    }
+
+    /// Set the [FailurePolicy] when the [Store] returns an error or times out.
+    /// If not set, [FailurePolicy::Closed] is used.
+    pub fn with_failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.failure_policy = policy;
+        self
+    }
+
     /// Execute this function whenever a request successfully passes
     /// (including those skipped by [Self::fn_do_rate_limit]).
     pub fn on_success(mut self, f: FromRequestWithRef<T, T::Value>) -> Self {
         self.fn_on_success = Some(f);
         self
     }
+
+    /// Call [Self::on_success] only for the requests picked by the [Sampler],
+    /// so logging the allowed requests stays cheap at scale.
+    /// If not set, it is called for every allowed request.
+    pub fn with_success_sampling(mut self, sampler: Sampler) -> Self {
+        self.success_sampler = Some(sampler);
+        self
+    }
+
+    /// Return the deadline of `req`, see [Self::with_deadline_header].
+    pub(crate) fn deadline(&self, req: &HttpRequest) -> Option<tokio::time::Instant> {
+        let millis = req.headers().get(self.deadline_header?)?.to_str().ok()?.trim().parse::<u64>().ok()?;
+        Some(tokio::time::Instant::now() + std::time::Duration::from_millis(millis))
+    }
+
+    /// Return the timeout of a [Store] call before `deadline`, see [Self::with_deadline_header].
+    pub(crate) fn timeout_until(&self, deadline: Option<tokio::time::Instant>) -> Option<std::time::Duration> {
+        let left = deadline.map(|deadline| deadline.saturating_duration_since(tokio::time::Instant::now()));
+        match (self.store_timeout, left) {
+            (Some(timeout), Some(left)) => Some(timeout.min(left)),
+            (timeout, left) => timeout.or(left),
+        }
+    }
+
+    /// Return the [Self::on_success] hook, if the request is sampled.
+    pub(crate) fn sampled_on_success(&self) -> Option<FromRequestWithRef<T, T::Value>> {
+        self.fn_on_success.filter(|_| self.success_sampler.as_ref().is_none_or(Sampler::sample))
+    }
+
+    /// Insert [DEFAULT_RATE_LIMIT_LIMIT_HEADER] and [DEFAULT_RATE_LIMIT_REMAINING_HEADER]
+    /// into the request forwarded to the inner services, so services behind
+    /// the middleware can make their own decisions.
+    ///
+    /// Headers with the same names sent by the client are always removed.
+    /// If not set, the request headers are untouched.
+    pub fn with_forward_quota_headers(mut self, enable: bool) -> Self {
+        self.forward_quota_headers = enable;
+        self
+    }
+
+    /// Insert rate-limit headers into the responses of allowed requests,
+    /// using the [Value](crate::store::Value) captured during the check.
+    /// If not set, the responses are untouched.
+    ///
+    /// The headers are inserted before the body is sent, including for streaming
+    /// responses; actix-web cannot send HTTP trailers, so there is no quota feedback
+    /// after the body.
+    pub fn with_success_headers(mut self, policy: SuccessHeaders) -> Self {
+        self.success_headers = policy;
+        self
+    }
+
+    /// Set the name of the limiter.
+    ///
+    /// By default, a request checked by any limiter is not checked again.
+    /// With a name, the "already checked" marker is scoped to the limiters with
+    /// the same name, so nested scopes can have different limiters.
+    pub fn with_name<N: ToString>(mut self, name: N) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Ignore the "already checked" marker, always check the requests,
+    /// even if they have been checked by other limiters.
+    pub fn with_ignore_checked(mut self, ignore: bool) -> Self {
+        self.ignore_checked = ignore;
+        self
+    }
+
+    /// Allow `grace` extra requests over `max` in each window.
+    ///
+    /// The requests within the grace margin pass, but are flagged by
+    /// [RateLimitByPass::is_grace](crate::utils::RateLimitByPass::is_grace),
+    /// so the services can warn the users before the hard rejection.
+    /// If not set, all requests over `max` are rejected.
+    ///
+    /// The margin applies to all the limits of the middleware, including the scoped policies
+    /// and the levels of [RateLimit::with_hierarchy](crate::middleware::RateLimit::with_hierarchy).
+    /// It only applies to the counts at or over `max`: the requests limited below
+    /// `max` by a custom [LimitEvaluator](crate::policy::LimitEvaluator) are rejected.
+    pub fn with_grace(mut self, grace: <<T as Store>::Value as Value>::Count) -> Self {
+        self.grace = Some(grace);
+        self
+    }
+
+    /// Execute this function for the requests of frozen keys
+    /// (see [RateLimit::freeze](crate::middleware::RateLimit::freeze)), with the current value
+    /// of the key and whether the key is limited by the middleware's evaluator, so the decisions
+    /// can be logged without affecting the users.
+    pub fn on_frozen(mut self, f: FromRequestFrozen<T::Value>) -> Self {
+        self.fn_on_frozen = Some(f);
+        self
+    }
+
+    /// Enforce the limits only for `percent` (0 to 100) of the keys, chosen by a
+    /// deterministic hash of the key, so all instances agree and a key stays enforced
+    /// as `percent` grows. The other keys run in shadow mode: they are counted,
+    /// but the requests over the limit pass (see [Self::on_shadow_limited]).
+    /// Ramp it from 1 to 100 to roll out the enforcement safely.
+    /// If not set, the limits are enforced for all keys.
+    pub fn with_rollout_percent(mut self, percent: u8) -> Self
+        where T::Key: Hash,
+    {
+        self.rollout = Some(Rollout {
+            percent: percent.min(100),
+            hash: Fnv1a::hash,
+        });
+        self
+    }
+
+    /// Execute this function for the requests which would have been rejected,
+    /// but pass since their keys run in shadow mode (see [Self::with_rollout_percent]).
+    pub fn on_shadow_limited(mut self, f: FromRequestShadow<T::Value>) -> Self {
+        self.fn_on_shadow_limited = Some(f);
+        self
+    }
+
+    /// Delay the rate-limit responses by `delay`, plus a random duration up to `jitter`,
+    /// to slow down naive scrapers. At most `max_concurrent` responses are delayed at once,
+    /// the others are returned at once, so the tarpit cannot exhaust the server.
+    /// If not set, the rate-limit responses are returned at once.
+    pub fn with_tarpit(mut self, delay: std::time::Duration, jitter: std::time::Duration, max_concurrent: usize) -> Self {
+        self.tarpit = Some(Tarpit {
+            delay,
+            jitter,
+            max_concurrent,
+            active: Arc::new(AtomicUsize::new(0)),
+        });
+        self
+    }
+
+    /// Execute `hook` when the count of a key crosses `fraction` of `max`
+    /// (such as `0.8` for 80%), once per window, so the users can be warned
+    /// (with a response header, an email or a metric) before they get blocked.
+    ///
+    /// Only allowed requests are checked.
+    pub fn on_threshold(mut self, fraction: f64, hook: FromRequestThreshold<T::Value>) -> Self
+        where
+            <<T as Store>::Value as Value>::Count: Into<f64>,
+            T::Count: Into<f64>,
+    {
+        self.threshold = Some(Threshold {
+            fraction,
+            hook,
+            to_f64: |count| count.clone().into(),
+            charge_to_f64: |charge| charge.clone().into(),
+        });
+        self
+    }
+
+    /// Execute `hook` on the first rejected request of a key in each window,
+    /// before [Self::on_rate_limit_error], so an abusive client raises
+    /// one alert per window instead of one per rejected request.
+    ///
+    /// The keys already notified are remembered in this process until their windows
+    /// (the reset time of the value over the limit) end, whatever the cost of the requests
+    /// and the [LimitSemantics](crate::policy::LimitSemantics). So each instance sharing
+    /// the [Store] fires once per window of the key.
+    pub fn on_first_violation(mut self, hook: FromRequestThreshold<T::Value>) -> Self
+        where T::Key: Hash,
+    {
+        self.first_violation = Some(FirstViolation::new(hook, Fnv1a::hash));
+        self
+    }
+}
+
+impl<T, B> Controller<T, B>
+    where
+        T: Store<Key = String>,
+        B: MessageBody,
+{
+    /// Normalize the identifiers extracted by [Self::with_find_identifier]
+    /// (such as lowercasing, trimming and truncation), see [Normalizer].
+    /// If not set, the identifiers are used as is.
+    pub fn with_normalizer(mut self, normalizer: Normalizer) -> Self {
+        self.normalizer = Some((normalizer, |normalizer, key| normalizer.normalize(&key)));
+        self
+    }
+
+    /// Limit the distinct paths of each identifier, for the identifiers in the form
+    /// `{identifier}:{path}`, such as [find_identifier_by_path]. See [CardinalityGuard].
+    /// The guard is applied after the [Normalizer].
+    pub fn with_cardinality_guard(mut self, guard: CardinalityGuard) -> Self {
+        self.cardinality = Some((guard, |guard, key| guard.guard(key)));
+        self
+    }
 }
 
 impl<T> Default for Controller<T, BoxBody>
     where T: Store<Key = String> + 'static,
 {
     /// alias of [Self::new], but use default functions.
+    /// The rate-limit responses are the default ones, localized by [Self::with_message_provider].
     fn default() -> Self {
         Self::new()
             .with_do_rate_limit(default_do_rate_limit)
             .with_find_identifier(default_find_identifier)
-            .on_rate_limit_error(default_on_rate_limit_error)
             .on_store_error(default_on_store_error::<T>)
     }
 }
 
+/// Insert the headers described by `policy` into `headers`.
+pub(crate) fn insert_success_headers<V: Value>(
+    headers: &mut HeaderMap,
+    policy: SuccessHeaders,
+    max: &V::Count,
+    value: &V,
+) {
+    let (limit, remaining, reset) = match policy {
+        SuccessHeaders::None => return,
+        SuccessHeaders::Standard => (
+            DEFAULT_RATE_LIMIT_LIMIT_HEADER,
+            DEFAULT_RATE_LIMIT_REMAINING_HEADER,
+            value.expire_date().map(|until| (DEFAULT_RATE_LIMIT_RESET_HEADER, until.timestamp())),
+        ),
+        SuccessHeaders::Ietf => (
+            IETF_RATE_LIMIT_LIMIT_HEADER,
+            IETF_RATE_LIMIT_REMAINING_HEADER,
+            value.expire_date().map(|until| (IETF_RATE_LIMIT_RESET_HEADER, (until - Utc::now()).num_seconds().max(0))),
+        ),
+    };
+
+    insert_header(headers, limit, max.to_string());
+    insert_header(headers, remaining, utils::remaining(max, &value.count()));
+    if let Some((reset, ts)) = reset {
+        insert_header(headers, reset, ts.to_string());
+    }
+}
+
 pub(crate) fn default_do_rate_limit(_: &HttpRequest) -> bool {
     true
 }
 
+/// The paths of the health checks and the metrics, skipped by [Controller::skip_infra_endpoints].
+pub const INFRA_ENDPOINTS: &[&str] = &["/healthz", "/livez", "/readyz", "/metrics"];
+
+/// Check if `req` is an `OPTIONS` request, or a request to one of [INFRA_ENDPOINTS]
+/// (with or without a trailing slash).
+pub fn is_infra_request(req: &HttpRequest) -> bool {
+    let path = req.path();
+    let path = path.strip_suffix('/').filter(|path| !path.is_empty()).unwrap_or(path);
+    req.method() == Method::OPTIONS || INFRA_ENDPOINTS.contains(&path)
+}
+
 pub(crate) fn default_find_identifier(req: &HttpRequest) -> String {
     req.peer_addr()
         .map(|addr| addr.ip().to_string())
         .unwrap_or("<Unknown Source IP>".to_string())
 }
 
+/// Return the matched route pattern of the request (such as `/users/{id}`),
+/// or the raw path if no route matches.
+///
+/// Use it in [Controller::with_do_rate_limit] so parameterized routes
+/// are treated uniformly.
+pub fn route_pattern(req: &HttpRequest) -> String {
+    req.match_pattern()
+        .unwrap_or_else(|| req.path().to_string())
+}
+
+/// Extract the identifier as the IP address, namespaced by the
+/// matched route pattern (see [route_pattern]), such as `127.0.0.1:/users/{id}`.
+///
+/// Requests to `/users/123` and `/users/456` share the same counter,
+/// while other routes have their own counters.
+pub fn find_identifier_by_route(req: &HttpRequest) -> String {
+    format!("{}:{}", default_find_identifier(req), route_pattern(req))
+}
+
+/// Extract the identifier as the IP address, namespaced by the raw path,
+/// such as `127.0.0.1:/users/123`.
+///
+/// Every path has its own counter, so use it with
+/// [Controller::with_cardinality_guard] to bound the number of keys.
+pub fn find_identifier_by_path(req: &HttpRequest) -> String {
+    format!("{}:{}", default_find_identifier(req), req.path())
+}
+
+/// The header of the milliseconds left to the caller, see [Controller::with_deadline_header].
+pub const DEFAULT_REQUEST_TIMEOUT_HEADER: &str = "X-Request-Timeout";
 pub const DEFAULT_RATE_LIMITED_UNTIL_HEADER: &str = "X-Rate-Limited-Until";
+pub const DEFAULT_RATE_LIMIT_LIMIT_HEADER: &str = "X-RateLimit-Limit";
+pub const DEFAULT_RATE_LIMIT_REMAINING_HEADER: &str = "X-RateLimit-Remaining";
+pub const DEFAULT_RATE_LIMIT_RESET_HEADER: &str = "X-RateLimit-Reset";
+pub const IETF_RATE_LIMIT_LIMIT_HEADER: &str = "RateLimit-Limit";
+pub const IETF_RATE_LIMIT_REMAINING_HEADER: &str = "RateLimit-Remaining";
+pub const IETF_RATE_LIMIT_RESET_HEADER: &str = "RateLimit-Reset";
 
 pub(crate) fn default_on_rate_limit_error(_: &HttpRequest, error: Error) -> HttpResponse {
     match error {
@@ -102,6 +831,53 @@ pub(crate) fn default_on_rate_limit_error(_: &HttpRequest, error: Error) -> Http
     }
 }
 
+/// Return the default rate-limit response, localized by `provider` (see [Controller::with_message_provider]).
+pub(crate) fn localized_on_rate_limit_error(req: &HttpRequest, error: Error, message: Option<RateLimitMessage>) -> HttpResponse {
+    let mut res = default_on_rate_limit_error(req, error);
+
+    if let Some(RateLimitMessage { locale, message }) = message {
+        let mut body = r#"{"error":"rate_limited","message":"#.to_string();
+        utils::write_json_string(&mut body, &message);
+        body.push('}');
+
+        let headers = res.headers_mut();
+        insert_header(headers, "Content-Type", "application/json".to_string());
+        insert_header(headers, "Content-Language", locale);
+        res = res.set_body(BoxBody::new(body));
+    }
+    res
+}
+
+/// Return the language of the `Accept-Language` header of `req` with the highest quality,
+/// such as `fr` for `fr-CH, fr;q=0.9, en;q=0.8`, ignoring the region and `*`.
+pub fn preferred_locale(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get(ACCEPT_LANGUAGE)?.to_str().ok()?;
+    header.split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim();
+            let language = tag.split('-').next()?.to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!language.is_empty() && language != "*" && quality > 0.0).then_some((language, quality))
+        })
+        // the first of the languages with the same quality.
+        .fold(None, |best: Option<(String, f32)>, (language, quality)| match best {
+            Some((_, best_quality)) if best_quality >= quality => best,
+            _ => Some((language, quality)),
+        })
+        .map(|(language, _)| language)
+}
+
+pub(crate) fn default_on_invalid_signature(_: &HttpRequest) -> HttpResponse {
+    HttpResponse::new(StatusCode::UNAUTHORIZED)
+}
+
 pub(crate) fn default_on_store_error<T: Store>(_: &HttpRequest, _: T::Error) -> HttpResponse {
     HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
 }
+
+pub(crate) fn default_on_store_timeout(_: &HttpRequest) -> HttpResponse {
+    HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE)
+}
@@ -0,0 +1,70 @@
+//! Ready-made [Controller]s for common cases, to start from a working policy in one line
+//! and adjust it with the builders of [Controller].
+//!
+//! ```rust
+//! use actix_rl::controller::presets;
+//! use actix_rl::middleware::RateLimit;
+//! use actix_rl::store::mem_store::MemStore;
+//!
+//! let store = MemStore::new(1024, actix_rl::time::Duration::minutes(1));
+//! let rate_limit = RateLimit::new(store, 600, presets::api_gateway())
+//!     .with_labels(actix_rl::policy::PolicyLabels::new().with_label("preset", "api_gateway"));
+//! ```
+
+use actix_web::HttpRequest;
+use crate::controller::{find_identifier_by_path, Controller, FailurePolicy, SuccessHeaders};
+use crate::store::Store;
+
+/// The timeout of the [Store] calls of the presets, except [login_protection].
+pub const PRESET_STORE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// The message of the JSON rate-limit responses of [api_gateway].
+pub const RATE_LIMITED_MESSAGE: &str = "Too many requests, try again later.";
+
+fn rate_limited_message(_: &HttpRequest, _: &str) -> Option<String> {
+    Some(RATE_LIMITED_MESSAGE.to_string())
+}
+
+/// For APIs: requests are counted by IP address, except the infrastructure endpoints
+/// (see [Controller::skip_infra_endpoints]). The allowed responses carry the
+/// [SuccessHeaders::Standard] headers, the rejected ones the JSON body
+/// `{"error":"rate_limited","message":"…"}` (see [Controller::with_message_provider]).
+/// Requests pass when the [Store] is slow or unavailable ([FailurePolicy::OpenOnTransient]
+/// after [PRESET_STORE_TIMEOUT]).
+pub fn api_gateway<T: Store<Key = String> + 'static>() -> Controller<T> {
+    Controller::default()
+        .skip_infra_endpoints()
+        .with_success_headers(SuccessHeaders::Standard)
+        .with_message_provider(rate_limited_message)
+        .with_store_timeout(PRESET_STORE_TIMEOUT)
+        .with_failure_policy(FailurePolicy::OpenOnTransient)
+}
+
+/// For login and other credential endpoints: attempts are counted by IP address and path
+/// (see [find_identifier_by_path]), requests are rejected when the [Store] fails
+/// ([FailurePolicy::Closed]), and the rejections are delayed by a tarpit of 1 to 2 seconds
+/// to slow down credential stuffing.
+///
+/// All attempts are counted, successful or not. For bans escalating with the violations,
+/// see [RedisStore::escalate](crate::store::redis_store::RedisStore::escalate).
+pub fn login_protection<T: Store<Key = String> + 'static>() -> Controller<T> {
+    Controller::default()
+        .with_find_identifier(find_identifier_by_path)
+        .with_failure_policy(FailurePolicy::Closed)
+        .with_tarpit(std::time::Duration::from_secs(1), std::time::Duration::from_secs(1), 32)
+}
+
+/// For public websites: requests are counted by IP address, except the infrastructure
+/// endpoints, without headers, and the rejections of scrapers are delayed by a tarpit
+/// of 2 to 3 seconds. Requests pass when the [Store] fails ([FailurePolicy::Open]
+/// after [PRESET_STORE_TIMEOUT]), so the site stays up.
+///
+/// Give crawlers and browsers their own limits with
+/// [RateLimit::with_user_agent_policies](crate::middleware::RateLimit::with_user_agent_policies).
+pub fn public_site<T: Store<Key = String> + 'static>() -> Controller<T> {
+    Controller::default()
+        .skip_infra_endpoints()
+        .with_tarpit(std::time::Duration::from_secs(2), std::time::Duration::from_secs(1), 64)
+        .with_store_timeout(PRESET_STORE_TIMEOUT)
+        .with_failure_policy(FailurePolicy::Open)
+}
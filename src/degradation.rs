@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use actix_web::{web, HttpResponse};
+use crate::time::{DateTime, Utc};
+
+/// [DegradationStatus] tells whether the limits are currently enforced accurately,
+/// see [RateLimit::degradation](crate::middleware::RateLimit::degradation).
+///
+/// The middleware is degraded when the last call to the [Store](crate::store::Store) failed
+/// (or timed out) and the request was let through by [FailurePolicy](crate::controller::FailurePolicy),
+/// until a later call succeeds.
+///
+/// Only the fail-open of [FailurePolicy](crate::controller::FailurePolicy) is recorded:
+/// the crate has no circuit breaker nor fallback store, whose states would be recorded here.
+///
+/// It is exposed by the handle ([RateLimit::degradation](crate::middleware::RateLimit::degradation)
+/// or [LimiterRegistry::degradation](crate::registry::LimiterRegistry::degradation)), and by
+/// [degradation_route] on an admin scope of the application.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub struct DegradationStatus {
+    /// The time of the first failure of the current degradation, or [None] if not degraded.
+    pub degraded_since: Option<DateTime<Utc>>,
+    /// The time of the last failure which let a request through.
+    pub last_failure: Option<DateTime<Utc>>,
+    /// The number of requests let through on failures, since the middleware is created.
+    pub fail_open: u64,
+}
+
+impl DegradationStatus {
+    /// Check if the limits are currently not enforced accurately.
+    pub fn is_degraded(&self) -> bool {
+        self.degraded_since.is_some()
+    }
+
+    /// Return the status as JSON, with the times in unix milliseconds, such as
+    /// `{"degraded":true,"degraded_since":1714585333250,"last_failure":1714585334000,"fail_open":2}`.
+    pub fn to_json(&self) -> String {
+        let time = |time: Option<DateTime<Utc>>| time.map_or("null".to_string(), |time| time.timestamp_millis().to_string());
+        format!(
+            r#"{{"degraded":{},"degraded_since":{},"last_failure":{},"fail_open":{}}}"#,
+            self.is_degraded(),
+            time(self.degraded_since),
+            time(self.last_failure),
+            self.fail_open,
+        )
+    }
+}
+
+/// Return a route answering the [DegradationStatus] of `status` as JSON (see [DegradationStatus::to_json]),
+/// to be mounted on an admin scope (behind the authentication of the application).
+/// It answers `503 Service Unavailable` while degraded, so health checks can alert on it.
+///
+/// ```rust
+/// use actix_web::{web, App};
+/// use actix_rl::degradation::degradation_route;
+/// use actix_rl::middleware::RateLimit;
+/// use actix_rl::store::mem_store::MemStore;
+///
+/// let rate_limit: RateLimit<MemStore> = RateLimit::new(
+///     MemStore::new(1024, actix_rl::time::Duration::seconds(10)),
+///     10,
+///     actix_rl::controller::Controller::default(),
+/// );
+/// let handle = rate_limit.clone();
+/// let app = App::new()
+///     .service(web::scope("/admin")
+///         .route("/degradation", degradation_route(move || handle.degradation())))
+///     .wrap(rate_limit);
+/// ```
+pub fn degradation_route<F>(status: F) -> actix_web::Route
+    where F: Fn() -> DegradationStatus + Clone + 'static,
+{
+    web::get().to(move || {
+        let status = status();
+        async move {
+            let mut response = match status.is_degraded() {
+                true => HttpResponse::ServiceUnavailable(),
+                false => HttpResponse::Ok(),
+            };
+            response.content_type("application/json").body(status.to_json())
+        }
+    })
+}
+
+/// [Degradation] records the requests let through on failures of the [Store](crate::store::Store).
+#[derive(Debug, Default)]
+pub(crate) struct Degradation {
+    fail_open: AtomicU64,
+    /// the unix timestamps in milliseconds, or 0 if not set.
+    since: AtomicI64,
+    last_failure: AtomicI64,
+}
+
+impl Degradation {
+    /// Record a request let through on a failure.
+    pub fn fail_open(&self) {
+        let now = Utc::now().timestamp_millis();
+        self.fail_open.fetch_add(1, Ordering::Relaxed);
+        self.last_failure.store(now, Ordering::Relaxed);
+        let _ = self.since.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Record a successful call to the store, which ends the degradation.
+    pub fn recover(&self) {
+        // avoid writing the shared cache line on the hot path.
+        if self.since.load(Ordering::Relaxed) != 0 {
+            self.since.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn status(&self) -> DegradationStatus {
+        let time = |millis: &AtomicI64| match millis.load(Ordering::Relaxed) {
+            0 => None,
+            millis => DateTime::from_timestamp_millis(millis),
+        };
+        DegradationStatus {
+            degraded_since: time(&self.since),
+            last_failure: time(&self.last_failure),
+            fail_open: self.fail_open.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use actix_web::http::StatusCode;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_degradation_route() {
+        let degradation = std::sync::Arc::new(Degradation::default());
+        let handle = degradation.clone();
+        let app = test::init_service(
+            App::new().route("/degradation", degradation_route(move || handle.status()))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/degradation").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, r#"{"degraded":false,"degraded_since":null,"last_failure":null,"fail_open":0}"#);
+
+        degradation.fail_open();
+        let status = degradation.status();
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/degradation").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(test::read_body(resp).await, status.to_json());
+        assert!(status.to_json().starts_with(r#"{"degraded":true,"degraded_since":1"#));
+    }
+}
@@ -1,5 +1,5 @@
 use std::fmt::{Display, Formatter};
-use chrono::{DateTime, Utc};
+use crate::time::{DateTime, Utc};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Error {
@@ -21,3 +21,289 @@ impl Display for Error {
 }
 
 impl std::error::Error for Error {}
+
+/// [ErrorClass] classifies the errors of a [Store](crate::store::Store),
+/// so the [FailurePolicy](crate::controller::FailurePolicy) can treat them differently.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ErrorClass {
+    /// The error may disappear on retry, such as timeouts, dropped connections,
+    /// or cluster redirections.
+    Transient,
+    /// The error will not disappear on retry, such as authentication failures
+    /// or misconfigurations.
+    Fatal,
+}
+
+/// [StoreError] wraps the error of any [Store](crate::store::Store), so one
+/// [on_any_store_error](crate::controller::Controller::on_any_store_error)
+/// handler works for all stores.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StoreError {
+    /// The class of the error, see [Store::classify_error](crate::store::Store::classify_error).
+    pub class: ErrorClass,
+    /// The [Debug] output of the error.
+    pub message: String,
+}
+
+impl StoreError {
+    pub(crate) fn new<T: crate::store::Store>(store: &T, error: &T::Error) -> Self {
+        Self {
+            class: store.classify_error(error),
+            message: format!("{:?}", error),
+        }
+    }
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "store error: {}", self.message)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// [ConfigError] is returned when the configuration of [RateLimit](crate::middleware::RateLimit)
+/// or [Policy](crate::policy::Policy) would never limit or always limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigError {
+    /// The max is zero, so every request is limited.
+    ZeroMax,
+    /// The window (TTL) is zero or negative.
+    NonPositiveWindow(crate::time::Duration),
+    /// The rate of the token bucket is not a positive number.
+    InvalidRate(f64),
+    /// A cost (the increment of a request) is over the max, so every request costing it is limited.
+    CostOverMax,
+    /// The [Controller](crate::controller::Controller) has no identifier extractor
+    /// (see [with_find_identifier](crate::controller::Controller::with_find_identifier)),
+    /// so no request is counted.
+    MissingIdentifier,
+    /// A window overrides the window of the [Store](crate::store::Store), such as the window
+    /// of a policy, but the store ignores it (see [Store::supports_ttl](crate::store::Store::supports_ttl)).
+    UnsupportedWindow,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ZeroMax => write!(f, "max is zero, every request would be limited"),
+            Self::NonPositiveWindow(window) => write!(f, "window must be positive, got {}", window),
+            Self::InvalidRate(rate) => write!(f, "rate must be a positive number, got {}", rate),
+            Self::CostOverMax => write!(f, "cost is over the max, every request costing it would be limited"),
+            Self::MissingIdentifier => write!(f, "no identifier extractor, no request would be limited"),
+            Self::UnsupportedWindow => write!(f, "the store does not support the windows of the policies"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// [ParseDurationError] is returned when a window/TTL string,
+/// such as `"10s"` or `"1h30m"`, cannot be parsed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseDurationError {
+    /// The input is empty.
+    Empty,
+    /// A number is expected at the given position.
+    NumberExpected(usize),
+    /// The unit is missing or not supported.
+    UnknownUnit(String),
+    /// The number is too large to be represented.
+    Overflow,
+}
+
+impl Display for ParseDurationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty duration"),
+            Self::NumberExpected(pos) => write!(f, "expected number at {}", pos),
+            Self::UnknownUnit(unit) => write!(f, "unknown time unit {:?}", unit),
+            Self::Overflow => write!(f, "duration is too large"),
+        }
+    }
+}
+
+impl std::error::Error for ParseDurationError {}
+
+/// [LoadPoliciesError] is returned when the config of a [PolicySet](crate::policy_provider::PolicySet)
+/// cannot be loaded, see [PolicySet::load](crate::policy_provider::PolicySet::load).
+#[derive(Debug)]
+pub enum LoadPoliciesError {
+    /// The config file cannot be read.
+    Io(std::io::Error),
+    /// The config is not a JSON object of policies by name, such as with an invalid window.
+    Parse(String),
+    /// The policy of the name fails [Policy::validate](crate::policy::Policy::validate).
+    Invalid(String, ConfigError),
+}
+
+impl Display for LoadPoliciesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "cannot read policies: {}", e),
+            Self::Parse(e) => write!(f, "invalid policies: {}", e),
+            Self::Invalid(name, e) => write!(f, "invalid policy {:?}: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for LoadPoliciesError {}
+
+/// [ParseCidrError] is returned when a trusted proxy, such as
+/// `"10.0.0.0/8"` or `"::1"`, cannot be parsed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseCidrError(pub String);
+
+impl Display for ParseCidrError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid ip or cidr {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseCidrError {}
+
+/// [UnknownKeyError] is returned by [StaticStore](crate::store::static_store::StaticStore)
+/// when the key is not one of the pre-registered keys.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct UnknownKeyError(pub usize);
+
+impl Display for UnknownKeyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown key {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownKeyError {}
+
+/// [ParseExportError] is returned when the record at the given position (starting at 0)
+/// of an [export](crate::store::export::export) cannot be parsed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ParseExportError(pub usize);
+
+impl Display for ParseExportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid export record {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseExportError {}
+
+/// [InvalidProofError] is returned when a proof token is malformed or not signed
+/// by the secret, see [ProofSigner::verify](crate::proof::ProofSigner::verify).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct InvalidProofError;
+
+impl Display for InvalidProofError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid proof token")
+    }
+}
+
+impl std::error::Error for InvalidProofError {}
+
+/// [ImportError] is returned by `import` and [migrate](crate::store::export::migrate) of [export](crate::store::export).
+#[derive(Debug)]
+pub enum ImportError<E> {
+    /// A record cannot be parsed, or its count does not fit the [Store](crate::store::Store).
+    Parse(ParseExportError),
+    /// The [Store](crate::store::Store) failed; the records before it are restored.
+    Store(E),
+}
+
+impl<E: Display> Display for ImportError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "{}", e),
+            Self::Store(e) => write!(f, "store error: {}", e),
+        }
+    }
+}
+
+impl<E: std::fmt::Debug + Display> std::error::Error for ImportError<E> {}
+
+/// [MigrateError] is the error of the source or the destination [Store](crate::store::Store)
+/// of [migrate](crate::store::export::migrate).
+#[derive(Debug)]
+pub enum MigrateError<A, B> {
+    From(A),
+    To(B),
+}
+
+impl<A: Display, B: Display> Display for MigrateError<A, B> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::From(e) => write!(f, "source: {}", e),
+            Self::To(e) => write!(f, "destination: {}", e),
+        }
+    }
+}
+
+impl<A: std::fmt::Debug + Display, B: std::fmt::Debug + Display> std::error::Error for MigrateError<A, B> {}
+
+/// [DualWriteError] is the error of the store `A` or `B` of
+/// [DualWriteStore](crate::store::dual_write_store::DualWriteStore).
+#[derive(Debug)]
+pub enum DualWriteError<A, B> {
+    A(A),
+    B(B),
+}
+
+impl<A: Display, B: Display> Display for DualWriteError<A, B> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::A(e) => write!(f, "store A: {}", e),
+            Self::B(e) => write!(f, "store B: {}", e),
+        }
+    }
+}
+
+impl<A: std::fmt::Debug + Display, B: std::fmt::Debug + Display> std::error::Error for DualWriteError<A, B> {}
+
+/// [ChaosError] is the error of [ChaosStore](crate::store::chaos_store::ChaosStore).
+#[derive(Debug)]
+pub enum ChaosError<E> {
+    /// The error injected by the chaos.
+    Injected,
+    /// The error of the wrapped store.
+    Store(E),
+}
+
+impl<E> From<E> for ChaosError<E> {
+    fn from(e: E) -> Self {
+        Self::Store(e)
+    }
+}
+
+impl<E: Display> Display for ChaosError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Injected => write!(f, "injected error"),
+            Self::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: std::fmt::Debug + Display> std::error::Error for ChaosError<E> {}
+
+/// [PolicyRefreshError] is reported by [spawn_policy_refresh](crate::policy_provider::spawn_policy_refresh).
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyRefreshError<E> {
+    /// The [PolicyProvider](crate::policy_provider::PolicyProvider) failed.
+    Fetch(E),
+    /// The [PolicyProvider](crate::policy_provider::PolicyProvider) did not respond within the period.
+    Timeout,
+    /// The policy of the name is invalid, and is dropped.
+    Invalid(String, ConfigError),
+}
+
+impl<E: std::fmt::Debug> Display for PolicyRefreshError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fetch(e) => write!(f, "policy provider error: {:?}", e),
+            Self::Timeout => write!(f, "policy provider timed out"),
+            Self::Invalid(name, e) => write!(f, "invalid policy {}: {}", name, e),
+        }
+    }
+}
+
+impl<E: std::fmt::Debug> std::error::Error for PolicyRefreshError<E> {}
@@ -0,0 +1,888 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use actix_web::{HttpMessage, HttpRequest};
+use actix_web::http::header::{HeaderName, AUTHORIZATION};
+use base64::Engine;
+use crate::controller::default_find_identifier;
+use crate::error::ParseCidrError;
+
+/// The identifier of the requests whose identifiers contain control characters
+/// (see [Normalizer::with_reject_control]), so they share one counter.
+pub const INVALID_IDENTIFIER: &str = "<Invalid Identifier>";
+
+/// The path of the keys over the limit of [CardinalityGuard::new],
+/// so the other paths of the identifier share one counter.
+pub const OVERFLOW_PATH: &str = "<Other Paths>";
+
+/// [KeyExtractor] extracts the identifier (the key of the [Store](crate::store::Store))
+/// of a request, see [Controller::with_key_extractor](crate::controller::Controller::with_key_extractor).
+/// It returns [None] for the requests which are not counted.
+///
+/// Implemented by [PeerIpKey], [HeaderKey] and the closures of `&HttpRequest -> Option<K>`;
+/// other crates can implement it for their own extractors, and test them on their own
+/// with [TestRequest](actix_web::test::TestRequest).
+pub trait KeyExtractor<K>: Send + Sync {
+    /// Return the identifier of `req`, or [None] to not count it.
+    fn extract(&self, req: &HttpRequest) -> Option<K>;
+}
+
+impl<K, F: Fn(&HttpRequest) -> Option<K> + Send + Sync> KeyExtractor<K> for F {
+    fn extract(&self, req: &HttpRequest) -> Option<K> {
+        self(req)
+    }
+}
+
+/// [PeerIpKey] extracts the IP address of the peer, such as `127.0.0.1`,
+/// same as [Controller::default](crate::controller::Controller).
+/// Behind proxies, see [find_identifier_by_trusted_proxy].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerIpKey;
+
+impl<K: From<String>> KeyExtractor<K> for PeerIpKey {
+    fn extract(&self, req: &HttpRequest) -> Option<K> {
+        Some(K::from(default_find_identifier(req)))
+    }
+}
+
+/// [HeaderKey] extracts the value of a header (such as an API key), prefixed by the name
+/// of the header, such as `x-api-key:5f1c…`. The requests without the header (or with
+/// a value which is not visible ASCII) use the IP address, so omitting the header
+/// does not escape the limit.
+#[derive(Debug, Clone)]
+pub struct HeaderKey {
+    name: HeaderName,
+}
+
+impl HeaderKey {
+    /// Extract the header `name`. Panics if `name` is not a valid header name.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: HeaderName::try_from(name).expect("invalid header name"),
+        }
+    }
+}
+
+impl<K: From<String>> KeyExtractor<K> for HeaderKey {
+    fn extract(&self, req: &HttpRequest) -> Option<K> {
+        let value = req.headers().get(&self.name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty() && value.chars().all(|c| c.is_ascii_graphic()));
+        Some(K::from(match value {
+            Some(value) => format!("{}:{}", self.name, value),
+            None => default_find_identifier(req),
+        }))
+    }
+}
+
+/// [Normalizer] normalizes the identifiers extracted by
+/// [Controller::with_find_identifier](crate::controller::Controller::with_find_identifier),
+/// so header-derived keys can neither blow up the memory of the [Store](crate::store::Store)
+/// nor split counters by case or whitespace.
+///
+/// ```rust
+/// use actix_rl::identifier::Normalizer;
+///
+/// let normalizer = Normalizer::new()
+///     .with_trim(true)
+///     .with_lowercase(true)
+///     .with_max_len(64);
+/// assert_eq!(normalizer.normalize(" API-Key-1 "), "api-key-1");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Normalizer {
+    trim: bool,
+    lowercase: bool,
+    max_len: Option<usize>,
+    reject_control: bool,
+}
+
+impl Normalizer {
+    /// Create a [Normalizer] which keeps identifiers as is.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trim the whitespaces around identifiers.
+    pub fn with_trim(mut self, trim: bool) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// Convert identifiers to lowercase.
+    pub fn with_lowercase(mut self, lowercase: bool) -> Self {
+        self.lowercase = lowercase;
+        self
+    }
+
+    /// Truncate the identifiers longer than `max_len` bytes, appending a hash
+    /// of the whole identifier, so long identifiers stay distinct.
+    /// `max_len` should be greater than the length of the hash suffix (17 bytes).
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Replace the identifiers containing control characters with [INVALID_IDENTIFIER].
+    pub fn with_reject_control(mut self, reject: bool) -> Self {
+        self.reject_control = reject;
+        self
+    }
+
+    /// Normalize `identifier`.
+    pub fn normalize(&self, identifier: &str) -> String {
+        let identifier = if self.trim { identifier.trim() } else { identifier };
+
+        if self.reject_control && identifier.chars().any(char::is_control) {
+            return INVALID_IDENTIFIER.to_string();
+        }
+
+        let identifier = if self.lowercase {
+            identifier.to_lowercase()
+        } else {
+            identifier.to_string()
+        };
+
+        match self.max_len {
+            Some(max_len) if identifier.len() > max_len => {
+                let suffix = format!("#{:016x}", fnv1a(identifier.as_bytes()));
+                let mut end = max_len.saturating_sub(suffix.len());
+                while !identifier.is_char_boundary(end) {
+                    end -= 1;
+                }
+                format!("{}{}", &identifier[..end], suffix)
+            },
+            _ => identifier,
+        }
+    }
+}
+
+/// [CardinalityGuard] limits the distinct paths of each identifier, for the keys
+/// in the form `{identifier}:{path}`, such as [find_identifier_by_route](crate::controller::find_identifier_by_route)
+/// and [find_identifier_by_path](crate::controller::find_identifier_by_path).
+///
+/// Once an identifier has used `max_paths` paths, its other paths share the
+/// [OVERFLOW_PATH] counter, so unparameterized paths (such as `/users/123`)
+/// can not blow up the memory of the [Store](crate::store::Store).
+/// Clones share the same paths.
+///
+/// ```rust
+/// use actix_rl::identifier::{CardinalityGuard, OVERFLOW_PATH};
+///
+/// let guard = CardinalityGuard::new(2);
+/// assert_eq!(guard.guard("1.2.3.4:/a".to_string()), "1.2.3.4:/a");
+/// assert_eq!(guard.guard("1.2.3.4:/b".to_string()), "1.2.3.4:/b");
+/// assert_eq!(guard.guard("1.2.3.4:/c".to_string()), format!("1.2.3.4:{}", OVERFLOW_PATH));
+/// ```
+#[derive(Debug, Clone)]
+pub struct CardinalityGuard {
+    max_paths: usize,
+    max_identifiers: usize,
+    paths: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+}
+
+impl CardinalityGuard {
+    /// The default of [Self::with_max_identifiers].
+    pub const DEFAULT_MAX_IDENTIFIERS: usize = 65536;
+
+    /// Create a [CardinalityGuard] which allows `max_paths` distinct paths per identifier.
+    pub fn new(max_paths: usize) -> Self {
+        Self {
+            max_paths,
+            max_identifiers: Self::DEFAULT_MAX_IDENTIFIERS,
+            paths: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Set the max number of identifiers whose paths are remembered.
+    /// Once reached, all the paths are forgotten, so the memory of the guard is bounded.
+    pub fn with_max_identifiers(mut self, max_identifiers: usize) -> Self {
+        self.max_identifiers = max_identifiers;
+        self
+    }
+
+    /// Return `key`, or the overflow key of its identifier if the identifier has used up its paths.
+    /// The keys without a path (no `:/`) are returned as is.
+    pub fn guard(&self, key: String) -> String {
+        let Some(index) = key.find(":/") else {
+            return key;
+        };
+        let (identifier, path) = (&key[..index], &key[index + 1..]);
+
+        let mut paths = self.paths.lock().unwrap_or_else(PoisonError::into_inner);
+        if !paths.contains_key(identifier) && paths.len() >= self.max_identifiers {
+            paths.clear();
+        }
+        let known = paths.entry(identifier.to_string()).or_default();
+        if known.contains(path) {
+            return key;
+        }
+        if known.len() < self.max_paths {
+            known.insert(path.to_string());
+            return key;
+        }
+
+        format!("{}:{}", identifier, OVERFLOW_PATH)
+    }
+}
+
+/// The 64-bit FNV-1a hash, which is stable across processes and versions,
+/// so instances sharing a [Store](crate::store::Store) agree on the keys.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// [Fnv1a] is the [Hasher] of [fnv1a], used to bucket keys deterministically
+/// across processes (on the same endianness), unlike the randomized default hasher.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    /// Return the hash of `value`.
+    pub fn hash<H: Hash + ?Sized>(value: &H) -> u64 {
+        let mut hasher = Self::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = bytes.iter().fold(self.0, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+    }
+}
+
+/// [IdentifierSource] records which source the identifier of a request comes from,
+/// see [find_identifier_by_trusted_proxy] and
+/// [RateLimitByPass::identifier_source](crate::utils::RateLimitByPass::identifier_source).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IdentifierSource {
+    /// The address of the peer (the TCP connection).
+    Peer,
+    /// The `X-Forwarded-For` header, sent by a trusted proxy.
+    ForwardedFor,
+}
+
+/// [TrustedProxies] is the list of proxies whose `X-Forwarded-For` headers are trusted,
+/// used by [find_identifier_by_trusted_proxy]. Register it with `App::app_data`.
+///
+/// ```rust
+/// use actix_rl::identifier::TrustedProxies;
+///
+/// let proxies = TrustedProxies::new()
+///     .with_proxy("10.0.0.0/8").unwrap()
+///     .with_proxy("::1").unwrap();
+/// let app = actix_web::App::new().app_data(proxies);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    /// Create an empty list, which trusts no proxies.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the proxy at `cidr`, such as `"10.0.0.0/8"` or `"192.168.1.1"`.
+    pub fn with_proxy(mut self, cidr: &str) -> Result<Self, ParseCidrError> {
+        let error = || ParseCidrError(cidr.to_string());
+        let (ip, prefix) = match cidr.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix.parse::<u8>().map_err(|_| error())?)),
+            None => (cidr, None),
+        };
+        let ip: IpAddr = ip.trim().parse().map_err(|_| error())?;
+        let bits = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return Err(error());
+        }
+
+        self.networks.push((ip, prefix));
+        Ok(self)
+    }
+
+    /// Check if `ip` is a trusted proxy.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.networks.iter().any(|(network, prefix)| match (network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        })
+    }
+}
+
+/// The header of the idempotency key, see [idempotency_key].
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// The max length of the idempotency keys returned by [idempotency_key].
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Return the [IDEMPOTENCY_KEY_HEADER] header of `req`, trimmed, or [None] if it is
+/// missing, empty, longer than 255 bytes or not visible ASCII.
+///
+/// The clients choose the idempotency keys, so they are never part of the key of a limit:
+/// see [IdempotencyFilter](crate::prefilter::IdempotencyFilter) to not charge the retries.
+pub fn idempotency_key(req: &HttpRequest) -> Option<&str> {
+    req.headers().get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+        .filter(|key| key.chars().all(|c| c.is_ascii_graphic()))
+}
+
+/// Extract the identifier as the username of the `Authorization: Basic` header,
+/// such as `basic:alice`, so the clients behind one NAT have their own counters.
+/// The password is never read into the identifier. The requests without valid
+/// basic credentials use the IP address.
+///
+/// The credentials are not verified, so any client can claim any username:
+/// use it behind the authentication, or nest it inside a limiter keyed on the IP address.
+pub fn find_identifier_by_basic_auth(req: &HttpRequest) -> String {
+    match basic_auth_username(req) {
+        Some(username) => format!("basic:{}", username),
+        None => default_find_identifier(req),
+    }
+}
+
+/// Return the username of the `Authorization: Basic` header.
+fn basic_auth_username(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let credentials = base64::engine::general_purpose::STANDARD.decode(credentials.trim()).ok()?;
+    let end = credentials.iter().position(|byte| *byte == b':')?;
+    let username = String::from_utf8(credentials[..end].to_vec()).ok()?;
+    (!username.is_empty()).then_some(username)
+}
+
+/// [ClientCertificate] is the client certificate of an mTLS connection, used by
+/// [find_identifier_by_client_cert] and [find_identifier_by_client_cert_subject].
+///
+/// With the `mtls-rustls` or `mtls-openssl` feature, [Self::on_connect] inserts it as
+/// connection data from the TLS stream of the server:
+///
+/// ```rust,ignore
+/// HttpServer::new(app)
+///     .on_connect(ClientCertificate::on_connect)
+///     .bind_rustls_0_23(("0.0.0.0", 443), tls_config)?
+/// ```
+///
+/// Otherwise, insert it as connection data in `HttpServer::on_connect`
+/// (or into the request extensions, by a middleware).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClientCertificate {
+    fingerprint: String,
+    subject: Option<String>,
+}
+
+impl ClientCertificate {
+    /// Create with the `fingerprint` of the certificate, such as its SHA-256 hash in hex.
+    pub fn new<F: ToString>(fingerprint: F) -> Self {
+        Self {
+            fingerprint: fingerprint.to_string(),
+            subject: None,
+        }
+    }
+
+    /// Create from the certificate in DER, with its SHA-256 fingerprint in lowercase hex.
+    #[cfg(feature = "mtls")]
+    pub fn from_der(der: &[u8]) -> Self {
+        use sha2::Digest;
+
+        let fingerprint = sha2::Sha256::digest(der).iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        Self::new(fingerprint)
+    }
+
+    /// Create from the certificate in DER, with its SHA-256 fingerprint and its subject,
+    /// or return None if it cannot be parsed.
+    #[cfg(feature = "mtls-rustls")]
+    pub fn parse_der(der: &[u8]) -> Option<Self> {
+        use x509_parser::objects::{oid2abbrev, oid_registry};
+
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let subject = cert.subject().iter_attributes()
+            .map(|attr| Some(format!("{}={}", oid2abbrev(attr.attr_type(), oid_registry()).ok()?, attr.as_str().ok()?)))
+            .collect::<Option<Vec<_>>>()?
+            .join(",");
+        Some(Self::from_der(der).with_subject(subject))
+    }
+
+    /// Create from an openssl certificate, with its SHA-256 fingerprint and its subject.
+    #[cfg(feature = "mtls-openssl")]
+    pub fn from_x509(cert: &openssl::x509::X509Ref) -> Option<Self> {
+        let der = cert.to_der().ok()?;
+        let subject = cert.subject_name().entries()
+            .map(|entry| Some(format!("{}={}", entry.object().nid().short_name().ok()?, entry.data().to_string().ok()?)))
+            .collect::<Option<Vec<_>>>()?
+            .join(",");
+        Some(Self::from_der(&der).with_subject(subject))
+    }
+
+    /// Insert the [ClientCertificate] of the peer of a rustls or openssl TLS stream into the
+    /// connection data, for `HttpServer::on_connect`. Nothing is inserted for the other
+    /// connections, or when the peer did not send a certificate.
+    #[cfg(any(feature = "mtls-rustls", feature = "mtls-openssl"))]
+    pub fn on_connect(conn: &dyn std::any::Any, data: &mut actix_web::dev::Extensions) {
+        use actix_web::rt::net::TcpStream;
+
+        let mut cert: Option<Self> = None;
+
+        #[cfg(feature = "mtls-rustls")]
+        if let Some(tls) = conn.downcast_ref::<actix_tls::accept::rustls_0_23::TlsStream<TcpStream>>() {
+            cert = tls.get_ref().1.peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|der| Self::parse_der(der).unwrap_or_else(|| Self::from_der(der)));
+        }
+
+        #[cfg(feature = "mtls-openssl")]
+        if let Some(tls) = conn.downcast_ref::<actix_tls::accept::openssl::TlsStream<TcpStream>>() {
+            cert = tls.ssl().peer_certificate().and_then(|cert| Self::from_x509(&cert));
+        }
+
+        if let Some(cert) = cert {
+            data.insert(cert);
+        }
+    }
+
+    /// Set the subject of the certificate, such as `CN=billing,O=Example`.
+    pub fn with_subject<S: ToString>(mut self, subject: S) -> Self {
+        self.subject = Some(subject.to_string());
+        self
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    /// Return the [ClientCertificate] of the connection, or of the request extensions.
+    pub fn from_request(req: &HttpRequest) -> Option<ClientCertificate> {
+        req.conn_data::<ClientCertificate>().cloned()
+            .or_else(|| req.extensions().get::<ClientCertificate>().cloned())
+    }
+}
+
+/// Extract the identifier as the fingerprint of the [ClientCertificate], such as
+/// `cert:5f1c…`, for machine-to-machine APIs authenticated by mTLS.
+/// The requests without a client certificate use the IP address.
+pub fn find_identifier_by_client_cert(req: &HttpRequest) -> String {
+    match ClientCertificate::from_request(req) {
+        Some(cert) => format!("cert:{}", cert.fingerprint),
+        None => default_find_identifier(req),
+    }
+}
+
+/// Extract the identifier as the subject of the [ClientCertificate], such as
+/// `cert-subject:CN=billing`, so the renewed certificates of a client share one counter.
+/// The certificates without a subject use the fingerprint, see [find_identifier_by_client_cert].
+pub fn find_identifier_by_client_cert_subject(req: &HttpRequest) -> String {
+    match ClientCertificate::from_request(req) {
+        Some(ClientCertificate { subject: Some(subject), .. }) => format!("cert-subject:{}", subject),
+        _ => find_identifier_by_client_cert(req),
+    }
+}
+
+/// [ConnectionId] is the unique id of a TCP connection, used by [find_identifier_by_connection].
+///
+/// Insert it as connection data in `HttpServer::on_connect`, so every request of a
+/// keep-alive (or pipelined) HTTP/1.1 connection shares it:
+///
+/// ```rust,ignore
+/// HttpServer::new(app)
+///     .on_connect(ConnectionId::on_connect)
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ConnectionId(pub u64);
+
+impl ConnectionId {
+    /// Return a new id, unique in the process.
+    pub fn next() -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        Self(SEQ.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Insert a new [ConnectionId] and [ConnectionStreams] into the connection data,
+    /// for `HttpServer::on_connect`.
+    pub fn on_connect(_conn: &dyn std::any::Any, data: &mut actix_web::dev::Extensions) {
+        data.insert(Self::next());
+        data.insert(ConnectionStreams::default());
+    }
+
+    /// Return the [ConnectionId] of the connection, or of the request extensions.
+    pub fn from_request(req: &HttpRequest) -> Option<ConnectionId> {
+        req.conn_data::<ConnectionId>().copied()
+            .or_else(|| req.extensions().get::<ConnectionId>().copied())
+    }
+}
+
+/// [ConnectionStreams] counts the requests in flight on one connection, which are
+/// the concurrent streams of an HTTP/2 connection, see
+/// [RateLimit::with_stream_cost](crate::middleware::RateLimit::with_stream_cost).
+///
+/// It is inserted as connection data by [ConnectionId::on_connect]. Clones share the count.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStreams(Arc<AtomicUsize>);
+
+impl ConnectionStreams {
+    /// Return the number of requests in flight.
+    pub fn active(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Count a request in flight, until the returned [StreamGuard] is dropped.
+    pub fn open(&self) -> StreamGuard {
+        let active = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+        StreamGuard {
+            streams: self.clone(),
+            active,
+        }
+    }
+
+    /// Return the [ConnectionStreams] of the connection, or of the request extensions.
+    pub fn from_request(req: &HttpRequest) -> Option<ConnectionStreams> {
+        req.conn_data::<ConnectionStreams>().cloned()
+            .or_else(|| req.extensions().get::<ConnectionStreams>().cloned())
+    }
+}
+
+/// [StreamGuard] is a request in flight of [ConnectionStreams::open].
+#[derive(Debug)]
+pub struct StreamGuard {
+    streams: ConnectionStreams,
+    active: usize,
+}
+
+impl StreamGuard {
+    /// Return the number of requests in flight when this one was opened, including itself.
+    pub fn active(&self) -> usize {
+        self.active
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.streams.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Extract the identifier as the IP address and the [ConnectionId], such as
+/// `1.2.3.4:conn:42`, to cap the requests of one TCP connection. It mitigates
+/// the floods pipelined on one HTTP/1.1 connection separately from the limits
+/// of the IP address, with its own [RateLimit](crate::middleware::RateLimit)
+/// (such as with a window of the keep-alive timeout):
+///
+/// ```rust
+/// use actix_rl::controller::Controller;
+/// use actix_rl::identifier::find_identifier_by_connection;
+/// use actix_rl::middleware::RateLimit;
+/// use actix_rl::store::mem_store::MemStore;
+///
+/// let per_connection: RateLimit<MemStore> = RateLimit::new(
+///     MemStore::new(1024, actix_rl::time::Duration::minutes(5)),
+///     1000,
+///     Controller::new().with_find_identifier(find_identifier_by_connection),
+/// );
+/// ```
+///
+/// The requests without a [ConnectionId] use the IP address.
+pub fn find_identifier_by_connection(req: &HttpRequest) -> String {
+    let ip = default_find_identifier(req);
+    match ConnectionId::from_request(req) {
+        Some(ConnectionId(id)) => format!("{}:conn:{}", ip, id),
+        None => ip,
+    }
+}
+
+/// Extract the identifier as the IP address of the client, refusing to use
+/// the client-supplied `X-Forwarded-For` header unless the peer is in the
+/// [TrustedProxies] registered with `App::app_data`.
+///
+/// The header is read from right to left, skipping the trusted proxies; the first
+/// untrusted address is the client. If the header is missing or malformed,
+/// the peer address is used. The source is recorded as an [IdentifierSource].
+pub fn find_identifier_by_trusted_proxy(req: &HttpRequest) -> String {
+    let (identifier, source) = match forwarded_for(req) {
+        Some(ip) => (ip.to_string(), IdentifierSource::ForwardedFor),
+        None => (default_find_identifier(req), IdentifierSource::Peer),
+    };
+
+    req.extensions_mut().insert(source);
+    identifier
+}
+
+/// Return the client address from the `X-Forwarded-For` header,
+/// if the peer is a trusted proxy.
+fn forwarded_for(req: &HttpRequest) -> Option<IpAddr> {
+    let proxies = req.app_data::<TrustedProxies>()?;
+    if !proxies.contains(req.peer_addr()?.ip()) {
+        return None;
+    }
+
+    let mut hops = Vec::new();
+    for value in req.headers().get_all("X-Forwarded-For") {
+        for hop in value.to_str().ok()?.split(',') {
+            hops.push(hop.trim().parse::<IpAddr>().ok()?);
+        }
+    }
+
+    hops.iter().rev()
+        .find(|ip| !proxies.contains(**ip))
+        .or(hops.first())
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    #[test]
+    fn test_key_extractor() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("X-Api-Key", "5f1c"))
+            .to_http_request();
+        let anonymous = TestRequest::default()
+            .peer_addr("10.0.0.2:1234".parse().unwrap())
+            .to_http_request();
+
+        assert_eq!(KeyExtractor::<String>::extract(&PeerIpKey, &req), Some("10.0.0.1".to_string()));
+
+        let header = HeaderKey::new("X-Api-Key");
+        assert_eq!(KeyExtractor::<String>::extract(&header, &req), Some("x-api-key:5f1c".to_string()));
+        assert_eq!(KeyExtractor::<String>::extract(&header, &anonymous), Some("10.0.0.2".to_string()));
+
+        let custom = |req: &HttpRequest| req.headers().get("X-Api-Key").map(|_| "client".to_string());
+        assert_eq!(custom.extract(&req), Some("client".to_string()));
+        assert_eq!(custom.extract(&anonymous), None);
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let proxies = TrustedProxies::new()
+            .with_proxy("10.0.0.0/8").unwrap()
+            .with_proxy("::1").unwrap();
+        assert!(proxies.contains("10.1.2.3".parse().unwrap()));
+        assert!(proxies.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(proxies.contains("::1".parse().unwrap()));
+        assert!(!proxies.contains("11.1.2.3".parse().unwrap()));
+        assert!(TrustedProxies::new().with_proxy("10.0.0.0/33").is_err());
+        assert!(TrustedProxies::new().with_proxy("localhost").is_err());
+    }
+
+    #[test]
+    fn test_find_identifier_by_trusted_proxy() {
+        let proxies = TrustedProxies::new().with_proxy("10.0.0.0/8").unwrap();
+
+        // the peer is not trusted, the header is ignored.
+        let req = TestRequest::default()
+            .app_data(proxies.clone())
+            .peer_addr("1.2.3.4:80".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "5.6.7.8"))
+            .to_http_request();
+        assert_eq!(find_identifier_by_trusted_proxy(&req), "1.2.3.4");
+        assert_eq!(req.extensions().get::<IdentifierSource>(), Some(&IdentifierSource::Peer));
+
+        // the peer is trusted, skip the trusted hops.
+        let req = TestRequest::default()
+            .app_data(proxies.clone())
+            .peer_addr("10.0.0.1:80".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "9.9.9.9, 5.6.7.8, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(find_identifier_by_trusted_proxy(&req), "5.6.7.8");
+        assert_eq!(req.extensions().get::<IdentifierSource>(), Some(&IdentifierSource::ForwardedFor));
+
+        // without the trusted proxies, the header is never used.
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:80".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "5.6.7.8"))
+            .to_http_request();
+        assert_eq!(find_identifier_by_trusted_proxy(&req), "10.0.0.1");
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let request = |key: Option<&str>| {
+            let req = TestRequest::default().peer_addr("1.2.3.4:80".parse().unwrap());
+            match key {
+                Some(key) => req.insert_header((IDEMPOTENCY_KEY_HEADER, key)),
+                None => req,
+            }.to_http_request()
+        };
+
+        assert_eq!(idempotency_key(&request(Some(" abc-123 "))), Some("abc-123"));
+        assert_eq!(idempotency_key(&request(None)), None);
+        assert_eq!(idempotency_key(&request(Some(""))), None);
+        assert_eq!(idempotency_key(&request(Some("a b"))), None);
+        assert_eq!(idempotency_key(&request(Some(&"a".repeat(256)))), None);
+    }
+
+    #[test]
+    fn test_find_identifier_by_basic_auth() {
+        let request = |authorization: &str| TestRequest::default()
+            .peer_addr("1.2.3.4:80".parse().unwrap())
+            .insert_header((AUTHORIZATION, authorization))
+            .to_http_request();
+
+        // alice:secret:with:colons
+        assert_eq!(find_identifier_by_basic_auth(&request("Basic YWxpY2U6c2VjcmV0OndpdGg6Y29sb25z")), "basic:alice");
+        assert_eq!(find_identifier_by_basic_auth(&request("basic YWxpY2U6")), "basic:alice");
+
+        // no username, no colon, invalid base64, other schemes.
+        assert_eq!(find_identifier_by_basic_auth(&request("Basic OnNlY3JldA==")), "1.2.3.4");
+        assert_eq!(find_identifier_by_basic_auth(&request("Basic YWxpY2U=")), "1.2.3.4");
+        assert_eq!(find_identifier_by_basic_auth(&request("Basic !!!")), "1.2.3.4");
+        assert_eq!(find_identifier_by_basic_auth(&request("Bearer YWxpY2U6")), "1.2.3.4");
+    }
+
+    #[test]
+    fn test_find_identifier_by_client_cert() {
+        let req = TestRequest::default().peer_addr("1.2.3.4:80".parse().unwrap()).to_http_request();
+        assert_eq!(find_identifier_by_client_cert(&req), "1.2.3.4");
+        assert_eq!(find_identifier_by_client_cert_subject(&req), "1.2.3.4");
+
+        req.extensions_mut().insert(ClientCertificate::new("ab12"));
+        assert_eq!(find_identifier_by_client_cert(&req), "cert:ab12");
+        assert_eq!(find_identifier_by_client_cert_subject(&req), "cert:ab12");
+
+        req.extensions_mut().insert(ClientCertificate::new("ab12").with_subject("CN=billing"));
+        assert_eq!(find_identifier_by_client_cert(&req), "cert:ab12");
+        assert_eq!(find_identifier_by_client_cert_subject(&req), "cert-subject:CN=billing");
+    }
+
+    #[test]
+    fn test_find_identifier_by_connection() {
+        let req = TestRequest::default().peer_addr("1.2.3.4:80".parse().unwrap()).to_http_request();
+        assert_eq!(find_identifier_by_connection(&req), "1.2.3.4");
+
+        req.extensions_mut().insert(ConnectionId(42));
+        assert_eq!(find_identifier_by_connection(&req), "1.2.3.4:conn:42");
+
+        let mut data = actix_web::dev::Extensions::new();
+        ConnectionId::on_connect(&(), &mut data);
+        let first = *data.get::<ConnectionId>().unwrap();
+        ConnectionId::on_connect(&(), &mut data);
+        assert_ne!(*data.get::<ConnectionId>().unwrap(), first);
+
+        let streams = data.get::<ConnectionStreams>().unwrap().clone();
+        let first = streams.open();
+        let second = streams.open();
+        assert_eq!((first.active(), second.active(), streams.active()), (1, 2, 2));
+        drop(first);
+        assert_eq!(streams.active(), 1);
+    }
+
+    #[cfg(feature = "mtls")]
+    #[test]
+    fn test_client_cert_from_der() {
+        let cert = ClientCertificate::from_der(b"abc");
+        assert_eq!(cert.fingerprint(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[cfg(any(feature = "mtls-rustls", feature = "mtls-openssl"))]
+    #[test]
+    fn test_client_cert_on_connect() {
+        let mut data = actix_web::dev::Extensions::new();
+        ClientCertificate::on_connect(&(), &mut data);
+        assert!(data.get::<ClientCertificate>().is_none());
+    }
+
+    #[cfg(feature = "mtls-openssl")]
+    #[test]
+    fn test_client_cert_from_x509() {
+        use openssl::x509::{X509Name, X509};
+
+        let key = openssl::pkey::PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("O", "Example").unwrap();
+        name.append_entry_by_text("CN", "billing").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&openssl::asn1::Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&openssl::asn1::Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, openssl::hash::MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let client = ClientCertificate::from_x509(&cert).unwrap();
+        assert_eq!(client.subject(), Some("O=Example,CN=billing"));
+        assert_eq!(client, ClientCertificate::from_der(&cert.to_der().unwrap()).with_subject("O=Example,CN=billing"));
+
+        // the same certificate from rustls.
+        #[cfg(feature = "mtls-rustls")]
+        assert_eq!(ClientCertificate::parse_der(&cert.to_der().unwrap()), Some(client));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(Normalizer::new().normalize(" Key "), " Key ");
+
+        let normalizer = Normalizer::new()
+            .with_trim(true)
+            .with_lowercase(true)
+            .with_max_len(24)
+            .with_reject_control(true);
+        assert_eq!(normalizer.normalize(" Key "), "key");
+        assert_eq!(normalizer.normalize("key\r\nX-Injected: 1"), INVALID_IDENTIFIER);
+
+        let long = normalizer.normalize(&"a".repeat(100));
+        assert_eq!(long.len(), 24);
+        assert!(long.starts_with("aaaaaaa#"));
+        assert_ne!(long, normalizer.normalize(&"a".repeat(101)));
+
+        // truncate at a char boundary
+        let long = normalizer.normalize(&"é".repeat(50));
+        assert!(long.len() <= 24);
+    }
+
+    #[test]
+    fn test_cardinality_guard() {
+        let guard = CardinalityGuard::new(2).with_max_identifiers(2);
+        let overflow = format!("::1:{}", OVERFLOW_PATH);
+        assert_eq!(guard.guard("::1:/a".to_string()), "::1:/a");
+        assert_eq!(guard.guard("::1:/b".to_string()), "::1:/b");
+        assert_eq!(guard.guard("::1:/c".to_string()), overflow);
+        assert_eq!(guard.guard("::1:/a".to_string()), "::1:/a");
+        assert_eq!(guard.guard("no-path".to_string()), "no-path");
+
+        // other identifiers have their own paths.
+        assert_eq!(guard.guard("1.2.3.4:/c".to_string()), "1.2.3.4:/c");
+
+        // the third identifier resets the guard.
+        assert_eq!(guard.guard("5.6.7.8:/c".to_string()), "5.6.7.8:/c");
+        assert_eq!(guard.guard("::1:/c".to_string()), "::1:/c");
+    }
+}
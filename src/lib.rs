@@ -13,6 +13,13 @@
 //! |:-------------:|:------------:|:---------------------------------------------------------------------------------:|
 //! |   `default`   |  `MemStore`  |                               Store data in memory                                |
 //! | `redis-store` | `RedisStore` | Store data using an async connection from [redis](https://crates.io/crates/redis) |
+//! |    `serde`    |   `Policy`   |            `Serialize`/`Deserialize` for `Policy`, such as in config files           |
+//! |    `mtls`     | `ClientCertificate` |          `ClientCertificate::from_der`, the SHA-256 fingerprint of certificates         |
+//! | `mtls-rustls` | `ClientCertificate` |     `ClientCertificate::on_connect`, the client certificates of rustls connections     |
+//! | `mtls-openssl` | `ClientCertificate` |    `ClientCertificate::on_connect`, the client certificates of openssl connections     |
+//! |    `hmac`     | `HmacSignature`, `ProofSigner` | Verify the HMAC-SHA256 signatures of requests before counting them, sign the rejections |
+//! |    `audit`    |  `AuditLog`  |           Append the rejections and bans as JSON lines to a rotating file           |
+//! |  `test-util`  | `ChaosStore` |        Inject latency, random errors and clock skew into a store, for rehearsals        |
 
 //! ## Usage
 //! 1. Define a `Store` where the program stores information and sets timeouts.
@@ -32,22 +39,30 @@
 
 //! ```rust
 //! // data timeout for each key is 10 seconds, with 1024 init capacity.
-//! let store = actix_rl::store::mem_store::MemStore::new(1024, chrono::Duration::seconds(10));
+//! let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::seconds(10));
+//! ```
+
+//! The window can also be written as a humantime-style string, such as `"10s"`, `"5m"` or `"1h30m"`:
+//! ```rust
+//! let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::utils::parse_duration("10s").unwrap());
 //! ```
 
 //! ### Controller
 //! `Controller` is a set of functions. To create a default one:
 //! ```rust
-//! let controller = actix_rl::controller::Controller::new();
+//! # use actix_rl::store::mem_store::MemStore;
+//! let controller: actix_rl::controller::Controller<MemStore> = actix_rl::controller::Controller::new();
 //! ```
 
 //! You can determine which requests should be checked, by modifying `Controller`:
 //! ```rust
-//! let controller = actix_rl::controller::Controller::new();
-//! let controller = controller.with_do_rate_limit(|req| !req.path().start_with("/healthz"));
+//! # use actix_rl::store::mem_store::MemStore;
+//! let controller: actix_rl::controller::Controller<MemStore> = actix_rl::controller::Controller::new();
+//! let controller = controller.with_do_rate_limit(|req| !req.path().starts_with("/healthz"));
 //! ```
 
 //! In this case, only those requests without prefix `/healthz` will be checked by RateLimiter.
+//! To skip the usual health-check and metrics endpoints and the `OPTIONS` requests, use `Controller::skip_infra_endpoints`.
 
 //! For more functions, please check the doc of `Controller`.
 
@@ -55,6 +70,8 @@
 //! Define a `RateLimiter` and `wrap` to HTTP server:
 
 //! ```rust
+//! # let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::seconds(10));
+//! # let controller = actix_rl::controller::Controller::default();
 //! let rate_limiter = actix_rl::middleware::RateLimitMiddleware::new(
 //!     store,
 //!     10, // max count is 10, which means max 10 hits per 10 seconds.
@@ -64,13 +81,52 @@
 
 //! Then, add it to `actix-web` HTTP server wrap:
 //! ```rust
+//! # use actix_web::App;
+//! # let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::seconds(10));
+//! # let rate_limiter = actix_rl::middleware::RateLimit::new(store, 10, actix_rl::controller::Controller::default());
 //! App::new()
 //!    .wrap(rate_limiter)
 //!     // ...
+//! # ;
+//! ```
+
+//! The body of the responses is `EitherBody<B, EitherBody<BoxBody, CB>>` (alias `RateLimitBody`).
+//! To keep `BoxBody`, such as in the functions returning an `App`, use `BoxedRateLimit` (or `RateLimit::boxed`):
+//! ```rust
+//! # use actix_web::{App, body::BoxBody, dev::{ServiceFactory, ServiceRequest, ServiceResponse}};
+//! # use actix_rl::store::mem_store::MemStore;
+//! fn app(store: MemStore) -> App<impl ServiceFactory<
+//!     ServiceRequest,
+//!     Config = (),
+//!     Response = ServiceResponse<BoxBody>,
+//!     Error = actix_web::Error,
+//!     InitError = (),
+//! >> {
+//!     App::new()
+//!         .wrap(actix_rl::middleware::BoxedRateLimit::new(store, 10, actix_rl::controller::Controller::default()))
+//!         // ...
+//! }
 //! ```
 
 pub mod store;
+pub mod budget;
+pub mod degradation;
+pub mod reservation;
 pub mod middleware;
 pub mod error;
 pub mod controller;
 pub mod utils;
+pub mod time;
+pub mod identifier;
+pub mod policy;
+pub mod policy_provider;
+pub mod prefilter;
+pub mod signature;
+pub mod client;
+pub mod self_test;
+pub mod registry;
+#[cfg(feature = "audit")]
+pub mod audit;
+#[cfg(feature = "hmac")]
+pub mod proof;
+mod queue;
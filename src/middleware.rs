@@ -1,31 +1,494 @@
+use std::hash::Hash;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::task::{ready, Context, Poll};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
 use actix_web::body::{BoxBody, EitherBody, MessageBody};
 use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
-use futures_util::future::{LocalBoxFuture, Ready, ready};
-use crate::controller::{Controller, default_do_rate_limit, default_on_rate_limit_error, default_on_store_error};
-use crate::error::Error;
-use crate::store::{Store, Value};
-use crate::utils::RateLimitByPass;
+use futures_util::future::{Either, LocalBoxFuture, MapOk, Ready, ready};
+use futures_util::TryFutureExt;
+use crate::time::{DateTime, Utc};
+use crate::controller::{Controller, FromRequestFunc, RateLimitMessage, default_do_rate_limit, default_on_invalid_signature, localized_on_rate_limit_error, default_on_store_error, default_on_store_timeout, insert_success_headers, DEFAULT_RATE_LIMIT_LIMIT_HEADER, DEFAULT_RATE_LIMIT_REMAINING_HEADER};
+use crate::error::{ConfigError, Error, ErrorClass, StoreError};
+use crate::policy::{Algorithm, CountEvaluator, Experiment, HierarchicalPolicy, LevelKeyFunc, LimitEvaluator, LimitSemantics, Policy, PolicyLabels, UserAgentPolicies};
+use crate::self_test::{self, SelfTestReport, SELF_TEST_KEY};
+use crate::policy_provider::{ContentTypePolicies, CurrentPolicy, DynamicPolicy, KeyPolicyProvider, PolicySet};
+#[cfg(feature = "audit")]
+use crate::audit::{AuditAction, AuditLog};
+#[cfg(feature = "hmac")]
+use crate::proof::{ProofSigner, DEFAULT_PROOF_HEADER};
+use crate::budget::WeightedBudget;
+use crate::degradation::{Degradation, DegradationStatus};
+use crate::prefilter::{FirstSeenFilter, IdempotencyFilter};
+use crate::identifier::{idempotency_key, ConnectionStreams, Fnv1a};
+use crate::queue::{FairQueue, RequestQueue};
+use crate::reservation::Reservation;
+use crate::signature::SignatureVerifier;
+use crate::store::mem_store::MemStore;
+use crate::store::{GrantStore, Store, Value, FLAGS_METADATA_KEY, NOTE_METADATA_KEY};
+use crate::utils::{insert_header, CacheHit, Outcome, RateLimitByPass, RateLimitExempt, remaining};
 
 /// alias of [RateLimit]
 pub type RateLimitMiddleware<T, CB> = RateLimit<T, CB>;
 
+/// The response body of [RateLimit]: the body `B` of the inner service, the default
+/// responses of [Controller], or the body `CB` of the custom responses.
+/// Use [RateLimit::boxed] to get [BoxBody] instead.
+pub type RateLimitBody<B, CB = BoxBody> = EitherBody<B, EitherBody<BoxBody, CB>>;
+
+/// The future of the requests which are not checked.
+type FastPath<F, B, CB> = MapOk<F, fn(ServiceResponse<B>) -> ServiceResponse<RateLimitBody<B, CB>>>;
+
+/// The future of [BoxedRateLimitService].
+type BoxedFuture<F, R> = MapOk<F, fn(R) -> ServiceResponse<BoxBody>>;
+
+type FrozenKeys<K> = Arc<RwLock<Vec<(K, fn(&K, &K) -> bool)>>>;
+
+/// [RejectionCache] remembers the keys which are hard-limited, until their windows end,
+/// see [RateLimit::with_rejection_cache].
+trait RejectionCache<K>: Send + Sync {
+    /// Return the end of the window of `key`, if it is rejected.
+    fn get(&self, key: &K) -> Option<DateTime<Utc>>;
+
+    fn insert(&self, key: K, until: DateTime<Utc>);
+
+    fn remove(&self, key: &K);
+}
+
+/// [LocalRejections] is the in-process [RejectionCache]. When it is full,
+/// the oldest rejection is evicted, which is about the first to end.
+struct LocalRejections<K> {
+    capacity: usize,
+    entries: RwLock<Rejections<K>>,
+}
+
+struct Rejections<K> {
+    /// the end of the window of each key, and its sequence number.
+    until: HashMap<K, (DateTime<Utc>, u64)>,
+    /// the keys by sequence number, including those rejected again or removed since.
+    order: VecDeque<(K, u64)>,
+    next: u64,
+}
+
+impl<K> LocalRejections<K> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: RwLock::new(Rejections {
+                until: HashMap::new(),
+                order: VecDeque::new(),
+                next: 0,
+            }),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync> RejectionCache<K> for LocalRejections<K> {
+    fn get(&self, key: &K) -> Option<DateTime<Utc>> {
+        // the ended windows are left to the eviction.
+        let entries = self.entries.read().unwrap_or_else(PoisonError::into_inner);
+        entries.until.get(key)
+            .map(|(until, _)| *until)
+            .filter(|until| *until > Utc::now())
+    }
+
+    fn insert(&self, key: K, until: DateTime<Utc>) {
+        let mut entries = self.entries.write().unwrap_or_else(PoisonError::into_inner);
+        let entries = &mut *entries;
+        let seq = entries.next;
+        entries.next += 1;
+        entries.until.insert(key.clone(), (until, seq));
+        entries.order.push_back((key, seq));
+
+        while entries.until.len() > self.capacity {
+            let Some((key, seq)) = entries.order.pop_front() else {
+                break;
+            };
+            if entries.until.get(&key).is_some_and(|(_, current)| *current == seq) {
+                entries.until.remove(&key);
+            }
+        }
+
+        // drop the keys rejected again or removed since, once they are half of the order.
+        if entries.order.len() > 2 * entries.until.len() {
+            let until = &entries.until;
+            entries.order.retain(|(key, seq)| until.get(key).is_some_and(|(_, current)| current == seq));
+        }
+    }
+
+    fn remove(&self, key: &K) {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner).until.remove(key);
+    }
+}
+
 /// [RateLimit] is the rate-limit middleware.
 ///
 /// Params [T]: the [Store];
 ///
 /// Params [CB]: the response body for [Controller]. (Controller.Body)
-#[derive(Clone)]
+///
+/// The builders may be called on a clone: the configuration is then copied, and the
+/// clones still share the [Store], the frozen keys and the other runtime state.
 pub struct RateLimit<T: Store, CB: MessageBody = BoxBody> {
     inner: Arc<RateLimitInner<T, CB>>,
 }
 
-#[derive(Clone)]
+impl<T: Store, CB: MessageBody> Clone for RateLimit<T, CB> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
 struct RateLimitInner<T: Store, CB: MessageBody = BoxBody> {
     pub store: T,
     pub max: <<T as Store>::Value as Value>::Count,
+    /// decide whether the requests are over the limit.
+    pub evaluator: Arc<dyn LimitEvaluator<T::Value>>,
+    /// passed to the evaluator.
+    pub semantics: LimitSemantics,
     pub controller: Controller<T, CB>,
+    /// the frozen keys, with the functions to compare them.
+    pub frozen: FrozenKeys<T::Key>,
+    /// the policy which overrides `max` and the window.
+    pub policy: Option<DynamicPolicy<T>>,
+    /// the keys which are hard-limited.
+    pub rejections: Option<Arc<dyn RejectionCache<T::Key>>>,
+    /// the requests over the limit wait in the queue.
+    pub queue: Option<Arc<dyn RequestQueue<T::Key>>>,
+    /// the signatures are verified before counting.
+    pub signature: Option<SignatureCheck<T::Count>>,
+    /// the namespace of the keys, with the function to prefix a key.
+    pub namespace: Option<(String, NamespaceFunc<T::Key>)>,
+    /// the increment and the window, overriding the window of the [Store].
+    pub window: Option<(T::Count, crate::time::Duration)>,
+    /// the global budget shared by the client classes.
+    pub budget: Option<(WeightedBudget, T::Count)>,
+    /// the refund of the responses served from cache, clamped to the charge.
+    pub cache_refund: Option<CacheRefund<T::Count>>,
+    /// the service is not ready while the queue is full.
+    pub backpressure: bool,
+    /// the requests let through on failures of the store.
+    pub degradation: Arc<Degradation>,
+    /// the limits above the key of each request.
+    pub hierarchy: Option<HierarchyCheck<T>>,
+    /// the labels inserted into the requests.
+    pub labels: Option<PolicyLabels>,
+    /// the free concurrent streams of a connection, and the increment of the others.
+    pub stream_cost: Option<(usize, T::Count)>,
+    /// the increment of each request without a policy, instead of the default one.
+    pub cost: Option<T::Count>,
+    /// the slice of the max reserved for the priority requests.
+    pub priority: Option<PriorityLane<T>>,
+    /// the first requests of the keys are not counted, with the function to check a key.
+    pub first_seen: Option<FirstSeenCheck<T::Key, T::Value>>,
+    /// the retries of the idempotency keys are not counted, with the function to hash a key.
+    pub idempotency: Option<(IdempotencyFilter, KeyHashFunc<T::Key>)>,
+    /// the rejections are appended to the audit log, with the function to format a key.
+    #[cfg(feature = "audit")]
+    pub audit: Option<(AuditLog, AuditKeyFunc<T::Key>)>,
+    /// the rate-limit responses carry a signed token, with the function to format a key.
+    #[cfg(feature = "hmac")]
+    pub proof: Option<(ProofSigner, ProofKeyFunc<T::Key>)>,
+}
+
+// not derived, since the body of the [Controller] is not [Clone].
+impl<T: Store, CB: MessageBody> Clone for RateLimitInner<T, CB> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            max: self.max.clone(),
+            evaluator: self.evaluator.clone(),
+            semantics: self.semantics,
+            controller: self.controller.clone(),
+            frozen: self.frozen.clone(),
+            policy: self.policy.clone(),
+            rejections: self.rejections.clone(),
+            queue: self.queue.clone(),
+            signature: self.signature.clone(),
+            namespace: self.namespace.clone(),
+            window: self.window.clone(),
+            budget: self.budget.clone(),
+            cache_refund: self.cache_refund.clone(),
+            backpressure: self.backpressure,
+            degradation: self.degradation.clone(),
+            hierarchy: self.hierarchy.clone(),
+            labels: self.labels.clone(),
+            stream_cost: self.stream_cost.clone(),
+            cost: self.cost.clone(),
+            priority: self.priority.clone(),
+            first_seen: self.first_seen.clone(),
+            idempotency: self.idempotency.clone(),
+            #[cfg(feature = "audit")]
+            audit: self.audit.clone(),
+            #[cfg(feature = "hmac")]
+            proof: self.proof.clone(),
+        }
+    }
+}
+
+/// Prefix a key with the namespace, see [RateLimit::scoped].
+type NamespaceFunc<K> = fn(&str, K) -> K;
+
+/// [FirstSeenFilter], with the function to check a key and its max,
+/// see [RateLimit::with_first_seen_filter].
+type FirstSeenCheck<K, V> = (FirstSeenFilter, fn(&FirstSeenFilter, &K, &<V as Value>::Count) -> bool);
+
+/// Hash a key for [IdempotencyFilter], see [RateLimit::with_idempotency_filter].
+type KeyHashFunc<K> = fn(&K) -> u64;
+
+/// The refund of [RateLimit::with_cache_refund], the default increment,
+/// and the function returning the lesser of two counts.
+type CacheRefund<C> = (C, C, fn(C, C) -> C);
+
+/// Format a key for [AuditLog], see [RateLimit::with_audit_log].
+#[cfg(feature = "audit")]
+type AuditKeyFunc<K> = fn(&K) -> String;
+
+/// Format a key for [ProofSigner], see [RateLimit::with_proof_token].
+#[cfg(feature = "hmac")]
+type ProofKeyFunc<K> = fn(&K) -> String;
+
+/// [HierarchyCheck] is set by [RateLimit::with_hierarchy].
+#[derive(Clone)]
+struct HierarchyCheck<T: Store> {
+    /// the name, the max and the key of each level.
+    levels: Vec<(String, <T::Value as Value>::Count, LevelKeyFunc)>,
+    /// the increment of each level.
+    incr: T::Count,
+    /// convert `{name}:{key}` to the key of the [Store].
+    to_key: fn(String) -> T::Key,
+}
+
+/// [PriorityLane] is set by [RateLimit::with_priority_lane].
+#[derive(Clone)]
+struct PriorityLane<T: Store> {
+    /// the count reserved for the priority requests.
+    reserved: <T::Value as Value>::Count,
+    is_priority: FromRequestFunc<bool>,
+    /// the default increment, refunded to the rejected requests.
+    one: T::Count,
+}
+
+/// [SignatureCheck] is set by [RateLimit::with_signature_check].
+#[derive(Clone)]
+struct SignatureCheck<C> {
+    verifier: Arc<dyn SignatureVerifier>,
+    /// the increment of the invalid requests, or [None] to reject them.
+    charge: Option<C>,
+}
+
+/// [Rejection] is a request over a limit, answered by [RateLimitInner::reject].
+struct Rejection<'a, T: Store> {
+    /// [Outcome::Rejected], or [Outcome::Banned] by the rejection cache.
+    outcome: Outcome,
+    /// the key of the request, if identified, for [Controller::on_first_violation], the audit log and the proof token.
+    key: Option<&'a T::Key>,
+    /// the value of the key, if counted.
+    value: Option<&'a T::Value>,
+    /// the value over the limit and the max, of the key or of a level of the hierarchy.
+    over: Option<(&'a T::Value, &'a <T::Value as Value>::Count)>,
+    /// when the request may be retried.
+    until: Option<DateTime<Utc>>,
+    /// the charges rolled back with [Store::grant] until the end of their windows,
+    /// so the rejected request does not use the budgets of their keys.
+    /// The charges not counted by the store (see [Value::is_charged]) are not refunded.
+    refunds: Vec<(T::Key, T::Count, &'a T::Value)>,
+}
+
+/// [Decision] is the decision on the value of a key, see [RateLimitInner::decide].
+#[derive(Debug, Clone, Copy)]
+struct Decision {
+    /// the value is over the limit, by the evaluator and the semantics.
+    over: bool,
+    /// the request is rejected: over the limit, and not within the grace margin.
+    limited: bool,
+}
+
+impl<T: Store, CB: MessageBody> RateLimitInner<T, CB> {
+    /// Check if the request counted into `value` is over `max`, by the evaluator and the semantics.
+    fn is_limited(&self, value: &T::Value, max: &<T::Value as Value>::Count) -> bool {
+        self.evaluator.is_limited(value, max, self.semantics)
+    }
+
+    /// Decide on the request counted into `value`, the same way for all the limits:
+    /// by the evaluator, then by the grace margin of the controller.
+    fn decide(&self, value: &T::Value, max: &<T::Value as Value>::Count) -> Decision {
+        let over = self.is_limited(value, max);
+        // the margin is over the max: a custom evaluator may limit below it.
+        let count = value.count();
+        let within_grace = over && count >= *max && self.controller.grace.as_ref()
+            .is_some_and(|margin| count - max.clone() <= *margin);
+
+        Decision {
+            over,
+            limited: over && !within_grace,
+        }
+    }
+
+    fn is_frozen(&self, key: &T::Key) -> bool {
+        self.frozen.read().unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|(frozen, eq)| eq(frozen, key))
+    }
+
+    /// Return the variant of the key, or the name of the controller, for the audit log and the proof token.
+    fn policy_name<'a>(&'a self, policy: Option<&'a CurrentPolicy<T>>) -> Option<&'a str> {
+        policy.and_then(|policy| policy.variant.as_ref())
+            .map(|variant| variant.as_str())
+            .or(self.controller.name.as_deref())
+    }
+
+    /// Answer a request over a limit, the same way for the limit of the key, the rejection
+    /// cache, the levels of [RateLimit::with_hierarchy] and [RateLimit::with_weighted_budget].
+    ///
+    /// In shadow mode (the key is not enforced, see [Controller::with_rollout]), call
+    /// [Controller::on_shadow_limited] and return [None], so the request is let through.
+    /// Otherwise, roll back the charges, call [Controller::on_first_violation], append to
+    /// the audit log, and return the response with the proof token, after the tarpit.
+    #[cfg_attr(not(any(feature = "audit", feature = "hmac")), allow(unused_variables))]
+    #[cfg_attr(not(feature = "hmac"), allow(unused_mut))]
+    async fn reject(
+        &self,
+        req: &HttpRequest,
+        policy: Option<&CurrentPolicy<T>>,
+        enforced: bool,
+        rejection: Rejection<'_, T>,
+    ) -> Option<HttpResponse<EitherBody<BoxBody, CB>>> {
+        if !enforced {
+            let value = rejection.value.or(rejection.over.map(|(value, _)| value));
+            if let (Some(f), Some(value)) = (self.controller.fn_on_shadow_limited, value) {
+                f(req, value);
+            }
+            return None;
+        }
+
+        Outcome::record(req, rejection.outcome);
+
+        let now = Utc::now();
+        for (key, charge, value) in rejection.refunds.into_iter().filter(|(_, _, value)| value.is_charged()) {
+            let ttl = value.expire_date().map(|until| until - now);
+            if let Some(ttl) = ttl.filter(|ttl| *ttl > crate::time::Duration::zero()) {
+                let _ = self.store.grant(key, charge, ttl).await;
+            }
+        }
+
+        if let (Some(violation), Some((value, _))) = (&self.controller.first_violation, rejection.over) {
+            if violation.first(rejection.key, value) {
+                (violation.hook)(req, value);
+            }
+        }
+
+        let policy = self.policy_name(policy);
+
+        #[cfg(feature = "audit")]
+        if let (Some((audit, format)), Some(key), Some(value)) = (&self.audit, rejection.key, rejection.value) {
+            let _ = audit.record(AuditAction::Rejected, &format(key), policy, value.count());
+        }
+
+        let err = Error::RateLimited(rejection.until);
+        let message = self.controller.message_provider.and_then(|provider| RateLimitMessage::provide(req, provider));
+        let mut body = match &self.controller.fn_on_rate_limit_error {
+            Some(f) => {
+                if let Some(message) = message {
+                    req.extensions_mut().insert(message);
+                }
+                f(req, err).map_into_right_body()
+            },
+            None => localized_on_rate_limit_error(req, err, message).map_into_left_body(),
+        };
+
+        #[cfg(feature = "hmac")]
+        if let (Some((signer, format)), Some(key)) = (&self.proof, rejection.key) {
+            let token = signer.sign(&format(key), policy.unwrap_or("default"), rejection.until);
+            insert_header(body.headers_mut(), DEFAULT_PROOF_HEADER, token);
+        }
+
+        if let Some(tarpit) = &self.controller.tarpit {
+            tarpit.wait().await;
+        }
+        Some(body)
+    }
+
+    /// Await `charge`, bounded by the store timeout and `deadline`. Return [None] on timeout.
+    async fn within_deadline<F: Future>(&self, charge: F, deadline: Option<tokio::time::Instant>) -> Option<F::Output> {
+        match self.controller.timeout_until(deadline) {
+            Some(timeout) => tokio::time::timeout(timeout, charge).await.ok(),
+            None => Some(charge.await),
+        }
+    }
+
+    /// Return the response to a request whose charge failed with `error`, or timed out.
+    fn store_failure(&self, req: &HttpRequest, error: Option<T::Error>) -> HttpResponse<EitherBody<BoxBody, CB>> {
+        Outcome::record(req, Outcome::Rejected);
+        match error {
+            Some(e) => match (&self.controller.fn_on_store_error, &self.controller.fn_on_any_store_error) {
+                (Some(f), _) => f(req, e).map_into_right_body(),
+                (None, Some(f)) => f(req, StoreError::new(&self.store, &e)).map_into_right_body(),
+                (None, None) => default_on_store_error::<T>(req, e).map_into_left_body(),
+            },
+            None => match &self.controller.fn_on_store_timeout {
+                Some(f) => f(req).map_into_right_body(),
+                None => default_on_store_timeout(req).map_into_left_body(),
+            },
+        }
+    }
+
+    /// Wait in `queue` for the window of `key` to reset, and count the request again.
+    /// Return the new value if the request is allowed in time, and before `request_deadline`.
+    ///
+    /// The waiters of `key` retry in FIFO order, and the keys in round-robin order.
+    /// A retry reads the count first, and charges the request only if it would not be limited.
+    async fn wait_in_queue(
+        &self,
+        queue: &dyn RequestQueue<T::Key>,
+        key: T::Key,
+        value: &T::Value,
+        policy: Option<&CurrentPolicy<T>>,
+        max: &<<T as Store>::Value as Value>::Count,
+        request_deadline: Option<tokio::time::Instant>,
+    ) -> Option<T::Value> {
+        let ticket = queue.admit(&key)?;
+        let deadline = tokio::time::Instant::now() + queue.max_wait();
+        let deadline = request_deadline.map_or(deadline, |request_deadline| deadline.min(request_deadline));
+
+        let wait = async {
+            ticket.first().await;
+
+            let mut until = value.expire_date()?;
+            loop {
+                // wait a little even if the window has just reset, so the retries never spin.
+                let wait = (until - Utc::now()).to_std().unwrap_or_default().max(std::time::Duration::from_millis(10));
+                if tokio::time::Instant::now() + wait > deadline {
+                    return None;
+                }
+                tokio::time::sleep(wait).await;
+                let _turn = ticket.turn().await;
+
+                let peeked = self.within_deadline(self.store.peek(key.clone()), request_deadline).await?.ok()?;
+                if let Some(peeked) = peeked.filter(|peeked| self.decide(peeked, max).limited) {
+                    until = peeked.expire_date()?;
+                    continue;
+                }
+
+                let incr = match (policy, &self.window, &self.cost) {
+                    (Some(policy), _, _) => self.store.incr_with_ttl(key.clone(), policy.incr.clone(), policy.window),
+                    (None, Some((incr, window)), cost) => self.store.incr_with_ttl(key.clone(), cost.as_ref().unwrap_or(incr).clone(), *window),
+                    (None, None, Some(cost)) => self.store.incr_by(key.clone(), cost.clone()),
+                    (None, None, None) => self.store.incr(key.clone()),
+                };
+                let value = self.within_deadline(incr, request_deadline).await?.ok()?;
+                if !self.decide(&value, max).limited {
+                    return Some(value);
+                }
+                until = value.expire_date()?;
+            }
+        };
+        tokio::time::timeout_at(deadline, wait).await.ok()?
+    }
 }
 
 impl<T, CB, S, B> Transform<S, ServiceRequest> for RateLimit<T, CB>
@@ -37,7 +500,7 @@ impl<T, CB, S, B> Transform<S, ServiceRequest> for RateLimit<T, CB>
         B: 'static,
         <T as Store>::Key: 'static,
 {
-    type Response = ServiceResponse<EitherBody<B, EitherBody<BoxBody, CB>>>;
+    type Response = ServiceResponse<RateLimitBody<B, CB>>;
     type Error = S::Error;
     type Transform = RateLimitService<T, CB, S>;
     type InitError = ();
@@ -70,94 +533,570 @@ impl<T, CB, S, B> Service<ServiceRequest> for RateLimitService<T, CB, S>
         B: 'static,
         <T as Store>::Key: 'static,
 {
-    type Response = ServiceResponse<EitherBody<B, EitherBody<BoxBody, CB>>>;
+    type Response = ServiceResponse<RateLimitBody<B, CB>>;
     type Error = S::Error;
-    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+    type Future = Either<
+        FastPath<S::Future, B, CB>,
+        LocalBoxFuture<'static, Result<Self::Response, Self::Error>>,
+    >;
 
-    forward_ready!(service);
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.service.poll_ready(cx))?;
+
+        // hold the next requests while the queue is full, see RateLimit::with_backpressure.
+        match (&self.inner.queue, self.inner.backpressure) {
+            (Some(queue), true) => queue.poll_capacity(cx).map(Ok),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&self, mut svc: ServiceRequest) -> Self::Future {
+        let name = self.inner.controller.name.as_deref();
+        let checked = !self.inner.controller.ignore_checked
+            && RateLimitByPass::<T>::checked(svc.request(), name);
+        let exempt = RateLimitExempt::is_exempt(svc.request());
+        let do_rate_limit = !checked && !exempt && if let Some(f) = &self.inner.controller.fn_do_rate_limit {
+            f(svc.request())
+        } else {
+            // use default function
+            default_do_rate_limit(svc.request())
+        };
+
+        if !do_rate_limit {
+            // fast path: no identifier, no store call and no boxed future.
+            if self.inner.controller.forward_quota_headers {
+                let headers = svc.headers_mut();
+                headers.remove(DEFAULT_RATE_LIMIT_LIMIT_HEADER);
+                headers.remove(DEFAULT_RATE_LIMIT_REMAINING_HEADER);
+            }
+
+            if !checked {
+                RateLimitByPass::<T>::check(svc.request(), name, Outcome::Bypassed, None, false, None);
+            }
+
+            if let Some(f) = self.inner.controller.sampled_on_success() {
+                f(svc.request(), &self.inner.store, None);
+            }
+
+            let map: fn(ServiceResponse<B>) -> Self::Response = ServiceResponse::map_into_left_body;
+            return Either::Left(self.service.call(svc).map_ok(map));
+        }
 
-    fn call(&self, svc: ServiceRequest) -> Self::Future {
         let service = self.service.clone();
         let inner = self.inner.clone();
 
-        Box::pin(async move {
-            let checked = RateLimitByPass::<T>::checked(svc.request());
-            let do_rate_limit = !checked && if let Some(f) = &inner.controller.fn_do_rate_limit {
-                f(svc.request())
-            } else {
-                // use default function
-                default_do_rate_limit(svc.request())
+        Either::Right(Box::pin(async move {
+            let mut svc = svc;
+            let name = inner.controller.name.as_deref();
+            let mut rate_limit_value = None;
+            // the key of the request and its charge, if counted.
+            let mut counted = None;
+            let mut enforced = true;
+            let mut grace = false;
+            let mut failed_open = false;
+            let deadline = inner.controller.deadline(svc.request());
+
+            // count the streams in flight on the connection, until the response.
+            let stream = inner.stream_cost.as_ref()
+                .and_then(|_| ConnectionStreams::from_request(svc.request()))
+                .map(|streams| streams.open());
+            let stream_charge = match (&inner.stream_cost, &stream) {
+                (Some((free, cost)), Some(stream)) if stream.active() > *free => Some(cost.clone()),
+                _ => None,
             };
 
-            let mut rate_limit_value = None;
+            // verify the signature before counting.
+            let signature_charge = match &inner.signature {
+                Some(check) if !check.verifier.verify(svc.request()) => match &check.charge {
+                    Some(charge) => Some(charge.clone()),
+                    None => {
+                        let req = svc.request();
+                        Outcome::record(req, Outcome::Rejected);
+                        let body = match &inner.controller.fn_on_invalid_signature {
+                            Some(f) => f(req).map_into_right_body(),
+                            None => default_on_invalid_signature(req).map_into_left_body(),
+                        };
+                        return Ok(respond(svc, body));
+                    },
+                },
+                _ => None,
+            };
 
-            if do_rate_limit {
-                // get identifier of this request
-                let identifier = inner.controller.fn_find_identifier.as_ref()
-                    .map(|f| f(svc.request()));
-
-                if let Some(identifier) = identifier { // continue only when identifier is found.
-                    let req = svc.request();
-                    match inner.store.incr(identifier).await {
-                        Err(e) => {
-                            // store error occur
-                            return if let Some(f) = &inner.controller.fn_on_store_error {
-                                let body = f(req, e);
-                                Ok(ServiceResponse::new(
-                                    req.clone(),
-                                    body.map_into_right_body().map_into_right_body(),
-                                ))
-                            } else {
-                                let body = default_on_store_error::<T>(req, e);
-                                Ok(ServiceResponse::new(
-                                    req.clone(),
-                                    body.map_into_left_body().map_into_right_body(),
-                                ))
+            // get identifier of this request
+            let identifier = inner.controller.find_identifier(svc.request())
+                .map(|identifier| match &inner.controller.normalizer {
+                    Some((normalizer, normalize)) => normalize(normalizer, identifier),
+                    None => identifier,
+                })
+                .map(|identifier| match &inner.controller.cardinality {
+                    Some((guard, guard_key)) => guard_key(guard, identifier),
+                    None => identifier,
+                })
+                .map(|identifier| match &inner.namespace {
+                    Some((namespace, prefix)) => prefix(namespace, identifier),
+                    None => identifier,
+                });
+
+            // the max, the increment and the window of the dynamic policy,
+            // and the keys scoped by the content type and the client class.
+            let (policy, scoped) = match (&inner.policy, &identifier) {
+                (Some(policy), Some(identifier)) => (policy.current(Some(identifier)).await, policy.scoped(identifier, svc.request())),
+                (Some(policy), None) => (policy.current(None).await, Vec::new()),
+                (None, _) => (None, Vec::new()),
+            };
+            let max = match &policy {
+                Some(policy) => policy.max.clone(),
+                None => inner.max.clone(),
+            };
+
+            // the other requests cannot use the slice reserved for the priority requests.
+            let lane = inner.priority.as_ref().filter(|lane| !(lane.is_priority)(svc.request()));
+            let max = match lane {
+                Some(lane) if lane.reserved < max => max.clone() - lane.reserved.clone(),
+                Some(_) => max.clone() - max,
+                None => max,
+            };
+
+            // tag the request with the variant and the labels, before calling the hooks.
+            if let Some(variant) = policy.as_ref().and_then(|policy| policy.variant.clone()) {
+                svc.extensions_mut().insert(variant);
+            }
+            if let Some(labels) = &inner.labels {
+                svc.extensions_mut().insert(labels.clone());
+            }
+
+            // frozen keys are not counted, only inspected.
+            let identifier = match identifier {
+                Some(identifier) if inner.is_frozen(&identifier) => {
+                    if let Some(f) = inner.controller.fn_on_frozen {
+                        let value = match inner.controller.timeout_until(deadline) {
+                            Some(timeout) => tokio::time::timeout(timeout, inner.store.peek(identifier)).await.ok(),
+                            None => Some(inner.store.peek(identifier).await),
+                        }.and_then(Result::ok).flatten();
+                        let limited = value.as_ref().is_some_and(|value| inner.decide(value, &max).limited);
+                        f(svc.request(), value.as_ref(), limited);
+                    }
+                    None
+                },
+                identifier => identifier,
+            };
+
+            // the first request of a key in the window is allowed without calling the store,
+            // unless the window of the key is longer than the filter's, or the key is rejected.
+            let window = policy.as_ref().map(|policy| policy.window).or(inner.window.as_ref().map(|(_, window)| *window));
+            let identifier = match (identifier, &inner.first_seen) {
+                (Some(identifier), Some((filter, check)))
+                    if window.is_none_or(|window| window.to_std().is_ok_and(|window| window <= filter.window()))
+                        && inner.rejections.as_ref().is_none_or(|cache| cache.get(&identifier).is_none())
+                        && check(filter, &identifier, &max) => None,
+                (identifier, _) => identifier,
+            };
+
+            // the retries of an idempotency key allowed in the window are not counted again.
+            let identifier = match (identifier, &inner.idempotency, idempotency_key(svc.request())) {
+                (Some(identifier), Some((filter, hash)), Some(idempotency)) if filter.is_retry(&hash(&identifier), idempotency) => None,
+                (identifier, _, _) => identifier,
+            };
+
+            if let Some(identifier) = identifier { // continue only when identifier is found.
+                let req = svc.request();
+                enforced = inner.controller.rollout.as_ref()
+                    .is_none_or(|rollout| rollout.enforces(&identifier));
+
+                // answer the rejected keys without calling the store,
+                // except the priority requests, which may use the reserved slice.
+                let priority = inner.priority.is_some() && lane.is_none();
+                if enforced && !priority {
+                    if let Some(until) = inner.rejections.as_ref().and_then(|cache| cache.get(&identifier)) {
+                        let rejection = Rejection {
+                            outcome: Outcome::Banned,
+                            key: Some(&identifier),
+                            value: None,
+                            over: None,
+                            until: Some(until),
+                            refunds: Vec::new(),
+                        };
+                        if let Some(body) = inner.reject(req, policy.as_ref(), enforced, rejection).await {
+                            return Ok(respond(svc, body));
+                        }
+                    }
+                }
+                let key = identifier.clone();
+                // the cost of the middleware applies without a policy, which has its own.
+                let cost = inner.cost.clone().filter(|_| policy.is_none());
+                let charge = signature_charge.or(stream_charge).or(cost);
+                // the increment of the key, if not the default one of the store.
+                let key_charge = match (&charge, &policy, &inner.window) {
+                    (Some(charge), _, _) => Some(charge.clone()),
+                    (None, Some(policy), _) => Some(policy.incr.clone()),
+                    (None, None, Some((incr, _))) => Some(incr.clone()),
+                    (None, None, None) => None,
+                };
+
+                let incr = match (&policy, charge, &inner.window) {
+                    (Some(policy), charge, _) => inner.store.incr_with_ttl(identifier, charge.unwrap_or_else(|| policy.incr.clone()), policy.window),
+                    (None, charge, Some((incr, window))) => inner.store.incr_with_ttl(identifier, charge.unwrap_or_else(|| incr.clone()), *window),
+                    (None, Some(charge), None) => inner.store.incr_by(identifier, charge),
+                    (None, None, None) => inner.store.incr(identifier),
+                };
+                let result = inner.within_deadline(incr, deadline).await;
+                let fail_open = match &result {
+                    None => inner.controller.failure_policy.is_open(ErrorClass::Transient),
+                    Some(Err(e)) => inner.controller.failure_policy.is_open(inner.store.classify_error(e)),
+                    Some(Ok(_)) => false,
+                };
+
+                match result {
+                    None | Some(Err(_)) if fail_open => {
+                        // store timeout or error occur, but let the request pass
+                        inner.degradation.fail_open();
+                        failed_open = true;
+                    },
+                    None => {
+                        // store timeout occur
+                        let body = inner.store_failure(req, None);
+                        return Ok(respond(svc, body));
+                    },
+                    Some(Err(e)) => {
+                        // store error occur
+                        let body = inner.store_failure(req, Some(e));
+                        return Ok(respond(svc, body));
+                    },
+                    Some(Ok(mut value)) => {
+                        inner.degradation.recover();
+                        let mut decision = inner.decide(&value, &max);
+
+                        // wait for the window to reset, instead of rejecting.
+                        if let Some(queue) = &inner.queue {
+                            if decision.limited && enforced {
+                                if let Some(queued) = inner.wait_in_queue(queue.as_ref(), key.clone(), &value, policy.as_ref(), &max, deadline).await {
+                                    value = queued;
+                                    decision = inner.decide(&value, &max);
+                                }
                             }
+                        }
+                        grace = decision.over;
 
-                        },
-                        Ok(value) => {
-                            if value.count() > inner.max {
-                                // rate limit error occur
-                                let err = Error::RateLimited(value.expire_date());
-
-                                return if let Some(f) = &inner.controller.fn_on_rate_limit_error {
-                                    let body = f(req, err);
-                                    Ok(ServiceResponse::new(
-                                        req.clone(),
-                                        body.map_into_right_body().map_into_right_body(),
-                                    ))
-                                } else {
-                                    let body = default_on_rate_limit_error(req, err);
-                                    Ok(ServiceResponse::new(
-                                        req.clone(),
-                                        body.map_into_left_body().map_into_right_body(),
-                                    ))
+                        if decision.limited {
+                            // refund the rejected request, so it does not take the reserved slice.
+                            let refunds = match lane {
+                                Some(lane) => vec![(key.clone(), key_charge.clone().unwrap_or_else(|| lane.one.clone()), &value)],
+                                None => Vec::new(),
+                            };
+                            let rejection = Rejection {
+                                outcome: Outcome::Rejected,
+                                key: Some(&key),
+                                value: Some(&value),
+                                over: Some((&value, &max)),
+                                until: value.expire_date(),
+                                refunds,
+                            };
+                            if let Some(body) = inner.reject(req, policy.as_ref(), enforced, rejection).await {
+                                if let (Some(cache), Some(until)) = (&inner.rejections, value.expire_date()) {
+                                    #[cfg(feature = "audit")]
+                                    if let Some((audit, format)) = &inner.audit {
+                                        let policy = inner.policy_name(policy.as_ref());
+                                        let _ = audit.record(AuditAction::Banned, &format(&key), policy, value.count());
+                                    }
+                                    cache.insert(key, until);
                                 }
+                                return Ok(respond(svc, body));
                             }
+                        }
 
-                            rate_limit_value = Some(value);
+                        if let Some(threshold) = &inner.controller.threshold {
+                            if threshold.crossed(&max, &value.count(), key_charge.as_ref()) {
+                                (threshold.hook)(req, &value);
+                            }
+                        }
+
+                        rate_limit_value = Some(value);
+                        counted = Some((key, key_charge));
+                    },
+                }
+            }
+
+            // charge the keys scoped by the content type and the client class, and roll them back
+            // with the charge of the key if any is over the max of its policy.
+            if let (Some((key, key_charge)), Some(key_value)) = (&counted, &rate_limit_value) {
+                let req = svc.request();
+                let mut charged = Vec::new();
+                for (scoped_key, scoped_policy) in &scoped {
+                    let charge = inner.store.incr_with_ttl(scoped_key.clone(), scoped_policy.incr.clone(), scoped_policy.window);
+                    match inner.within_deadline(charge, deadline).await {
+                        Some(Ok(value)) => charged.push((scoped_key, scoped_policy, value)),
+                        failed => {
+                            let class = match &failed {
+                                Some(Err(e)) => inner.store.classify_error(e),
+                                _ => ErrorClass::Transient,
+                            };
+                            if !inner.controller.failure_policy.is_open(class) {
+                                let body = inner.store_failure(req, failed.and_then(Result::err));
+                                return Ok(respond(svc, body));
+                            }
+                            inner.degradation.fail_open();
+                            failed_open = true;
                         },
                     }
                 }
+
+                let over = charged.iter().find(|(_, scoped_policy, value)| {
+                    let decision = inner.decide(value, &scoped_policy.max);
+                    grace |= decision.over;
+                    decision.limited
+                });
+                if let Some((_, scoped_policy, value)) = over {
+                    let mut refunds: Vec<_> = charged.iter()
+                        .map(|(scoped_key, scoped_policy, value)| ((*scoped_key).clone(), scoped_policy.incr.clone(), value))
+                        .collect();
+                    refunds.push((key.clone(), key_charge.clone().unwrap_or_else(|| scoped_policy.incr.clone()), key_value));
+                    let rejection = Rejection {
+                        outcome: Outcome::Rejected,
+                        key: Some(key),
+                        value: Some(key_value),
+                        over: Some((value, &scoped_policy.max)),
+                        until: value.expire_date(),
+                        refunds,
+                    };
+                    if let Some(body) = inner.reject(req, policy.as_ref(), enforced, rejection).await {
+                        return Ok(respond(svc, body));
+                    }
+                }
+            }
+
+            // charge all levels of the hierarchy at once, and roll them back if any is over its max.
+            if let Some(hierarchy) = &inner.hierarchy {
+                let req = svc.request();
+                let levels: Vec<_> = hierarchy.levels.iter()
+                    .filter_map(|(name, max, key)| Some(((hierarchy.to_key)(format!("{}:{}", name, key(req)?)), max)))
+                    .collect();
+                let charges = levels.iter()
+                    .map(|(key, _)| (key.clone(), hierarchy.incr.clone()))
+                    .collect();
+
+                let result = inner.within_deadline(inner.store.incr_many(charges), deadline).await;
+
+                match result {
+                    Some(Ok(values)) => {
+                        let over = values.iter().zip(&levels).find(|(value, (_, max))| {
+                            let decision = inner.decide(value, max);
+                            grace |= decision.over;
+                            decision.limited
+                        });
+                        if let Some((value, (_, max))) = over {
+                            // roll back the charges of all levels and of the key.
+                            let mut refunds: Vec<_> = levels.iter().zip(&values)
+                                .map(|((key, _), value)| (key.clone(), hierarchy.incr.clone(), value))
+                                .collect();
+                            if let (Some((key, charge)), Some(value)) = (&counted, &rate_limit_value) {
+                                refunds.push((key.clone(), charge.clone().unwrap_or_else(|| hierarchy.incr.clone()), value));
+                            }
+                            let rejection = Rejection {
+                                outcome: Outcome::Rejected,
+                                key: counted.as_ref().map(|(key, _)| key),
+                                value: rate_limit_value.as_ref(),
+                                over: Some((value, *max)),
+                                until: value.expire_date(),
+                                refunds,
+                            };
+                            if let Some(body) = inner.reject(req, policy.as_ref(), enforced, rejection).await {
+                                return Ok(respond(svc, body));
+                            }
+                        }
+                    },
+                    failed => {
+                        let class = match &failed {
+                            Some(Err(e)) => inner.store.classify_error(e),
+                            _ => ErrorClass::Transient,
+                        };
+                        if !inner.controller.failure_policy.is_open(class) {
+                            let body = inner.store_failure(req, failed.and_then(Result::err));
+                            return Ok(respond(svc, body));
+                        }
+                        inner.degradation.fail_open();
+                        failed_open = true;
+                    },
+                }
+            }
+
+            // the global budget, after the limits of the key.
+            if let Some((budget, one)) = &inner.budget {
+                if let Err(until) = budget.acquire(svc.request()) {
+                    // roll back the charge of the key.
+                    let refunds = match (&counted, &rate_limit_value) {
+                        (Some((key, charge)), Some(value)) => vec![(key.clone(), charge.clone().unwrap_or_else(|| one.clone()), value)],
+                        _ => Vec::new(),
+                    };
+                    let rejection = Rejection {
+                        outcome: Outcome::Rejected,
+                        key: counted.as_ref().map(|(key, _)| key),
+                        value: rate_limit_value.as_ref(),
+                        over: None,
+                        until: Some(until),
+                        refunds,
+                    };
+                    if let Some(body) = inner.reject(svc.request(), policy.as_ref(), enforced, rejection).await {
+                        return Ok(respond(svc, body));
+                    }
+                }
+            }
+
+            // remember the idempotency key of the allowed request, until the end of the window.
+            if let (Some((filter, hash)), Some((key, _)), Some(value)) = (&inner.idempotency, &counted, &rate_limit_value) {
+                if let (Some(idempotency), Some(until)) = (idempotency_key(svc.request()), value.expire_date()) {
+                    if !failed_open {
+                        filter.remember(&hash(key), idempotency, until);
+                    }
+                }
+            }
+
+            // forward quota headers to inner services
+            if inner.controller.forward_quota_headers {
+                let headers = svc.headers_mut();
+                headers.remove(DEFAULT_RATE_LIMIT_LIMIT_HEADER);
+                headers.remove(DEFAULT_RATE_LIMIT_REMAINING_HEADER);
+
+                if let Some(value) = &rate_limit_value {
+                    insert_header(headers, DEFAULT_RATE_LIMIT_LIMIT_HEADER, max.to_string());
+                    insert_header(headers, DEFAULT_RATE_LIMIT_REMAINING_HEADER, remaining(&max, &value.count()));
+                }
             }
 
             // rate-limit bypass
             // Add a marker to the request to ensure that no further checks are performed on it.
-            RateLimitByPass::<T>::check(svc.request(), rate_limit_value.clone());
+            let remaining = rate_limit_value.as_ref().map(|value| remaining(&max, &value.count()));
+            let outcome = match &rate_limit_value {
+                _ if failed_open => Outcome::FailedOpen,
+                Some(_) => Outcome::Allowed,
+                None => Outcome::Bypassed,
+            };
+            RateLimitByPass::<T>::check(svc.request(), name, outcome, rate_limit_value.clone(), grace, remaining);
 
             // call on-success
-            if let Some(f) = inner.controller.fn_on_success {
+            if let Some(f) = inner.controller.sampled_on_success() {
                 f(svc.request(), &inner.store, rate_limit_value.as_ref());
             }
 
-            // rate-limit bypass
-            let res = service.call(svc).await?.map_into_left_body();
-            Ok(res)
-        })
+            let mut res = service.call(svc).await?;
+            drop(stream);
+
+            // refund the responses served from cache, until the end of the window.
+            if let (Some((refund, one, min)), Some((key, key_charge)), Some(value)) = (&inner.cache_refund, counted, &rate_limit_value) {
+                if CacheHit::is_cache_hit(res.response()) {
+                    let ttl = value.expire_date().map(|until| until - Utc::now());
+                    if let Some(ttl) = ttl.filter(|ttl| *ttl > crate::time::Duration::zero()) {
+                        let refund = min(refund.clone(), key_charge.unwrap_or_else(|| one.clone()));
+                        let _ = inner.store.grant(key, refund, ttl).await;
+                    }
+                }
+            }
+
+            // insert success headers
+            if let Some(value) = &rate_limit_value {
+                insert_success_headers(res.headers_mut(), inner.controller.success_headers, &max, value);
+            }
+
+            Ok(res.map_into_left_body())
+        }))
+    }
+}
+
+/// [BoxedRateLimit] is a [RateLimit] which maps the responses into [BoxBody]
+/// (see [RateLimit::boxed]), so the body type of the wrapped service stays
+/// `BoxBody` for [Condition](actix_web::middleware::Condition), scopes and the
+/// functions returning an [App](actix_web::App).
+pub struct BoxedRateLimit<T: Store, CB: MessageBody = BoxBody> {
+    inner: RateLimit<T, CB>,
+}
+
+impl<T: Store, CB: MessageBody> BoxedRateLimit<T, CB> {
+    /// create a new [BoxedRateLimit] middleware, same as `RateLimit::new(store, max, controller).boxed()`.
+    pub fn new(
+        store: T,
+        max: <<T as Store>::Value as Value>::Count,
+        controller: Controller<T, CB>
+    ) -> Self {
+        RateLimit::new(store, max, controller).boxed()
+    }
+
+    /// Return the [RateLimit], such as the handle to [RateLimit::grant].
+    pub fn inner(&self) -> &RateLimit<T, CB> {
+        &self.inner
+    }
+}
+
+impl<T: Store, CB: MessageBody> Clone for BoxedRateLimit<T, CB> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, CB, S, B> Transform<S, ServiceRequest> for BoxedRateLimit<T, CB>
+    where
+        T: Store + 'static,
+        CB: MessageBody + 'static,
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+        S::Future: 'static,
+        B: MessageBody + 'static,
+        <T as Store>::Key: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = S::Error;
+    type Transform = BoxedRateLimitService<T, CB, S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(BoxedRateLimitService {
+            service: RateLimitService {
+                inner: self.inner.inner.clone(),
+                service: Rc::new(service),
+            },
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct BoxedRateLimitService<T, CB, S>
+    where
+        T: Store,
+        CB: MessageBody,
+{
+    service: RateLimitService<T, CB, S>,
+}
+
+impl<T, CB, S, B> Service<ServiceRequest> for BoxedRateLimitService<T, CB, S>
+    where
+        T: Store + 'static,
+        CB: MessageBody + 'static,
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+        S::Future: 'static,
+        B: MessageBody + 'static,
+        <T as Store>::Key: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxedFuture<
+        <RateLimitService<T, CB, S> as Service<ServiceRequest>>::Future,
+        <RateLimitService<T, CB, S> as Service<ServiceRequest>>::Response,
+    >;
+
+    forward_ready!(service);
+
+    fn call(&self, svc: ServiceRequest) -> Self::Future {
+        let map: fn(_) -> Self::Response = ServiceResponse::map_into_boxed_body;
+        self.service.call(svc).map_ok(map)
     }
 }
 
+/// Respond to the rejected request with `res`, consuming the request instead of cloning it.
+fn respond<B, CB>(svc: ServiceRequest, res: HttpResponse<EitherBody<BoxBody, CB>>) -> ServiceResponse<RateLimitBody<B, CB>> {
+    let (req, _) = svc.into_parts();
+    ServiceResponse::new(req, res.map_into_right_body())
+}
+
 impl<T: Store, CB: MessageBody> RateLimit<T, CB> {
     /// create a new [RateLimit] middleware, with all custom functions.
     pub fn new(
@@ -169,21 +1108,711 @@ impl<T: Store, CB: MessageBody> RateLimit<T, CB> {
             inner: Arc::new(RateLimitInner {
                 store,
                 max,
+                evaluator: Arc::new(CountEvaluator),
+                semantics: LimitSemantics::default(),
                 controller,
+                frozen: Default::default(),
+                policy: None,
+                rejections: None,
+                queue: None,
+                signature: None,
+                namespace: None,
+                window: None,
+                budget: None,
+                cache_refund: None,
+                backpressure: false,
+                degradation: Arc::default(),
+                hierarchy: None,
+                labels: None,
+                stream_cost: None,
+                cost: None,
+                priority: None,
+                first_seen: None,
+                idempotency: None,
+                #[cfg(feature = "audit")]
+                audit: None,
+                #[cfg(feature = "hmac")]
+                proof: None,
             })
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use actix_web::{App, HttpRequest, HttpResponse, test, web};
-    use actix_web::http::StatusCode;
-    use chrono::{Utc};
-    use tokio::time::Instant;
-    use crate::controller::{default_find_identifier, DEFAULT_RATE_LIMITED_UNTIL_HEADER};
-    use crate::store::MemStore;
-    use super::*;
+    /// Create a [RateLimit] for a nested scope, which shares the [Store] (and so its clock)
+    /// and the [Controller] of `parent`, but allows `max` requests per `window`,
+    /// counted under the keys `{namespace}:{identifier}`. The [Controller] is named by
+    /// `namespace` (see [Controller::with_name]).
+    ///
+    /// ```rust
+    /// # use actix_web::{App, web};
+    /// use actix_rl::middleware::RateLimit;
+    ///
+    /// let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::minutes(1));
+    /// let global = RateLimit::new(store, 600, actix_rl::controller::Controller::default());
+    /// let login = RateLimit::scoped(&global, "login", 5, actix_rl::time::Duration::minutes(10));
+    /// App::new()
+    ///     .wrap(global)
+    ///     .service(web::scope("/login").wrap(login));
+    /// ```
+    pub fn scoped<N: ToString>(parent: &Self, namespace: N, max: <<T as Store>::Value as Value>::Count, window: crate::time::Duration) -> Self
+        where
+            T: Store<Key = String>,
+            T::Count: From<u8>,
+    {
+        // named by the namespace, so the requests checked by `parent` are checked again.
+        let controller = parent.inner.controller.clone().with_name(namespace.to_string());
+        let mut scoped = Self::new(parent.inner.store.clone(), max, controller);
+        let inner = Arc::make_mut(&mut scoped.inner);
+        inner.signature = parent.inner.signature.clone();
+        inner.namespace = Some((namespace.to_string(), |namespace, key| format!("{}:{}", namespace, key)));
+        inner.window = Some((1u8.into(), window));
+        scoped
+    }
+
+    /// Like [Self::new], but reject the configurations which would never limit
+    /// (no identifier extractor) or always limit (zero `max`, or a window of the [Store]
+    /// which is not positive, see [Store::window]).
+    pub fn try_new(
+        store: T,
+        max: <<T as Store>::Value as Value>::Count,
+        controller: Controller<T, CB>
+    ) -> Result<Self, ConfigError>
+        where <<T as Store>::Value as Value>::Count: Default,
+    {
+        let rate_limit = Self::new(store, max, controller);
+        rate_limit.check()?;
+        Ok(rate_limit)
+    }
+
+    /// Check the configuration after the builders, like [Self::try_new], and that the costs
+    /// (of [Self::with_cost], the charge of [Self::with_signature_check] and the cost
+    /// of [Self::with_stream_cost]) are at most the max, as the requests costing more
+    /// are always limited. The windows of the policies and of [Self::scoped] are refused
+    /// with [ConfigError::UnsupportedWindow] if the [Store] would ignore them.
+    ///
+    /// ```rust
+    /// use actix_rl::error::ConfigError;
+    /// use actix_rl::middleware::RateLimit;
+    ///
+    /// let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::minutes(1));
+    /// let controller = actix_rl::controller::Controller::default();
+    /// let rate_limit = RateLimit::try_new(store, 10, controller).unwrap().with_stream_cost(4, 20);
+    /// assert_eq!(rate_limit.validate().err(), Some(ConfigError::CostOverMax));
+    /// ```
+    pub fn validate(&self) -> Result<(), ConfigError>
+        where
+            <<T as Store>::Value as Value>::Count: Default,
+            T::Count: Into<<<T as Store>::Value as Value>::Count>,
+    {
+        self.check()?;
+
+        let inner = &*self.inner;
+        let mut costs = inner.window.iter().map(|(incr, _)| incr)
+            .chain(&inner.cost)
+            .chain(inner.signature.iter().filter_map(|check| check.charge.as_ref()))
+            .chain(inner.stream_cost.iter().map(|(_, cost)| cost));
+        if costs.any(|cost| cost.clone().into() > inner.max) {
+            return Err(ConfigError::CostOverMax);
+        }
+
+        Ok(())
+    }
+
+    /// Check the max, the windows (and that the [Store] supports the windows overriding its own)
+    /// and the identifier extractor.
+    fn check(&self) -> Result<(), ConfigError>
+        where <<T as Store>::Value as Value>::Count: Default,
+    {
+        let inner = &*self.inner;
+        if inner.max <= Default::default() {
+            return Err(ConfigError::ZeroMax);
+        }
+
+        let mut windows = inner.store.window().into_iter().chain(inner.window.iter().map(|(_, window)| *window));
+        if let Some(window) = windows.find(|window| *window <= crate::time::Duration::zero()) {
+            return Err(ConfigError::NonPositiveWindow(window));
+        }
+
+        if (inner.window.is_some() || inner.policy.is_some()) && !inner.store.supports_ttl() {
+            return Err(ConfigError::UnsupportedWindow);
+        }
+
+        if !inner.controller.has_identifier() {
+            return Err(ConfigError::MissingIdentifier);
+        }
+
+        Ok(())
+    }
+
+    /// Use the [Policy](crate::policy::Policy) of `name` in `set` for each request,
+    /// overriding `max` and the window of the [Store] (see [Store::incr_with_ttl]),
+    /// so the limits can be changed at runtime, such as by [spawn_policy_refresh](crate::policy_provider::spawn_policy_refresh).
+    /// `max` and the window of the [Store] apply while `set` has no policy of `name`.
+    ///
+    /// The algorithm and the headers of the policy are not applied.
+    pub fn with_policy_set<N: ToString>(mut self, set: PolicySet, name: N) -> Self
+        where
+            <<T as Store>::Value as Value>::Count: TryFrom<u32>,
+            T::Count: TryFrom<u32>,
+    {
+        self.policy_mut().set = Some((set, name.to_string()));
+        self
+    }
+
+    /// Use the [Policy](crate::policy::Policy) of each key (such as a tenant) from `provider`,
+    /// overriding [Self::with_policy_set], `max` and the window of the [Store].
+    pub fn with_key_policies<P: KeyPolicyProvider<T::Key> + 'static>(mut self, provider: P) -> Self
+        where
+            <<T as Store>::Value as Value>::Count: TryFrom<u32>,
+            T::Count: TryFrom<u32>,
+    {
+        self.policy_mut().keys = Some(Arc::new(provider));
+        self
+    }
+
+    /// Assign each key to a variant of `experiment`, and use the [Policy](crate::policy::Policy)
+    /// of the variant, overriding [Self::with_policy_set], `max` and the window of the [Store].
+    /// The requests are tagged with their [PolicyVariant] (see [RateLimitByPass::variant]).
+    /// The policies of [Self::with_key_policies] take precedence.
+    pub fn with_experiment(mut self, experiment: Experiment) -> Self
+        where
+            <<T as Store>::Value as Value>::Count: TryFrom<u32>,
+            T::Count: TryFrom<u32>,
+            T::Key: Hash,
+    {
+        self.policy_mut().experiment = Some((experiment, |experiment, key| {
+            experiment.assign(key).map(|(variant, policy)| (variant.clone(), *policy))
+        }));
+        self
+    }
+
+    /// Check `policy` for the requests whose `Content-Type` matches `content_type`, such as
+    /// `application/grpc-web` or `multipart/*` (matching `multipart/form-data`), in addition
+    /// to the limit of the key. The parameters of the header (such as `; charset=utf-8`) are
+    /// ignored. The first matching policy applies.
+    ///
+    /// Each content type has its own counter, with the key `{identifier}:{content_type}`,
+    /// charged after the key is allowed; the rejected requests are refunded to both counters
+    /// (with [Store::grant], so the store is a [GrantStore]), so a content type can only
+    /// lower the limit of the key.
+    pub fn with_content_type_policy<C: ToString>(mut self, content_type: C, policy: Policy) -> Self
+        where
+            T: GrantStore + Store<Key = String>,
+            <<T as Store>::Value as Value>::Count: TryFrom<u32>,
+            T::Count: TryFrom<u32>,
+    {
+        self.policy_mut().content_types
+            .get_or_insert_with(|| ContentTypePolicies {
+                rules: Vec::new(),
+                scope: |key, content_type| format!("{}:{}", key, content_type),
+            })
+            .rules
+            .push((content_type.to_string().to_ascii_lowercase(), policy));
+        self
+    }
+
+    /// Check the [Policy] of the client class of each request (such as crawlers, headless
+    /// browsers or requests without a `User-Agent`) in addition to the limit of the key,
+    /// see [UserAgentPolicies].
+    ///
+    /// Each class has its own counter, with the key `{identifier}:ua:{class}`, charged as
+    /// for [Self::with_content_type_policy], so a class can only lower the limit of the key.
+    pub fn with_user_agent_policies(mut self, policies: UserAgentPolicies) -> Self
+        where
+            T: GrantStore + Store<Key = String>,
+            <<T as Store>::Value as Value>::Count: TryFrom<u32>,
+            T::Count: TryFrom<u32>,
+    {
+        self.policy_mut().user_agents = Some((policies, |key, class| format!("{}:ua:{}", key, class)));
+        self
+    }
+
+    /// Check the levels of `hierarchy` (such as global and per-tenant limits) after the limit
+    /// of the key. All levels are charged in one call (see [Store::incr_many]); if any of them
+    /// is over its max, the request is rejected as by the limit of the key (with
+    /// [Controller::on_rate_limit_error], the audit log, the proof token and the tarpit),
+    /// and the charges of all levels and of the key are rolled back with [Store::grant]
+    /// (so the store is a [GrantStore]) until the end of their windows, so the rejected
+    /// requests do not use the budgets of the other levels.
+    ///
+    /// Panics if a max does not fit the count of the [Store].
+    pub fn with_hierarchy(mut self, hierarchy: HierarchicalPolicy) -> Self
+        where
+            T: GrantStore + Store<Key = String>,
+            <<T as Store>::Value as Value>::Count: TryFrom<u32>,
+            T::Count: From<u8>,
+    {
+        let levels = hierarchy.levels.into_iter()
+            .map(|(name, max, key)| match max.try_into() {
+                Ok(max) => (name, max, key),
+                Err(_) => panic!("the max of level {} does not fit the count of the store", name),
+            })
+            .collect();
+        Arc::make_mut(&mut self.inner)
+            .hierarchy = Some(HierarchyCheck {
+                levels,
+                incr: 1u8.into(),
+                to_key: |key| key,
+            });
+        self
+    }
+
+    fn policy_mut(&mut self) -> &mut DynamicPolicy<T>
+        where
+            <<T as Store>::Value as Value>::Count: TryFrom<u32>,
+            T::Count: TryFrom<u32>,
+    {
+        Arc::make_mut(&mut self.inner)
+            .policy
+            .get_or_insert_with(|| DynamicPolicy::new(|policy| Some((policy.max.try_into().ok()?, policy.cost.try_into().ok()?))))
+    }
+
+    /// Map the responses into [BoxBody], see [BoxedRateLimit].
+    ///
+    /// ```rust
+    /// # use actix_web::App;
+    /// # use actix_web::middleware::Condition;
+    /// # let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::seconds(10));
+    /// # let rate_limit = actix_rl::middleware::RateLimit::new(store, 10, actix_rl::controller::Controller::default());
+    /// let enabled = std::env::var("RATE_LIMIT").is_ok();
+    /// App::new()
+    ///     .wrap(Condition::new(enabled, rate_limit.boxed()))
+    ///     // ...
+    /// # ;
+    /// ```
+    pub fn boxed(self) -> BoxedRateLimit<T, CB> {
+        BoxedRateLimit {
+            inner: self,
+        }
+    }
+
+    /// Freeze `key`: its requests are allowed and not counted, while
+    /// [Controller::on_frozen] receives what the decisions would have been.
+    /// Use it to reproduce the rate-limit complaints of a user against live traffic.
+    pub fn freeze(&self, key: T::Key)
+        where T::Key: PartialEq,
+    {
+        let mut frozen = self.inner.frozen.write().unwrap_or_else(PoisonError::into_inner);
+        if !frozen.iter().any(|(frozen, _)| *frozen == key) {
+            frozen.push((key, |a, b| a == b));
+        }
+    }
+
+    /// Unfreeze `key`, see [Self::freeze].
+    pub fn unfreeze(&self, key: &T::Key)
+        where T::Key: PartialEq,
+    {
+        self.inner.frozen.write().unwrap_or_else(PoisonError::into_inner)
+            .retain(|(frozen, _)| frozen != key);
+    }
+
+    /// Return whether the limits are currently enforced accurately, such as for a status
+    /// endpoint. Keep a clone of the middleware as the handle. See [DegradationStatus].
+    pub fn degradation(&self) -> DegradationStatus {
+        self.inner.degradation.status()
+    }
+
+    /// Record the failures in `degradation`, shared with the other middlewares of a
+    /// [LimiterRegistry](crate::registry::LimiterRegistry).
+    pub(crate) fn with_degradation(mut self, degradation: Arc<Degradation>) -> Self {
+        Arc::make_mut(&mut self.inner)
+            .degradation = degradation;
+        self
+    }
+
+    /// Count, read and delete [SELF_TEST_KEY] (in the namespace of [Self::scoped]) in the [Store],
+    /// bounded by [Controller::with_store_timeout], and check the dates of the window against
+    /// the local clock. Call it before `HttpServer::run`, so a misconfigured limiter fails fast:
+    ///
+    /// ```rust
+    /// # async fn start() {
+    /// use actix_rl::middleware::RateLimit;
+    ///
+    /// let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::minutes(1));
+    /// let rate_limit = RateLimit::new(store, 10, actix_rl::controller::Controller::default());
+    /// let report = rate_limit.self_test().await;
+    /// assert!(report.is_ok(), "{}", report);
+    /// // HttpServer::new(...).run()
+    /// # }
+    /// ```
+    pub async fn self_test(&self) -> SelfTestReport
+        where T::Key: From<String>,
+    {
+        let key = T::Key::from(SELF_TEST_KEY.to_string());
+        let key = match &self.inner.namespace {
+            Some((namespace, prefix)) => prefix(namespace, key),
+            None => key,
+        };
+        self_test::run(&self.inner.store, key, self.inner.controller.store_timeout).await
+    }
+
+    /// Charge `key` by `cost` for a long-running request, and hold the charge until the
+    /// [Reservation] is committed or cancelled (see [Reservation]).
+    /// Return [None] if the charge is over the max of the middleware, then it is cancelled at once.
+    /// Keep a clone of the middleware as the handle.
+    pub async fn reserve(&self, key: T::Key, cost: T::Count) -> Result<Option<Reservation<T>>, T::Error>
+        where
+            T: GrantStore + 'static,
+            T::Key: 'static,
+            T::Count: 'static,
+    {
+        let reservation = Reservation::reserve(self.inner.store.clone(), key, cost).await?;
+        if self.inner.decide(reservation.value(), &self.inner.max).limited {
+            reservation.cancel().await?;
+            return Ok(None);
+        }
+        Ok(Some(reservation))
+    }
+
+    /// Attach an operator `note` and `flags` (such as `known partner` and `do-not-ban`) to `key`
+    /// for `ttl`, as its [Metadata] under [NOTE_METADATA_KEY] and [FLAGS_METADATA_KEY], so they
+    /// reach the hooks with [Value::metadata]. A [None] note or no flags remove them;
+    /// the other metadata of the key is kept while the key is in its window.
+    /// Keep a clone of the middleware as the handle. See [Store::set_metadata].
+    pub async fn annotate(&self, key: T::Key, note: Option<&str>, flags: &[&str], ttl: crate::time::Duration) -> Result<(), T::Error> {
+        let mut metadata = self.inner.store.peek(key.clone()).await?
+            .and_then(|value| value.metadata().cloned())
+            .unwrap_or_default();
+        match note {
+            Some(note) => metadata.insert(NOTE_METADATA_KEY.to_string(), note.to_string()),
+            None => metadata.remove(NOTE_METADATA_KEY),
+        };
+        match flags {
+            [] => metadata.remove(FLAGS_METADATA_KEY),
+            flags => metadata.insert(FLAGS_METADATA_KEY.to_string(), flags.join(",")),
+        };
+        self.inner.store.set_metadata(key, metadata, ttl).await
+    }
+
+    /// Give `key` `extra` quota for `ttl`, without resetting its counter.
+    /// Keep a clone of the middleware as the handle. See [Store::grant].
+    pub async fn grant(&self, key: T::Key, extra: T::Count, ttl: crate::time::Duration) -> Result<(), T::Error>
+        where T: GrantStore,
+    {
+        if let Some(cache) = &self.inner.rejections {
+            cache.remove(&key);
+        }
+        self.inner.store.grant(key, extra, ttl).await
+    }
+
+    /// Let the requests over the limit wait for the windows of their keys to reset,
+    /// up to `max_wait`, instead of rejecting them at once. When the window resets, the
+    /// waiting requests of a key retry in FIFO order, and the keys take turns in
+    /// round-robin order. A retry is counted only when the window has room.
+    ///
+    /// At most `max_waiting` requests wait at once, and each waiting key gets a fair
+    /// share of them, with a share always kept for the next key, so one noisy key cannot
+    /// take all the capacity. The requests which cannot wait, or are still over the limit
+    /// after `max_wait`, are rejected.
+    pub fn with_queue(mut self, max_waiting: usize, max_wait: std::time::Duration) -> Self
+        where T::Key: Hash + Eq + 'static,
+    {
+        Arc::make_mut(&mut self.inner)
+            .queue = Some(Arc::new(FairQueue::new(max_waiting, max_wait)));
+        self
+    }
+
+    /// Decide whether the requests are over the limit with `evaluator`, instead of comparing
+    /// their counts with the max ([CountEvaluator]). It applies to the max of the middleware,
+    /// of the policies and of the levels of [Self::with_hierarchy], and keeps the semantics
+    /// of [Self::with_limit_semantics].
+    ///
+    /// ```rust
+    /// use actix_rl::middleware::RateLimit;
+    /// use actix_rl::store::Value;
+    /// use actix_rl::store::mem_store::{DateCountUntil, MemStore};
+    ///
+    /// let store = MemStore::new(1024, actix_rl::time::Duration::minutes(1));
+    /// // allow bursts of twice the max in a window.
+    /// let rate_limit = RateLimit::new(store, 10, actix_rl::controller::Controller::default())
+    ///     .with_evaluator(|value: &DateCountUntil, max: &u32| value.count() > max * 2);
+    /// ```
+    pub fn with_evaluator<E: LimitEvaluator<T::Value> + 'static>(mut self, evaluator: E) -> Self {
+        Arc::make_mut(&mut self.inner)
+            .evaluator = Arc::new(evaluator);
+        self
+    }
+
+    /// Set whether the max is allowed ([LimitSemantics::AllowMax], the default: a max of 10
+    /// rejects the 11th request) or rejected ([LimitSemantics::RejectAtMax]: a max of 10
+    /// rejects the 10th request), so the limits match the written policies exactly.
+    /// The semantics are passed to the evaluator of [Self::with_evaluator], whatever the order
+    /// of the builders: [CountEvaluator] follows them, and functions ignore them.
+    ///
+    /// The remaining headers still count down to the max, so with [LimitSemantics::RejectAtMax]
+    /// the last allowed request reads 1.
+    pub fn with_limit_semantics(mut self, semantics: LimitSemantics) -> Self {
+        Arc::make_mut(&mut self.inner)
+            .semantics = semantics;
+        self
+    }
+
+    /// Report the service as not ready while the queue of [Self::with_queue] is full,
+    /// so actix-web stops reading the next requests of the connections (applying
+    /// backpressure to the clients) instead of accepting requests it may reject at once.
+    ///
+    /// All requests are held, including those within their limits. Has no effect without [Self::with_queue].
+    pub fn with_backpressure(mut self, enable: bool) -> Self {
+        Arc::make_mut(&mut self.inner)
+            .backpressure = enable;
+        self
+    }
+
+    /// Attach `labels` (such as the owning team) to the requests counted by this middleware,
+    /// for the hooks, the metrics and the access logs. See [PolicyLabels].
+    pub fn with_labels(mut self, labels: PolicyLabels) -> Self {
+        Arc::make_mut(&mut self.inner)
+            .labels = Some(labels);
+        self
+    }
+
+    /// Count the requests in windows of `window`, a humantime-style string such as `"90s"`
+    /// (see [parse_duration](crate::utils::parse_duration)), instead of the TTL of the [Store]
+    /// (see [Store::incr_with_ttl]), so the deployment configs read naturally.
+    ///
+    /// ```rust
+    /// let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::minutes(1));
+    /// let rate_limit = actix_rl::middleware::RateLimit::new(store, 100, actix_rl::controller::Controller::default())
+    ///     .window("90s");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window` cannot be parsed, or is not positive.
+    pub fn window(mut self, window: &str) -> Self
+        where T::Count: From<u8>,
+    {
+        let window = match crate::utils::parse_duration(window) {
+            Ok(window) if window > crate::time::Duration::zero() => window,
+            Ok(window) => panic!("invalid window: {}", ConfigError::NonPositiveWindow(window)),
+            Err(e) => panic!("invalid window: {}", e),
+        };
+        Arc::make_mut(&mut self.inner)
+            .window = Some((1u8.into(), window));
+        self
+    }
+
+    /// Count each request as `cost` requests, instead of the increment of the [Store]
+    /// (or of [Self::scoped]). The policies (such as of [Self::with_policy_set]) keep their
+    /// own [Policy::cost], and the charges of [Self::with_signature_check] and
+    /// [Self::with_stream_cost] replace it.
+    pub fn with_cost(mut self, cost: T::Count) -> Self {
+        Arc::make_mut(&mut self.inner)
+            .cost = Some(cost);
+        self
+    }
+
+    /// Charge `cost` instead of the increment for the requests arriving while more than
+    /// `free_streams` requests are in flight on the same connection, such as the concurrent
+    /// streams of an HTTP/2 connection, so a client multiplexing many streams uses up its
+    /// limit faster than a browser with a few. The invalid signatures keep their own charge.
+    ///
+    /// The streams are counted by [ConnectionStreams], inserted by [ConnectionId::on_connect](crate::identifier::ConnectionId::on_connect);
+    /// the connections without it are charged as usual.
+    pub fn with_stream_cost(mut self, free_streams: usize, cost: T::Count) -> Self {
+        Arc::make_mut(&mut self.inner)
+            .stream_cost = Some((free_streams, cost));
+        self
+    }
+
+    /// Verify the signature of each counted request with `verifier` (such as
+    /// [HmacSignature](crate::signature::HmacSignature)) before calling the [Store],
+    /// and reject the invalid ones with [Controller::on_invalid_signature],
+    /// so unauthenticated floods cannot pollute the [Store] with junk keys.
+    pub fn with_signature_check<V: SignatureVerifier + 'static>(mut self, verifier: V) -> Self {
+        self.signature_mut(verifier, None);
+        self
+    }
+
+    /// Like [Self::with_signature_check], but count the requests with invalid
+    /// signatures as `charge` requests instead of rejecting them.
+    pub fn with_signature_check_charged<V: SignatureVerifier + 'static>(mut self, verifier: V, charge: T::Count) -> Self {
+        self.signature_mut(verifier, Some(charge));
+        self
+    }
+
+    fn signature_mut<V: SignatureVerifier + 'static>(&mut self, verifier: V, charge: Option<T::Count>) {
+        Arc::make_mut(&mut self.inner)
+            .signature = Some(SignatureCheck {
+                verifier: Arc::new(verifier),
+                charge,
+            });
+    }
+
+    /// Count the requests allowed by the limits of their keys in `budget`, a global budget
+    /// divided between client classes, and reject them as by the limit of the key when the
+    /// budget of their class is used up. The charge of the key is then rolled back with
+    /// [Store::grant], so the store is a [GrantStore]. See [WeightedBudget].
+    pub fn with_weighted_budget(mut self, budget: WeightedBudget) -> Self
+        where
+            T: GrantStore,
+            T::Count: From<u8>,
+    {
+        Arc::make_mut(&mut self.inner)
+            .budget = Some((budget, T::Count::from(1)));
+        self
+    }
+
+    /// Reserve `reserved` of the max of each key for the requests classified as priority by
+    /// `is_priority` (such as health checks and payment callbacks), so they keep working
+    /// when the other traffic of the key is at its limit. The other requests are limited
+    /// at the max minus `reserved`, and their rejections are refunded (with [Store::grant],
+    /// so the store is a [GrantStore]) so they do not take the reserved slice, unless the
+    /// store did not count them (see [Value::is_charged]). The priority requests use the full max.
+    ///
+    /// ```rust
+    /// use actix_rl::middleware::RateLimit;
+    ///
+    /// let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::minutes(1));
+    /// let rate_limit = RateLimit::new(store, 100, actix_rl::controller::Controller::default())
+    ///     .with_priority_lane(10, |req| req.path().starts_with("/callbacks/"));
+    /// ```
+    pub fn with_priority_lane(mut self, reserved: <T::Value as Value>::Count, is_priority: FromRequestFunc<bool>) -> Self
+        where
+            T: GrantStore,
+            T::Count: From<u8>,
+    {
+        Arc::make_mut(&mut self.inner)
+            .priority = Some(PriorityLane {
+                reserved,
+                is_priority,
+                one: T::Count::from(1),
+            });
+        self
+    }
+
+    /// Refund `refund` when the response is served from cache, as marked by [CacheHit],
+    /// so the limits follow the actual cost in the backend. The refund is at most the charge
+    /// of the request (1 with the default increment of the store).
+    ///
+    /// The refund is a [Store::grant] until the end of the window of the key, so the store
+    /// is a [GrantStore]. The headers of the response are not updated.
+    pub fn with_cache_refund(mut self, refund: T::Count) -> Self
+        where
+            T: GrantStore,
+            T::Count: PartialOrd + From<u8>,
+    {
+        Arc::make_mut(&mut self.inner)
+            .cache_refund = Some((refund, T::Count::from(1), |a, b| if a < b { a } else { b }));
+        self
+    }
+
+    /// Append the rejections to `audit`, with the [PolicyVariant](crate::policy::PolicyVariant)
+    /// of the key or the name of the [Controller] as the policy. The keys entering
+    /// [Self::with_rejection_cache] are recorded as [AuditAction::Banned], and the requests
+    /// answered by the cache are not recorded again. Errors writing the file are ignored.
+    #[cfg(feature = "audit")]
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self
+        where T::Key: ToString,
+    {
+        Arc::make_mut(&mut self.inner)
+            .audit = Some((audit, |key| key.to_string()));
+        self
+    }
+
+    /// Add a token signed by `signer` to the rate-limit responses of the keys, in
+    /// [DEFAULT_PROOF_HEADER], with the hash of the key, the [PolicyVariant](crate::policy::PolicyVariant)
+    /// of the key or the name of the [Controller], and the end of the window.
+    /// Support can check the tokens reported by the clients with [ProofSigner::verify].
+    #[cfg(feature = "hmac")]
+    pub fn with_proof_token(mut self, signer: ProofSigner) -> Self
+        where T::Key: ToString,
+    {
+        Arc::make_mut(&mut self.inner)
+            .proof = Some((signer, |key| key.to_string()));
+        self
+    }
+
+    /// Allow the first request of each key in `filter`'s window without calling the [Store],
+    /// so the keys making a single request (such as the addresses of a scan) cost no
+    /// round-trip. The later requests are counted as usual, without the first one, so a key
+    /// may make `max + 1` requests per window: use the window of the [Store] for `filter`.
+    /// See [FirstSeenFilter].
+    ///
+    /// The filter is skipped for the keys with a max of 0, with a window (of the policy or
+    /// of [Self::scoped]) longer than `filter`'s, or in the cache of [Self::with_rejection_cache],
+    /// so a blocked key gets no free request when the filter forgets it.
+    ///
+    /// The allowed first requests are not counted: they are [Outcome::Bypassed], with no
+    /// [RateLimitByPass::value] and no quota headers.
+    pub fn with_first_seen_filter(mut self, filter: FirstSeenFilter) -> Self
+        where
+            T::Key: Hash,
+            <T::Value as Value>::Count: Default,
+    {
+        Arc::make_mut(&mut self.inner)
+            .first_seen = Some((filter, |filter, key, max| *max > Default::default() && filter.first_seen(key)));
+        self
+    }
+
+    /// Do not count the retries of a write: a request whose [IDEMPOTENCY_KEY_HEADER](crate::identifier::IDEMPOTENCY_KEY_HEADER)
+    /// was sent by an allowed request of the same key in the window passes without calling
+    /// the [Store], even if the key is over its limit since. See [IdempotencyFilter].
+    ///
+    /// The idempotency keys are chosen by the clients, so they never change the key of
+    /// the request: the first request of each idempotency key is counted as usual.
+    /// The retries have no [RateLimitByPass::value], and no quota headers.
+    pub fn with_idempotency_filter(mut self, filter: IdempotencyFilter) -> Self
+        where T::Key: Hash,
+    {
+        Arc::make_mut(&mut self.inner)
+            .idempotency = Some((filter, Fnv1a::hash));
+        self
+    }
+
+    /// Remember up to `capacity` hard-limited keys in this process, until their windows end,
+    /// and reject their requests without calling the [Store], so an attack does not
+    /// load the [Store]. Only the values with [Value::expire_date] are remembered,
+    /// and the oldest rejection is evicted when the cache is full.
+    ///
+    /// The rejections are not updated when the counters change in the [Store]
+    /// (such as by other instances), except by [Self::grant]. The priority requests
+    /// of [Self::with_priority_lane] are always counted, so they can use the reserved slice.
+    pub fn with_rejection_cache(mut self, capacity: usize) -> Self
+        where T::Key: Hash + Eq + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.inner)
+            .rejections = Some(Arc::new(LocalRejections::new(capacity)));
+        self
+    }
+}
+
+impl<CB: MessageBody> RateLimit<MemStore, CB> {
+    /// Return the [Policy] of this middleware, the reverse of [Policy::mem_middleware]:
+    /// the max, the window (of [Self::scoped] or of the [MemStore]), the token bucket
+    /// of the [MemStore], the cost of [Self::with_cost] and the [SuccessHeaders](crate::controller::SuccessHeaders)
+    /// of the [Controller], so the configuration can be stored, diffed and audited.
+    ///
+    /// The settings are read without locking the store. The other settings of the builders
+    /// (such as the policies of [Self::with_policy_set]) are not part of the [Policy].
+    pub fn policy(&self) -> Policy {
+        let inner = &*self.inner;
+        let store = &inner.store;
+        let algorithm = match store.config.load().bucket {
+            Some(bucket) => Algorithm::TokenBucket { rate: bucket.rate },
+            None => Algorithm::FixedWindow,
+        };
+        Policy {
+            max: inner.max,
+            window: inner.window.map_or(store.ttl, |(_, window)| window),
+            algorithm,
+            cost: inner.cost.or(inner.window.map(|(incr, _)| incr)).unwrap_or(1),
+            headers: Some(inner.controller.success_headers),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpRequest, HttpResponse, test, web};
+    use actix_web::http::StatusCode;
+    use crate::time::Utc;
+    use tokio::time::Instant;
+    use crate::controller::{default_find_identifier, default_on_rate_limit_error, find_identifier_by_path, find_identifier_by_route, SuccessHeaders, DEFAULT_RATE_LIMITED_UNTIL_HEADER, DEFAULT_RATE_LIMIT_RESET_HEADER};
+    use crate::controller::{FailurePolicy, Sampler, DEFAULT_REQUEST_TIMEOUT_HEADER};
+    use crate::identifier::CardinalityGuard;
+    use crate::store::mem_store::{MemStore, TokenBucket};
+    use crate::store::static_store::StaticStore;
+    use super::*;
 
     async fn empty() -> HttpResponse {
         HttpResponse::new(StatusCode::NO_CONTENT)
@@ -191,7 +1820,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_middleware() -> anyhow::Result<()> {
-        let store = MemStore::new(1024, chrono::Duration::seconds(10));
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
         let controller = Controller::default();
 
         let app = test::init_service(
@@ -222,7 +1851,7 @@ mod tests {
 
         println!("rate limited until: {}", wait_until);
         tokio::time::sleep_until(
-            Instant::now() + chrono::Duration::seconds(wait_until - Utc::now().timestamp() + 1).to_std().unwrap()
+            Instant::now() + crate::time::Duration::seconds(wait_until - Utc::now().timestamp() + 1).to_std().unwrap()
         ).await;
 
         // ok...
@@ -241,7 +1870,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_do_rate_limit() -> anyhow::Result<()> {
-        let store = MemStore::new(1024, chrono::Duration::seconds(10));
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
 
         let controller = Controller::<_, BoxBody>::new()
             .with_do_rate_limit(test_do_rate_limit_default_rate_limit_func)
@@ -285,7 +1914,7 @@ mod tests {
 
         println!("rate limited until: {}", wait_until);
         tokio::time::sleep_until(
-            Instant::now() + chrono::Duration::seconds(wait_until - Utc::now().timestamp() + 1).to_std().unwrap()
+            Instant::now() + crate::time::Duration::seconds(wait_until - Utc::now().timestamp() + 1).to_std().unwrap()
         ).await;
 
         // ok...
@@ -297,4 +1926,1856 @@ mod tests {
 
         Ok(())
     }
+
+    async fn echo_remaining(req: HttpRequest) -> HttpResponse {
+        let remaining = req.headers().get(DEFAULT_RATE_LIMIT_REMAINING_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        HttpResponse::Ok().body(remaining)
+    }
+
+    #[tokio::test]
+    async fn test_forward_quota_headers() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default()
+            .with_forward_quota_headers(true);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(
+                    store,
+                    3,
+                    controller,
+                ))
+                .route("/", web::get().to(echo_remaining))
+        ).await;
+
+        for expected in ["2", "1", "0"] {
+            let req = test::TestRequest::get()
+                .insert_header((DEFAULT_RATE_LIMIT_REMAINING_HEADER, "100"))
+                .to_request();
+            let body = test::call_and_read_body(&app, req).await;
+            assert_eq!(body, expected);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_success_headers() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default()
+            .with_success_headers(SuccessHeaders::Standard);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(
+                    store,
+                    3,
+                    controller,
+                ))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for expected in ["2", "1", "0"] {
+            let req = test::TestRequest::get().to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+            assert_eq!(resp.headers().get(DEFAULT_RATE_LIMIT_LIMIT_HEADER).unwrap(), "3");
+            assert_eq!(resp.headers().get(DEFAULT_RATE_LIMIT_REMAINING_HEADER).unwrap(), expected);
+            assert!(resp.headers().contains_key(DEFAULT_RATE_LIMIT_RESET_HEADER));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_identifier_by_route() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default()
+            .with_find_identifier(find_identifier_by_route);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(
+                    store,
+                    2,
+                    controller,
+                ))
+                .route("/users/{id}", web::get().to(empty))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for (uri, status) in [
+            ("/users/123", StatusCode::NO_CONTENT),
+            ("/users/456", StatusCode::NO_CONTENT),
+            ("/users/789", StatusCode::TOO_MANY_REQUESTS),
+            ("/", StatusCode::NO_CONTENT),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cardinality_guard() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default()
+            .with_find_identifier(find_identifier_by_path)
+            .with_cardinality_guard(CardinalityGuard::new(2));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, controller))
+                .default_service(web::to(empty))
+        ).await;
+
+        for (uri, status) in [
+            ("/a", StatusCode::NO_CONTENT),
+            ("/b", StatusCode::NO_CONTENT),
+            // the other paths share one counter.
+            ("/c", StatusCode::NO_CONTENT),
+            ("/d", StatusCode::TOO_MANY_REQUESTS),
+            ("/a", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exempt() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(
+                    store,
+                    1,
+                    controller,
+                ))
+                .wrap_fn(|req, srv| {
+                    if req.headers().contains_key("X-Service-Account") {
+                        RateLimitExempt::mark(req.request());
+                    }
+                    srv.call(req)
+                })
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for _ in 0..5 {
+            let req = test::TestRequest::get().insert_header(("X-Service-Account", "1")).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+
+        let req = test::TestRequest::get().to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get().to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_provider() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default()
+            .with_message_provider(|_, locale| match locale {
+                "fr" => Some("Trop de requêtes".to_string()),
+                "en" => Some("Too \"many\" requests".to_string()),
+                _ => None,
+            });
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 0, controller))
+                .default_service(web::to(empty))
+        ).await;
+
+        for (language, body, content_language) in [
+            (Some("fr-CH, fr;q=0.9, en;q=0.8"), r#"{"error":"rate_limited","message":"Trop de requêtes"}"#, Some("fr")),
+            (Some("de;q=0.5, en;q=0.9"), r#"{"error":"rate_limited","message":"Too \"many\" requests"}"#, Some("en")),
+            (None, r#"{"error":"rate_limited","message":"Too \"many\" requests"}"#, Some("en")),
+            (Some("de"), "", None),
+        ] {
+            let mut req = test::TestRequest::get();
+            if let Some(language) = language {
+                req = req.insert_header(("Accept-Language", language));
+            }
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(resp.headers().get("Content-Language").map(|value| value.to_str().unwrap()), content_language);
+            assert_eq!(test::read_body(resp).await, body);
+        }
+
+        // a custom response set before the provider is kept, and gets the message.
+        let controller = Controller::default()
+            .on_rate_limit_error(|req, _| {
+                let message = RateLimitMessage::from_request(req).map(|message| message.message).unwrap_or_default();
+                HttpResponse::TooManyRequests().body(message)
+            })
+            .with_message_provider(|_, _| Some("Slow down".to_string()));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(MemStore::new(1024, crate::time::Duration::seconds(10)), 0, controller))
+                .default_service(web::to(empty))
+        ).await;
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(test::read_body(resp).await, "Slow down");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_labels() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default()
+            .on_rate_limit_error(|req, _| {
+                let labels = PolicyLabels::from_request(req).unwrap_or_default();
+                HttpResponse::TooManyRequests().body(labels.to_string())
+            });
+        let labels = PolicyLabels::new().with_label("team", "billing").with_label("group", "invoices");
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, controller).with_labels(labels))
+                .route("/", web::get().to(|req: HttpRequest| async move {
+                    let by_pass = RateLimitByPass::<MemStore>::from_request(&req).unwrap();
+                    HttpResponse::Ok().body(by_pass.labels().and_then(|labels| labels.get("team")).unwrap_or("-").to_string())
+                }))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(test::read_body(resp).await, "billing");
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(test::read_body(resp).await, "team=billing,group=invoices");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evaluator() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        // allow bursts of twice the max.
+        let rate_limit = RateLimit::new(store, 2, Controller::default())
+            .with_evaluator(|value: &crate::store::mem_store::DateCountUntil, max: &u32| value.count() > max * 2);
+        let app = test::init_service(
+            App::new()
+                .wrap(rate_limit)
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for _ in 0..4 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evaluator_below_max() -> anyhow::Result<()> {
+        // an evaluator limiting below the max, with a grace margin.
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimit::new(store, 10, Controller::default().with_grace(2))
+                        .with_evaluator(|value: &crate::store::mem_store::DateCountUntil, _: &u32| value.count() > 1)
+                )
+                .route("/", web::get().to(empty))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limit_semantics() -> anyhow::Result<()> {
+        for (semantics, allowed) in [(LimitSemantics::AllowMax, 3), (LimitSemantics::RejectAtMax, 2)] {
+            let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+            let app = test::init_service(
+                App::new()
+                    .wrap(RateLimit::new(store, 3, Controller::default()).with_limit_semantics(semantics))
+                    .route("/", web::get().to(empty))
+            ).await;
+
+            for _ in 0..allowed {
+                let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+                assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+            }
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        // the semantics are kept by an evaluator set later.
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let rate_limit = RateLimit::new(store, 3, Controller::default())
+            .with_limit_semantics(LimitSemantics::RejectAtMax)
+            .with_evaluator(CountEvaluator);
+        let app = test::init_service(App::new().wrap(rate_limit).route("/", web::get().to(empty))).await;
+        for _ in 0..2 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_self_test() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let report = RateLimit::new(store.clone(), 10, Controller::default()).self_test().await;
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.steps.iter().map(|step| step.name).collect::<Vec<_>>(), vec!["incr", "clock", "peek", "del"]);
+        assert!(store.peek(SELF_TEST_KEY.to_string()).await.unwrap().is_none());
+
+        // the store is slower than the timeout.
+        let controller = Controller::default().with_store_timeout(std::time::Duration::from_millis(20));
+        let report = RateLimit::new(SlowStore(store), 10, controller).self_test().await;
+        assert!(!report.is_ok());
+        assert_eq!(report.first_error().map(|step| step.name), Some("incr"));
+        assert!(report.to_string().starts_with("incr: failed"), "{}", report);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_presets() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, crate::controller::presets::api_gateway()))
+                .route("/", web::get().to(empty))
+                .route("/healthz", web::get().to(empty))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get(DEFAULT_RATE_LIMIT_REMAINING_HEADER).unwrap(), "0");
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "application/json");
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outcome() -> anyhow::Result<()> {
+        let outcome = |req: HttpRequest| async move {
+            HttpResponse::Ok().body(Outcome::from_request(&req).map_or("-", |outcome| outcome.as_str()))
+        };
+
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default()
+            .with_do_rate_limit(|req| req.path() != "/skip")
+            .on_rate_limit_error(|req, _| HttpResponse::TooManyRequests().body(Outcome::from_request(req).unwrap().to_string()));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store.clone(), 1, controller))
+                .route("/", web::get().to(outcome))
+                .route("/skip", web::get().to(outcome))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(test::read_body(resp).await, "allowed");
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/skip").to_request()).await;
+        assert_eq!(test::read_body(resp).await, "bypassed");
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(test::read_body(resp).await, "rejected");
+
+        // the store is slower than the timeout, and the request is let through.
+        let controller = Controller::default()
+            .with_store_timeout(std::time::Duration::from_millis(20))
+            .with_failure_policy(FailurePolicy::Open);
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(SlowStore(store), 1, controller))
+                .route("/", web::get().to(outcome))
+        ).await;
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(test::read_body(resp).await, "failed_open");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_priority_lane() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 3, Controller::default())
+                    .with_priority_lane(1, |req| req.path() == "/callback"))
+                .route("/", web::get().to(empty))
+                .route("/callback", web::get().to(empty))
+        ).await;
+
+        for _ in 0..2 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        // the other requests cannot take the reserved slice, even when retried.
+        for _ in 0..3 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/callback").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/callback").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_priority_lane_uncharged() -> anyhow::Result<()> {
+        // the token bucket does not count the rejected requests.
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10))
+            .with_token_bucket(TokenBucket { burst: 3, rate: 0.001 });
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 3, Controller::default())
+                    .with_priority_lane(1, |req| req.path() == "/callback"))
+                .route("/", web::get().to(empty))
+                .route("/callback", web::get().to(empty))
+        ).await;
+
+        for _ in 0..2 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/callback").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // the rejections are not refunded, so they do not free the bucket.
+        for _ in 0..2 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/callback").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_extractor() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        // only the requests with an API key are counted.
+        let controller = Controller::default()
+            .with_key_extractor(|req: &HttpRequest| req.headers().get("X-Api-Key").map(|_| "client".to_string()));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::try_new(store, 1, controller)?)
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for _ in 0..2 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        let resp = test::call_service(&app, test::TestRequest::get().insert_header(("X-Api-Key", "a")).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, test::TestRequest::get().insert_header(("X-Api-Key", "a")).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_first_seen_filter() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let filter = FirstSeenFilter::new(1000, 0.01, std::time::Duration::from_secs(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store.clone(), 1, Controller::default()).with_first_seen_filter(filter))
+                .default_service(web::to(|req: HttpRequest| async move {
+                    let outcome = Outcome::from_request(&req).unwrap_or_default();
+                    HttpResponse::NoContent().insert_header(("X-Outcome", outcome.as_str())).finish()
+                }))
+        ).await;
+
+        // the first request is not counted.
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get("X-Outcome").unwrap(), Outcome::Bypassed.as_str());
+        assert_eq!(store.snapshot().await.map_err(|_| anyhow::anyhow!("snapshot"))?.len(), 0);
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // the filter is skipped with a max of 0, or a window longer than the filter's.
+        let global = RateLimit::new(store.clone(), 1, Controller::default());
+        let filter = FirstSeenFilter::new(1000, 0.01, std::time::Duration::from_secs(10));
+        for (max, window) in [(0, 10), (1, 60)] {
+            let scoped = RateLimit::scoped(&global, format!("{}:{}", max, window), max, crate::time::Duration::seconds(window))
+                .with_first_seen_filter(filter.clone());
+            let app = test::init_service(App::new().wrap(scoped).default_service(web::to(empty))).await;
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status().is_success(), max > 0);
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_filter() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let filter = IdempotencyFilter::new(1000);
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store.clone(), 2, Controller::default()).with_idempotency_filter(filter))
+                .route("/", web::post().to(empty))
+        ).await;
+        let request = |key: &str| test::TestRequest::post()
+            .peer_addr("1.2.3.4:80".parse().unwrap())
+            .insert_header((crate::identifier::IDEMPOTENCY_KEY_HEADER, key))
+            .to_request();
+
+        // the retries are not counted, and the key of the limit is the address.
+        for _ in 0..3 {
+            let resp = test::call_service(&app, request("a")).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        let usage = store.snapshot().await.map_err(|_| anyhow::anyhow!("snapshot"))?;
+        assert_eq!(usage.iter().map(|(key, value)| (key.as_str(), value.count())).collect::<Vec<_>>(), vec![("1.2.3.4", 1)]);
+
+        // a new idempotency key is counted, and rejected over the limit.
+        let resp = test::call_service(&app, request("b")).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, request("c")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let resp = test::call_service(&app, request("c")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // the allowed writes can still be retried.
+        let resp = test::call_service(&app, request("a")).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_log_rate_limit() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 2, Controller::default()))
+                .wrap(crate::utils::log_rate_limit::<MemStore>(actix_web::middleware::Logger::new(
+                    "%s rl=%{rate_limit_decision}xo count=%{rate_limit_count}xo remaining=%{rate_limit_remaining}xo",
+                )))
+                .route("/", web::get().to(|req: HttpRequest| async move {
+                    let by_pass = RateLimitByPass::<MemStore>::from_request(&req).unwrap();
+                    HttpResponse::Ok().body(by_pass.remaining().unwrap_or("-").to_string())
+                }))
+        ).await;
+
+        for (status, body) in [(StatusCode::OK, "1"), (StatusCode::OK, "0"), (StatusCode::TOO_MANY_REQUESTS, "")] {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), status);
+            if status == StatusCode::OK {
+                assert_eq!(test::read_body(resp).await, body);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn echo_by_pass(req: HttpRequest) -> HttpResponse {
+        let by_pass = RateLimitByPass::<MemStore>::from_request(&req).unwrap();
+        let count = by_pass.get_value().map(|value| value.count()).unwrap_or_default();
+        HttpResponse::Ok().body(format!("{}:{}", by_pass.is_bypassed(), count))
+    }
+
+    #[tokio::test]
+    async fn test_by_pass_extension() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = || Controller::default()
+            .with_do_rate_limit(test_do_rate_limit_default_rate_limit_func);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(
+                    store.clone(),
+                    10,
+                    controller(),
+                ))
+                .wrap(RateLimit::new(
+                    store,
+                    10,
+                    controller(),
+                ))
+                .route("/", web::get().to(echo_by_pass))
+                .route("/bypass", web::get().to(echo_by_pass))
+        ).await;
+
+        for (uri, expected) in [("/", "false:1"), ("/bypass", "true:0"), ("/", "false:2")] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let body = test::call_and_read_body(&app, req).await;
+            assert_eq!(body, expected);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_named_limiters() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(
+                    store.clone(),
+                    10,
+                    Controller::default().with_name("outer"),
+                ))
+                .service(
+                    web::scope("/inner")
+                        .wrap(RateLimit::new(
+                            MemStore::new(1024, crate::time::Duration::seconds(10)),
+                            2,
+                            Controller::default().with_name("inner"),
+                        ))
+                        .route("", web::get().to(empty))
+                )
+        ).await;
+
+        for status in [StatusCode::NO_CONTENT, StatusCode::NO_CONTENT, StatusCode::TOO_MANY_REQUESTS] {
+            let req = test::TestRequest::get().uri("/inner").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        // the outer limiter counts all requests
+        assert_eq!(store.incr("<Unknown Source IP>".to_string()).await.unwrap().count(), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_burst_sustained() -> anyhow::Result<()> {
+        let app = test::init_service(
+            App::new()
+                .wrap(Policy::burst_sustained(3, 5.0).mem_middleware(1024, Controller::default()))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for status in [StatusCode::NO_CONTENT, StatusCode::NO_CONTENT, StatusCode::NO_CONTENT, StatusCode::TOO_MANY_REQUESTS] {
+            let req = test::TestRequest::get().to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        // one token is refilled every 200ms
+        tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
+        let req = test::TestRequest::get().to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        Ok(())
+    }
+
+    async fn echo_grace(req: HttpRequest) -> HttpResponse {
+        let by_pass = RateLimitByPass::<MemStore>::from_request(&req).unwrap();
+        HttpResponse::Ok().body(by_pass.is_grace().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_grace() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 2, Controller::default().with_grace(2)))
+                .route("/", web::get().to(echo_grace))
+        ).await;
+
+        for expected in ["false", "false", "true", "true"] {
+            let req = test::TestRequest::get().to_request();
+            let body = test::call_and_read_body(&app, req).await;
+            assert_eq!(body, expected);
+        }
+
+        let req = test::TestRequest::get().to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    static FROZEN_LIMITED: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+    fn boxed_app(store: MemStore) -> App<impl actix_web::dev::ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<BoxBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >> {
+        App::new()
+            .wrap(BoxedRateLimit::new(store, 1, Controller::default()))
+            .route("/", web::get().to(empty))
+    }
+
+    #[tokio::test]
+    async fn test_boxed_app() -> anyhow::Result<()> {
+        let app = test::init_service(boxed_app(MemStore::new(1024, crate::time::Duration::seconds(10)))).await;
+
+        for status in [StatusCode::NO_CONTENT, StatusCode::TOO_MANY_REQUESTS] {
+            let req = test::TestRequest::get().to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_try_new() {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        assert!(RateLimit::try_new(store.clone(), 10, Controller::default()).is_ok());
+        assert_eq!(RateLimit::try_new(store.clone(), 0, Controller::default()).err(), Some(ConfigError::ZeroMax));
+        assert_eq!(RateLimit::try_new(store.clone(), 10, Controller::<MemStore>::new()).err(), Some(ConfigError::MissingIdentifier));
+        let zero = MemStore::new(1024, crate::time::Duration::zero());
+        assert_eq!(RateLimit::try_new(zero, 10, Controller::default()).err(), Some(ConfigError::NonPositiveWindow(crate::time::Duration::zero())));
+
+        // the costs set by the builders are checked by validate.
+        let global = RateLimit::try_new(store, 10, Controller::default()).unwrap();
+        assert!(global.validate().is_ok());
+        assert_eq!(global.clone().with_stream_cost(4, 11).validate(), Err(ConfigError::CostOverMax));
+        assert_eq!(RateLimit::scoped(&global, "login", 5, crate::time::Duration::seconds(-1)).validate(), Err(ConfigError::NonPositiveWindow(crate::time::Duration::seconds(-1))));
+
+        // the windows are refused if the store ignores them.
+        let sketch = crate::store::sketch_store::SketchStore::new(1024, 4, crate::time::Duration::seconds(10));
+        let fixed = RateLimit::try_new(sketch, 10, Controller::default()).unwrap();
+        assert!(fixed.validate().is_ok());
+        assert_eq!(RateLimit::scoped(&fixed, "login", 5, crate::time::Duration::seconds(20)).validate(), Err(ConfigError::UnsupportedWindow));
+        let bucket = MemStore::new(1024, crate::time::Duration::seconds(10)).with_token_bucket(TokenBucket { burst: 10, rate: 1.0 });
+        let bucket = RateLimit::try_new(bucket, 10, Controller::default()).unwrap();
+        assert_eq!(bucket.with_content_type_policy("text/csv", Policy::fixed_window(5, crate::time::Duration::seconds(10))).validate(), Err(ConfigError::UnsupportedWindow));
+
+        assert_eq!(Policy::fixed_window(10, crate::time::Duration::zero()).validate(), Err(ConfigError::NonPositiveWindow(crate::time::Duration::zero())));
+        assert_eq!(Policy::burst_sustained(10, 0.0).validate(), Err(ConfigError::InvalidRate(0.0)));
+        assert!(Policy::burst_sustained(10, 2.0).try_mem_middleware(1024, Controller::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_policy_round_trip() -> anyhow::Result<()> {
+        let policy = Policy::burst_sustained(10, 2.0).with_cost(2).with_headers(SuccessHeaders::Ietf);
+        let rate_limit = policy.try_mem_middleware(1024, Controller::default())?;
+        assert_eq!(rate_limit.policy(), policy);
+        // the same while the store is in use.
+        let guard = rate_limit.inner.store.inner.lock().await;
+        assert_eq!(rate_limit.policy(), policy);
+        drop(guard);
+
+        let global = Policy::fixed_window(10, crate::time::Duration::seconds(10)).mem_middleware(1024, Controller::default());
+        let scoped = RateLimit::scoped(&global, "login", 5, crate::time::Duration::minutes(1)).with_cost(5);
+        assert_eq!(scoped.policy(), Policy::fixed_window(5, crate::time::Duration::minutes(1)).with_cost(5).with_headers(SuccessHeaders::None));
+        assert_eq!(Policy::fixed_window(1, crate::time::Duration::seconds(10)).with_cost(2).validate(), Err(ConfigError::CostOverMax));
+
+        let window = RateLimit::new(MemStore::new(1024, crate::time::Duration::seconds(10)), 10, Controller::default()).window("1m30s");
+        assert_eq!(window.policy().window, crate::time::Duration::seconds(90));
+
+        // each request counts as its cost.
+        let app = test::init_service(App::new().wrap(scoped).route("/", web::get().to(empty))).await;
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_policy_set() -> anyhow::Result<()> {
+        let set = PolicySet::default();
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, Controller::default()).with_policy_set(set.clone(), "api"))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for status in [StatusCode::NO_CONTENT, StatusCode::TOO_MANY_REQUESTS] {
+            let req = test::TestRequest::get().to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        // raise the limit at runtime
+        set.store(std::collections::HashMap::from([
+            ("api".to_string(), Policy::fixed_window(3, crate::time::Duration::seconds(10))),
+        ]));
+        for status in [StatusCode::NO_CONTENT, StatusCode::TOO_MANY_REQUESTS] {
+            let req = test::TestRequest::get().to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        Ok(())
+    }
+
+    struct TenantPolicies;
+
+    #[async_trait::async_trait]
+    impl KeyPolicyProvider<String> for TenantPolicies {
+        async fn policy(&self, key: String) -> Option<Policy> {
+            (key == "vip").then(|| Policy::fixed_window(3, crate::time::Duration::seconds(10)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_key_policies() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::<MemStore>::default()
+            .with_find_identifier(|req| req.path().trim_start_matches('/').to_string());
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, controller).with_key_policies(TenantPolicies))
+                .route("/{tenant}", web::get().to(empty))
+        ).await;
+
+        for (uri, status) in [
+            ("/free", StatusCode::NO_CONTENT),
+            ("/free", StatusCode::TOO_MANY_REQUESTS),
+            ("/vip", StatusCode::NO_CONTENT),
+            ("/vip", StatusCode::NO_CONTENT),
+            ("/vip", StatusCode::NO_CONTENT),
+            ("/vip", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        Ok(())
+    }
+
+    static SHADOW_LIMITED: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+    #[tokio::test]
+    async fn test_rollout() -> anyhow::Result<()> {
+        for (percent, status) in [(0, StatusCode::NO_CONTENT), (100, StatusCode::TOO_MANY_REQUESTS)] {
+            let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+            let controller = Controller::<MemStore>::default()
+                .with_rollout_percent(percent)
+                .on_shadow_limited(|_, _| {
+                    SHADOW_LIMITED.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                });
+            let app = test::init_service(
+                App::new()
+                    .wrap(RateLimit::new(store, 1, controller))
+                    .route("/", web::get().to(empty))
+            ).await;
+
+            for expected in [StatusCode::NO_CONTENT, status] {
+                let req = test::TestRequest::get().to_request();
+                let resp = test::call_service(&app, req).await;
+                assert_eq!(resp.status(), expected);
+            }
+        }
+        assert_eq!(SHADOW_LIMITED.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // the keys enforced at a percentage stay enforced at a higher one.
+        let rollout = |percent| Controller::<MemStore>::new().with_rollout_percent(percent).rollout.unwrap();
+        let keys: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+        let enforced = |percent| keys.iter().filter(|key| rollout(percent).enforces(key)).cloned().collect::<Vec<_>>();
+        let (ten, fifty) = (enforced(10), enforced(50));
+        assert!(ten.iter().all(|key| fifty.contains(key)));
+        assert!((50..150).contains(&ten.len()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_experiment() -> anyhow::Result<()> {
+        async fn echo_variant(req: HttpRequest) -> HttpResponse {
+            let variant = RateLimitByPass::<MemStore>::from_request(&req)
+                .and_then(|rl| rl.variant().cloned());
+            HttpResponse::Ok().body(variant.map(|variant| variant.as_str().to_string()).unwrap_or_default())
+        }
+
+        let experiment = Experiment::new("test")
+            .with_variant("loose", 1, Policy::fixed_window(3, crate::time::Duration::seconds(10)))
+            .with_variant("strict", 1, Policy::fixed_window(1, crate::time::Duration::seconds(10)));
+        let controller = Controller::<MemStore>::default()
+            .with_find_identifier(|req| req.path().trim_start_matches('/').to_string());
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(MemStore::new(1024, crate::time::Duration::seconds(10)), 100, controller).with_experiment(experiment.clone()))
+                .route("/{key}", web::get().to(echo_variant))
+        ).await;
+
+        for key in ["a", "b", "c", "d"] {
+            let (variant, policy) = experiment.assign(key).unwrap();
+            for _ in 0..policy.max {
+                let req = test::TestRequest::get().uri(&format!("/{}", key)).to_request();
+                let body = test::call_and_read_body(&app, req).await;
+                assert_eq!(body, variant.as_str());
+            }
+
+            let req = test::TestRequest::get().uri(&format!("/{}", key)).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejection_cache() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let rate_limit = RateLimit::new(store.clone(), 1, Controller::default()).with_rejection_cache(16);
+        let app = test::init_service(
+            App::new()
+                .wrap(rate_limit.clone())
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for status in [StatusCode::NO_CONTENT, StatusCode::TOO_MANY_REQUESTS, StatusCode::TOO_MANY_REQUESTS, StatusCode::TOO_MANY_REQUESTS] {
+            let req = test::TestRequest::get().to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+            assert!(status == StatusCode::NO_CONTENT || resp.headers().contains_key(DEFAULT_RATE_LIMITED_UNTIL_HEADER));
+        }
+
+        // only the first rejection reaches the store.
+        let key = default_find_identifier(&test::TestRequest::get().to_http_request());
+        assert_eq!(store.peek(key.clone()).await.unwrap().map(|value| value.count()), Some(2));
+
+        // a grant lifts the cached rejection.
+        rate_limit.grant(key, 5, crate::time::Duration::seconds(10)).await.unwrap();
+        let req = test::TestRequest::get().to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // the oldest rejection is evicted when the cache is full.
+        let cache = LocalRejections::new(2);
+        let until = Utc::now() + crate::time::Duration::seconds(10);
+        for key in ["a", "b", "a", "c"] {
+            cache.insert(key, until);
+        }
+        assert!(cache.get(&"a").is_some() && cache.get(&"c").is_some());
+        assert!(cache.get(&"b").is_none());
+        assert!(cache.entries.read().unwrap().order.len() <= 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejection_cache_priority() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 2, Controller::default())
+                    .with_priority_lane(1, |req| req.path() == "/callback")
+                    .with_rejection_cache(16))
+                .route("/", web::get().to(empty))
+                .route("/callback", web::get().to(empty))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // the cached rejection does not close the reserved slice.
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/callback").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tarpit() -> anyhow::Result<()> {
+        let delay = std::time::Duration::from_millis(200);
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default().with_tarpit(delay, std::time::Duration::from_millis(50), 1);
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, controller))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        let start = Instant::now();
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(start.elapsed() < delay);
+
+        // only one of the concurrent rejections is delayed.
+        let start = Instant::now();
+        let (a, b) = tokio::join!(
+            async { let resp = test::call_service(&app, test::TestRequest::get().to_request()).await; (resp.status(), start.elapsed()) },
+            async { let resp = test::call_service(&app, test::TestRequest::get().to_request()).await; (resp.status(), start.elapsed()) },
+        );
+        assert_eq!((a.0, b.0), (StatusCode::TOO_MANY_REQUESTS, StatusCode::TOO_MANY_REQUESTS));
+        assert!(a.1.max(b.1) >= delay);
+        assert!(a.1.min(b.1) < delay);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_queue() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(1));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, Controller::default()).with_queue(1, std::time::Duration::from_secs(3)))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // one request waits for the next window, the other cannot wait.
+        let (a, b) = tokio::join!(
+            test::call_service(&app, test::TestRequest::get().to_request()),
+            test::call_service(&app, test::TestRequest::get().to_request()),
+        );
+        let mut statuses = [a.status(), b.status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::NO_CONTENT, StatusCode::TOO_MANY_REQUESTS]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(1));
+        let rate_limit = RateLimit::new(store, 1, Controller::default())
+            .with_queue(1, std::time::Duration::from_secs(3))
+            .with_backpressure(true);
+        let app = test::init_service(
+            App::new()
+                .wrap(rate_limit)
+                .route("/", web::get().to(empty))
+        ).await;
+        let ready = || std::future::poll_fn(|cx| Poll::Ready(app.poll_ready(cx).is_ready()));
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(ready().await);
+
+        // the service is not ready while a request waits for the next window.
+        let mut waiting = std::pin::pin!(test::call_service(&app, test::TestRequest::get().to_request()));
+        assert!(futures_util::poll!(waiting.as_mut()).is_pending());
+        assert!(!ready().await);
+
+        assert_eq!(waiting.await.status(), StatusCode::NO_CONTENT);
+        assert!(ready().await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hierarchy() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let hierarchy = HierarchicalPolicy::new()
+            .with_level("global", 3, |_| Some("all".to_string()))
+            .with_level("tenant", 2, |req| Some(req.headers().get("X-Tenant")?.to_str().ok()?.to_string()));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store.clone(), 100, Controller::default().with_find_identifier(|_| "John".to_string())).with_hierarchy(hierarchy))
+                .default_service(web::to(empty))
+        ).await;
+
+        for (tenant, status) in [
+            ("a", StatusCode::NO_CONTENT),
+            ("a", StatusCode::NO_CONTENT),
+            // over the limit of the tenant, the global charge is rolled back.
+            ("a", StatusCode::TOO_MANY_REQUESTS),
+            ("b", StatusCode::NO_CONTENT),
+            // over the global limit, the charge of the tenant is rolled back.
+            ("b", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let req = test::TestRequest::get().insert_header(("X-Tenant", tenant)).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", tenant);
+        }
+
+        let count = |key: &str| {
+            let store = store.clone();
+            let key = key.to_string();
+            async move { store.peek(key).await.unwrap().map(|value| value.count()) }
+        };
+        assert_eq!(count("global:all").await, Some(3));
+        assert_eq!(count("tenant:a").await, Some(2));
+        assert_eq!(count("tenant:b").await, Some(1));
+        // the charges of the key are rolled back too.
+        assert_eq!(count("John").await, Some(3));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_cost() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default().with_find_identifier(|_| "John".to_string());
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store.clone(), 100, controller).with_stream_cost(1, 5))
+                .default_service(web::to(empty))
+        ).await;
+        let count = || {
+            let store = store.clone();
+            async move { store.peek("John".to_string()).await.unwrap().map(|value| value.count()) }
+        };
+
+        // a single stream is charged as usual.
+        let streams = ConnectionStreams::default();
+        let req = test::TestRequest::get().to_request();
+        req.extensions_mut().insert(streams.clone());
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(count().await, Some(1));
+        assert_eq!(streams.active(), 0);
+
+        // a concurrent stream is charged the cost.
+        let _held = streams.open();
+        let req = test::TestRequest::get().to_request();
+        req.extensions_mut().insert(streams.clone());
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(count().await, Some(6));
+        assert_eq!(streams.active(), 1);
+
+        // the requests without connection streams are charged as usual.
+        assert_eq!(test::call_service(&app, test::TestRequest::get().to_request()).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(count().await, Some(7));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_annotate() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let rate_limit = RateLimit::new(store.clone(), 5, Controller::default());
+        let metadata = || {
+            let store = store.clone();
+            async move { store.incr_by("John".to_string(), 0).await.unwrap().metadata().cloned().unwrap_or_default() }
+        };
+
+        store.incr("John".to_string()).await.unwrap();
+        store.set_metadata("John".to_string(), crate::store::Metadata::from([("tier".to_string(), "gold".to_string())]), crate::time::Duration::seconds(60)).await.unwrap();
+
+        rate_limit.annotate("John".to_string(), Some("known partner"), &["do-not-ban", "vip"], crate::time::Duration::seconds(60)).await.unwrap();
+        let annotated = metadata().await;
+        assert_eq!(annotated.get(NOTE_METADATA_KEY).map(String::as_str), Some("known partner"));
+        assert!(crate::store::has_flag(&annotated, "do-not-ban"));
+        assert!(!crate::store::has_flag(&annotated, "do-not"));
+        assert_eq!(annotated.get("tier").map(String::as_str), Some("gold"));
+
+        rate_limit.annotate("John".to_string(), None, &[], crate::time::Duration::seconds(60)).await.unwrap();
+        let annotated = metadata().await;
+        assert!(!annotated.contains_key(NOTE_METADATA_KEY));
+        assert!(!crate::store::has_flag(&annotated, "vip"));
+        assert_eq!(annotated.get("tier").map(String::as_str), Some("gold"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reserve() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let rate_limit = RateLimit::new(store.clone(), 5, Controller::default());
+
+        let reservation = rate_limit.reserve("John".to_string(), 3).await.unwrap();
+        assert!(reservation.is_some());
+        assert!(rate_limit.reserve("John".to_string(), 3).await.unwrap().is_none());
+
+        // the quota is released when the reservation is cancelled.
+        reservation.unwrap().cancel().await.unwrap();
+        let reservation = rate_limit.reserve("John".to_string(), 5).await.unwrap();
+        assert_eq!(reservation.as_ref().map(|reservation| reservation.value().count()), Some(5));
+        reservation.unwrap().commit();
+        assert_eq!(store.peek("John".to_string()).await.unwrap().map(|value| value.count()), Some(5));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_content_type_policy() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let rate_limit = RateLimit::new(store.clone(), 3, Controller::default().with_find_identifier(|_| "John".to_string()))
+            .with_content_type_policy("Multipart/*", Policy::fixed_window(1, crate::time::Duration::seconds(10)))
+            .with_content_type_policy("application/grpc-web", Policy::fixed_window(3, crate::time::Duration::seconds(10)));
+        let app = test::init_service(
+            App::new()
+                .wrap(rate_limit)
+                .route("/", web::post().to(empty))
+        ).await;
+
+        let call = |content_type: Option<&'static str>| {
+            let req = test::TestRequest::post();
+            match content_type {
+                Some(content_type) => req.insert_header((actix_web::http::header::CONTENT_TYPE, content_type)),
+                None => req,
+            }.to_request()
+        };
+
+        for (content_type, status) in [
+            (Some("multipart/form-data; boundary=x"), StatusCode::NO_CONTENT),
+            // over the policy of the content type, the charge of the key is rolled back.
+            (Some("multipart/mixed"), StatusCode::TOO_MANY_REQUESTS),
+            (Some("application/grpc-web"), StatusCode::NO_CONTENT),
+            (Some("application/json"), StatusCode::NO_CONTENT),
+            // the limit of the key applies to all content types.
+            (None, StatusCode::TOO_MANY_REQUESTS),
+            (Some("application/grpc-web"), StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let resp = test::call_service(&app, call(content_type)).await;
+            assert_eq!(resp.status(), status, "{:?}", content_type);
+        }
+
+        let count = |key: &'static str| {
+            let store = store.clone();
+            async move { store.peek(key.to_string()).await.unwrap().map(|value| value.count()) }
+        };
+        assert_eq!(count("John").await, Some(5));
+        assert_eq!(count("John:multipart/*").await, Some(1));
+        assert_eq!(count("John:application/grpc-web").await, Some(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_policies() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let policies = UserAgentPolicies::new(|req| req.headers().contains_key("X-Bot").then_some("bot"))
+            .with_policy("bot", Policy::fixed_window(1, crate::time::Duration::seconds(10)));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 2, Controller::default()).with_user_agent_policies(policies))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        // the budget of the class is counted in addition to the limit of the key.
+        for (bot, status) in [
+            (true, StatusCode::NO_CONTENT),
+            (true, StatusCode::TOO_MANY_REQUESTS),
+            (false, StatusCode::NO_CONTENT),
+            (false, StatusCode::TOO_MANY_REQUESTS),
+            (true, StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let req = test::TestRequest::get();
+            let req = if bot { req.insert_header(("X-Bot", "1")) } else { req };
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signature_check() -> anyhow::Result<()> {
+        let signed = |req: &HttpRequest| req.headers().get("X-Signature").is_some_and(|value| value == "ok");
+        let call = |signature: &'static str| test::TestRequest::get()
+            .insert_header(("X-Signature", signature))
+            .to_request();
+
+        // rejected before counting.
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store.clone(), 1, Controller::default()).with_signature_check(signed))
+                .route("/", web::get().to(empty))
+        ).await;
+        assert_eq!(test::call_service(&app, call("bad")).await.status(), StatusCode::UNAUTHORIZED);
+        assert!(store.snapshot().await.unwrap().is_empty());
+        assert_eq!(test::call_service(&app, call("ok")).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(test::call_service(&app, call("ok")).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // charged at a higher cost.
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 4, Controller::default()).with_signature_check_charged(signed, 3))
+                .route("/", web::get().to(empty))
+        ).await;
+        assert_eq!(test::call_service(&app, call("bad")).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(test::call_service(&app, call("ok")).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(test::call_service(&app, call("bad")).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_infra_endpoints() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, Controller::default().skip_infra_endpoints()))
+                .default_service(web::to(empty))
+        ).await;
+
+        for (req, status) in [
+            (test::TestRequest::get().uri("/api"), StatusCode::NO_CONTENT),
+            (test::TestRequest::get().uri("/api"), StatusCode::TOO_MANY_REQUESTS),
+            (test::TestRequest::get().uri("/healthz"), StatusCode::NO_CONTENT),
+            (test::TestRequest::get().uri("/readyz/"), StatusCode::NO_CONTENT),
+            (test::TestRequest::get().uri("/metrics"), StatusCode::NO_CONTENT),
+            (test::TestRequest::default().method(actix_web::http::Method::OPTIONS).uri("/api"), StatusCode::NO_CONTENT),
+            (test::TestRequest::get().uri("/metrics/other"), StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scoped() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let global = RateLimit::new(store.clone(), 3, Controller::default());
+        let scoped = RateLimit::scoped(&global, "admin", 1, crate::time::Duration::seconds(60));
+        let app = test::init_service(
+            App::new()
+                .wrap(global)
+                .service(web::scope("/admin").wrap(scoped).route("", web::get().to(empty)))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for (uri, status) in [
+            ("/admin", StatusCode::NO_CONTENT),
+            ("/admin", StatusCode::TOO_MANY_REQUESTS),
+            ("/", StatusCode::NO_CONTENT),
+            ("/", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        // the scope has its own keys and window in the shared store.
+        let key = default_find_identifier(&test::TestRequest::get().to_http_request());
+        let value = store.peek(format!("admin:{}", key)).await.unwrap().unwrap();
+        assert_eq!(value.count(), 2);
+        assert!(value.expire_date().unwrap() > Utc::now() + crate::time::Duration::seconds(30));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_weighted_budget() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let budget = WeightedBudget::new(4, crate::time::Duration::seconds(60), |req| {
+            if req.headers().contains_key("Authorization") { "authenticated" } else { "anonymous" }
+        })
+            .with_class("authenticated", 3)
+            .with_class("anonymous", 1);
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store.clone(), 100, Controller::default().with_find_identifier(|req| req.path().to_string())).with_weighted_budget(budget))
+                .default_service(web::to(empty))
+        ).await;
+
+        // the anonymous clients borrow the share of the idle authenticated ones,
+        // which then only get the capacity left in this window.
+        for (uri, authorized, status) in [
+            ("/a", false, StatusCode::NO_CONTENT),
+            ("/b", false, StatusCode::NO_CONTENT),
+            ("/c", true, StatusCode::NO_CONTENT),
+            ("/d", true, StatusCode::NO_CONTENT),
+            ("/e", true, StatusCode::TOO_MANY_REQUESTS),
+            ("/f", false, StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let req = test::TestRequest::get().uri(uri);
+            let req = if authorized { req.insert_header(("Authorization", "Bearer x")) } else { req };
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status, "{}", uri);
+        }
+
+        // the charge of the rejected key is rolled back.
+        assert_eq!(store.peek("/d".to_string()).await.unwrap().map(|value| value.count()), Some(1));
+        assert_eq!(store.peek("/e".to_string()).await.unwrap().map(|value| value.count()), Some(0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_refund() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 2, Controller::default()).with_cache_refund(1))
+                .route("/cached", web::get().to(|| async {
+                    HttpResponse::Ok().insert_header((crate::utils::DEFAULT_CACHE_STATUS_HEADER, "HIT from proxy")).finish()
+                }))
+                .route("/marked", web::get().to(|| async {
+                    let mut res = HttpResponse::Ok().finish();
+                    CacheHit::mark(&mut res);
+                    res
+                }))
+                .default_service(web::to(empty))
+        ).await;
+
+        for uri in ["/cached", "/marked", "/cached", "/marked", "/a", "/b"] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert!(resp.status().is_success(), "{}", uri);
+        }
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/c").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // the refund is at most the charge of the request.
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 2, Controller::default()).with_cache_refund(5))
+                .route("/marked", web::get().to(|| async {
+                    let mut res = HttpResponse::Ok().finish();
+                    CacheHit::mark(&mut res);
+                    res
+                }))
+                .default_service(web::to(empty))
+        ).await;
+
+        for uri in ["/marked", "/a", "/b"] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert!(resp.status().is_success(), "{}", uri);
+        }
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/c").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "audit")]
+    #[tokio::test]
+    async fn test_audit_log() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("actix-rl-audit-middleware-{}.jsonl", std::process::id()));
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default().with_name("api");
+        let audit = AuditLog::open(&path, 1 << 20)?;
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, controller).with_audit_log(audit.clone()).with_rejection_cache(16))
+                .default_service(web::to(empty))
+        ).await;
+
+        for status in [StatusCode::NO_CONTENT, StatusCode::TOO_MANY_REQUESTS, StatusCode::TOO_MANY_REQUESTS] {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        audit.flush()?;
+        let lines = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        let records = lines.lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        // the key enters the rejection cache, which answers the third request.
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["action"], "rejected");
+        assert_eq!(records[0]["policy"], "api");
+        assert_eq!(records[0]["count"], 2);
+        assert_eq!(records[1]["action"], "banned");
+        assert_eq!(records[1]["policy"], "api");
+        assert_eq!(records[1]["count"], 2);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "hmac")]
+    #[tokio::test]
+    async fn test_proof_token() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default().with_name("api");
+        let signer = ProofSigner::new(b"secret");
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 1, controller).with_proof_token(signer.clone()))
+                .default_service(web::to(empty))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(!resp.headers().contains_key(DEFAULT_PROOF_HEADER));
+
+        let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let token = resp.headers().get(DEFAULT_PROOF_HEADER).expect("proof header").to_str()?;
+        let proof = signer.verify(token)?;
+        assert_eq!(proof.policy, "api");
+        assert!(proof.reset.is_some_and(|reset| reset > Utc::now()));
+        assert!(ProofSigner::new(b"other").verify(token).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_boxed() -> anyhow::Result<()> {
+        for (enabled, status) in [(true, StatusCode::TOO_MANY_REQUESTS), (false, StatusCode::NO_CONTENT)] {
+            let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+            let rate_limit = RateLimit::new(store, 1, Controller::default());
+
+            let app = test::init_service(
+                App::new()
+                    .wrap(actix_web::middleware::Condition::new(enabled, rate_limit.boxed()))
+                    .route("/", web::get().to(empty))
+            ).await;
+
+            let req = test::TestRequest::get().to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+            let req = test::TestRequest::get().to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_freeze() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::<MemStore>::default()
+            .on_frozen(|_, value, limited| {
+                assert_eq!(value.map(|value| value.count()), Some(3));
+                if limited {
+                    FROZEN_LIMITED.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            });
+        let rate_limit = RateLimit::new(store.clone(), 2, controller);
+        let app = test::init_service(
+            App::new()
+                .wrap(rate_limit.clone())
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for _ in 0..2 {
+            let req = test::TestRequest::get().to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        }
+        let req = test::TestRequest::get().to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // the requests of the frozen key pass, and are not counted.
+        rate_limit.freeze("<Unknown Source IP>".to_string());
+        for _ in 0..3 {
+            let req = test::TestRequest::get().to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        }
+        assert_eq!(FROZEN_LIMITED.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        rate_limit.unfreeze(&"<Unknown Source IP>".to_string());
+        let req = test::TestRequest::get().to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(store.peek("<Unknown Source IP>".to_string()).await.unwrap().unwrap().count(), 4);
+
+        Ok(())
+    }
+
+    static SAMPLED: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+    #[tokio::test]
+    async fn test_success_sampling() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::default()
+            .on_success(|_, _, _| {
+                SAMPLED.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            })
+            .with_success_sampling(Sampler::one_in(3));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 10, controller))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for _ in 0..5 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        assert_eq!(SAMPLED.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    static VIOLATIONS: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+    #[tokio::test]
+    async fn test_first_violation() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::<MemStore>::default()
+            .with_grace(1)
+            .on_first_violation(|_, value| {
+                assert_eq!(value.count(), 4);
+                VIOLATIONS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            });
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 2, controller))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for _ in 0..3 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        for _ in 0..3 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        assert_eq!(VIOLATIONS.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    static VIOLATIONS_AT_MAX: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+    #[tokio::test]
+    async fn test_first_violation_at_max() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(1));
+        let controller = Controller::<MemStore>::default()
+            .on_first_violation(|_, _| {
+                VIOLATIONS_AT_MAX.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            });
+
+        // the first rejection is at the max, not over it.
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 2, controller).with_limit_semantics(LimitSemantics::RejectAtMax))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for window in 1..=2 {
+            let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+            for _ in 0..3 {
+                let resp = test::call_service(&app, test::TestRequest::get().to_request()).await;
+                assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+            }
+            assert_eq!(VIOLATIONS_AT_MAX.load(std::sync::atomic::Ordering::SeqCst), window);
+
+            // fires again in the next window.
+            tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        }
+
+        Ok(())
+    }
+
+    static THRESHOLD_HITS: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+    #[tokio::test]
+    async fn test_threshold() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::<MemStore>::default()
+            .on_threshold(0.8, |_, value| {
+                assert_eq!(value.count(), 4);
+                THRESHOLD_HITS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            });
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 5, controller))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for _ in 0..7 {
+            let req = test::TestRequest::get().to_request();
+            test::call_service(&app, req).await;
+        }
+        assert_eq!(THRESHOLD_HITS.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    static THRESHOLD_COST_HITS: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+    #[tokio::test]
+    async fn test_threshold_cost() -> anyhow::Result<()> {
+        // the counts are 3, 6 and 9: the threshold of 5 is crossed by the second request.
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let controller = Controller::<MemStore>::default()
+            .on_threshold(0.5, |_, value| {
+                assert_eq!(value.count(), 6);
+                THRESHOLD_COST_HITS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            });
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 10, controller).with_cost(3))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        for _ in 0..3 {
+            let req = test::TestRequest::get().to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        }
+        assert_eq!(THRESHOLD_COST_HITS.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    fn on_any_store_error(_: &HttpRequest, error: StoreError) -> HttpResponse {
+        HttpResponse::BadGateway().body(error.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_any_store_error() -> anyhow::Result<()> {
+        // the same handler works for any store.
+        let _ = Controller::<MemStore>::new().on_any_store_error(on_any_store_error);
+        let controller = Controller::<StaticStore>::new()
+            .with_find_identifier(|_| 7)
+            .on_any_store_error(on_any_store_error);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(StaticStore::new(1, crate::time::Duration::seconds(10)), 10, controller))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        let req = test::TestRequest::get().to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(test::read_body(resp).await, "store error: UnknownKeyError(7)");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_degradation() -> anyhow::Result<()> {
+        let controller = Controller::<StaticStore>::new()
+            .with_find_identifier(|req| if req.path() == "/" { 0 } else { 7 })
+            .with_failure_policy(FailurePolicy::Open);
+        let rate_limit = RateLimit::new(StaticStore::new(1, crate::time::Duration::seconds(10)), 10, controller);
+        let app = test::init_service(
+            App::new()
+                .wrap(rate_limit.clone())
+                .default_service(web::to(empty))
+        ).await;
+
+        assert_eq!(rate_limit.degradation(), DegradationStatus::default());
+
+        // the unknown key fails, and the request passes.
+        for _ in 0..2 {
+            let resp = test::call_service(&app, test::TestRequest::get().uri("/unknown").to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        let status = rate_limit.degradation();
+        assert!(status.is_degraded());
+        assert_eq!(status.fail_open, 2);
+        assert!(status.degraded_since <= status.last_failure);
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let recovered = rate_limit.degradation();
+        assert!(!recovered.is_degraded());
+        assert_eq!(recovered.fail_open, 2);
+        assert_eq!(recovered.last_failure, status.last_failure);
+
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct SlowStore(MemStore);
+
+    #[async_trait::async_trait]
+    impl Store for SlowStore {
+        type Error = ();
+        type Key = String;
+        type Value = <MemStore as Store>::Value;
+        type Count = u32;
+
+        async fn incr_by(&self, key: Self::Key, val: u32) -> Result<Self::Value, Self::Error> {
+            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+            self.0.incr_by(key, val).await
+        }
+
+        async fn incr(&self, key: Self::Key) -> Result<Self::Value, Self::Error> {
+            self.incr_by(key, 1).await
+        }
+
+        async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+            self.0.del(key).await
+        }
+
+        async fn clear(&self) -> Result<(), Self::Error> {
+            self.0.clear().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_timeout() -> anyhow::Result<()> {
+        let store = SlowStore(MemStore::new(1024, crate::time::Duration::seconds(10)));
+
+        for (policy, status) in [
+            (FailurePolicy::Closed, StatusCode::SERVICE_UNAVAILABLE),
+            (FailurePolicy::Open, StatusCode::NO_CONTENT),
+            (FailurePolicy::OpenOnTransient, StatusCode::NO_CONTENT),
+        ] {
+            let controller = Controller::default()
+                .with_store_timeout(std::time::Duration::from_millis(20))
+                .with_failure_policy(policy);
+
+            let app = test::init_service(
+                App::new()
+                    .wrap(RateLimit::new(
+                        store.clone(),
+                        10,
+                        controller,
+                    ))
+                    .route("/", web::get().to(empty))
+            ).await;
+
+            let req = test::TestRequest::get().to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deadline_header() -> anyhow::Result<()> {
+        let store = SlowStore(MemStore::new(1024, crate::time::Duration::seconds(10)));
+        let controller = Controller::default()
+            .with_deadline_header(DEFAULT_REQUEST_TIMEOUT_HEADER);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RateLimit::new(store, 10, controller))
+                .route("/", web::get().to(empty))
+        ).await;
+
+        // the store is slower than the time left to the caller.
+        let start = std::time::Instant::now();
+        let req = test::TestRequest::get().insert_header((DEFAULT_REQUEST_TIMEOUT_HEADER, "20")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(start.elapsed() < std::time::Duration::from_millis(150));
+
+        // without a deadline, the store call is not bounded.
+        let req = test::TestRequest::get().insert_header((DEFAULT_REQUEST_TIMEOUT_HEADER, "invalid")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        Ok(())
+    }
 }
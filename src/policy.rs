@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use actix_web::body::MessageBody;
+use actix_web::http::header::USER_AGENT;
+use actix_web::{HttpMessage, HttpRequest};
+use crate::controller::{Controller, SuccessHeaders};
+use crate::error::ConfigError;
+use crate::identifier::Fnv1a;
+use crate::middleware::RateLimit;
+use crate::store::mem_store::{MemStore, TokenBucket};
+use crate::store::{Store, Value};
+
+/// [Algorithm] is the way requests are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(tag = "type", rename_all = "snake_case"))]
+pub enum Algorithm {
+    /// Count requests in fixed windows.
+    #[default]
+    FixedWindow,
+    /// Count requests with a token bucket,
+    /// which refills `rate` requests per second.
+    TokenBucket { rate: f64 },
+}
+
+/// [Policy] describes how many requests are allowed,
+/// and how they are counted.
+///
+/// With the `serde` feature, [Policy] can be stored in databases or config files,
+/// with the window as a humantime-style string (see [parse_duration](crate::utils::parse_duration)):
+///
+/// ```json
+/// {"max": 20, "window": "10s", "algorithm": {"type": "token_bucket", "rate": 2.0}, "cost": 2, "headers": "ietf"}
+/// ```
+///
+/// A [RateLimit] is created from a policy by [Self::mem_middleware], and converted back
+/// by [RateLimit::policy].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Policy {
+    /// Max requests per window, or the burst of [Algorithm::TokenBucket].
+    pub max: u32,
+    /// The window, or the time to refill the whole bucket of [Algorithm::TokenBucket].
+    #[cfg_attr(feature = "serde", serde(with = "window"))]
+    pub window: crate::time::Duration,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub algorithm: Algorithm,
+    /// The cost of each request, counted into the max, 1 by default. The policies of
+    /// [RateLimit::with_content_type_policy] and [RateLimit::with_user_agent_policies]
+    /// have their own costs, such as to charge the uploads more than the other requests.
+    #[cfg_attr(feature = "serde", serde(default = "one", skip_serializing_if = "is_one"))]
+    pub cost: u32,
+    /// The [SuccessHeaders] of the [Controller], [None] means keeping the one of the [Controller].
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub headers: Option<SuccessHeaders>,
+}
+
+impl Policy {
+    /// Allow `max` requests per `window`.
+    pub fn fixed_window(max: u32, window: crate::time::Duration) -> Self {
+        Self {
+            max,
+            window,
+            algorithm: Algorithm::FixedWindow,
+            cost: 1,
+            headers: None,
+        }
+    }
+
+    /// Allow a burst of `burst` requests at once,
+    /// with a sustained rate of `rate` requests per second.
+    ///
+    /// ```rust
+    /// // burst 20, sustained 2/sec
+    /// let policy = actix_rl::policy::Policy::burst_sustained(20, 2.0);
+    /// let rate_limiter = policy.mem_middleware(1024, actix_rl::controller::Controller::default());
+    /// ```
+    pub fn burst_sustained(burst: u32, rate: f64) -> Self {
+        Self {
+            max: burst,
+            window: crate::time::Duration::milliseconds((burst as f64 / rate * 1000.0) as i64),
+            algorithm: Algorithm::TokenBucket { rate },
+            cost: 1,
+            headers: None,
+        }
+    }
+
+    /// Count each request as `cost` requests.
+    pub fn with_cost(mut self, cost: u32) -> Self {
+        self.cost = cost;
+        self
+    }
+
+    /// Insert the [SuccessHeaders] into the responses of allowed requests.
+    pub fn with_headers(mut self, headers: SuccessHeaders) -> Self {
+        self.headers = Some(headers);
+        self
+    }
+
+    /// Check that the policy neither never limits nor always limits.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max == 0 {
+            return Err(ConfigError::ZeroMax);
+        }
+
+        if self.window <= crate::time::Duration::zero() {
+            return Err(ConfigError::NonPositiveWindow(self.window));
+        }
+
+        if let Algorithm::TokenBucket { rate } = self.algorithm {
+            TokenBucket { burst: self.max, rate }.validate()?;
+        }
+
+        if self.cost > self.max {
+            return Err(ConfigError::CostOverMax);
+        }
+
+        Ok(())
+    }
+
+    /// Apply the settings of this policy to `controller`, such as [Self::headers].
+    pub fn apply<T: Store, CB: MessageBody>(&self, controller: Controller<T, CB>) -> Controller<T, CB> {
+        match self.headers {
+            Some(headers) => controller.with_success_headers(headers),
+            None => controller,
+        }
+    }
+
+    /// Create a [MemStore] which counts requests for this policy.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rate of the token bucket is not valid, see [Self::validate].
+    pub fn mem_store(&self, capacity: usize) -> MemStore {
+        let store = MemStore::new(capacity, self.window);
+        match self.algorithm {
+            Algorithm::FixedWindow => store,
+            Algorithm::TokenBucket { rate } => store.with_token_bucket(TokenBucket {
+                burst: self.max,
+                rate,
+            }),
+        }
+    }
+
+    /// Create a [RateLimit] middleware for this policy, storing data in memory.
+    pub fn mem_middleware<CB: MessageBody>(&self, capacity: usize, controller: Controller<MemStore, CB>) -> RateLimit<MemStore, CB> {
+        let rate_limit = RateLimit::new(self.mem_store(capacity), self.max, self.apply(controller));
+        match self.cost {
+            1 => rate_limit,
+            cost => rate_limit.with_cost(cost),
+        }
+    }
+
+    /// Like [Self::mem_middleware], but validate the policy and the controller first,
+    /// see [Self::validate] and [RateLimit::try_new].
+    pub fn try_mem_middleware<CB: MessageBody>(&self, capacity: usize, controller: Controller<MemStore, CB>) -> Result<RateLimit<MemStore, CB>, ConfigError> {
+        self.validate()?;
+        let rate_limit = self.mem_middleware(capacity, controller);
+        rate_limit.validate()?;
+        Ok(rate_limit)
+    }
+}
+
+/// [LimitEvaluator] decides whether a request is over the limit, from the value of its key
+/// (after counting it) and the max, see [RateLimit::with_evaluator].
+///
+/// The default is [CountEvaluator]. Algorithms whose decisions are not simple comparisons
+/// of the counts (such as token buckets or GCRA, deciding by the dates of the value)
+/// implement their own. Functions of `(&value, &max) -> bool` are evaluators too,
+/// and ignore the [LimitSemantics].
+pub trait LimitEvaluator<V: Value>: Send + Sync {
+    /// Check if the request counted into `value` is over `max`, with the `semantics`
+    /// of [RateLimit::with_limit_semantics].
+    fn is_limited(&self, value: &V, max: &V::Count, semantics: LimitSemantics) -> bool;
+}
+
+impl<V: Value, F: Fn(&V, &V::Count) -> bool + Send + Sync> LimitEvaluator<V> for F {
+    fn is_limited(&self, value: &V, max: &V::Count, _: LimitSemantics) -> bool {
+        self(value, max)
+    }
+}
+
+/// [LimitSemantics] is how [CountEvaluator] compares the counts with the max,
+/// see [RateLimit::with_limit_semantics].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum LimitSemantics {
+    /// Limit the requests counted over the max (`count > max`),
+    /// so a max of 10 allows 10 requests in a window and rejects the 11th.
+    #[default]
+    AllowMax,
+    /// Limit the requests counted at the max (`count >= max`),
+    /// so a max of 10 allows 9 requests in a window and rejects the 10th.
+    RejectAtMax,
+}
+
+/// [CountEvaluator] limits the requests by comparing their counts with the max,
+/// by the [LimitSemantics]. The default evaluator.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CountEvaluator;
+
+impl<V: Value> LimitEvaluator<V> for CountEvaluator {
+    fn is_limited(&self, value: &V, max: &V::Count, semantics: LimitSemantics) -> bool {
+        match semantics {
+            LimitSemantics::AllowMax => value.count() > *max,
+            LimitSemantics::RejectAtMax => value.count() >= *max,
+        }
+    }
+}
+
+/// [PolicyVariant] is the name of the variant of an [Experiment] assigned to the key of a request.
+///
+/// The middleware inserts it into the extensions of the request before calling the hooks
+/// of the [Controller], so decisions and metrics can be tagged with
+/// `req.extensions().get::<PolicyVariant>()` or [RateLimitByPass::variant](crate::utils::RateLimitByPass::variant).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PolicyVariant(pub Arc<str>);
+
+impl PolicyVariant {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// [PolicyLabels] are the user-defined labels of a limiter (such as the owning team
+/// and the endpoint group), see [RateLimit::with_labels].
+///
+/// The middleware inserts them into the extensions of the request before counting it,
+/// so the hooks of the [Controller] (for events and metrics) and the access logs
+/// (see [log_rate_limit](crate::utils::log_rate_limit)) can attribute the rejected
+/// traffic, with `req.extensions().get::<PolicyLabels>()` or
+/// [RateLimitByPass::labels](crate::utils::RateLimitByPass::labels).
+///
+/// ```rust
+/// use actix_rl::policy::PolicyLabels;
+///
+/// let labels = PolicyLabels::new().with_label("team", "billing").with_label("group", "invoices");
+/// assert_eq!(labels.get("team"), Some("billing"));
+/// assert_eq!(labels.to_string(), "team=billing,group=invoices");
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PolicyLabels(Arc<Vec<(String, String)>>);
+
+impl PolicyLabels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the label `name` to `value`, replacing the previous value.
+    pub fn with_label<N: ToString, V: ToString>(mut self, name: N, value: V) -> Self {
+        let (name, value) = (name.to_string(), value.to_string());
+        let labels = Arc::make_mut(&mut self.0);
+        match labels.iter_mut().find(|(label, _)| *label == name) {
+            Some((_, previous)) => *previous = value,
+            None => labels.push((name, value)),
+        }
+        self
+    }
+
+    /// Return the value of the label `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter()
+            .find(|(label, _)| label == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Return the labels, in the order they were set.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn from_request(req: &HttpRequest) -> Option<PolicyLabels> {
+        req.extensions().get::<PolicyLabels>().cloned()
+    }
+}
+
+/// Format as `name=value` pairs separated by commas.
+impl std::fmt::Display for PolicyLabels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (name, value)) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// [Experiment] assigns keys to named [Policy] variants, so the impact of
+/// different limits can be compared before choosing one.
+///
+/// The keys are bucketed by a deterministic hash of the name of the experiment and the key,
+/// so all instances agree and a key stays in its variant while the variants are unchanged.
+///
+/// ```rust
+/// use actix_rl::policy::{Experiment, Policy};
+///
+/// let experiment = Experiment::new("search-limits")
+///     .with_variant("control", 90, Policy::fixed_window(100, actix_rl::time::Duration::minutes(1)))
+///     .with_variant("strict", 10, Policy::fixed_window(50, actix_rl::time::Duration::minutes(1)));
+/// let (variant, policy) = experiment.assign("John").unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Experiment {
+    name: String,
+    /// the variants, with their weights.
+    variants: Vec<(PolicyVariant, u32, Policy)>,
+}
+
+impl Experiment {
+    pub fn new<N: ToString>(name: N) -> Self {
+        Self {
+            name: name.to_string(),
+            variants: Vec::new(),
+        }
+    }
+
+    /// Add the variant `name`, which gets `weight` out of the sum of the weights of the keys.
+    pub fn with_variant<N: AsRef<str>>(mut self, name: N, weight: u32, policy: Policy) -> Self {
+        self.variants.push((PolicyVariant(Arc::from(name.as_ref())), weight, policy));
+        self
+    }
+
+    /// Return the variant of `key`, or [None] if there is no variant.
+    pub fn assign<K: Hash + ?Sized>(&self, key: &K) -> Option<(&PolicyVariant, &Policy)> {
+        let total: u64 = self.variants.iter().map(|(_, weight, _)| *weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut bucket = Fnv1a::hash(&(&self.name, key)) % total;
+        self.variants.iter()
+            .find(|(_, weight, _)| match bucket.checked_sub(*weight as u64) {
+                Some(rest) => {
+                    bucket = rest;
+                    false
+                },
+                None => true,
+            })
+            .map(|(variant, _, policy)| (variant, policy))
+    }
+}
+
+/// The class of the requests without a `User-Agent`, see [classify_user_agent].
+pub const UA_CLASS_EMPTY: &str = "empty";
+/// The class of the known crawlers and HTTP libraries, see [classify_user_agent].
+pub const UA_CLASS_CRAWLER: &str = "crawler";
+/// The class of the headless and automated browsers, see [classify_user_agent].
+pub const UA_CLASS_HEADLESS: &str = "headless";
+
+/// Classify a request into a client class (such as [UA_CLASS_CRAWLER]),
+/// or [None] for the other clients. See [UserAgentPolicies].
+pub type UserAgentClassifier = fn(&HttpRequest) -> Option<&'static str>;
+
+/// The default [UserAgentClassifier], which classifies requests by their `User-Agent`:
+/// [UA_CLASS_EMPTY] for missing or blank ones, [UA_CLASS_HEADLESS] for headless and
+/// automated browsers, and [UA_CLASS_CRAWLER] for crawlers and HTTP libraries.
+///
+/// The `User-Agent` is chosen by the client, so the classes only throttle the bots
+/// which do not hide, on top of the limits of all clients.
+pub fn classify_user_agent(req: &HttpRequest) -> Option<&'static str> {
+    const HEADLESS: &[&str] = &["headless", "phantomjs", "puppeteer", "playwright", "selenium", "webdriver"];
+    const CRAWLER: &[&str] = &[
+        "bot", "crawl", "spider", "slurp", "curl/", "wget/", "python-requests", "python-urllib",
+        "aiohttp", "go-http-client", "java/", "libwww-perl", "scrapy", "httpclient",
+    ];
+
+    let user_agent = req.headers().get(USER_AGENT)
+        .map(|user_agent| String::from_utf8_lossy(user_agent.as_bytes()).trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    if user_agent.is_empty() {
+        Some(UA_CLASS_EMPTY)
+    } else if HEADLESS.iter().any(|marker| user_agent.contains(marker)) {
+        Some(UA_CLASS_HEADLESS)
+    } else if CRAWLER.iter().any(|marker| user_agent.contains(marker)) {
+        Some(UA_CLASS_CRAWLER)
+    } else {
+        None
+    }
+}
+
+/// [UserAgentPolicies] gives the client classes (such as bots) their own [Policy]s,
+/// checked in addition to the limit of the key, see [RateLimit::with_user_agent_policies].
+///
+/// ```rust
+/// use actix_rl::policy::{Policy, UserAgentPolicies, UA_CLASS_CRAWLER};
+///
+/// let policies = UserAgentPolicies::preset()
+///     .with_policy(UA_CLASS_CRAWLER, Policy::fixed_window(10, actix_rl::time::Duration::minutes(1)));
+/// let rate_limit = actix_rl::middleware::RateLimit::new(
+///     actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::minutes(1)),
+///     100,
+///     actix_rl::controller::Controller::default(),
+/// ).with_user_agent_policies(policies);
+/// ```
+#[derive(Debug, Clone)]
+pub struct UserAgentPolicies {
+    classify: UserAgentClassifier,
+    policies: HashMap<&'static str, Policy>,
+}
+
+impl UserAgentPolicies {
+    /// Create with a custom [UserAgentClassifier], without policies.
+    pub fn new(classify: UserAgentClassifier) -> Self {
+        Self {
+            classify,
+            policies: HashMap::new(),
+        }
+    }
+
+    /// Classify the requests by [classify_user_agent], with the budgets per minute:
+    /// 60 for [UA_CLASS_CRAWLER], 20 for [UA_CLASS_HEADLESS] and 10 for [UA_CLASS_EMPTY],
+    /// within the limit of the key.
+    pub fn preset() -> Self {
+        Self::new(classify_user_agent)
+            .with_policy(UA_CLASS_CRAWLER, Policy::fixed_window(60, crate::time::Duration::minutes(1)))
+            .with_policy(UA_CLASS_HEADLESS, Policy::fixed_window(20, crate::time::Duration::minutes(1)))
+            .with_policy(UA_CLASS_EMPTY, Policy::fixed_window(10, crate::time::Duration::minutes(1)))
+    }
+
+    /// Use `policy` for the requests of `class`, replacing the previous one.
+    pub fn with_policy(mut self, class: &'static str, policy: Policy) -> Self {
+        self.policies.insert(class, policy);
+        self
+    }
+
+    /// Return the class of `req` and its policy, or [None] if the class has no policy.
+    pub fn policy(&self, req: &HttpRequest) -> Option<(&'static str, Policy)> {
+        let class = (self.classify)(req)?;
+        self.policies.get(class).map(|policy| (class, *policy))
+    }
+}
+
+/// The default [Policy::cost].
+#[cfg(feature = "serde")]
+fn one() -> u32 {
+    1
+}
+
+#[cfg(feature = "serde")]
+fn is_one(cost: &u32) -> bool {
+    *cost == 1
+}
+
+/// (De)serialize the window as a humantime-style string.
+#[cfg(feature = "serde")]
+mod window {
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+    use crate::utils::{format_duration, parse_duration};
+
+    pub fn serialize<S: Serializer>(window: &crate::time::Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        if *window < crate::time::Duration::zero() {
+            return Err(ser::Error::custom(format!("negative window {}", window)));
+        }
+        serializer.serialize_str(&format_duration(*window))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<crate::time::Duration, D::Error> {
+        let window = String::deserialize(deserializer)?;
+        parse_duration(&window).map_err(de::Error::custom)
+    }
+}
+
+/// Return the key of a request at a level of [HierarchicalPolicy],
+/// or [None] to skip the level, such as the tenant id from a header.
+pub type LevelKeyFunc = fn(&HttpRequest) -> Option<String>;
+
+/// [HierarchicalPolicy] is a chain of limits above the key of each request, such as
+/// a global limit and a per-tenant limit above the per-user limit of the middleware,
+/// see [RateLimit::with_hierarchy](crate::middleware::RateLimit::with_hierarchy).
+///
+/// ```rust
+/// use actix_rl::policy::HierarchicalPolicy;
+///
+/// let hierarchy = HierarchicalPolicy::new()
+///     .with_level("global", 10000, |_| Some("all".to_string()))
+///     .with_level("tenant", 1000, |req| {
+///         req.headers().get("X-Tenant")?.to_str().ok().map(str::to_string)
+///     });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HierarchicalPolicy {
+    /// the name, the max and the key of each level.
+    pub(crate) levels: Vec<(String, u32, LevelKeyFunc)>,
+}
+
+impl HierarchicalPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow `max` requests per window of the [Store] for each key of the level `name`,
+    /// counted with the key `{name}:{key}`.
+    pub fn with_level<N: ToString>(mut self, name: N, max: u32, key: LevelKeyFunc) -> Self {
+        self.levels.push((name.to_string(), max, key));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    #[test]
+    fn user_agent_classes() {
+        let classify = |user_agent: Option<&str>| {
+            let req = match user_agent {
+                Some(user_agent) => TestRequest::default().insert_header((USER_AGENT, user_agent)),
+                None => TestRequest::default(),
+            }.to_http_request();
+            classify_user_agent(&req)
+        };
+
+        assert_eq!(classify(None), Some(UA_CLASS_EMPTY));
+        assert_eq!(classify(Some(" ")), Some(UA_CLASS_EMPTY));
+        assert_eq!(classify(Some("Mozilla/5.0 (compatible; Googlebot/2.1)")), Some(UA_CLASS_CRAWLER));
+        assert_eq!(classify(Some("curl/8.4.0")), Some(UA_CLASS_CRAWLER));
+        assert_eq!(classify(Some("Mozilla/5.0 (X11; Linux x86_64) HeadlessChrome/120.0")), Some(UA_CLASS_HEADLESS));
+        assert_eq!(classify(Some("Mozilla/5.0 (Windows NT 10.0; Win64; x64) Firefox/121.0")), None);
+
+        let policies = UserAgentPolicies::preset();
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(policies.policy(&req), Some((UA_CLASS_EMPTY, Policy::fixed_window(10, crate::time::Duration::minutes(1)))));
+        assert_eq!(UserAgentPolicies::new(classify_user_agent).policy(&req), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let policy = Policy::burst_sustained(20, 2.0).with_headers(SuccessHeaders::Ietf);
+        let json = serde_json::to_string(&policy).unwrap();
+        assert_eq!(json, r#"{"max":20,"window":"10s","algorithm":{"type":"token_bucket","rate":2.0},"headers":"ietf"}"#);
+        assert_eq!(serde_json::from_str::<Policy>(&json).unwrap(), policy);
+
+        let policy: Policy = serde_json::from_str(r#"{"max":10,"window":"1m"}"#).unwrap();
+        assert_eq!(policy, Policy::fixed_window(10, crate::time::Duration::minutes(1)));
+
+        let policy = Policy::fixed_window(10, crate::time::Duration::minutes(1)).with_cost(2);
+        let json = serde_json::to_string(&policy).unwrap();
+        assert_eq!(json, r#"{"max":10,"window":"1m","algorithm":{"type":"fixed_window"},"cost":2}"#);
+        assert_eq!(serde_json::from_str::<Policy>(&json).unwrap(), policy);
+
+        assert!(serde_json::from_str::<Policy>(r#"{"max":10,"window":"1y"}"#).is_err());
+    }
+}
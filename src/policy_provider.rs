@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use arc_swap::ArcSwap;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::HttpRequest;
+use crate::error::{ConfigError, PolicyRefreshError};
+#[cfg(feature = "serde")]
+use crate::error::LoadPoliciesError;
+use crate::policy::{Experiment, Policy, PolicyVariant, UserAgentPolicies};
+use crate::store::{Store, Value};
+
+/// [PolicyProvider] fetches the [Policy]s from a central config service,
+/// such as a database or an HTTP API. The policies are named, and each
+/// [RateLimit](crate::middleware::RateLimit) uses the one set by
+/// [with_policy_set](crate::middleware::RateLimit::with_policy_set).
+#[async_trait::async_trait]
+pub trait PolicyProvider: Send + Sync {
+    type Error: Debug;
+
+    /// Fetch all policies, by name.
+    async fn fetch(&self) -> Result<HashMap<String, Policy>, Self::Error>;
+}
+
+/// [KeyPolicyProvider] returns the [Policy] of a key (such as a tenant),
+/// overriding the policy of the [RateLimit](crate::middleware::RateLimit).
+/// It is called for each request, so it should be cached, such as
+/// [RedisTenantPolicies](crate::store::redis_tenant_policy::RedisTenantPolicies).
+#[async_trait::async_trait]
+pub trait KeyPolicyProvider<K>: Send + Sync {
+    /// Return the policy of `key`, or [None] to use the policy of the [RateLimit](crate::middleware::RateLimit).
+    async fn policy(&self, key: K) -> Option<Policy>;
+}
+
+/// [PolicySet] holds the current policies, which can be swapped without locking,
+/// such as by [spawn_policy_refresh]. Clones share the same policies.
+#[derive(Debug, Clone, Default)]
+pub struct PolicySet {
+    policies: Arc<ArcSwap<HashMap<String, Policy>>>,
+}
+
+impl PolicySet {
+    /// Create with `policies`, or return the error of the first policy failing [Policy::validate].
+    pub fn new(policies: HashMap<String, Policy>) -> Result<Self, ConfigError> {
+        for policy in policies.values() {
+            policy.validate()?;
+        }
+
+        Ok(Self {
+            policies: Arc::new(ArcSwap::from_pointee(policies)),
+        })
+    }
+
+    /// Load the policies from a JSON object of [Policy]s by name, with humantime-style
+    /// windows (see [parse_duration](crate::utils::parse_duration)), such as a deployment config,
+    /// or return the error of the first policy failing [Policy::validate].
+    ///
+    /// ```rust
+    /// let set = actix_rl::policy_provider::PolicySet::from_json(r#"{
+    ///     "api": {"max": 100, "window": "90s"},
+    ///     "login": {"max": 5, "window": "10m"}
+    /// }"#).unwrap();
+    /// assert_eq!(set.get("api").unwrap().window, actix_rl::time::Duration::seconds(90));
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> Result<Self, LoadPoliciesError> {
+        let policies: HashMap<String, Policy> = serde_json::from_str(json)
+            .map_err(|e| LoadPoliciesError::Parse(e.to_string()))?;
+        for (name, policy) in &policies {
+            policy.validate().map_err(|e| LoadPoliciesError::Invalid(name.clone(), e))?;
+        }
+
+        Ok(Self {
+            policies: Arc::new(ArcSwap::from_pointee(policies)),
+        })
+    }
+
+    /// Like [Self::from_json], reading the config file at `path`.
+    #[cfg(feature = "serde")]
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self, LoadPoliciesError> {
+        let json = std::fs::read_to_string(path).map_err(LoadPoliciesError::Io)?;
+        Self::from_json(&json)
+    }
+
+    /// Return the policy of `name`.
+    pub fn get(&self, name: &str) -> Option<Policy> {
+        self.policies.load().get(name).copied()
+    }
+
+    /// Replace all policies. The policies which fail [Policy::validate] are dropped,
+    /// and returned with their errors.
+    pub fn store(&self, mut policies: HashMap<String, Policy>) -> Vec<(String, ConfigError)> {
+        let mut dropped = Vec::new();
+        policies.retain(|name, policy| match policy.validate() {
+            Ok(()) => true,
+            Err(e) => {
+                dropped.push((name.clone(), e));
+                false
+            },
+        });
+        self.policies.store(Arc::new(policies));
+        dropped
+    }
+
+    /// Set the policy of `name`, keeping the others.
+    pub fn insert<N: ToString>(&self, name: N, policy: Policy) -> Result<(), ConfigError> {
+        policy.validate()?;
+        let name = name.to_string();
+        self.policies.rcu(|policies| {
+            let mut policies = HashMap::clone(policies);
+            policies.insert(name.clone(), policy);
+            policies
+        });
+        Ok(())
+    }
+
+    /// Remove the policy of `name`, so the `max` of the [RateLimit](crate::middleware::RateLimit)s applies again.
+    pub fn remove(&self, name: &str) {
+        self.policies.rcu(|policies| {
+            let mut policies = HashMap::clone(policies);
+            policies.remove(name);
+            policies
+        });
+    }
+}
+
+/// Convert [Policy::max] to the max, and [Policy::cost] to the increment of the [Store].
+type ResolveFunc<T> = fn(&Policy) -> Option<(<<T as Store>::Value as Value>::Count, <T as Store>::Count)>;
+
+/// [DynamicPolicy] is the [Policy] of a [RateLimit](crate::middleware::RateLimit),
+/// looked up for each request: the policy of the key first, then the variant of the
+/// [Experiment], then the one of the [PolicySet]. The policies of the content type and
+/// of the client class are checked in addition, see [Self::scoped].
+pub(crate) struct DynamicPolicy<T: Store> {
+    /// the policies of the content types, see [ContentTypePolicies].
+    pub content_types: Option<ContentTypePolicies<T::Key>>,
+    /// the policies of the client classes, with the function to scope a key by the class.
+    pub user_agents: Option<(UserAgentPolicies, ScopeFunc<T::Key>)>,
+    /// the [PolicySet] and the name of the policy.
+    pub set: Option<(PolicySet, String)>,
+    pub keys: Option<Arc<dyn KeyPolicyProvider<T::Key>>>,
+    /// the [Experiment], with the function to assign a key to a variant.
+    pub experiment: Option<(Experiment, AssignFunc<T::Key>)>,
+    pub resolve: ResolveFunc<T>,
+}
+
+/// Assign a key to a variant of an [Experiment].
+type AssignFunc<K> = fn(&Experiment, &K) -> Option<(PolicyVariant, Policy)>;
+
+/// Scope a key by the matched content type or client class, so each of them has its own counter.
+type ScopeFunc<K> = fn(K, &str) -> K;
+
+/// [ContentTypePolicies] are the [Policy]s of the requests by their `Content-Type`,
+/// see [RateLimit::with_content_type_policy](crate::middleware::RateLimit::with_content_type_policy).
+pub(crate) struct ContentTypePolicies<K> {
+    /// the patterns (such as `multipart/*`) and their policies, in order.
+    pub rules: Vec<(String, Policy)>,
+    pub scope: ScopeFunc<K>,
+}
+
+impl<K> Clone for ContentTypePolicies<K> {
+    fn clone(&self) -> Self {
+        Self {
+            rules: self.rules.clone(),
+            scope: self.scope,
+        }
+    }
+}
+
+impl<K> ContentTypePolicies<K> {
+    /// Return the first rule matching `content_type`.
+    fn matching(&self, content_type: &str) -> Option<&(String, Policy)> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.rules.iter().find(|(pattern, _)| match pattern.strip_suffix("/*") {
+            Some(kind) => essence.split_once('/').is_some_and(|(essence, _)| essence == kind),
+            None => essence == *pattern,
+        })
+    }
+}
+
+/// [CurrentPolicy] is the policy of a request, see [DynamicPolicy::current].
+pub(crate) struct CurrentPolicy<T: Store> {
+    pub max: <T::Value as Value>::Count,
+    pub incr: T::Count,
+    pub window: crate::time::Duration,
+    pub variant: Option<PolicyVariant>,
+}
+
+impl<T: Store> Clone for DynamicPolicy<T> {
+    fn clone(&self) -> Self {
+        Self {
+            content_types: self.content_types.clone(),
+            user_agents: self.user_agents.clone(),
+            set: self.set.clone(),
+            keys: self.keys.clone(),
+            experiment: self.experiment.clone(),
+            resolve: self.resolve,
+        }
+    }
+}
+
+impl<T: Store> DynamicPolicy<T> {
+    pub fn new(resolve: ResolveFunc<T>) -> Self {
+        Self {
+            content_types: None,
+            user_agents: None,
+            set: None,
+            keys: None,
+            experiment: None,
+            resolve,
+        }
+    }
+
+    /// Return the current policy of `key`.
+    pub async fn current(&self, key: Option<&T::Key>) -> Option<CurrentPolicy<T>> {
+        let mut policy = None;
+        let mut variant = None;
+
+        if let Some(key) = key {
+            if let Some((experiment, assign)) = &self.experiment {
+                (variant, policy) = assign(experiment, key).unzip();
+            }
+            if let Some(keys) = &self.keys {
+                if let Some(key_policy) = keys.policy(key.clone()).await {
+                    // the keys with their own policies are not part of the experiment.
+                    (variant, policy) = (None, Some(key_policy));
+                }
+            }
+        }
+
+        let policy = policy.or_else(|| self.set.as_ref().and_then(|(set, name)| set.get(name)));
+        policy.and_then(|policy| self.resolve(policy, variant))
+    }
+
+    /// Return `key` scoped by the matched content type and client class of `req`, with
+    /// their policies. They have their own counters, which are checked in addition to
+    /// the policy of `key`, so they can only reject more requests.
+    pub fn scoped(&self, key: &T::Key, req: &HttpRequest) -> Vec<(T::Key, CurrentPolicy<T>)> {
+        let mut scoped = Vec::new();
+
+        let content_type = req.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        let rule = self.content_types.as_ref()
+            .zip(content_type)
+            .and_then(|(content_types, content_type)| Some((content_types.scope, content_types.matching(content_type)?)));
+        if let Some((scope, (pattern, policy))) = rule {
+            scoped.extend(self.resolve(*policy, None).map(|policy| (scope(key.clone(), pattern), policy)));
+        }
+
+        let class = self.user_agents.as_ref()
+            .and_then(|(policies, scope)| Some((*scope, policies.policy(req)?)));
+        if let Some((scope, (class, policy))) = class {
+            scoped.extend(self.resolve(policy, None).map(|policy| (scope(key.clone(), class), policy)));
+        }
+
+        scoped
+    }
+
+    fn resolve(&self, policy: Policy, variant: Option<PolicyVariant>) -> Option<CurrentPolicy<T>> {
+        let (max, incr) = (self.resolve)(&policy)?;
+        Some(CurrentPolicy {
+            max,
+            incr,
+            window: policy.window,
+            variant,
+        })
+    }
+}
+
+/// Spawn a task which calls [PolicyProvider::fetch] every `period`, and stores
+/// the policies into `set`, so the limits change within `period` on all instances.
+///
+/// A fetch is cancelled after `period`. On errors and timeouts, the previous policies are
+/// kept; they are passed to `on_error`, such as to log them, with the invalid policies,
+/// which are dropped (see [PolicySet::store]).
+/// Abort the returned handle to stop refreshing.
+/// Must be called inside a tokio runtime, such as an actix-web server.
+pub fn spawn_policy_refresh<P, F>(provider: P, set: PolicySet, period: std::time::Duration, on_error: F) -> tokio::task::JoinHandle<()>
+    where
+        P: PolicyProvider + 'static,
+        F: Fn(PolicyRefreshError<P::Error>) + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match tokio::time::timeout(period, provider.fetch()).await {
+                Ok(Ok(policies)) => {
+                    for (name, e) in set.store(policies) {
+                        on_error(PolicyRefreshError::Invalid(name, e));
+                    }
+                },
+                Ok(Err(e)) => on_error(PolicyRefreshError::Fetch(e)),
+                Err(_) => on_error(PolicyRefreshError::Timeout),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use super::*;
+
+    struct Provider(AtomicU32);
+
+    #[async_trait::async_trait]
+    impl PolicyProvider for Provider {
+        type Error = ();
+
+        async fn fetch(&self) -> Result<HashMap<String, Policy>, Self::Error> {
+            let max = self.0.fetch_add(1, Ordering::SeqCst);
+            if max == 2 {
+                return Err(());
+            }
+            if max == 3 {
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+            Ok(HashMap::from([
+                ("api".to_string(), Policy::fixed_window(max, crate::time::Duration::seconds(10))),
+            ]))
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_json() {
+        let set = PolicySet::from_json(r#"{"api": {"max": 100, "window": "1h30m", "cost": 2}}"#).unwrap();
+        assert_eq!(set.get("api"), Some(Policy::fixed_window(100, crate::time::Duration::minutes(90)).with_cost(2)));
+
+        assert!(matches!(PolicySet::from_json(r#"{"api": {"max": 100, "window": "90"}}"#), Err(LoadPoliciesError::Parse(_))));
+        assert!(matches!(
+            PolicySet::from_json(r#"{"api": {"max": 0, "window": "90s"}}"#),
+            Err(LoadPoliciesError::Invalid(name, ConfigError::ZeroMax)) if name == "api"
+        ));
+        assert!(matches!(PolicySet::load("/nonexistent/policies.json"), Err(LoadPoliciesError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn refresh() {
+        let set = PolicySet::default();
+        let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let on_error = {
+            let errors = errors.clone();
+            move |e: PolicyRefreshError<()>| errors.lock().unwrap().push(e)
+        };
+        let refresh = spawn_policy_refresh(Provider(AtomicU32::new(0)), set.clone(), std::time::Duration::from_millis(20), on_error);
+
+        // the first policy has zero max, which is dropped.
+        while set.get("api").is_none() {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let max = set.get("api").unwrap().max;
+        assert!(max >= 1);
+
+        // after the error and the slow fetch.
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        assert!(set.get("api").unwrap().max > max);
+
+        // the invalid policy, the error and the slow fetch are reported.
+        assert_eq!(errors.lock().unwrap()[..3], [
+            PolicyRefreshError::Invalid("api".to_string(), ConfigError::ZeroMax),
+            PolicyRefreshError::Fetch(()),
+            PolicyRefreshError::Timeout,
+        ]);
+        assert!(PolicySet::new(HashMap::from([
+            ("api".to_string(), Policy::fixed_window(0, crate::time::Duration::seconds(10))),
+        ])).is_err());
+
+        refresh.abort();
+    }
+}
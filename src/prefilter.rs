@@ -0,0 +1,229 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use crate::time::{DateTime, Utc};
+
+/// [FirstSeenFilter] remembers the keys seen in this process with a rotating bloom filter,
+/// so the first request of a key in a window is allowed without calling the [Store](crate::store::Store),
+/// see [RateLimit::with_first_seen_filter](crate::middleware::RateLimit::with_first_seen_filter).
+/// Under a scan from many addresses, most keys make a single request, which then costs
+/// no round-trip to a shared store.
+///
+/// The keys are remembered for 1 to 2 windows after their last request: the filter has a
+/// current and a previous generation, and rotates when a window has passed. A key is seen
+/// when it is in either generation. A key not seen before may be taken as seen
+/// (with about `false_positive_rate`), and its request is then counted as usual.
+///
+/// Clones share the same filter.
+///
+/// ```rust
+/// use actix_rl::prefilter::FirstSeenFilter;
+///
+/// // 1 million keys per minute, with 1% false positives (1.2 MB per generation).
+/// let filter = FirstSeenFilter::new(1_000_000, 0.01, std::time::Duration::from_secs(60));
+/// ```
+#[derive(Debug, Clone)]
+pub struct FirstSeenFilter {
+    inner: Arc<FirstSeenFilterInner>,
+}
+
+#[derive(Debug)]
+struct FirstSeenFilterInner {
+    /// The number of bits of a generation.
+    bits: usize,
+    /// The number of bits set per key.
+    hashes: usize,
+    window: Duration,
+    hasher: RandomState,
+    generations: Mutex<Generations>,
+}
+
+#[derive(Debug)]
+struct Generations {
+    current: Vec<u64>,
+    previous: Vec<u64>,
+    rotated: Instant,
+}
+
+impl FirstSeenFilter {
+    /// Create a filter sized for `expected_keys` per `window`, with `false_positive_rate`
+    /// (such as `0.01`) when that many keys are remembered.
+    pub fn new(expected_keys: usize, false_positive_rate: f64, window: Duration) -> Self {
+        let keys = expected_keys.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-keys * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let hashes = (bits as f64 / keys * ln2).round().max(1.0) as usize;
+        let words = bits.div_ceil(64);
+
+        Self {
+            inner: Arc::new(FirstSeenFilterInner {
+                bits: words * 64,
+                hashes,
+                window,
+                hasher: RandomState::new(),
+                generations: Mutex::new(Generations {
+                    current: vec![0; words],
+                    previous: vec![0; words],
+                    rotated: Instant::now(),
+                }),
+            }),
+        }
+    }
+
+    /// Return the number of bits of a generation, and the number of bits set per key.
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.inner.bits, self.inner.hashes)
+    }
+
+    /// Return the window of the filter.
+    pub fn window(&self) -> Duration {
+        self.inner.window
+    }
+
+    /// Remember `key`, and return true if it was not seen in the last windows.
+    pub(crate) fn first_seen<K: Hash>(&self, key: &K) -> bool {
+        let inner = &*self.inner;
+        let hash = inner.hasher.hash_one(key);
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        let bits = (0..inner.hashes).map(|i| h1.wrapping_add(i.wrapping_mul(h2)) % inner.bits);
+        let contains = |words: &[u64]| bits.clone().all(|bit| words[bit / 64] & (1 << (bit % 64)) != 0);
+
+        let mut generations = inner.generations.lock().unwrap_or_else(PoisonError::into_inner);
+        let elapsed = generations.rotated.elapsed();
+        if elapsed >= inner.window {
+            let generations = &mut *generations;
+            if elapsed >= inner.window * 2 {
+                generations.previous.fill(0);
+            } else {
+                std::mem::swap(&mut generations.previous, &mut generations.current);
+            }
+            generations.current.fill(0);
+            generations.rotated = Instant::now();
+        }
+
+        let seen = contains(&generations.current) || contains(&generations.previous);
+        for bit in bits {
+            generations.current[bit / 64] |= 1 << (bit % 64);
+        }
+        !seen
+    }
+}
+
+/// [IdempotencyFilter] remembers the idempotency keys (see [idempotency_key](crate::identifier::idempotency_key))
+/// of the allowed requests of each key until their windows end, so the retries of an idempotent
+/// write are not charged again, see [RateLimit::with_idempotency_filter](crate::middleware::RateLimit::with_idempotency_filter).
+///
+/// The idempotency keys only skip the charge, and are never part of the key of the limit:
+/// a client sending a new idempotency key on each request is counted as usual.
+///
+/// Up to `capacity` pairs are remembered in this process, the later ones are charged as usual.
+/// The hashes are seeded randomly for each filter. Clones share the same filter.
+///
+/// ```rust
+/// use actix_rl::prefilter::IdempotencyFilter;
+///
+/// let filter = IdempotencyFilter::new(100_000);
+/// ```
+#[derive(Debug, Clone)]
+pub struct IdempotencyFilter {
+    inner: Arc<IdempotencyFilterInner>,
+}
+
+#[derive(Debug)]
+struct IdempotencyFilterInner {
+    capacity: usize,
+    hasher: RandomState,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    /// The end of the window of each pair (by hash).
+    until: HashMap<u64, DateTime<Utc>>,
+    /// The time the ended windows may be removed again, when the filter is full.
+    next_sweep: Option<DateTime<Utc>>,
+}
+
+impl IdempotencyFilter {
+    /// Create a filter remembering up to `capacity` idempotency keys.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(IdempotencyFilterInner {
+                capacity,
+                hasher: RandomState::new(),
+                seen: Default::default(),
+            }),
+        }
+    }
+
+    /// Check if `idempotency_key` of `key` was remembered, and its window has not ended.
+    pub(crate) fn is_retry<K: Hash>(&self, key: &K, idempotency_key: &str) -> bool {
+        let hash = self.inner.hasher.hash_one((key, idempotency_key));
+        let seen = self.inner.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        seen.until.get(&hash).is_some_and(|until| *until > Utc::now())
+    }
+
+    /// Remember `idempotency_key` of `key` until `until`, unless the filter is full.
+    pub(crate) fn remember<K: Hash>(&self, key: &K, idempotency_key: &str, until: DateTime<Utc>) {
+        let hash = self.inner.hasher.hash_one((key, idempotency_key));
+        let mut seen = self.inner.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if seen.until.len() >= self.inner.capacity {
+            // sweep at most once per second, so a full filter does not scan on each request.
+            let now = Utc::now();
+            if seen.next_sweep.is_none_or(|next| next <= now) {
+                seen.until.retain(|_, until| *until > now);
+                seen.next_sweep = Some(now + crate::time::Duration::seconds(1));
+            }
+            if seen.until.len() >= self.inner.capacity {
+                return;
+            }
+        }
+        seen.until.insert(hash, until);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idempotency_filter() {
+        let filter = IdempotencyFilter::new(2);
+        let until = Utc::now() + crate::time::Duration::seconds(10);
+
+        filter.remember(&"John", "a", until);
+        assert!(filter.is_retry(&"John", "a"));
+        assert!(!filter.is_retry(&"John", "b"));
+        assert!(!filter.is_retry(&"Meg", "a"));
+
+        // the ended windows are forgotten, and the filter does not grow beyond its capacity.
+        filter.remember(&"John", "b", Utc::now() - crate::time::Duration::seconds(1));
+        assert!(!filter.is_retry(&"John", "b"));
+        filter.remember(&"John", "c", until);
+        assert!(filter.is_retry(&"John", "c"));
+        filter.remember(&"John", "d", until);
+        assert!(!filter.is_retry(&"John", "d"));
+    }
+
+    #[test]
+    fn first_seen_filter() {
+        let filter = FirstSeenFilter::new(1000, 0.01, Duration::from_millis(50));
+        assert_eq!(filter.dimensions(), (9600, 7));
+
+        assert!(filter.first_seen(&"John"));
+        assert!(!filter.first_seen(&"John"));
+        assert!(filter.first_seen(&"Meg"));
+
+        // few false positives within the expected keys.
+        let first = (0..1000).filter(|i| filter.first_seen(&format!("ip:{}", i))).count();
+        assert!(first >= 970, "{}", first);
+
+        // remembered in the previous generation, then forgotten.
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!filter.first_seen(&"Meg"));
+        std::thread::sleep(Duration::from_millis(110));
+        assert!(filter.first_seen(&"John"));
+    }
+}
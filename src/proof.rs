@@ -0,0 +1,158 @@
+//! Signed tokens in the rate-limit responses, so the rejections can be verified later,
+//! see [RateLimit::with_proof_token](crate::middleware::RateLimit::with_proof_token).
+
+use base64::Engine;
+use crate::time::{DateTime, Utc};
+use hmac::Mac;
+use crate::error::InvalidProofError;
+
+/// The header of the proof tokens in the rate-limit responses.
+pub const DEFAULT_PROOF_HEADER: &str = "X-Rate-Limit-Proof";
+
+/// The version prefix of the tokens.
+const PROOF_VERSION: &str = "v1";
+
+/// The number of bytes of the key hash, in lowercase hex.
+const KEY_HASH_LEN: usize = 8;
+
+/// [Proof] is the content of a token signed by [ProofSigner].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Proof {
+    /// The hash of the key, see [ProofSigner::key_hash].
+    pub key_hash: String,
+    /// The [PolicyVariant](crate::policy::PolicyVariant) of the key, or the name of the
+    /// [Controller](crate::controller::Controller), or `default`.
+    pub policy: String,
+    /// When the window of the key ends, if known.
+    pub reset: Option<DateTime<Utc>>,
+    /// When the token was signed.
+    pub issued: DateTime<Utc>,
+}
+
+/// [ProofSigner] signs the rejections with HMAC-SHA256, as tokens such as
+/// `v1.<payload>.<signature>` (both in URL-safe base64), which support can check with
+/// [Self::verify] when a client reports a rejection. The key itself is not in the token,
+/// only [Self::key_hash].
+///
+/// ```rust
+/// use actix_rl::proof::ProofSigner;
+///
+/// let signer = ProofSigner::new(b"secret");
+/// let token = signer.sign("10.0.0.1", "default", None);
+/// let proof = signer.verify(&token).unwrap();
+/// assert_eq!(proof.key_hash, signer.key_hash("10.0.0.1"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProofSigner {
+    secret: Vec<u8>,
+}
+
+impl ProofSigner {
+    pub fn new<S: AsRef<[u8]>>(secret: S) -> Self {
+        Self {
+            secret: secret.as_ref().to_vec(),
+        }
+    }
+
+    fn hmac(&self, context: &[u8], message: &[u8]) -> hmac::Hmac<sha2::Sha256> {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(context);
+        mac.update(message);
+        mac
+    }
+
+    fn mac(&self, context: &[u8], message: &[u8]) -> Vec<u8> {
+        self.hmac(context, message).finalize().into_bytes().to_vec()
+    }
+
+    /// Return the hash of `key` in the tokens, in lowercase hex, to look up the tokens of a key.
+    pub fn key_hash(&self, key: &str) -> String {
+        self.mac(b"key\n", key.as_bytes())[..KEY_HASH_LEN].iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Return a token for the rejection of `key` by `policy`, until `reset`.
+    pub fn sign(&self, key: &str, policy: &str, reset: Option<DateTime<Utc>>) -> String {
+        let payload = format!(
+            "{}|{}|{}|{}",
+            self.key_hash(key),
+            policy,
+            reset.map_or("-".to_string(), |reset| reset.timestamp().to_string()),
+            Utc::now().timestamp(),
+        );
+        let engine = &base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let signature = self.mac(b"proof\n", payload.as_bytes());
+        format!("{}.{}.{}", PROOF_VERSION, engine.encode(payload), engine.encode(signature))
+    }
+
+    /// Check that `token` was signed with this secret, and return its content.
+    pub fn verify(&self, token: &str) -> Result<Proof, InvalidProofError> {
+        let engine = &base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let mut parts = token.trim().split('.');
+        let (Some(PROOF_VERSION), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(InvalidProofError);
+        };
+        let payload = engine.decode(payload).map_err(|_| InvalidProofError)?;
+        let signature = engine.decode(signature).map_err(|_| InvalidProofError)?;
+
+        // compared in constant time.
+        self.hmac(b"proof\n", &payload)
+            .verify_slice(&signature)
+            .map_err(|_| InvalidProofError)?;
+
+        // the policy may contain `|`, so it is between the first and the last two fields.
+        let payload = String::from_utf8(payload).map_err(|_| InvalidProofError)?;
+        let (key_hash, rest) = payload.split_once('|').ok_or(InvalidProofError)?;
+        let mut fields = rest.rsplitn(3, '|');
+        let (Some(issued), Some(reset), Some(policy)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(InvalidProofError);
+        };
+        let timestamp = |value: &str| value.parse().ok()
+            .and_then(|value| DateTime::from_timestamp(value, 0))
+            .ok_or(InvalidProofError);
+
+        Ok(Proof {
+            key_hash: key_hash.to_string(),
+            policy: policy.to_string(),
+            reset: match reset {
+                "-" => None,
+                reset => Some(timestamp(reset)?),
+            },
+            issued: timestamp(issued)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proof_token() {
+        let signer = ProofSigner::new(b"key");
+        let reset = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let token = signer.sign("10.0.0.1", "tier|gold", Some(reset));
+        let proof = signer.verify(&token).unwrap();
+        assert_eq!(proof.key_hash, signer.key_hash("10.0.0.1"));
+        assert_eq!(proof.key_hash.len(), 16);
+        assert_eq!(proof.policy, "tier|gold");
+        assert_eq!(proof.reset, Some(reset));
+        assert!((Utc::now() - proof.issued).num_seconds() < 5);
+
+        let proof = signer.verify(&signer.sign("10.0.0.1", "default", None)).unwrap();
+        assert_eq!(proof.reset, None);
+
+        // another secret, tampered payload, malformed tokens.
+        assert_eq!(ProofSigner::new(b"other").verify(&token), Err(InvalidProofError));
+        let (head, signature) = token.rsplit_once('.').unwrap();
+        let forged = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(format!("{}|admin|-|0", signer.key_hash("10.0.0.1")));
+        assert_eq!(signer.verify(&format!("v1.{}.{}", forged, signature)), Err(InvalidProofError));
+        assert_eq!(signer.verify(&format!("v2{}.{}", &head[2..], signature)), Err(InvalidProofError));
+        assert_eq!(signer.verify(&token[..token.len() - 1]), Err(InvalidProofError));
+        assert_eq!(signer.verify(""), Err(InvalidProofError));
+        assert_ne!(signer.key_hash("10.0.0.1"), signer.key_hash("10.0.0.2"));
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use tokio::sync::Notify;
+
+/// [RequestQueue] admits the requests over the limit to wait for their keys,
+/// see [RateLimit::with_queue](crate::middleware::RateLimit::with_queue).
+pub(crate) trait RequestQueue<K>: Send + Sync {
+    /// Admit a request of `key` to wait, or return [None] if there is no capacity for `key`.
+    fn admit(&self, key: &K) -> Option<QueueTicket>;
+
+    /// The max time a request waits.
+    fn max_wait(&self) -> std::time::Duration;
+
+    /// Return [Poll::Ready] if the queue is not full, or wake `cx` when it is no longer full.
+    fn poll_capacity(&self, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+/// [FairQueue] caps the number of waiting requests, and gives each waiting key
+/// a fair share of the capacity, so one noisy key cannot take all of it.
+///
+/// A share is always kept for the next key: with `n` waiting keys, each of them may
+/// hold `max_waiting / (n + 1)` requests. The waiters of a key retry in FIFO order, and
+/// the keys take turns to retry in round-robin order.
+///
+/// The keys are tracked by their hashes, seeded randomly for each queue.
+pub(crate) struct FairQueue<K> {
+    max_waiting: usize,
+    max_wait: std::time::Duration,
+    hasher: RandomState,
+    shared: Arc<Shared>,
+    _key: PhantomData<fn(&K)>,
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    /// notified when a ticket leaves or a turn ends.
+    notify: Notify,
+}
+
+struct QueueState {
+    total: usize,
+    next_id: u64,
+    /// the tickets of each waiting key (by hash), in FIFO order.
+    keys: HashMap<u64, VecDeque<u64>>,
+    /// the keys whose first ticket waits for its turn to retry, in round-robin order.
+    turns: VecDeque<u64>,
+    /// the tasks waiting for the queue to be no longer full.
+    wakers: Vec<Waker>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<K> FairQueue<K> {
+    pub fn new(max_waiting: usize, max_wait: std::time::Duration) -> Self {
+        Self {
+            max_waiting,
+            max_wait,
+            hasher: RandomState::new(),
+            _key: PhantomData,
+            shared: Arc::new(Shared {
+                state: Mutex::new(QueueState {
+                    total: 0,
+                    next_id: 0,
+                    keys: HashMap::new(),
+                    turns: VecDeque::new(),
+                    wakers: Vec::new(),
+                }),
+                notify: Notify::new(),
+            }),
+        }
+    }
+}
+
+impl<K: Hash> RequestQueue<K> for FairQueue<K> {
+    fn admit(&self, key: &K) -> Option<QueueTicket> {
+        let key = self.hasher.hash_one(key);
+        let mut state = self.shared.lock();
+        let waiting_keys = state.keys.len() + usize::from(!state.keys.contains_key(&key));
+        let share = (self.max_waiting / (waiting_keys + 1)).max(1);
+        let waiting = state.keys.get(&key).map_or(0, VecDeque::len);
+        if state.total >= self.max_waiting || waiting >= share {
+            return None;
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.total += 1;
+        state.keys.entry(key).or_default().push_back(id);
+
+        Some(QueueTicket {
+            shared: self.shared.clone(),
+            key,
+            id,
+        })
+    }
+
+    fn max_wait(&self) -> std::time::Duration {
+        self.max_wait
+    }
+
+    fn poll_capacity(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.shared.lock();
+        if state.total < self.max_waiting {
+            return Poll::Ready(());
+        }
+
+        if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// [QueueTicket] is held while the request waits, and releases the capacity on drop.
+pub(crate) struct QueueTicket {
+    shared: Arc<Shared>,
+    key: u64,
+    id: u64,
+}
+
+impl QueueTicket {
+    /// Wait until the earlier waiters of the key have left.
+    pub async fn first(&self) {
+        self.wait_until(|state| state.keys.get(&self.key).and_then(VecDeque::front) == Some(&self.id)).await
+    }
+
+    /// Wait for the turn of the key to retry, after the keys which were ready before it.
+    /// The turn ends when the returned [QueueTurn] is dropped.
+    pub async fn turn(&self) -> QueueTurn<'_> {
+        self.shared.lock().turns.push_back(self.key);
+        let turn = QueueTurn {
+            ticket: self,
+        };
+        self.wait_until(|state| state.turns.front() == Some(&self.key)).await;
+        turn
+    }
+
+    async fn wait_until<F: Fn(&QueueState) -> bool>(&self, ready: F) {
+        loop {
+            // register before checking, so a notification in between is not missed.
+            let mut notified = std::pin::pin!(self.shared.notify.notified());
+            notified.as_mut().enable();
+            if ready(&self.shared.lock()) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.total -= 1;
+        state.wakers.drain(..).for_each(Waker::wake);
+        if let Some(tickets) = state.keys.get_mut(&self.key) {
+            tickets.retain(|id| *id != self.id);
+            if tickets.is_empty() {
+                state.keys.remove(&self.key);
+            }
+        }
+        drop(state);
+        self.shared.notify.notify_waiters();
+    }
+}
+
+/// [QueueTurn] is the turn of a key to retry, see [QueueTicket::turn].
+pub(crate) struct QueueTurn<'a> {
+    ticket: &'a QueueTicket,
+}
+
+impl Drop for QueueTurn<'_> {
+    fn drop(&mut self) {
+        let shared = &self.ticket.shared;
+        let mut state = shared.lock();
+        if let Some(position) = state.turns.iter().position(|key| *key == self.ticket.key) {
+            state.turns.remove(position);
+        }
+        drop(state);
+        shared.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fair_share() {
+        let queue = FairQueue::new(4, std::time::Duration::from_secs(1));
+
+        // a single key keeps a share for the next key...
+        let a: Vec<_> = (0..4).map_while(|_| queue.admit(&"a")).collect();
+        assert_eq!(a.len(), 2);
+        let b = queue.admit(&"b");
+        assert!(b.is_some());
+
+        // ...and no key holds more than its share when others wait.
+        assert!(queue.admit(&"a").is_none());
+        assert!(queue.admit(&"b").is_none());
+        let c = queue.admit(&"c");
+        assert!(c.is_some());
+
+        // the queue is full until a request leaves.
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(queue.poll_capacity(&mut cx).is_pending());
+        assert_eq!(queue.shared.lock().wakers.len(), 1);
+        drop(c);
+        assert!(queue.shared.lock().wakers.is_empty());
+        assert!(queue.poll_capacity(&mut cx).is_ready());
+
+        drop((a, b));
+        assert_eq!(queue.shared.lock().total, 0);
+        assert!(queue.shared.lock().keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn turns() {
+        let queue = FairQueue::new(8, std::time::Duration::from_secs(1));
+        let (a1, a2, b) = (queue.admit(&"a").unwrap(), queue.admit(&"a").unwrap(), queue.admit(&"b").unwrap());
+
+        // the waiters of a key go in FIFO order.
+        a1.first().await;
+        let mut a2_first = std::pin::pin!(a2.first());
+        assert!(futures_util::poll!(a2_first.as_mut()).is_pending());
+
+        // the keys take turns in the order they are ready.
+        let turn = a1.turn().await;
+        let mut b_turn = std::pin::pin!(b.turn());
+        assert!(futures_util::poll!(b_turn.as_mut()).is_pending());
+        drop(turn);
+        drop(b_turn.await);
+
+        drop(a1);
+        a2_first.await;
+    }
+}
@@ -0,0 +1,216 @@
+//! A handle on the [RateLimit]s of an app, such as one per scope, to list them and
+//! change their limits together.
+//!
+//! ```rust
+//! # use actix_web::{App, web};
+//! use actix_rl::controller::Controller;
+//! use actix_rl::middleware::RateLimit;
+//! use actix_rl::policy::Policy;
+//! use actix_rl::registry::LimiterRegistry;
+//!
+//! let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::minutes(1));
+//! let registry = LimiterRegistry::new();
+//! let api = RateLimit::new(store, 600, Controller::default().with_name("api"));
+//! let login = RateLimit::scoped(&api, "login", 5, actix_rl::time::Duration::minutes(10));
+//! let api = registry.register("api", api);
+//! let login = registry.register("login", login);
+//! App::new()
+//!     .service(web::scope("/api").wrap(api))
+//!     .service(web::scope("/login").wrap(login));
+//!
+//! // later, such as from an admin endpoint:
+//! registry.policies().insert("login", Policy::fixed_window(3, actix_rl::time::Duration::minutes(1))).unwrap();
+//! ```
+
+use std::sync::{Arc, PoisonError, RwLock};
+use actix_web::body::{BoxBody, MessageBody};
+use crate::degradation::{Degradation, DegradationStatus};
+use crate::middleware::RateLimit;
+use crate::policy_provider::PolicySet;
+use crate::self_test::SelfTestReport;
+use crate::store::{Store, Value};
+
+/// The registered middlewares, by name.
+type Limiters<T, CB> = Arc<RwLock<Vec<(String, RateLimit<T, CB>)>>>;
+
+/// [LimiterRegistry] holds the registered [RateLimit]s by name. Clones share the same registry.
+///
+/// The registered middlewares share:
+/// - a [PolicySet], where the policy of each name overrides its `max` and the window
+///   of the [Store] (see [RateLimit::with_policy_set]);
+/// - the [DegradationStatus], since they usually share the [Store].
+pub struct LimiterRegistry<T: Store, CB: MessageBody = BoxBody> {
+    limiters: Limiters<T, CB>,
+    policies: PolicySet,
+    degradation: Arc<Degradation>,
+}
+
+impl<T: Store, CB: MessageBody> Clone for LimiterRegistry<T, CB> {
+    fn clone(&self) -> Self {
+        Self {
+            limiters: self.limiters.clone(),
+            policies: self.policies.clone(),
+            degradation: self.degradation.clone(),
+        }
+    }
+}
+
+impl<T: Store, CB: MessageBody> Default for LimiterRegistry<T, CB> {
+    fn default() -> Self {
+        Self {
+            limiters: Default::default(),
+            policies: Default::default(),
+            degradation: Default::default(),
+        }
+    }
+}
+
+impl<T: Store, CB: MessageBody> LimiterRegistry<T, CB> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `rate_limit` as `name`, and return it to wrap the app or a scope.
+    /// Register the middleware after its other builders, since it is cloned.
+    ///
+    /// Panics if `name` is already registered.
+    pub fn register<N: ToString>(&self, name: N, rate_limit: RateLimit<T, CB>) -> RateLimit<T, CB>
+        where
+            <<T as Store>::Value as Value>::Count: TryFrom<u32>,
+            T::Count: TryFrom<u32>,
+    {
+        let name = name.to_string();
+        let mut limiters = self.limiters.write().unwrap_or_else(PoisonError::into_inner);
+        assert!(limiters.iter().all(|(registered, _)| *registered != name), "RateLimit {} is already registered", name);
+
+        let rate_limit = rate_limit
+            .with_policy_set(self.policies.clone(), &name)
+            .with_degradation(self.degradation.clone());
+        limiters.push((name, rate_limit.clone()));
+        rate_limit
+    }
+
+    /// Return the names of the registered middlewares, in the order of registration.
+    pub fn names(&self) -> Vec<String> {
+        self.limiters.read().unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Return the middleware registered as `name`.
+    pub fn get(&self, name: &str) -> Option<RateLimit<T, CB>> {
+        self.limiters.read().unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|(registered, _)| registered == name)
+            .map(|(_, rate_limit)| rate_limit.clone())
+    }
+
+    /// Return the shared policies: set the policy of a name to change the limits of its
+    /// middleware, see [PolicySet::insert].
+    pub fn policies(&self) -> &PolicySet {
+        &self.policies
+    }
+
+    /// Return whether the limits of the registered middlewares are enforced accurately.
+    pub fn degradation(&self) -> DegradationStatus {
+        self.degradation.status()
+    }
+
+    /// Freeze `key` in all registered middlewares, see [RateLimit::freeze].
+    pub fn freeze(&self, key: T::Key)
+        where T::Key: PartialEq,
+    {
+        for rate_limit in self.limiters() {
+            rate_limit.freeze(key.clone());
+        }
+    }
+
+    /// Unfreeze `key` in all registered middlewares, see [RateLimit::unfreeze].
+    pub fn unfreeze(&self, key: &T::Key)
+        where T::Key: PartialEq,
+    {
+        for rate_limit in self.limiters() {
+            rate_limit.unfreeze(key);
+        }
+    }
+
+    /// Run [RateLimit::self_test] for each registered middleware, by name.
+    pub async fn self_test(&self) -> Vec<(String, SelfTestReport)>
+        where T::Key: From<String>,
+    {
+        let mut reports = Vec::new();
+        for name in self.names() {
+            if let Some(rate_limit) = self.get(&name) {
+                reports.push((name, rate_limit.self_test().await));
+            }
+        }
+        reports
+    }
+
+    /// Clone the registered middlewares, so the lock is not held while using them.
+    fn limiters(&self) -> Vec<RateLimit<T, CB>> {
+        self.limiters.read().unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(_, rate_limit)| rate_limit.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+    use actix_web::http::StatusCode;
+    use crate::controller::Controller;
+    use crate::policy::Policy;
+    use crate::store::mem_store::MemStore;
+    use super::*;
+
+    #[tokio::test]
+    async fn registry() -> anyhow::Result<()> {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let registry = LimiterRegistry::new();
+        let api = RateLimit::new(store, 2, Controller::default().with_name("api"));
+        let login = RateLimit::scoped(&api, "login", 2, crate::time::Duration::seconds(10));
+        let api = registry.register("api", api);
+        let login = registry.register("login", login);
+        assert_eq!(registry.names(), vec!["api", "login"]);
+        assert!(registry.get("login").is_some());
+        assert!(registry.get("other").is_none());
+
+        let app = test::init_service(
+            App::new()
+                .service(web::scope("/api").wrap(api).default_service(web::to(HttpResponse::NoContent)))
+                .service(web::scope("/login").wrap(login).default_service(web::to(HttpResponse::NoContent)))
+        ).await;
+
+        // one policy changes the limit of its middleware only.
+        registry.policies().insert("login", Policy::fixed_window(1, crate::time::Duration::seconds(10)))?;
+        for (path, status) in [
+            ("/login", StatusCode::NO_CONTENT),
+            ("/login", StatusCode::TOO_MANY_REQUESTS),
+            ("/api", StatusCode::NO_CONTENT),
+            ("/api", StatusCode::NO_CONTENT),
+            ("/api", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+            assert_eq!(resp.status(), status, "{}", path);
+        }
+
+        assert!(!registry.degradation().is_degraded());
+        let reports = registry.self_test().await;
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|(_, report)| report.is_ok()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "already registered")]
+    async fn duplicate() {
+        let store = MemStore::new(1024, crate::time::Duration::seconds(10));
+        let registry = LimiterRegistry::new();
+        let _ = registry.register("api", RateLimit::new(store.clone(), 2, Controller::default()));
+        let _ = registry.register("api", RateLimit::new(store, 2, Controller::default()));
+    }
+}
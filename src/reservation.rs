@@ -0,0 +1,136 @@
+use crate::time::{DateTime, Utc};
+use crate::store::{GrantStore, Value};
+
+/// [Reservation] holds the quota charged for a long-running request, until it is
+/// committed (the charge stays) or cancelled (the charge is refunded), see
+/// [RateLimit::reserve](crate::middleware::RateLimit::reserve).
+///
+/// Dropping a [Reservation] without committing it cancels it in the background, so the
+/// quota of a request aborted early (such as when the client disconnects) is released.
+///
+/// The refund is a [Store::grant](crate::store::Store::grant) until the end of the window
+/// of the key, so only the stores with grants ([GrantStore]) can be used.
+///
+/// ```rust
+/// use actix_rl::reservation::Reservation;
+/// use actix_rl::store::mem_store::MemStore;
+///
+/// # #[tokio::main] async fn main() -> Result<(), ()> {
+/// let store = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let reservation = Reservation::reserve(store, "export:John".to_string(), 10).await?;
+/// // ... run the export ...
+/// reservation.commit();
+/// # Ok(())
+/// # }
+/// ```
+pub struct Reservation<T>
+    where
+        T: GrantStore + 'static,
+        T::Key: 'static,
+        T::Count: 'static,
+{
+    store: T,
+    /// [None] once committed or cancelled.
+    key: Option<T::Key>,
+    cost: T::Count,
+    value: T::Value,
+}
+
+impl<T> Reservation<T>
+    where
+        T: GrantStore + 'static,
+        T::Key: 'static,
+        T::Count: 'static,
+{
+    /// Charge `key` by `cost` in `store`, and hold the charge.
+    pub async fn reserve(store: T, key: T::Key, cost: T::Count) -> Result<Self, T::Error> {
+        let value = store.incr_by(key.clone(), cost.clone()).await?;
+        Ok(Self {
+            store,
+            key: Some(key),
+            cost,
+            value,
+        })
+    }
+
+    /// Return the value of the key after the charge, such as to check it against the max.
+    pub fn value(&self) -> &T::Value {
+        &self.value
+    }
+
+    /// Keep the charge.
+    pub fn commit(mut self) {
+        self.key = None;
+    }
+
+    /// Refund the charge, until the end of the window of the key.
+    pub async fn cancel(mut self) -> Result<(), T::Error> {
+        match self.key.take() {
+            Some(key) => refund(&self.store, key, self.cost.clone(), self.value.expire_date()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T> Drop for Reservation<T>
+    where
+        T: GrantStore + 'static,
+        T::Key: 'static,
+        T::Count: 'static,
+{
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+
+        // refund in the background, if in a tokio runtime.
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let (store, cost, until) = (self.store.clone(), self.cost.clone(), self.value.expire_date());
+            runtime.spawn(async move {
+                let _ = refund(&store, key, cost, until).await;
+            });
+        }
+    }
+}
+
+/// Refund `cost` to `key` until the end of its window.
+async fn refund<T: GrantStore>(store: &T, key: T::Key, cost: T::Count, until: Option<DateTime<Utc>>) -> Result<(), T::Error> {
+    let ttl = until.map(|until| until - Utc::now());
+    match ttl.filter(|ttl| *ttl > crate::time::Duration::zero()) {
+        Some(ttl) => store.grant(key, cost, ttl).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::mem_store::MemStore;
+    use crate::store::Store;
+    use super::*;
+
+    #[tokio::test]
+    async fn reservation() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(60));
+        let count = |key: &str| {
+            let store = store.clone();
+            let key = key.to_string();
+            async move { store.peek(key).await.unwrap().map(|value| value.count()) }
+        };
+
+        let reservation = Reservation::reserve(store.clone(), "John".to_string(), 3).await?;
+        assert_eq!(reservation.value().count(), 3);
+        reservation.commit();
+        assert_eq!(count("John").await, Some(3));
+
+        let reservation = Reservation::reserve(store.clone(), "Meg".to_string(), 3).await?;
+        reservation.cancel().await?;
+        assert_eq!(count("Meg").await, Some(0));
+
+        // dropping refunds in the background.
+        drop(Reservation::reserve(store.clone(), "Bob".to_string(), 3).await?);
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(count("Bob").await, Some(0));
+
+        Ok(())
+    }
+}
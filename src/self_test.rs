@@ -0,0 +1,106 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use crate::time::{DateTime, Utc};
+use crate::store::{Store, Value};
+
+/// The key counted by the self-test, see [RateLimit::self_test](crate::middleware::RateLimit::self_test).
+pub const SELF_TEST_KEY: &str = "actix-rl:self-test";
+
+/// The max difference between the dates of the [Store] and the local clock.
+pub const SELF_TEST_CLOCK_TOLERANCE: crate::time::Duration = crate::time::Duration::seconds(5);
+
+/// [SelfTestStep] is a step of [SelfTestReport].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SelfTestStep {
+    /// The name of the step: `incr`, `clock`, `peek` or `del`.
+    pub name: &'static str,
+    /// The time the step took.
+    pub elapsed: std::time::Duration,
+    /// Why the step failed, or [None] if it passed.
+    pub error: Option<String>,
+}
+
+/// [SelfTestReport] is the result of [RateLimit::self_test](crate::middleware::RateLimit::self_test).
+///
+/// It displays one line per step, such as `incr: ok (2ms)` or `clock: failed (0ms): ...`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SelfTestReport {
+    pub steps: Vec<SelfTestStep>,
+}
+
+impl SelfTestReport {
+    /// Check if all steps passed.
+    pub fn is_ok(&self) -> bool {
+        self.steps.iter().all(|step| step.error.is_none())
+    }
+
+    /// Return the first failed step.
+    pub fn first_error(&self) -> Option<&SelfTestStep> {
+        self.steps.iter().find(|step| step.error.is_some())
+    }
+
+    /// Run `step` within `timeout`, and record it.
+    async fn step<R>(
+        &mut self,
+        name: &'static str,
+        timeout: Option<std::time::Duration>,
+        step: impl Future<Output = Result<R, String>>,
+    ) -> Option<R> {
+        let start = std::time::Instant::now();
+        let result = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, step).await
+                .unwrap_or_else(|_| Err(format!("timed out after {:?}", timeout))),
+            None => step.await,
+        };
+        self.steps.push(SelfTestStep {
+            name,
+            elapsed: start.elapsed(),
+            error: result.as_ref().err().cloned(),
+        });
+        result.ok()
+    }
+}
+
+impl Display for SelfTestReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for step in &self.steps {
+            match &step.error {
+                None => writeln!(f, "{}: ok ({}ms)", step.name, step.elapsed.as_millis())?,
+                Some(e) => writeln!(f, "{}: failed ({}ms): {}", step.name, step.elapsed.as_millis(), e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Count `key` in `store`, check the dates of its value against the local clock,
+/// read it back and delete it. Each call is bounded by `timeout`.
+pub(crate) async fn run<T: Store>(store: &T, key: T::Key, timeout: Option<std::time::Duration>) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+
+    let debug = |e: T::Error| format!("{:?}", e);
+
+    let value = report.step("incr", timeout, async { store.incr(key.clone()).await.map_err(debug) }).await;
+    if let Some(value) = value {
+        report.step("clock", None, async { check_clock(&value, Utc::now()) }).await;
+    }
+    report.step("peek", timeout, async { store.peek(key.clone()).await.map_err(debug) }).await;
+    report.step("del", timeout, async { store.del(key).await.map_err(debug) }).await;
+
+    report
+}
+
+/// Check that the window of `value` contains `now`, within [SELF_TEST_CLOCK_TOLERANCE].
+fn check_clock<V: Value>(value: &V, now: DateTime<Utc>) -> Result<(), String> {
+    if let Some(create_date) = value.create_date() {
+        if create_date - now > SELF_TEST_CLOCK_TOLERANCE {
+            return Err(format!("the window starts at {}, after the local time {}", create_date, now));
+        }
+    }
+    if let Some(expire_date) = value.expire_date() {
+        if now - expire_date > SELF_TEST_CLOCK_TOLERANCE {
+            return Err(format!("the window ends at {}, before the local time {}", expire_date, now));
+        }
+    }
+    Ok(())
+}
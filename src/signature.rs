@@ -0,0 +1,200 @@
+use actix_web::HttpRequest;
+
+/// [SignatureVerifier] checks the signature of a request before it is counted,
+/// see [RateLimit::with_signature_check](crate::middleware::RateLimit::with_signature_check).
+///
+/// It is implemented for functions and closures, such as
+/// `|req: &HttpRequest| req.headers().contains_key("X-Signature")`.
+pub trait SignatureVerifier: Send + Sync {
+    /// Return true if the signature of `req` is valid.
+    fn verify(&self, req: &HttpRequest) -> bool;
+}
+
+impl<F> SignatureVerifier for F
+    where F: Fn(&HttpRequest) -> bool + Send + Sync,
+{
+    fn verify(&self, req: &HttpRequest) -> bool {
+        self(req)
+    }
+}
+
+/// The default header of [HmacSignature].
+#[cfg(feature = "hmac")]
+pub const DEFAULT_SIGNATURE_HEADER: &str = "X-Signature";
+
+/// [HmacSignature] verifies the HMAC-SHA256 signature of a request, in lowercase hex,
+/// sent in [DEFAULT_SIGNATURE_HEADER] (see [Self::with_header]).
+///
+/// The signed message is the method and the path with the query, then the values of
+/// the signed headers (see [Self::with_signed_header]), separated by `\n`, such as
+/// `"POST\n/orders?dry=1\n1700000000"`. The body is not signed, since it is not read
+/// by the middleware.
+///
+/// Without a timestamp, a captured signature can be replayed as long as the secret is used:
+/// sign a timestamp with [Self::with_timestamp_header] to reject the old ones.
+///
+/// ```rust
+/// use actix_rl::signature::HmacSignature;
+///
+/// let signature = HmacSignature::new(b"secret")
+///     .with_timestamp_header("X-Timestamp", actix_rl::time::Duration::minutes(5));
+/// let rate_limit = actix_rl::middleware::RateLimit::new(
+///     actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::minutes(1)),
+///     100,
+///     actix_rl::controller::Controller::default(),
+/// ).with_signature_check(signature);
+/// ```
+#[cfg(feature = "hmac")]
+#[derive(Debug, Clone)]
+pub struct HmacSignature {
+    secret: Vec<u8>,
+    header: String,
+    signed_headers: Vec<String>,
+    /// The header of the unix timestamp (in seconds) of the signatures, and their max age.
+    timestamp: Option<(String, crate::time::Duration)>,
+}
+
+#[cfg(feature = "hmac")]
+impl HmacSignature {
+    pub fn new<S: AsRef<[u8]>>(secret: S) -> Self {
+        Self {
+            secret: secret.as_ref().to_vec(),
+            header: DEFAULT_SIGNATURE_HEADER.to_string(),
+            signed_headers: Vec::new(),
+            timestamp: None,
+        }
+    }
+
+    /// Read the signature from `header`.
+    pub fn with_header<H: ToString>(mut self, header: H) -> Self {
+        self.header = header.to_string();
+        self
+    }
+
+    /// Append the value of `header` to the signed message, such as a timestamp or a nonce.
+    /// A missing header is signed as an empty string.
+    pub fn with_signed_header<H: ToString>(mut self, header: H) -> Self {
+        self.signed_headers.push(header.to_string());
+        self
+    }
+
+    /// Sign the unix timestamp (in seconds) in `header`, and reject the requests whose timestamp
+    /// is missing or more than `max_age` away from now, so the captured signatures cannot be
+    /// replayed later.
+    pub fn with_timestamp_header<H: ToString>(mut self, header: H, max_age: crate::time::Duration) -> Self {
+        self.signed_headers.push(header.to_string());
+        self.timestamp = Some((header.to_string(), max_age));
+        self
+    }
+
+    fn hmac(&self, req: &HttpRequest) -> hmac::Hmac<sha2::Sha256> {
+        use hmac::Mac;
+
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(req.method().as_str().as_bytes());
+        mac.update(b"\n");
+        mac.update(req.uri().path_and_query().map_or(req.path(), |path| path.as_str()).as_bytes());
+        for header in &self.signed_headers {
+            mac.update(b"\n");
+            mac.update(req.headers().get(header).map_or(&b""[..], |value| value.as_bytes()));
+        }
+        mac
+    }
+
+    /// Return the signature of `req`, in lowercase hex.
+    pub fn sign(&self, req: &HttpRequest) -> String {
+        use hmac::Mac;
+
+        self.hmac(req).finalize().into_bytes().iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+#[cfg(feature = "hmac")]
+impl SignatureVerifier for HmacSignature {
+    fn verify(&self, req: &HttpRequest) -> bool {
+        use hmac::Mac;
+
+        let Some(signature) = req.headers().get(&self.header) else {
+            return false;
+        };
+        let signature = signature.as_bytes();
+        if signature.len() % 2 != 0 || !signature.iter().all(u8::is_ascii_hexdigit) {
+            return false;
+        }
+        let signature: Vec<u8> = signature.chunks(2)
+            .filter_map(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+            .collect();
+
+        if let Some((header, max_age)) = &self.timestamp {
+            let timestamp = req.headers().get(header)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<i64>().ok());
+            let fresh = timestamp.is_some_and(|timestamp| {
+                (crate::time::Utc::now().timestamp() - timestamp).abs() <= max_age.num_seconds()
+            });
+            if !fresh {
+                return false;
+            }
+        }
+
+        // compared in constant time.
+        self.hmac(req).verify_slice(&signature).is_ok()
+    }
+}
+
+#[cfg(all(test, feature = "hmac"))]
+mod tests {
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    #[test]
+    fn hmac_signature_timestamp() {
+        let signature = HmacSignature::new(b"key")
+            .with_timestamp_header("X-Timestamp", crate::time::Duration::minutes(5));
+        let signed = |timestamp: i64| {
+            let request = |value: &str| TestRequest::get()
+                .uri("/orders")
+                .insert_header(("X-Timestamp", timestamp.to_string()))
+                .insert_header((DEFAULT_SIGNATURE_HEADER, value))
+                .to_http_request();
+            request(&signature.sign(&request("")))
+        };
+
+        let now = crate::time::Utc::now().timestamp();
+        assert!(signature.verify(&signed(now)));
+        assert!(signature.verify(&signed(now - 60)));
+        // replayed after the max age, or signed far in the future.
+        assert!(!signature.verify(&signed(now - 3600)));
+        assert!(!signature.verify(&signed(now + 3600)));
+
+        // the timestamp is signed.
+        let request = signed(now - 3600);
+        let replayed = TestRequest::get()
+            .uri("/orders")
+            .insert_header(("X-Timestamp", now.to_string()))
+            .insert_header((DEFAULT_SIGNATURE_HEADER, request.headers().get(DEFAULT_SIGNATURE_HEADER).unwrap().clone()))
+            .to_http_request();
+        assert!(!signature.verify(&replayed));
+    }
+
+    #[test]
+    fn hmac_signature() {
+        let signature = HmacSignature::new(b"key").with_signed_header("X-Timestamp");
+        let request = |value: &str| TestRequest::post()
+            .uri("/orders?dry=1")
+            .insert_header(("X-Timestamp", "1700000000"))
+            .insert_header((DEFAULT_SIGNATURE_HEADER, value))
+            .to_http_request();
+
+        let expected = signature.sign(&request(""));
+        assert_eq!(expected.len(), 64);
+        assert!(signature.verify(&request(&expected)));
+        assert!(signature.verify(&request(&expected.to_ascii_uppercase())));
+        assert!(!signature.verify(&request(&expected[1..])));
+        assert!(!HmacSignature::new(b"other").verify(&request(&expected)));
+        assert!(!signature.verify(&TestRequest::post().uri("/orders?dry=1").to_http_request()));
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::time::{DateTime, Utc};
+
+/// [AtomicWindow] counts hits of fixed windows in one [AtomicU64], without locks.
+///
+/// The high 32 bits are the epoch (the index of the window), and the low 32 bits
+/// are the count, so the count resets atomically when a new window starts.
+#[derive(Debug, Default)]
+pub(crate) struct AtomicWindow(AtomicU64);
+
+impl AtomicWindow {
+    /// Increase the count by `val` in the window `epoch`, return the new count.
+    pub fn incr(&self, epoch: u32, val: u32) -> u32 {
+        let prev = self.0.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            let count = if (current >> 32) as u32 == epoch { current as u32 } else { 0 };
+            Some(pack(epoch, count.saturating_add(val)))
+        }).unwrap_or_default();
+
+        let count = if (prev >> 32) as u32 == epoch { prev as u32 } else { 0 };
+        count.saturating_add(val)
+    }
+
+    /// Decrease the count by `val` (not below 0) in the window `epoch`.
+    pub fn decr(&self, epoch: u32, val: u32) {
+        let _ = self.0.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            ((current >> 32) as u32 == epoch).then(|| pack(epoch, (current as u32).saturating_sub(val)))
+        });
+    }
+
+    /// Return the count in the window `epoch`.
+    pub fn get(&self, epoch: u32) -> u32 {
+        let current = self.0.load(Ordering::Acquire);
+        if (current >> 32) as u32 == epoch { current as u32 } else { 0 }
+    }
+
+    /// Reset the count.
+    pub fn reset(&self) {
+        self.0.store(0, Ordering::Release);
+    }
+}
+
+// copy the current count, for the stores configured after being cloned.
+impl Clone for AtomicWindow {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.0.load(Ordering::Acquire)))
+    }
+}
+
+fn pack(epoch: u32, count: u32) -> u64 {
+    ((epoch as u64) << 32) | count as u64
+}
+
+/// Return the epoch and the start of the window of `ttl` containing `now`,
+/// with windows aligned to the unix epoch.
+pub(crate) fn window_epoch(now: DateTime<Utc>, ttl: crate::time::Duration) -> (u32, DateTime<Utc>) {
+    let ttl = ttl.num_milliseconds().max(1);
+    let index = now.timestamp_millis().div_euclid(ttl);
+    let start = DateTime::from_timestamp_millis(index * ttl).unwrap_or(now);
+    (index as u32, start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atomic_window() {
+        let window = AtomicWindow::default();
+        assert_eq!(window.incr(7, 1), 1);
+        assert_eq!(window.incr(7, 2), 3);
+        assert_eq!(window.get(7), 3);
+        window.decr(7, 1);
+        assert_eq!(window.get(7), 2);
+        window.decr(7, 5);
+        assert_eq!(window.get(7), 0);
+        window.incr(7, 3);
+
+        // a new window starts
+        assert_eq!(window.get(8), 0);
+        assert_eq!(window.incr(8, 1), 1);
+
+        window.reset();
+        assert_eq!(window.get(8), 0);
+    }
+}
@@ -0,0 +1,283 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::time::{DateTime, Utc};
+use crate::error::{ChaosError, ErrorClass};
+use crate::store::{GrantStore, Metadata, Store, StoreStats, Value};
+
+/// [ChaosStore] wraps a [Store] and injects latency, random errors and clock skew
+/// into its calls, so the [FailurePolicy](crate::controller::FailurePolicy),
+/// the store timeouts and the alerts can be rehearsed before the real store misbehaves.
+///
+/// Each call waits for a random latency between the bounds of [Self::with_latency],
+/// then fails with [ChaosError::Injected] at the rate of [Self::with_error_rate]
+/// (classified as [ErrorClass::Transient]). The dates of the returned values are shifted
+/// by [Self::with_clock_skew], as if the clock of the store were off.
+///
+/// The random numbers follow [Self::with_seed], so a failing scenario can be replayed.
+/// Clones share the random sequence.
+///
+/// ```rust
+/// use actix_rl::store::chaos_store::ChaosStore;
+/// use actix_rl::store::mem_store::MemStore;
+///
+/// let store = ChaosStore::new(MemStore::new(1024, actix_rl::time::Duration::seconds(60)))
+///     .with_latency(std::time::Duration::from_millis(5), std::time::Duration::from_millis(50))
+///     .with_error_rate(0.1)
+///     .with_clock_skew(actix_rl::time::Duration::seconds(2))
+///     .with_seed(42);
+/// ```
+#[derive(Clone)]
+pub struct ChaosStore<T: Store> {
+    inner: T,
+    latency: (std::time::Duration, std::time::Duration),
+    error_rate: f64,
+    skew: crate::time::Duration,
+    seed: Arc<AtomicU64>,
+}
+
+impl<T: Store> ChaosStore<T> {
+    /// create from the `inner` store, without any chaos.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            latency: (std::time::Duration::ZERO, std::time::Duration::ZERO),
+            error_rate: 0.0,
+            skew: crate::time::Duration::zero(),
+            seed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Wait between `min` and `max` (uniformly) before each call.
+    pub fn with_latency(mut self, min: std::time::Duration, max: std::time::Duration) -> Self {
+        self.latency = (min, max.max(min));
+        self
+    }
+
+    /// Fail each call with a probability of `rate`, between 0 and 1.
+    pub fn with_error_rate(mut self, rate: f64) -> Self {
+        self.error_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Shift the dates of the returned values by `skew`.
+    pub fn with_clock_skew(mut self, skew: crate::time::Duration) -> Self {
+        self.skew = skew;
+        self
+    }
+
+    /// Set the seed of the random sequence, default to 0.
+    pub fn with_seed(self, seed: u64) -> Self {
+        self.seed.store(seed, Ordering::Relaxed);
+        self
+    }
+
+    /// Return the wrapped store.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Return a random number in `[0, 1)`, by SplitMix64.
+    fn random(&self) -> f64 {
+        let mut z = self.seed.fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed).wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Wait for the latency, and fail at the error rate.
+    async fn chaos(&self) -> Result<(), ChaosError<T::Error>> {
+        let (min, max) = self.latency;
+        if !max.is_zero() {
+            tokio::time::sleep(min + (max - min).mul_f64(self.random())).await;
+        }
+        if self.error_rate > 0.0 && self.random() < self.error_rate {
+            return Err(ChaosError::Injected);
+        }
+        Ok(())
+    }
+
+    fn skewed(&self, value: T::Value) -> ChaosValue<T::Value> {
+        ChaosValue {
+            value,
+            skew: self.skew,
+        }
+    }
+}
+
+/// [ChaosValue] is a value of [ChaosStore], with its dates shifted by the clock skew.
+#[derive(Debug, Clone)]
+pub struct ChaosValue<V> {
+    pub value: V,
+    pub skew: crate::time::Duration,
+}
+
+impl<V: Value> Value for ChaosValue<V> {
+    type Count = V::Count;
+
+    fn count(&self) -> Self::Count {
+        self.value.count()
+    }
+
+    fn create_date(&self) -> Option<DateTime<Utc>> {
+        self.value.create_date().map(|date| date + self.skew)
+    }
+
+    fn expire_date(&self) -> Option<DateTime<Utc>> {
+        self.value.expire_date().map(|date| date + self.skew)
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        self.value.metadata()
+    }
+
+    fn is_charged(&self) -> bool {
+        self.value.is_charged()
+    }
+}
+
+#[async_trait::async_trait]
+impl<T> Store for ChaosStore<T>
+    where
+        T: Store,
+        T::Error: Send,
+{
+    type Error = ChaosError<T::Error>;
+    type Key = T::Key;
+    type Value = ChaosValue<T::Value>;
+    type Count = T::Count;
+
+    async fn incr_by(&self, key: Self::Key, val: Self::Count) -> Result<Self::Value, Self::Error> {
+        self.chaos().await?;
+        Ok(self.skewed(self.inner.incr_by(key, val).await?))
+    }
+
+    async fn incr(&self, key: Self::Key) -> Result<Self::Value, Self::Error> {
+        self.chaos().await?;
+        Ok(self.skewed(self.inner.incr(key).await?))
+    }
+
+    async fn incr_with_ttl(&self, key: Self::Key, val: Self::Count, ttl: crate::time::Duration) -> Result<Self::Value, Self::Error> {
+        self.chaos().await?;
+        Ok(self.skewed(self.inner.incr_with_ttl(key, val, ttl).await?))
+    }
+
+    async fn incr_many(&self, charges: Vec<(Self::Key, Self::Count)>) -> Result<Vec<Self::Value>, Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.incr_many(charges).await?
+            .into_iter()
+            .map(|value| self.skewed(value))
+            .collect())
+    }
+
+    async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.del(key).await?.map(|value| self.skewed(value)))
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.clear().await?)
+    }
+
+    async fn del_prefix(&self, prefix: &str) -> Result<Option<usize>, Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.del_prefix(prefix).await?)
+    }
+
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.peek(key).await?.map(|value| self.skewed(value)))
+    }
+
+    async fn ttl(&self, key: Self::Key) -> Result<Option<crate::time::Duration>, Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.ttl(key).await?.map(|ttl| (ttl + self.skew).max(crate::time::Duration::zero())))
+    }
+
+    async fn snapshot(&self) -> Result<Vec<(Self::Key, Self::Value)>, Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.snapshot().await?
+            .into_iter()
+            .map(|(key, value)| (key, self.skewed(value)))
+            .collect())
+    }
+
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.stats().await?)
+    }
+
+    async fn set_metadata(&self, key: Self::Key, metadata: Metadata, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.set_metadata(key, metadata, ttl).await?)
+    }
+
+    async fn grant(&self, key: Self::Key, extra: Self::Count, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        self.chaos().await?;
+        Ok(self.inner.grant(key, extra, ttl).await?)
+    }
+
+    fn classify_error(&self, error: &Self::Error) -> ErrorClass {
+        match error {
+            ChaosError::Injected => ErrorClass::Transient,
+            ChaosError::Store(e) => self.inner.classify_error(e),
+        }
+    }
+
+    fn window(&self) -> Option<crate::time::Duration> {
+        self.inner.window()
+    }
+
+    fn supports_ttl(&self) -> bool {
+        self.inner.supports_ttl()
+    }
+}
+
+impl<T> GrantStore for ChaosStore<T>
+    where
+        T: GrantStore,
+        T::Error: Send,
+{}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::mem_store::MemStore;
+    use super::*;
+
+    #[tokio::test]
+    async fn chaos() -> Result<(), ChaosError<()>> {
+        let mem = MemStore::new(8, crate::time::Duration::seconds(100));
+
+        // no chaos by default.
+        let store = ChaosStore::new(mem.clone());
+        assert_eq!(store.incr("John".to_string()).await?.count(), 1);
+
+        // the errors follow the rate, and the seed.
+        let store = ChaosStore::new(mem.clone()).with_error_rate(0.5).with_seed(7);
+        let mut results = Vec::new();
+        for _ in 0..200 {
+            results.push(store.incr("Meg".to_string()).await.is_ok());
+        }
+        let ok = results.iter().filter(|ok| **ok).count();
+        assert!((60..140).contains(&ok), "{}", ok);
+        assert_eq!(mem.peek("Meg".to_string()).await.unwrap().unwrap().count(), ok as u32);
+        assert_eq!(store.classify_error(&ChaosError::Injected), ErrorClass::Transient);
+
+        let replay = ChaosStore::new(mem.clone()).with_error_rate(0.5).with_seed(7);
+        for ok in results.into_iter().take(20) {
+            assert_eq!(replay.peek("Meg".to_string()).await.is_ok(), ok);
+        }
+
+        // the latency and the clock skew.
+        let store = ChaosStore::new(mem.clone())
+            .with_latency(std::time::Duration::from_millis(20), std::time::Duration::from_millis(30))
+            .with_clock_skew(crate::time::Duration::seconds(-5));
+        let start = tokio::time::Instant::now();
+        let value = store.incr("Bob".to_string()).await?;
+        assert!(start.elapsed() >= std::time::Duration::from_millis(20));
+        assert_eq!(value.expire_date(), Some(value.value.until - crate::time::Duration::seconds(5)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use crate::time::{DateTime, Utc};
+
+/// [Clock] is the time source of the window math of a [Store](crate::store::Store).
+pub trait Clock: Debug + Send + Sync {
+    /// Return the current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// [SystemClock] reads the system time on every call. It is the default [Clock].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// [CoarseClock] caches the system time, which is updated every `resolution`
+/// by a background task, so reading the time costs an atomic load instead of a syscall.
+/// The time lags behind the system time by at most `resolution` (plus scheduling delays).
+///
+/// The background task stops when all clones of the clock are dropped.
+#[derive(Debug, Clone)]
+pub struct CoarseClock {
+    millis: Arc<AtomicI64>,
+}
+
+impl CoarseClock {
+    /// Create the clock and spawn its background task, updating every `resolution`.
+    ///
+    /// Must be called inside a tokio runtime.
+    pub fn new(resolution: std::time::Duration) -> Self {
+        let millis = Arc::new(AtomicI64::new(Utc::now().timestamp_millis()));
+        let weak = Arc::downgrade(&millis);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(resolution);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                interval.tick().await;
+                match weak.upgrade() {
+                    Some(millis) => millis.store(Utc::now().timestamp_millis(), Ordering::Relaxed),
+                    None => break,
+                }
+            }
+        });
+
+        Self {
+            millis,
+        }
+    }
+}
+
+impl Clock for CoarseClock {
+    fn now(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.millis.load(Ordering::Relaxed))
+            .unwrap_or_else(Utc::now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn coarse_clock() {
+        let clock = CoarseClock::new(std::time::Duration::from_millis(5));
+        let start = clock.now();
+        assert!((Utc::now() - start).num_milliseconds() < 50);
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(clock.now() > start);
+        assert!((Utc::now() - clock.now()).num_milliseconds() < 50);
+    }
+}
@@ -0,0 +1,355 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::time::{DateTime, Utc};
+use futures_util::future::join;
+use crate::error::{DualWriteError, ErrorClass};
+use crate::store::{GrantStore, Metadata, Store, StoreStats, Value};
+
+/// [Primary] is the store of [DualWriteStore] whose results are returned.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Primary {
+    A,
+    B,
+}
+
+/// [Divergence] counts the writes of [DualWriteStore] whose results differ
+/// between the stores, see [DualWriteStore::divergence].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Divergence {
+    /// The number of increments run on both stores.
+    pub writes: u64,
+    /// The number of increments whose counts differ between the stores.
+    pub diverged: u64,
+    /// The number of writes failed on the secondary store.
+    pub secondary_errors: u64,
+}
+
+/// [DualWriteStore] writes to both stores `A` and `B`, and reads from the [Primary] one,
+/// for a gradual cutover between two backends (such as two redis clusters):
+/// write to both until the counters of the new store are warm, compare them with
+/// [Self::divergence], switch the primary with [Self::set_primary], then drop the old store.
+///
+/// The writes run concurrently on both stores and return the result of the primary;
+/// errors of the secondary are only counted. The reads ([Store::peek], [Store::snapshot]…)
+/// only use the primary. Both stores must use the same keys and counts.
+///
+/// ```rust
+/// use actix_rl::store::dual_write_store::{DualWriteStore, Primary};
+/// use actix_rl::store::mem_store::MemStore;
+///
+/// let old = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let new = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let store = DualWriteStore::new(old, new);
+/// // ... later, once the divergence is low:
+/// store.set_primary(Primary::B);
+/// ```
+#[derive(Clone)]
+pub struct DualWriteStore<A: Store, B: Store> {
+    a: A,
+    b: B,
+    /// `true` if [Primary::B].
+    primary_b: Arc<AtomicBool>,
+    counters: Arc<DivergenceCounters>,
+}
+
+#[derive(Debug, Default)]
+struct DivergenceCounters {
+    writes: AtomicU64,
+    diverged: AtomicU64,
+    secondary_errors: AtomicU64,
+}
+
+impl<A: Store, B: Store> DualWriteStore<A, B> {
+    /// create from the stores `a` and `b`, reading from `a`.
+    pub fn new(a: A, b: B) -> Self {
+        Self {
+            a,
+            b,
+            primary_b: Arc::new(AtomicBool::new(false)),
+            counters: Arc::new(DivergenceCounters::default()),
+        }
+    }
+
+    /// Read from `primary`, default to [Primary::A].
+    pub fn with_primary(self, primary: Primary) -> Self {
+        self.set_primary(primary);
+        self
+    }
+
+    /// Switch the primary store. Clones share the primary.
+    pub fn set_primary(&self, primary: Primary) {
+        self.primary_b.store(primary == Primary::B, Ordering::Relaxed);
+    }
+
+    pub fn primary(&self) -> Primary {
+        match self.primary_b.load(Ordering::Relaxed) {
+            true => Primary::B,
+            false => Primary::A,
+        }
+    }
+
+    /// Return the counts of the writes which differ between the stores, such as
+    /// to export as metrics. Clones share the counts.
+    pub fn divergence(&self) -> Divergence {
+        Divergence {
+            writes: self.counters.writes.load(Ordering::Relaxed),
+            diverged: self.counters.diverged.load(Ordering::Relaxed),
+            secondary_errors: self.counters.secondary_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn a(&self) -> &A {
+        &self.a
+    }
+
+    pub fn b(&self) -> &B {
+        &self.b
+    }
+
+    /// Return the result of the primary, counting the errors of the secondary.
+    fn pick<TA, TB>(&self, a: Result<TA, A::Error>, b: Result<TB, B::Error>) -> Result<DualValue<TA, TB>, DualWriteError<A::Error, B::Error>> {
+        let secondary_failed = match self.primary() {
+            Primary::A => b.is_err(),
+            Primary::B => a.is_err(),
+        };
+        if secondary_failed {
+            self.counters.secondary_errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match self.primary() {
+            Primary::A => a.map(DualValue::A).map_err(DualWriteError::A),
+            Primary::B => b.map(DualValue::B).map_err(DualWriteError::B),
+        }
+    }
+
+    /// Count an increment, and whether its counts differ.
+    fn compare<'a, VA, VB>(&self, a: impl IntoIterator<Item = &'a VA>, b: impl IntoIterator<Item = &'a VB>)
+        where
+            VA: Value + 'a,
+            VB: Value<Count = VA::Count> + 'a,
+    {
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        let mut b = b.into_iter();
+        if a.into_iter().any(|a| b.next().is_none_or(|b| a.count() != b.count())) {
+            self.counters.diverged.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// [DualValue] is the value of the primary store of [DualWriteStore].
+#[derive(Debug, Clone)]
+pub enum DualValue<VA, VB> {
+    A(VA),
+    B(VB),
+}
+
+impl<VA, VB> Value for DualValue<VA, VB>
+    where
+        VA: Value,
+        VB: Value<Count = VA::Count>,
+{
+    type Count = VA::Count;
+
+    fn count(&self) -> Self::Count {
+        match self {
+            DualValue::A(value) => value.count(),
+            DualValue::B(value) => value.count(),
+        }
+    }
+
+    fn create_date(&self) -> Option<DateTime<Utc>> {
+        match self {
+            DualValue::A(value) => value.create_date(),
+            DualValue::B(value) => value.create_date(),
+        }
+    }
+
+    fn expire_date(&self) -> Option<DateTime<Utc>> {
+        match self {
+            DualValue::A(value) => value.expire_date(),
+            DualValue::B(value) => value.expire_date(),
+        }
+    }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        match self {
+            DualValue::A(value) => value.metadata(),
+            DualValue::B(value) => value.metadata(),
+        }
+    }
+
+    fn is_charged(&self) -> bool {
+        match self {
+            DualValue::A(value) => value.is_charged(),
+            DualValue::B(value) => value.is_charged(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<A, B> Store for DualWriteStore<A, B>
+    where
+        A: Store,
+        A::Error: Send,
+        B: Store<Key = A::Key, Count = A::Count>,
+        B::Error: Send,
+        B::Value: Value<Count = <A::Value as Value>::Count>,
+{
+    type Error = DualWriteError<A::Error, B::Error>;
+    type Key = A::Key;
+    type Value = DualValue<A::Value, B::Value>;
+    type Count = A::Count;
+
+    async fn incr_by(&self, key: Self::Key, val: Self::Count) -> Result<Self::Value, Self::Error> {
+        let (a, b) = join(self.a.incr_by(key.clone(), val.clone()), self.b.incr_by(key, val)).await;
+        if let (Ok(a), Ok(b)) = (&a, &b) {
+            self.compare([a], [b]);
+        }
+        self.pick(a, b)
+    }
+
+    async fn incr(&self, key: Self::Key) -> Result<Self::Value, Self::Error> {
+        let (a, b) = join(self.a.incr(key.clone()), self.b.incr(key)).await;
+        if let (Ok(a), Ok(b)) = (&a, &b) {
+            self.compare([a], [b]);
+        }
+        self.pick(a, b)
+    }
+
+    async fn incr_with_ttl(&self, key: Self::Key, val: Self::Count, ttl: crate::time::Duration) -> Result<Self::Value, Self::Error> {
+        let (a, b) = join(self.a.incr_with_ttl(key.clone(), val.clone(), ttl), self.b.incr_with_ttl(key, val, ttl)).await;
+        if let (Ok(a), Ok(b)) = (&a, &b) {
+            self.compare([a], [b]);
+        }
+        self.pick(a, b)
+    }
+
+    async fn incr_many(&self, charges: Vec<(Self::Key, Self::Count)>) -> Result<Vec<Self::Value>, Self::Error> {
+        let (a, b) = join(self.a.incr_many(charges.clone()), self.b.incr_many(charges)).await;
+        if let (Ok(a), Ok(b)) = (&a, &b) {
+            self.compare(a, b);
+        }
+        Ok(match self.pick(a, b)? {
+            DualValue::A(values) => values.into_iter().map(DualValue::A).collect(),
+            DualValue::B(values) => values.into_iter().map(DualValue::B).collect(),
+        })
+    }
+
+    async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        let (a, b) = join(self.a.del(key.clone()), self.b.del(key)).await;
+        Ok(match self.pick(a, b)? {
+            DualValue::A(value) => value.map(DualValue::A),
+            DualValue::B(value) => value.map(DualValue::B),
+        })
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let (a, b) = join(self.a.clear(), self.b.clear()).await;
+        self.pick(a, b).map(|_| ())
+    }
+
+    async fn del_prefix(&self, prefix: &str) -> Result<Option<usize>, Self::Error> {
+        let (a, b) = join(self.a.del_prefix(prefix), self.b.del_prefix(prefix)).await;
+        Ok(match self.pick(a, b)? {
+            DualValue::A(deleted) | DualValue::B(deleted) => deleted,
+        })
+    }
+
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        match self.primary() {
+            Primary::A => self.a.peek(key).await.map(|value| value.map(DualValue::A)).map_err(DualWriteError::A),
+            Primary::B => self.b.peek(key).await.map(|value| value.map(DualValue::B)).map_err(DualWriteError::B),
+        }
+    }
+
+    async fn ttl(&self, key: Self::Key) -> Result<Option<crate::time::Duration>, Self::Error> {
+        match self.primary() {
+            Primary::A => self.a.ttl(key).await.map_err(DualWriteError::A),
+            Primary::B => self.b.ttl(key).await.map_err(DualWriteError::B),
+        }
+    }
+
+    async fn snapshot(&self) -> Result<Vec<(Self::Key, Self::Value)>, Self::Error> {
+        match self.primary() {
+            Primary::A => Ok(self.a.snapshot().await.map_err(DualWriteError::A)?
+                .into_iter()
+                .map(|(key, value)| (key, DualValue::A(value)))
+                .collect()),
+            Primary::B => Ok(self.b.snapshot().await.map_err(DualWriteError::B)?
+                .into_iter()
+                .map(|(key, value)| (key, DualValue::B(value)))
+                .collect()),
+        }
+    }
+
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        match self.primary() {
+            Primary::A => self.a.stats().await.map_err(DualWriteError::A),
+            Primary::B => self.b.stats().await.map_err(DualWriteError::B),
+        }
+    }
+
+    async fn set_metadata(&self, key: Self::Key, metadata: Metadata, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        let (a, b) = join(self.a.set_metadata(key.clone(), metadata.clone(), ttl), self.b.set_metadata(key, metadata, ttl)).await;
+        self.pick(a, b).map(|_| ())
+    }
+
+    async fn grant(&self, key: Self::Key, extra: Self::Count, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        let (a, b) = join(self.a.grant(key.clone(), extra.clone(), ttl), self.b.grant(key, extra, ttl)).await;
+        self.pick(a, b).map(|_| ())
+    }
+
+    fn classify_error(&self, error: &Self::Error) -> ErrorClass {
+        match error {
+            DualWriteError::A(e) => self.a.classify_error(e),
+            DualWriteError::B(e) => self.b.classify_error(e),
+        }
+    }
+
+    fn window(&self) -> Option<crate::time::Duration> {
+        self.a.window().or(self.b.window())
+    }
+
+    fn supports_ttl(&self) -> bool {
+        self.a.supports_ttl() && self.b.supports_ttl()
+    }
+}
+
+impl<A, B> GrantStore for DualWriteStore<A, B>
+    where
+        A: GrantStore,
+        A::Error: Send,
+        B: GrantStore<Key = A::Key, Count = A::Count>,
+        B::Error: Send,
+        B::Value: Value<Count = <A::Value as Value>::Count>,
+{}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::mem_store::MemStore;
+    use super::*;
+
+    #[tokio::test]
+    async fn dual_write() -> Result<(), DualWriteError<(), ()>> {
+        let a = MemStore::new(8, crate::time::Duration::seconds(100));
+        let b = MemStore::new(8, crate::time::Duration::seconds(100));
+        let store = DualWriteStore::new(a.clone(), b.clone());
+
+        // the hits before the dual writes are only in the old store.
+        a.incr_by("John".to_string(), 5).await.map_err(DualWriteError::A)?;
+
+        assert_eq!(store.incr("John".to_string()).await?.count(), 6);
+        assert_eq!(store.incr("Meg".to_string()).await?.count(), 1);
+        assert_eq!(b.peek("John".to_string()).await.map_err(DualWriteError::B)?.unwrap().count(), 1);
+        assert_eq!(store.divergence(), Divergence { writes: 2, diverged: 1, secondary_errors: 0 });
+
+        store.clone().set_primary(Primary::B);
+        assert_eq!(store.primary(), Primary::B);
+        assert_eq!(store.peek("John".to_string()).await?.unwrap().count(), 1);
+        assert_eq!(store.incr_many(vec![("John".to_string(), 1), ("Meg".to_string(), 1)]).await?
+            .iter().map(|value| value.count()).collect::<Vec<_>>(), vec![2, 2]);
+        assert_eq!(store.divergence(), Divergence { writes: 3, diverged: 2, secondary_errors: 0 });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,540 @@
+use std::fmt::Display;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use crate::error::{ImportError, MigrateError, ParseExportError};
+use crate::store::{Store, StoreStats, Value};
+use crate::utils::write_json_string;
+
+/// [ExportFormat] is the format of [export].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExportFormat {
+    /// The header `key,count,reset`, then one line per key.
+    Csv,
+    /// An array of `{"key":"…","count":3,"reset":"2024-01-01T00:00:00.000Z"}`.
+    Json,
+}
+
+impl ExportFormat {
+    /// Return the content type of the format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+        }
+    }
+}
+
+/// Dump all active keys of `store` (see [Store::snapshot]) with their counts and the
+/// times their windows reset, such as for offline analysis during an incident.
+/// The reset is empty (or `null`) if the [Value] has no expiration.
+///
+/// ```rust
+/// use actix_rl::store::export::{export, ExportFormat};
+/// use actix_rl::store::mem_store::MemStore;
+///
+/// # #[tokio::main] async fn main() -> Result<(), ()> {
+/// let store = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let csv = export(&store, ExportFormat::Csv).await?;
+/// assert_eq!(csv, "key,count,reset\n");
+/// # Ok(())
+/// # }
+/// ```
+pub async fn export<S>(store: &S, format: ExportFormat) -> Result<String, S::Error>
+    where
+        S: Store,
+        S::Key: Display,
+{
+    let mut usage = store.snapshot().await?;
+    usage.sort_by_cached_key(|(key, _)| key.to_string());
+
+    let mut out = String::new();
+    match format {
+        ExportFormat::Csv => {
+            out.push_str("key,count,reset\n");
+            for (key, value) in usage {
+                let reset = value.expire_date()
+                    .map(|reset| reset.to_rfc3339_opts(SecondsFormat::Millis, true))
+                    .unwrap_or_default();
+                out.push_str(&format!("{},{},{}\n", csv_field(&key.to_string()), value.count(), reset));
+            }
+        },
+        ExportFormat::Json => {
+            out.push('[');
+            for (i, (key, value)) in usage.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(r#"{"key":"#);
+                write_json_string(&mut out, &key.to_string());
+                out.push_str(&format!(r#","count":{},"reset":"#, value.count()));
+                match value.expire_date() {
+                    Some(reset) => write_json_string(&mut out, &reset.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                    None => out.push_str("null"),
+                }
+                out.push('}');
+            }
+            out.push(']');
+        },
+    }
+    Ok(out)
+}
+
+/// Return a route answering [export] of `store`, to be mounted on an admin scope
+/// (behind the authentication of the application). Errors of the [Store] are answered
+/// with `500 Internal Server Error`.
+///
+/// ```rust
+/// use actix_web::{web, App};
+/// use actix_rl::store::export::{export_route, ExportFormat};
+/// use actix_rl::store::mem_store::MemStore;
+///
+/// let store = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let app = App::new()
+///     .service(web::scope("/admin")
+///         .route("/counters.csv", export_route(store.clone(), ExportFormat::Csv))
+///         .route("/counters.json", export_route(store, ExportFormat::Json)));
+/// ```
+pub fn export_route<S>(store: S, format: ExportFormat) -> actix_web::Route
+    where
+        S: Store + 'static,
+        S::Key: Display,
+{
+    web::get().to(move || {
+        let store = store.clone();
+        async move {
+            match export(&store, format).await {
+                Ok(body) => HttpResponse::Ok().content_type(format.content_type()).body(body),
+                Err(_) => HttpResponse::InternalServerError().finish(),
+            }
+        }
+    })
+}
+
+/// Quote a CSV field if it contains a comma, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// [ExportRecord] is a counter of [export], see [parse_export].
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ExportRecord {
+    pub key: String,
+    pub count: u64,
+    /// The time the window resets, or [None] if the value has no expiration.
+    pub reset: Option<DateTime<Utc>>,
+}
+
+/// Parse the records of [export] in `format`. The JSON is parsed by `serde_json`.
+///
+/// The position of a CSV record is its line after the header, blank lines included.
+/// A JSON text which is not an array fails at the position 0.
+#[cfg(feature = "serde")]
+pub fn parse_export(text: &str, format: ExportFormat) -> Result<Vec<ExportRecord>, ParseExportError> {
+    match format {
+        ExportFormat::Csv => parse_csv(text),
+        ExportFormat::Json => parse_json(text),
+    }
+}
+
+/// Restore the counters of [export] (in `format`) into `store`, such as to migrate from
+/// [MemStore](crate::store::mem_store::MemStore) to `RedisStore`
+/// without resetting the limits. Each key is charged its count until its reset time, so
+/// the windows end when they would have; the records already reset are skipped, and the
+/// records without a reset time use the window of `store`.
+///
+/// The counts are added to the counters of `store`, so import into an empty store (or namespace).
+/// Return the number of restored keys. Requires the `serde` feature.
+///
+/// ```rust
+/// use actix_rl::store::export::{export, import, ExportFormat};
+/// use actix_rl::store::mem_store::MemStore;
+/// use actix_rl::store::Store;
+///
+/// # #[tokio::main] async fn main() -> Result<(), ()> {
+/// let old = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// old.incr_by("John".to_string(), 3).await?;
+///
+/// let new = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let restored = import(&new, &export(&old, ExportFormat::Json).await?, ExportFormat::Json).await.unwrap();
+/// assert_eq!(restored, 1);
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "serde")]
+pub async fn import<S>(store: &S, text: &str, format: ExportFormat) -> Result<usize, ImportError<S::Error>>
+    where
+        S: Store,
+        S::Key: From<String>,
+        S::Count: TryFrom<u64>,
+{
+    let records = parse_export(text, format).map_err(ImportError::Parse)?;
+    let now = Utc::now();
+    let mut restored = 0;
+    for (i, record) in records.into_iter().enumerate() {
+        let count = S::Count::try_from(record.count).map_err(|_| ImportError::Parse(ParseExportError(i)))?;
+        let charge = match record.reset {
+            Some(reset) if reset <= now => continue,
+            Some(reset) => store.incr_with_ttl(record.key.into(), count, reset - now).await,
+            None => store.incr_by(record.key.into(), count).await,
+        };
+        charge.map_err(ImportError::Store)?;
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+/// Copy the active counters of `from` whose keys start with `prefix` (or all of them,
+/// with an empty prefix) into `to`, each until its reset time, such as to switch from
+/// [MemStore](crate::store::mem_store::MemStore) to `RedisStore` in production
+/// without resetting the limits. Same as `import` of an [export], without the text.
+///
+/// The counts of [Store::snapshot] are not reduced by grants, and are added to the counters of `to`.
+/// Return the number of copied keys.
+///
+/// ```rust
+/// use actix_rl::store::export::migrate;
+/// use actix_rl::store::mem_store::MemStore;
+/// use actix_rl::store::Store;
+///
+/// # #[tokio::main] async fn main() -> Result<(), ()> {
+/// let old = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// old.incr_by("api:John".to_string(), 3).await?;
+///
+/// let new = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// assert_eq!(migrate(&old, &new, "api:").await.unwrap(), 1);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn migrate<A, B>(from: &A, to: &B, prefix: &str) -> Result<usize, ImportError<MigrateError<A::Error, B::Error>>>
+    where
+        A: Store,
+        A::Key: Display,
+        B: Store,
+        B::Key: From<String>,
+        <A::Value as Value>::Count: TryInto<B::Count>,
+{
+    let usage = from.snapshot().await
+        .map_err(|e| ImportError::Store(MigrateError::From(e)))?;
+    let now = Utc::now();
+    let mut copied = 0;
+    for (i, (key, value)) in usage.into_iter().enumerate() {
+        let key = key.to_string();
+        if !key.starts_with(prefix) {
+            continue;
+        }
+        let count = value.count().try_into().map_err(|_| ImportError::Parse(ParseExportError(i)))?;
+        let charge = match value.expire_date() {
+            Some(reset) if reset <= now => continue,
+            Some(reset) => to.incr_with_ttl(key.into(), count, reset - now).await,
+            None => to.incr_by(key.into(), count).await,
+        };
+        charge.map_err(|e| ImportError::Store(MigrateError::To(e)))?;
+        copied += 1;
+    }
+    Ok(copied)
+}
+
+/// Return the reset time of the record at `i`, or [None] if it is empty.
+#[cfg(feature = "serde")]
+fn parse_reset(i: usize, reset: Option<&str>) -> Result<Option<DateTime<Utc>>, ParseExportError> {
+    match reset {
+        Some(reset) if !reset.is_empty() => DateTime::parse_from_rfc3339(reset)
+            .map(|reset| Some(reset.with_timezone(&Utc)))
+            .map_err(|_| ParseExportError(i)),
+        _ => Ok(None),
+    }
+}
+
+/// Parse the records of the CSV of [export], skipping the header and the blank lines.
+#[cfg(feature = "serde")]
+fn parse_csv(text: &str) -> Result<Vec<ExportRecord>, ParseExportError> {
+    let mut lines = Vec::new();
+    let mut chars = text.chars().peekable();
+    while chars.peek().is_some() {
+        let mut fields = Vec::new();
+        loop {
+            let mut field = String::new();
+            if chars.next_if_eq(&'"').is_some() {
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                        Some('"') => break,
+                        Some(c) => field.push(c),
+                        // the header is the line 0.
+                        None => return Err(ParseExportError(lines.len().saturating_sub(1))),
+                    }
+                }
+            }
+            while let Some(c) = chars.next_if(|c| !matches!(c, ',' | '\n' | '\r')) {
+                field.push(c);
+            }
+            fields.push(field);
+            if chars.next_if_eq(&',').is_none() {
+                break;
+            }
+        }
+        chars.next_if_eq(&'\r');
+        chars.next_if_eq(&'\n');
+        lines.push(fields);
+    }
+
+    lines.into_iter().skip(1).enumerate()
+        .filter(|(_, fields)| fields.iter().any(|field| !field.is_empty()))
+        .map(|(i, fields)| match <[String; 3]>::try_from(fields) {
+            Ok([key, count, reset]) => Ok(ExportRecord {
+                key,
+                count: count.parse().map_err(|_| ParseExportError(i))?,
+                reset: parse_reset(i, Some(&reset))?,
+            }),
+            Err(_) => Err(ParseExportError(i)),
+        })
+        .collect()
+}
+
+/// Parse the records of the JSON of [export].
+#[cfg(feature = "serde")]
+fn parse_json(text: &str) -> Result<Vec<ExportRecord>, ParseExportError> {
+    #[derive(serde::Deserialize)]
+    struct Row {
+        key: String,
+        count: u64,
+        reset: Option<String>,
+    }
+
+    let rows: Vec<serde_json::Value> = serde_json::from_str(text).map_err(|_| ParseExportError(0))?;
+    rows.into_iter().enumerate()
+        .map(|(i, row)| {
+            let row: Row = serde_json::from_value(row).map_err(|_| ParseExportError(i))?;
+            Ok(ExportRecord {
+                key: row.key,
+                count: row.count,
+                reset: parse_reset(i, row.reset.as_deref())?,
+            })
+        })
+        .collect()
+}
+
+/// Spawn a task which calls [Store::snapshot] every `period`, and delivers
+/// the usage of all keys (the counts and windows) to `sink`, so the usage counted
+/// by the limiter can be the input of billing or analytics.
+///
+/// Errors of the [Store] are skipped. Abort the returned handle to stop exporting.
+/// Must be called inside a tokio runtime, such as an actix-web server.
+///
+/// ```rust
+/// use actix_rl::store::export::spawn_usage_exporter;
+/// use actix_rl::store::mem_store::MemStore;
+///
+/// # #[tokio::main] async fn main() {
+/// let store = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let (tx, rx) = std::sync::mpsc::channel();
+/// let exporter = spawn_usage_exporter(store.clone(), std::time::Duration::from_secs(60), move |usage| {
+///     let _ = tx.send(usage);
+/// });
+/// # exporter.abort();
+/// # }
+/// ```
+pub fn spawn_usage_exporter<S, F>(store: S, period: std::time::Duration, mut sink: F) -> tokio::task::JoinHandle<()>
+    where
+        S: Store + 'static,
+        S::Key: 'static,
+        F: FnMut(Vec<(S::Key, S::Value)>) + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Ok(usage) = store.snapshot().await {
+                sink(usage);
+            }
+        }
+    })
+}
+
+/// Spawn a task which calls [Store::stats] every `period`, and delivers the
+/// [StoreStats] to `sink`, such as to set the gauges of a metrics registry,
+/// so the capacity of the limiter itself can be planned.
+///
+/// Errors of the [Store] are skipped. Abort the returned handle to stop exporting.
+/// Must be called inside a tokio runtime, such as an actix-web server.
+///
+/// ```rust
+/// use actix_rl::store::export::spawn_stats_exporter;
+/// use actix_rl::store::mem_store::MemStore;
+///
+/// # #[tokio::main] async fn main() {
+/// let store = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let exporter = spawn_stats_exporter(store.clone(), std::time::Duration::from_secs(15), |stats| {
+///     println!("rate_limit_active_keys{{namespace=\"api\"}} {}", stats.active_keys);
+/// });
+/// # exporter.abort();
+/// # }
+/// ```
+pub fn spawn_stats_exporter<S, F>(store: S, period: std::time::Duration, mut sink: F) -> tokio::task::JoinHandle<()>
+    where
+        S: Store + 'static,
+        F: FnMut(StoreStats) + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Ok(stats) = store.stats().await {
+                sink(stats);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::mem_store::MemStore;
+    use crate::store::Value;
+    use super::*;
+
+    #[tokio::test]
+    async fn export() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100));
+        store.incr_by("John".to_string(), 3).await?;
+        store.incr("Meg".to_string()).await?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let exporter = spawn_usage_exporter(store.clone(), std::time::Duration::from_millis(50), move |usage| {
+            let _ = tx.send(usage);
+        });
+
+        let mut usage = rx.recv().await.unwrap();
+        usage.sort_by(|a, b| a.0.cmp(&b.0));
+        let usage: Vec<_> = usage.into_iter().map(|(key, value)| (key, value.count())).collect();
+        assert_eq!(usage, vec![("John".to_string(), 3), ("Meg".to_string(), 1)]);
+
+        // the later snapshots see the new usage.
+        store.incr("Meg".to_string()).await?;
+        loop {
+            let usage = rx.recv().await.unwrap();
+            if usage.iter().any(|(key, value)| key == "Meg" && value.count() == 2) {
+                break;
+            }
+        }
+
+        exporter.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_counters() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100));
+        store.incr_by("John".to_string(), 3).await?;
+        store.incr("Meg, \"Jr\"".to_string()).await?;
+        let reset = store.peek("John".to_string()).await?.unwrap().expire_date().unwrap()
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+
+        let csv = super::export(&store, ExportFormat::Csv).await?;
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "key,count,reset");
+        assert_eq!(lines[1], format!("John,3,{}", reset));
+        assert!(lines[2].starts_with(r#""Meg, ""Jr""",1,"#));
+
+        let json: serde_json::Value = serde_json::from_str(&super::export(&store, ExportFormat::Json).await?).unwrap();
+        assert_eq!(json[0]["key"], "John");
+        assert_eq!(json[0]["count"], 3);
+        assert_eq!(json[0]["reset"], reset.as_str());
+        assert_eq!(json[1]["key"], "Meg, \"Jr\"");
+
+        let app = actix_web::test::init_service(
+            actix_web::App::new().route("/counters", export_route(store.clone(), ExportFormat::Json))
+        ).await;
+        let resp = actix_web::test::call_service(&app, actix_web::test::TestRequest::get().uri("/counters").to_request()).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/json");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn import_counters() -> Result<(), ()> {
+        let old = MemStore::new(8, crate::time::Duration::seconds(100));
+        old.incr_by("John".to_string(), 3).await?;
+        old.incr("Meg, \"Jr\"\n".to_string()).await?;
+        let reset = old.peek("John".to_string()).await?.unwrap().expire_date().unwrap();
+
+        for format in [ExportFormat::Csv, ExportFormat::Json] {
+            let text = super::export(&old, format).await?;
+            let records = parse_export(&text, format).unwrap();
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[1].key, "Meg, \"Jr\"\n");
+
+            let new = MemStore::new(8, crate::time::Duration::seconds(1000));
+            assert_eq!(import(&new, &text, format).await.unwrap(), 2);
+            let value = new.peek("John".to_string()).await?.unwrap();
+            assert_eq!(value.count(), 3);
+            // the window ends when it would have in the old store.
+            assert!((value.expire_date().unwrap() - reset).num_seconds().abs() <= 1);
+            assert_eq!(new.peek("Meg, \"Jr\"\n".to_string()).await?.unwrap().count(), 1);
+        }
+
+        // the records already reset are skipped.
+        let new = MemStore::new(8, crate::time::Duration::seconds(100));
+        let text = "key,count,reset\nJohn,3,2020-01-01T00:00:00.000Z\nMeg,1,\n";
+        assert_eq!(import(&new, text, ExportFormat::Csv).await.unwrap(), 1);
+        assert!(new.peek("John".to_string()).await?.is_none());
+        assert_eq!(new.peek("Meg".to_string()).await?.unwrap().count(), 1);
+
+        assert_eq!(parse_export("key,count,reset\nJohn,three,\n", ExportFormat::Csv), Err(ParseExportError(0)));
+        // the positions count the blank lines.
+        assert_eq!(parse_export("key,count,reset\nJohn,3,\n\nMeg,one,\n", ExportFormat::Csv), Err(ParseExportError(2)));
+        assert_eq!(parse_export(r#"[{"key":"J\u00f6hn \ud83d\ude00","count":3}]"#, ExportFormat::Json).unwrap()[0].key, "Jöhn 😀");
+        assert_eq!(parse_export(r#"[{"key":"John","count":3,"reset":null},{"key":"Meg"}]"#, ExportFormat::Json), Err(ParseExportError(1)));
+        assert!(matches!(
+            import(&new, r#"[{"key":"John","count":5000000000,"reset":null}]"#, ExportFormat::Json).await,
+            Err(ImportError::Parse(ParseExportError(0))),
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_counters() -> Result<(), ()> {
+        let old = MemStore::new(8, crate::time::Duration::seconds(100));
+        old.incr_by("api:John".to_string(), 3).await?;
+        old.incr("api:Meg".to_string()).await?;
+        old.incr("web:John".to_string()).await?;
+        let reset = old.peek("api:John".to_string()).await?.unwrap().expire_date().unwrap();
+
+        let new = MemStore::new(8, crate::time::Duration::seconds(1000));
+        assert_eq!(migrate(&old, &new, "api:").await.unwrap(), 2);
+        let value = new.peek("api:John".to_string()).await?.unwrap();
+        assert_eq!(value.count(), 3);
+        assert!((value.expire_date().unwrap() - reset).num_seconds().abs() <= 1);
+        assert_eq!(new.peek("api:Meg".to_string()).await?.unwrap().count(), 1);
+        assert!(new.peek("web:John".to_string()).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stats() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100));
+        store.incr("John".to_string()).await?;
+        store.incr("Meg".to_string()).await?;
+        store.grant("Meg".to_string(), 1, crate::time::Duration::seconds(100)).await?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let exporter = spawn_stats_exporter(store.clone(), std::time::Duration::from_millis(50), move |stats| {
+            let _ = tx.send(stats);
+        });
+
+        let stats = rx.recv().await.unwrap();
+        assert_eq!(stats.active_keys, 2);
+        assert_eq!(stats.stored_keys, 3);
+        assert!(stats.approx_bytes.unwrap() > 0);
+
+        exporter.abort();
+        Ok(())
+    }
+}
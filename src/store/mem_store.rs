@@ -1,9 +1,15 @@
 use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
-use chrono::{DateTime, Utc};
+use arc_swap::ArcSwap;
+use crate::time::{DateTime, Utc};
+#[cfg(feature = "chrono")]
+use chrono::FixedOffset;
 use tokio::sync::Mutex;
-use crate::store::{Store, Value};
+use crate::error::ConfigError;
+use crate::store::atomic::{window_epoch, AtomicWindow};
+use crate::store::time_wheel::TimeWheel;
+use crate::store::{Clock, Expiration, GrantStore, Metadata, Schedule, Store, StoreStats, SystemClock, Value};
 
 pub const DEFAULT_STORE_CAPACITY: usize = 4096;
 
@@ -12,6 +18,8 @@ pub const DEFAULT_STORE_CAPACITY: usize = 4096;
 pub struct DateCount {
     pub create_date: DateTime<Utc>,
     pub count: u32,
+    /// TTL override of this key, [None] means using the TTL of [MemStore].
+    pub ttl: Option<crate::time::Duration>,
 }
 
 impl Default for DateCount {
@@ -19,18 +27,19 @@ impl Default for DateCount {
         Self {
             create_date: Utc::now(),
             count: 0u32,
+            ttl: None,
         }
     }
 }
 
 impl DateCount {
     /// Check if [DateCount] has expired.
-    pub fn expired(&self, ttl: chrono::Duration) -> bool {
+    pub fn expired(&self, ttl: crate::time::Duration) -> bool {
         self.expired_at(ttl, Utc::now())
     }
 
     /// Check if [DateCount] has expired at instant.
-    pub fn expired_at(&self, ttl: chrono::Duration, instant: DateTime<Utc>) -> bool {
+    pub fn expired_at(&self, ttl: crate::time::Duration, instant: DateTime<Utc>) -> bool {
         self.create_date + ttl < instant
     }
 }
@@ -39,6 +48,26 @@ impl DateCount {
 pub struct DateCountUntil {
     pub date_count: DateCount,
     pub until: DateTime<Utc>,
+    /// The metadata of the key, see [Store::set_metadata].
+    pub metadata: Option<Arc<Metadata>>,
+    /// Whether the increment was counted, see [Value::is_charged].
+    pub charged: bool,
+}
+
+impl DateCountUntil {
+    /// Return the value of a window of `ttl` starting at `start`.
+    pub(crate) fn window(start: DateTime<Utc>, count: u32, ttl: crate::time::Duration) -> Self {
+        Self {
+            date_count: DateCount {
+                create_date: start,
+                count,
+                ttl: Some(ttl),
+            },
+            until: start + ttl,
+            metadata: None,
+            charged: true,
+        }
+    }
 }
 
 impl Value for DateCountUntil {
@@ -55,25 +84,225 @@ impl Value for DateCountUntil {
     fn expire_date(&self) -> Option<DateTime<Utc>> {
         Some(self.until)
     }
+
+    fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_deref()
+    }
+
+    fn is_charged(&self) -> bool {
+        self.charged
+    }
+}
+
+/// [TokenBucket] describes a token bucket, which allows `burst` requests at once,
+/// and refills `rate` requests per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    pub burst: u32,
+    pub rate: f64,
+}
+
+impl TokenBucket {
+    /// Check that `burst` is not zero and `rate` is a positive number.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.burst == 0 {
+            return Err(ConfigError::ZeroMax);
+        }
+
+        if !(self.rate.is_finite() && self.rate > 0.0) {
+            return Err(ConfigError::InvalidRate(self.rate));
+        }
+
+        Ok(())
+    }
+
+    /// Return the time to refill one token.
+    pub fn interval(&self) -> crate::time::Duration {
+        crate::time::Duration::nanoseconds((1e9 / self.rate) as i64)
+    }
 }
 
 /// [MemStore] stores data in memory.
+///
+/// Clones share the counters and the settings, so a builder (such as [Self::with_token_bucket])
+/// called on a clone configures all of them, without locking the store.
 #[derive(Debug, Clone)]
 pub struct MemStore {
     pub(crate) inner: Arc<Mutex<MemStoreInner>>,
+    pub(crate) config: Arc<ArcSwap<MemStoreConfig>>,
+    /// The TTL of windows, read without locking the store.
+    pub(crate) ttl: crate::time::Duration,
+}
+
+/// [MemStoreConfig] is the settings of [MemStore], set by its builders.
+#[derive(Debug, Clone)]
+pub(crate) struct MemStoreConfig {
+    /// If set, windows are aligned to wall-clock time by the [Schedule].
+    pub schedule: Option<Arc<dyn Schedule>>,
+    /// How the TTL of a key is counted.
+    pub expiration: Expiration,
+    /// If set, requests are counted by the [TokenBucket].
+    pub bucket: Option<TokenBucket>,
+    /// The time source of the window math.
+    pub clock: Arc<dyn Clock>,
+    /// If set, the expired keys are removed by a [TimeWheel] of this resolution.
+    pub wheel: Option<crate::time::Duration>,
+    /// The keys counted with atomic counters, see [MemStore::with_hot_keys].
+    pub hot: Option<Arc<HotKeys>>,
 }
 
 impl MemStore {
-    pub fn new(capacity: usize, ttl: chrono::Duration) -> Self {
+    pub fn new(capacity: usize, ttl: crate::time::Duration) -> Self {
+        let config = Arc::new(ArcSwap::from_pointee(MemStoreConfig {
+            schedule: None,
+            expiration: Expiration::FixedWindow,
+            bucket: None,
+            clock: Arc::new(SystemClock),
+            wheel: None,
+            hot: None,
+        }));
         Self {
-            inner: Arc::new(Mutex::new(MemStoreInner::new(capacity, ttl))),
+            inner: Arc::new(Mutex::new(MemStoreInner::new(capacity, ttl, config.clone()))),
+            config,
+            ttl,
+        }
+    }
+
+    /// Count the `keys` (such as the key of a global limit) with atomic counters,
+    /// without locking the store. Use it for extremely hot keys known ahead of time.
+    ///
+    /// The windows of hot keys are aligned to the unix epoch, with the TTL of the store.
+    /// Schedules, token buckets, TTL overrides, grants and metadata do not apply to them.
+    pub fn with_hot_keys<K: ToString, I: IntoIterator<Item = K>>(self, keys: I) -> Self {
+        let hot = Arc::new(HotKeys {
+            counters: keys.into_iter()
+                .map(|key| (key.to_string(), AtomicWindow::default()))
+                .collect(),
+        });
+        self.configure(|config| config.hot = Some(hot.clone()));
+        self
+    }
+
+    /// Align windows to wall-clock boundaries in the timezone `offset`
+    /// (such as the top of the minute/hour/day), rather than starting
+    /// from the first request. See [aligned_window_start](crate::store::aligned_window_start).
+    #[cfg(feature = "chrono")]
+    pub fn with_aligned_window(self, offset: FixedOffset) -> Self {
+        self.with_schedule(offset)
+    }
+
+    /// Use a [Schedule] to decide the bounds of windows, such as [DailyQuota](crate::store::DailyQuota).
+    pub fn with_schedule<S: Schedule + 'static>(self, schedule: S) -> Self {
+        let schedule: Arc<dyn Schedule> = Arc::new(schedule);
+        self.configure(|config| config.schedule = Some(schedule.clone()));
+        self
+    }
+
+    /// Set the [Expiration] of keys. The default is [Expiration::FixedWindow].
+    /// [Expiration::Inactivity] has no effect on scheduled windows (see [Self::with_schedule]).
+    pub fn with_expiration(self, expiration: Expiration) -> Self {
+        self.configure(|config| config.expiration = expiration);
+        self
+    }
+
+    /// Count requests with a [TokenBucket] (implemented as GCRA) instead of fixed windows.
+    ///
+    /// The count of the value is the number of tokens in use, which is
+    /// greater than [TokenBucket::burst] if the request is not allowed.
+    /// Rejected requests do not consume tokens. Use [TokenBucket::burst] as the max of the middleware.
+    ///
+    /// The buckets which are full again are removed when the number of buckets doubles,
+    /// so the idle keys do not stay in memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bucket` is not valid, see [TokenBucket::validate].
+    pub fn with_token_bucket(self, bucket: TokenBucket) -> Self {
+        if let Err(e) = bucket.validate() {
+            panic!("invalid token bucket: {}", e);
         }
+        self.configure(|config| config.bucket = Some(bucket));
+        self
+    }
+
+    /// Use a [Clock] for the window math, such as [CoarseClock](crate::store::CoarseClock)
+    /// which avoids reading the system time on every request. The default is [SystemClock].
+    pub fn with_clock<C: Clock + 'static>(self, clock: C) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        self.configure(|config| config.clock = clock.clone());
+        self
+    }
+
+    /// Remove the expired keys with a hierarchical timing wheel, ticking every `resolution`.
+    /// Without it, the expired keys stay in memory until they are counted again or deleted,
+    /// which adds up for long windows and many keys (such as one per IP address).
+    ///
+    /// Each key is scheduled once per window, at O(1), and each tick only visits the keys
+    /// due then, so the cost does not grow with the number of keys. The keys are removed
+    /// up to `resolution` after their windows end, on the next call counting a key.
+    /// Hot keys (see [Self::with_hot_keys]) are not stored in the map.
+    ///
+    /// The keys counted before the wheel is set are removed when they are counted again.
+    pub fn with_time_wheel(self, resolution: crate::time::Duration) -> Self {
+        self.configure(|config| config.wheel = Some(resolution));
+        self
+    }
+
+    /// Update the settings, shared with the clones.
+    fn configure(&self, configure: impl Fn(&mut MemStoreConfig)) {
+        self.config.rcu(|config| {
+            let mut config = MemStoreConfig::clone(config);
+            configure(&mut config);
+            config
+        });
+    }
+}
+
+/// [HotKeys] counts the hot keys of [MemStore] with atomic counters.
+#[derive(Debug, Clone)]
+pub(crate) struct HotKeys {
+    pub counters: HashMap<String, AtomicWindow>,
+}
+
+/// The methods of [HotKeys] take the `clock` and the `ttl` of the [MemStore].
+impl HotKeys {
+    /// Increase the count of `key`, or return [None] if `key` is not hot.
+    fn incr(&self, key: &str, val: u32, clock: &dyn Clock, ttl: crate::time::Duration) -> Option<DateCountUntil> {
+        let counter = self.counters.get(key)?;
+        let (epoch, start) = window_epoch(clock.now(), ttl);
+        Some(DateCountUntil::window(start, counter.incr(epoch, val), ttl))
+    }
+
+    /// Return the count of `key`, or [None] if `key` is not hot.
+    fn peek(&self, key: &str, clock: &dyn Clock, ttl: crate::time::Duration) -> Option<Option<DateCountUntil>> {
+        let counter = self.counters.get(key)?;
+        let (epoch, start) = window_epoch(clock.now(), ttl);
+        let count = counter.get(epoch);
+        Some((count > 0).then(|| DateCountUntil::window(start, count, ttl)))
+    }
+
+    fn stats(&self, clock: &dyn Clock, ttl: crate::time::Duration) -> StoreStats {
+        let (epoch, _) = window_epoch(clock.now(), ttl);
+        StoreStats {
+            active_keys: self.counters.values().filter(|counter| counter.get(epoch) > 0).count(),
+            stored_keys: self.counters.len(),
+            approx_bytes: Some(entries_size(&self.counters, |_| 0)),
+        }
+    }
+
+    fn snapshot(&self, clock: &dyn Clock, ttl: crate::time::Duration) -> Vec<(String, DateCountUntil)> {
+        let (epoch, start) = window_epoch(clock.now(), ttl);
+        self.counters.iter()
+            .map(|(key, counter)| (key, counter.get(epoch)))
+            .filter(|(_, count)| *count > 0)
+            .map(|(key, count)| (key.clone(), DateCountUntil::window(start, count, ttl)))
+            .collect()
     }
 }
 
 impl Default for MemStore {
     fn default() -> Self {
-        Self::new(DEFAULT_STORE_CAPACITY, chrono::Duration::seconds(60))
+        Self::new(DEFAULT_STORE_CAPACITY, crate::time::Duration::seconds(60))
     }
 }
 
@@ -85,6 +314,11 @@ impl Store for MemStore {
     type Count = u32;
 
     async fn incr_by(&self, key: Self::Key, val: u32) -> Result<Self::Value, Self::Error> {
+        let config = self.config.load();
+        if let Some(value) = config.hot.as_ref().and_then(|hot| hot.incr(&key, val, &*config.clock, self.ttl)) {
+            return Ok(value);
+        }
+
         Ok(self.inner.lock().await.incr_by(key, val))
     }
 
@@ -92,61 +326,519 @@ impl Store for MemStore {
         self.incr_by(key, 1).await
     }
 
+    async fn incr_with_ttl(&self, key: Self::Key, val: u32, ttl: crate::time::Duration) -> Result<Self::Value, Self::Error> {
+        let config = self.config.load();
+        if let Some(value) = config.hot.as_ref().and_then(|hot| hot.incr(&key, val, &*config.clock, self.ttl)) {
+            return Ok(value);
+        }
+
+        Ok(self.inner.lock().await.incr_with_ttl(key, val, Some(ttl)))
+    }
+
+    /// Increase the keys under one lock.
+    async fn incr_many(&self, charges: Vec<(Self::Key, Self::Count)>) -> Result<Vec<Self::Value>, Self::Error> {
+        let config = self.config.load();
+        let mut inner = self.inner.lock().await;
+        Ok(charges.into_iter()
+            .map(|(key, val)| match config.hot.as_ref().and_then(|hot| hot.incr(&key, val, &*config.clock, self.ttl)) {
+                Some(value) => value,
+                None => inner.incr_by(key, val),
+            })
+            .collect())
+    }
+
     async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        if let Some(counter) = self.config.load().hot.as_ref().and_then(|hot| hot.counters.get(&key)) {
+            counter.reset();
+            return Ok(None);
+        }
+
         Ok(self.inner.lock().await.del(key))
     }
 
     async fn clear(&self) -> Result<(), Self::Error> {
+        if let Some(hot) = &self.config.load().hot {
+            hot.counters.values().for_each(AtomicWindow::reset);
+        }
+
         self.inner.lock().await.clear();
         Ok(())
     }
+
+    async fn del_prefix(&self, prefix: &str) -> Result<Option<usize>, Self::Error> {
+        let mut deleted = 0;
+        if let Some(hot) = &self.config.load().hot {
+            for (_, counter) in hot.counters.iter().filter(|(key, _)| key.starts_with(prefix)) {
+                counter.reset();
+                deleted += 1;
+            }
+        }
+
+        Ok(Some(deleted + self.inner.lock().await.del_prefix(prefix)))
+    }
+
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        let config = self.config.load();
+        if let Some(value) = config.hot.as_ref().and_then(|hot| hot.peek(&key, &*config.clock, self.ttl)) {
+            return Ok(value);
+        }
+
+        Ok(self.inner.lock().await.peek(key))
+    }
+
+    async fn ttl(&self, key: Self::Key) -> Result<Option<crate::time::Duration>, Self::Error> {
+        let config = self.config.load();
+        if let Some(hot) = &config.hot {
+            if let Some(value) = hot.peek(&key, &*config.clock, self.ttl) {
+                let now = config.clock.now();
+                return Ok(value.map(|value| (value.until - now).max(crate::time::Duration::zero())));
+            }
+        }
+
+        Ok(self.inner.lock().await.ttl(&key))
+    }
+
+    async fn snapshot(&self) -> Result<Vec<(Self::Key, Self::Value)>, Self::Error> {
+        let mut snapshot = self.inner.lock().await.snapshot();
+        let config = self.config.load();
+        if let Some(hot) = &config.hot {
+            snapshot.extend(hot.snapshot(&*config.clock, self.ttl));
+        }
+
+        Ok(snapshot)
+    }
+
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        let mut stats = self.inner.lock().await.stats();
+        let config = self.config.load();
+        if let Some(hot) = &config.hot {
+            let hot = hot.stats(&*config.clock, self.ttl);
+            stats.active_keys += hot.active_keys;
+            stats.stored_keys += hot.stored_keys;
+            stats.approx_bytes = stats.approx_bytes.zip(hot.approx_bytes).map(|(a, b)| a + b);
+        }
+
+        Ok(stats)
+    }
+
+    async fn set_metadata(&self, key: Self::Key, metadata: Metadata, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        self.inner.lock().await.set_metadata(key, metadata, ttl);
+        Ok(())
+    }
+
+    /// The grants of hot keys (see [MemStore::with_hot_keys]) lower their counts
+    /// in the current window (not below 0) instead, whatever `ttl`.
+    async fn grant(&self, key: Self::Key, extra: u32, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        let config = self.config.load();
+        if let Some(hot) = &config.hot {
+            if let Some(counter) = hot.counters.get(&key) {
+                let (epoch, _) = window_epoch(config.clock.now(), self.ttl);
+                counter.decr(epoch, extra);
+                return Ok(());
+            }
+        }
+
+        self.inner.lock().await.grant(key, extra, ttl);
+        Ok(())
+    }
+
+    fn window(&self) -> Option<crate::time::Duration> {
+        Some(self.ttl)
+    }
+
+    /// The token buckets have no windows.
+    fn supports_ttl(&self) -> bool {
+        self.config.load().bucket.is_none()
+    }
 }
 
+impl GrantStore for MemStore {}
+
 #[derive(Debug, Clone)]
 pub(crate) struct MemStoreInner {
     pub(crate) data: HashMap<String, DateCount>,
     /// The [ttl] field indicates the time-to-live (TTL)
     /// of data from its creation. Once this TTL expires,
     /// the data in the cache is considered empty or expired.
-    pub(crate) ttl: chrono::Duration,
+    pub(crate) ttl: crate::time::Duration,
+    /// The settings, shared with [MemStore].
+    pub(crate) config: Arc<ArcSwap<MemStoreConfig>>,
+    /// The theoretical arrival time of each key, used by [TokenBucket].
+    pub(crate) buckets: HashMap<String, DateTime<Utc>>,
+    /// The number of buckets at which the full buckets are removed.
+    pub(crate) buckets_sweep: usize,
+    /// The extra quota of each key and its expiration, see [Store::grant].
+    pub(crate) grants: HashMap<String, (u32, DateTime<Utc>)>,
+    /// The metadata of each key and its expiration, see [Store::set_metadata].
+    pub(crate) metadata: HashMap<String, (Arc<Metadata>, DateTime<Utc>)>,
+    /// If set, the expired keys are removed by the [TimeWheel], see [MemStoreConfig::wheel].
+    pub(crate) wheel: Option<TimeWheel>,
 }
 
 impl MemStoreInner {
-    pub fn new(capacity: usize, ttl: chrono::Duration) -> Self {
+    pub fn new(capacity: usize, ttl: crate::time::Duration, config: Arc<ArcSwap<MemStoreConfig>>) -> Self {
         Self {
             data: HashMap::with_capacity(capacity),
             ttl,
+            config,
+            buckets: HashMap::new(),
+            buckets_sweep: capacity.max(1),
+            grants: HashMap::new(),
+            metadata: HashMap::new(),
+            wheel: None,
+        }
+    }
+
+    /// Return the settings, and create the [TimeWheel] once it is set.
+    fn config(&mut self) -> Arc<MemStoreConfig> {
+        let config = self.config.load_full();
+        if let (None, Some(resolution)) = (&self.wheel, config.wheel) {
+            self.wheel = Some(TimeWheel::new(resolution));
+        }
+        config
+    }
+
+    /// Create a [DateCount] for a new window.
+    fn new_window(&self, config: &MemStoreConfig, now: DateTime<Utc>, ttl: Option<crate::time::Duration>) -> DateCount {
+        match &config.schedule {
+            Some(schedule) => {
+                let (start, end) = schedule.window(now, ttl.unwrap_or(self.ttl));
+                DateCount {
+                    create_date: start,
+                    count: 0,
+                    ttl: Some(end - start),
+                }
+            },
+            None => DateCount {
+                create_date: now,
+                count: 0,
+                ttl,
+            },
         }
     }
 
     pub fn incr_by(&mut self, key: String, val: u32) -> DateCountUntil {
-        let entry = self.data.entry(key).or_default();
+        self.incr_with_ttl(key, val, None)
+    }
 
-        if entry.expired(self.ttl) {
-            *entry = DateCount::default()
+    /// Increase the count of `key`. If a new window starts,
+    /// `ttl` (or the default TTL if [None]) is used for the new window.
+    pub fn incr_with_ttl(&mut self, key: String, val: u32, ttl: Option<crate::time::Duration>) -> DateCountUntil {
+        let config = self.config();
+        let now = config.clock.now();
+        self.expire(now);
+        let granted = self.granted(&key, now);
+        let metadata = self.metadata_of(&key, now);
+
+        if let Some(bucket) = config.bucket {
+            return DateCountUntil {
+                metadata,
+                ..self.incr_token_bucket(key, val, bucket, granted, now)
+            };
+        }
+
+        let default_ttl = self.ttl;
+        let window = self.new_window(&config, now, ttl);
+        let scheduled = (self.wheel.is_some() && !self.tracked(&key)).then(|| key.clone());
+        let entry = self.data.entry(key).or_insert(window);
+
+        if entry.expired_at(entry.ttl.unwrap_or(default_ttl), now) {
+            *entry = window
         }
 
         entry.count += val;
 
+        if config.expiration == Expiration::Inactivity && config.schedule.is_none() {
+            // extend the TTL, so the key expires `ttl` after this hit.
+            entry.ttl = Some(now - entry.create_date + ttl.unwrap_or(default_ttl));
+        }
+
+        let value = DateCountUntil {
+            date_count: DateCount {
+                count: entry.count.saturating_sub(granted),
+                ..*entry
+            },
+            until: entry.create_date + entry.ttl.unwrap_or(default_ttl),
+            metadata,
+            charged: true,
+        };
+        if let (Some(wheel), Some(key)) = (&mut self.wheel, scheduled) {
+            wheel.insert(key, value.until);
+        }
+        value
+    }
+
+    /// Take `val` tokens from the bucket of `key`, using GCRA.
+    /// `granted` extra tokens are available in addition to [TokenBucket::burst].
+    fn incr_token_bucket(&mut self, key: String, val: u32, bucket: TokenBucket, granted: u32, now: DateTime<Utc>) -> DateCountUntil {
+        let interval = bucket.interval();
+        let tat = self.buckets.get(&key).copied().unwrap_or(now).max(now);
+        let new_tat = tat + interval * val as i32;
+
+        // number of tokens in use, after taking `val` tokens.
+        let in_use = (new_tat - now).num_nanoseconds().unwrap_or(i64::MAX) as f64
+            / interval.num_nanoseconds().unwrap_or(1).max(1) as f64;
+        let count = (in_use.ceil() as u32).saturating_sub(granted);
+
+        let charged = count <= bucket.burst;
+        let until = if charged {
+            if !self.buckets.contains_key(&key) {
+                if let Some(wheel) = self.wheel.as_mut() {
+                    wheel.insert(key.clone(), new_tat);
+                } else if self.buckets.len() >= self.buckets_sweep {
+                    // the full buckets are the same as no bucket.
+                    self.buckets.retain(|_, tat| *tat > now);
+                    self.buckets_sweep = self.buckets_sweep.max(self.buckets.len() * 2);
+                }
+            }
+            self.buckets.insert(key, new_tat);
+            // the bucket is full again
+            new_tat
+        } else {
+            // enough tokens are refilled for this request
+            new_tat - interval * bucket.burst as i32
+        };
+
         DateCountUntil {
-            date_count: *entry,
-            until: entry.create_date + self.ttl,
+            date_count: DateCount {
+                create_date: now,
+                count,
+                ttl: None,
+            },
+            until,
+            metadata: None,
+            charged,
         }
     }
 
     pub fn del(&mut self, key: String) -> Option<DateCountUntil> {
+        self.buckets.remove(&key);
+        let ttl = self.ttl;
         self.data.remove(&key)
             .map(|entry| DateCountUntil {
                 date_count: entry,
-                until: entry.create_date + self.ttl,
+                until: entry.create_date + entry.ttl.unwrap_or(ttl),
+                metadata: None,
+                charged: true,
             })
     }
 
     pub fn clear(&mut self) {
-        self.data.clear()
+        self.data.clear();
+        self.buckets.clear();
+        self.grants.clear();
+        self.metadata.clear();
+        if let Some(wheel) = &mut self.wheel {
+            wheel.clear();
+        }
+    }
+
+    /// Remove the windows, buckets, grants and metadata which ended before `now`, see [MemStore::with_time_wheel].
+    /// The keys with an entry still alive, such as counted again in a new window, are scheduled again.
+    fn expire(&mut self, now: DateTime<Utc>) {
+        let Some(wheel) = &mut self.wheel else {
+            return;
+        };
+
+        for key in wheel.advance(now) {
+            let mut alive = None;
+            let mut keep = |until: DateTime<Utc>| {
+                alive = alive.max(Some(until));
+                until >= now
+            };
+
+            if let Some(entry) = self.data.get(&key) {
+                if !keep(entry.create_date + entry.ttl.unwrap_or(self.ttl)) {
+                    self.data.remove(&key);
+                }
+            }
+            if self.buckets.get(&key).is_some_and(|tat| !keep(*tat)) {
+                self.buckets.remove(&key);
+            }
+            if self.grants.get(&key).is_some_and(|(_, until)| !keep(*until)) {
+                self.grants.remove(&key);
+            }
+            if self.metadata.get(&key).is_some_and(|(_, until)| !keep(*until)) {
+                self.metadata.remove(&key);
+            }
+
+            // deleted since, or nothing left.
+            if let Some(until) = alive.filter(|until| *until >= now) {
+                wheel.insert(key, until);
+            }
+        }
+    }
+
+    /// Delete the keys starting with `prefix`, return the number of deleted counters.
+    pub fn del_prefix(&mut self, prefix: &str) -> usize {
+        let len = self.data.len();
+        self.data.retain(|key, _| !key.starts_with(prefix));
+        self.buckets.retain(|key, _| !key.starts_with(prefix));
+        self.grants.retain(|key, _| !key.starts_with(prefix));
+        self.metadata.retain(|key, _| !key.starts_with(prefix));
+        len - self.data.len()
+    }
+
+    /// Return the value of `key` in the current window, without increasing it.
+    /// Token buckets are not supported.
+    pub fn peek(&mut self, key: String) -> Option<DateCountUntil> {
+        let config = self.config.load();
+        if config.bucket.is_some() {
+            return None;
+        }
+
+        let now = config.clock.now();
+        let granted = self.granted(&key, now);
+        let metadata = self.metadata_of(&key, now);
+        let entry = self.data.get(&key)?;
+        let ttl = entry.ttl.unwrap_or(self.ttl);
+        if entry.expired_at(ttl, now) {
+            return None;
+        }
+
+        Some(DateCountUntil {
+            date_count: DateCount {
+                count: entry.count.saturating_sub(granted),
+                ..*entry
+            },
+            until: entry.create_date + ttl,
+            metadata,
+            charged: true,
+        })
+    }
+
+    /// Return the time until the window of `key` resets, or [None] if it does not exist.
+    /// Token buckets are not supported.
+    pub fn ttl(&self, key: &str) -> Option<crate::time::Duration> {
+        let config = self.config.load();
+        if config.bucket.is_some() {
+            return None;
+        }
+
+        let now = config.clock.now();
+        let entry = self.data.get(key)?;
+        let ttl = entry.ttl.unwrap_or(self.ttl);
+        if entry.expired_at(ttl, now) {
+            return None;
+        }
+
+        Some((entry.create_date + ttl - now).max(crate::time::Duration::zero()))
+    }
+
+    /// Return the values of all keys which are not expired.
+    /// Token buckets are not supported.
+    pub fn snapshot(&self) -> Vec<(String, DateCountUntil)> {
+        let config = self.config.load();
+        if config.bucket.is_some() {
+            return Vec::new();
+        }
+
+        let now = config.clock.now();
+        self.data.iter()
+            .filter_map(|(key, entry)| {
+                let ttl = entry.ttl.unwrap_or(self.ttl);
+                let metadata = self.metadata.get(key)
+                    .filter(|(_, until)| *until > now)
+                    .map(|(metadata, _)| metadata.clone());
+                (!entry.expired_at(ttl, now)).then(|| (key.clone(), DateCountUntil {
+                    date_count: *entry,
+                    until: entry.create_date + ttl,
+                    metadata,
+                    charged: true,
+                }))
+            })
+            .collect()
+    }
+
+    /// Return the number of active and stored keys, and their approximate memory.
+    pub fn stats(&self) -> StoreStats {
+        let config = self.config.load();
+        let now = config.clock.now();
+        let active_keys = match config.bucket {
+            Some(_) => self.buckets.values().filter(|tat| **tat > now).count(),
+            None => self.data.values().filter(|entry| !entry.expired_at(entry.ttl.unwrap_or(self.ttl), now)).count(),
+        };
+        let metadata_size = |(metadata, _): &(Arc<Metadata>, DateTime<Utc>)| {
+            metadata.iter().map(|(key, value)| key.capacity() + value.capacity()).sum()
+        };
+
+        StoreStats {
+            active_keys,
+            stored_keys: self.data.len() + self.buckets.len() + self.grants.len() + self.metadata.len(),
+            approx_bytes: Some(
+                entries_size(&self.data, |_| 0)
+                    + entries_size(&self.buckets, |_| 0)
+                    + entries_size(&self.grants, |_| 0)
+                    + entries_size(&self.metadata, metadata_size)
+            ),
+        }
+    }
+
+    /// Attach `metadata` to `key` for `ttl`, replacing the previous one.
+    pub fn set_metadata(&mut self, key: String, metadata: Metadata, ttl: crate::time::Duration) {
+        let until = self.config().clock.now() + ttl;
+        self.schedule(&key, until);
+        self.metadata.insert(key, (Arc::new(metadata), until));
+    }
+
+    /// Return true if `key` has a window, a bucket, a grant or metadata.
+    fn tracked(&self, key: &str) -> bool {
+        self.data.contains_key(key)
+            || self.buckets.contains_key(key)
+            || self.grants.contains_key(key)
+            || self.metadata.contains_key(key)
+    }
+
+    /// Schedule `key` in the [TimeWheel] (if any) when it is not tracked yet.
+    /// The tracked keys are scheduled again by [Self::expire] until all their entries end.
+    fn schedule(&mut self, key: &str, until: DateTime<Utc>) {
+        if !self.tracked(key) {
+            if let Some(wheel) = &mut self.wheel {
+                wheel.insert(key.to_string(), until);
+            }
+        }
+    }
+
+    /// Return the metadata of `key`, removing the expired one.
+    fn metadata_of(&mut self, key: &str, now: DateTime<Utc>) -> Option<Arc<Metadata>> {
+        match self.metadata.get(key) {
+            Some((_, until)) if *until <= now => {
+                self.metadata.remove(key);
+                None
+            },
+            Some((metadata, _)) => Some(metadata.clone()),
+            None => None,
+        }
+    }
+
+    /// Give `key` `extra` quota for `ttl`, adding up with the active grant.
+    pub fn grant(&mut self, key: String, extra: u32, ttl: crate::time::Duration) {
+        let now = self.config().clock.now();
+        let extra = self.granted(&key, now) + extra;
+        self.schedule(&key, now + ttl);
+        self.grants.insert(key, (extra, now + ttl));
+    }
+
+    /// Return the active grant of `key`, removing the expired one.
+    fn granted(&mut self, key: &str, now: DateTime<Utc>) -> u32 {
+        match self.grants.get(key) {
+            Some((_, until)) if *until <= now => {
+                self.grants.remove(key);
+                0
+            },
+            Some((extra, _)) => *extra,
+            None => 0,
+        }
     }
 }
 
+/// Return the approximate memory of `map`, with `heap` as the heap memory of a value.
+fn entries_size<V>(map: &HashMap<String, V>, heap: impl Fn(&V) -> usize) -> usize {
+    map.iter()
+        .map(|(key, value)| std::mem::size_of::<(String, V)>() + key.capacity() + heap(value))
+        .sum()
+}
+
 impl Deref for MemStoreInner {
     type Target = HashMap<String, DateCount>;
 
@@ -167,7 +859,7 @@ mod tests {
 
     #[tokio::test]
     async fn incr_del() -> Result<(), ()> {
-        let store = MemStore::new(8, chrono::Duration::seconds(100000));
+        let store = MemStore::new(8, crate::time::Duration::seconds(100000));
 
         assert_eq!(store.incr("John".to_string()).await?.date_count.count, 1);
         assert_eq!(store.incr("John".to_string()).await?.date_count.count, 2);
@@ -194,9 +886,44 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn incr_many() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100000))
+            .with_hot_keys(["global"]);
+        store.incr("John".to_string()).await?;
+
+        let values = store.incr_many(vec![
+            ("global".to_string(), 2),
+            ("John".to_string(), 3),
+            ("Meg".to_string(), 1),
+        ]).await?;
+        assert_eq!(values.iter().map(Value::count).collect::<Vec<_>>(), [2, 4, 1]);
+        assert!(store.incr_many(Vec::new()).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn del_prefix() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100000));
+
+        store.incr("tenant-a:John".to_string()).await?;
+        store.incr("tenant-a:Meg".to_string()).await?;
+        store.incr("tenant-b:John".to_string()).await?;
+        store.grant("tenant-a:John".to_string(), 5, crate::time::Duration::seconds(100)).await?;
+
+        assert_eq!(store.del_prefix("tenant-a:").await?, Some(2));
+        assert_eq!(store.del_prefix("tenant-a:").await?, Some(0));
+        assert!(store.peek("tenant-a:John".to_string()).await?.is_none());
+        assert_eq!(store.incr("tenant-a:John".to_string()).await?.count(), 1);
+        assert_eq!(store.incr("tenant-b:John".to_string()).await?.count(), 2);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn clear() -> Result<(), ()> {
-        let store = MemStore::new(8, chrono::Duration::seconds(100000));
+        let store = MemStore::new(8, crate::time::Duration::seconds(100000));
 
         assert_eq!(store.incr("John".to_string()).await?.date_count.count, 1);
         assert_eq!(store.incr_by("Meg".to_string(), 3).await?.date_count.count, 3);
@@ -210,9 +937,14 @@ mod tests {
 
     #[tokio::test]
     async fn ttl() -> Result<(), ()> {
-        let store = MemStore::new(8, chrono::Duration::seconds(5));
+        let store = MemStore::new(8, crate::time::Duration::seconds(5));
         assert_eq!(store.incr("John".to_string()).await?.date_count.count, 1);
 
+        // the window is known while the store is in use.
+        let guard = store.inner.lock().await;
+        assert_eq!(store.window(), Some(crate::time::Duration::seconds(5)));
+        drop(guard);
+
         // wait 2 seconds to add a new one...
         tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
         assert_eq!(store.incr_by("Meg".to_string(), 3).await?.date_count.count, 3);
@@ -224,4 +956,267 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn incr_with_ttl() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100000));
+
+        let value = store.incr_with_ttl("John".to_string(), 1, crate::time::Duration::seconds(1)).await?;
+        assert_eq!(value.date_count.count, 1);
+        assert_eq!(value.until, value.date_count.create_date + crate::time::Duration::seconds(1));
+        assert_eq!(store.incr_with_ttl("John".to_string(), 1, crate::time::Duration::seconds(1)).await?.date_count.count, 2);
+        assert_eq!(store.incr("Meg".to_string()).await?.date_count.count, 1);
+
+        // "John" uses its own TTL, while "Meg" uses the TTL of store.
+        tokio::time::sleep(tokio::time::Duration::from_millis(1100)).await;
+        assert_eq!(store.incr_with_ttl("John".to_string(), 1, crate::time::Duration::seconds(1)).await?.date_count.count, 1);
+        assert_eq!(store.incr("Meg".to_string()).await?.date_count.count, 2);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "chrono")]
+    #[tokio::test]
+    async fn aligned_window() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::minutes(1))
+            .with_aligned_window(FixedOffset::east_opt(8 * 3600).unwrap());
+
+        let value = store.incr("John".to_string()).await?;
+        assert_eq!(value.date_count.create_date.timestamp() % 60, 0);
+        assert_eq!(value.until - value.date_count.create_date, crate::time::Duration::minutes(1));
+        assert!(value.until > Utc::now());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn token_bucket() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100000))
+            .with_token_bucket(TokenBucket { burst: 3, rate: 2.0 });
+
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 1);
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 2);
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 3);
+        // rejected requests do not take tokens
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 4);
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 4);
+        assert_eq!(store.incr("Meg".to_string()).await?.date_count.count, 1);
+
+        // one token is refilled every 500ms
+        tokio::time::sleep(tokio::time::Duration::from_millis(600)).await;
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 3);
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn configure_clone() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100000));
+        store.incr("John".to_string()).await?;
+
+        // a clone is configured while the store is in use, and still shares the counters.
+        let guard = store.inner.lock().await;
+        let clone = store.clone().with_expiration(Expiration::Inactivity).with_hot_keys(["global"]);
+        drop(guard);
+        assert_eq!(clone.incr("John".to_string()).await?.date_count.count, 2);
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 3);
+
+        // the settings are shared too.
+        assert_eq!(store.config.load().expiration, Expiration::Inactivity);
+        clone.incr("global".to_string()).await?;
+        assert_eq!(store.peek("global".to_string()).await?.map(|value| value.count()), Some(1));
+        assert!(!store.inner.lock().await.data.contains_key("global"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn token_bucket_sweep() -> Result<(), ()> {
+        let store = MemStore::new(2, crate::time::Duration::seconds(100000))
+            .with_token_bucket(TokenBucket { burst: 3, rate: 1000.0 });
+
+        store.incr("John".to_string()).await?;
+        store.incr("Meg".to_string()).await?;
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+
+        // the full buckets are removed when the buckets reach the capacity.
+        store.incr("Bob".to_string()).await?;
+        assert_eq!(store.inner.lock().await.buckets.len(), 1);
+
+        assert!(TokenBucket { burst: 3, rate: 0.0 }.validate().is_err());
+        assert!(TokenBucket { burst: 3, rate: f64::NAN }.validate().is_err());
+        assert!(TokenBucket { burst: 0, rate: 1.0 }.validate().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inactivity_expiration() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(2))
+            .with_expiration(Expiration::Inactivity);
+
+        // "John" keeps hitting, the count never resets.
+        for i in 1..=3 {
+            assert_eq!(store.incr("John".to_string()).await?.date_count.count, i);
+            tokio::time::sleep(tokio::time::Duration::from_millis(1500)).await;
+        }
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn grant() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100));
+
+        assert_eq!(store.incr_by("John".to_string(), 5).await?.date_count.count, 5);
+        store.grant("John".to_string(), 3, crate::time::Duration::milliseconds(100)).await?;
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 3);
+        store.grant("John".to_string(), 10, crate::time::Duration::milliseconds(100)).await?;
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 0);
+
+        // the grant expires, but the counter is kept.
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 8);
+
+        // the grants of hot keys lower their counts.
+        let store = MemStore::new(8, crate::time::Duration::seconds(100)).with_hot_keys(["Meg"]);
+        assert_eq!(store.incr_by("Meg".to_string(), 5).await?.date_count.count, 5);
+        store.grant("Meg".to_string(), 3, crate::time::Duration::milliseconds(100)).await?;
+        assert_eq!(store.incr("Meg".to_string()).await?.date_count.count, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metadata() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100));
+
+        assert!(store.incr("John".to_string()).await?.metadata().is_none());
+        let metadata = Metadata::from([("tier".to_string(), "gold".to_string())]);
+        store.set_metadata("John".to_string(), metadata, crate::time::Duration::milliseconds(100)).await?;
+        let value = store.incr("John".to_string()).await?;
+        assert_eq!(value.metadata().and_then(|metadata| metadata.get("tier")).map(String::as_str), Some("gold"));
+
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        assert!(store.incr("John".to_string()).await?.metadata().is_none());
+
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct FixedClock(DateTime<Utc>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn clock() -> Result<(), ()> {
+        let now = DateTime::from_timestamp(1_714_585_333, 0).unwrap();
+        let store = MemStore::new(8, crate::time::Duration::seconds(10))
+            .with_clock(FixedClock(now));
+
+        let value = store.incr("John".to_string()).await?;
+        assert_eq!(value.date_count.create_date, now);
+        assert_eq!(value.until, now + crate::time::Duration::seconds(10));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn key_ttl() -> Result<(), ()> {
+        let now = DateTime::from_timestamp(1_714_585_333, 0).unwrap();
+        let store = MemStore::new(8, crate::time::Duration::seconds(10))
+            .with_clock(FixedClock(now));
+
+        assert_eq!(store.ttl("John".to_string()).await?, None);
+        store.incr("John".to_string()).await?;
+        store.incr_with_ttl("Meg".to_string(), 1, crate::time::Duration::seconds(3)).await?;
+        assert_eq!(store.ttl("John".to_string()).await?, Some(crate::time::Duration::seconds(10)));
+        assert_eq!(store.ttl("Meg".to_string()).await?, Some(crate::time::Duration::seconds(3)));
+
+        // the counter is not increased.
+        assert_eq!(store.peek("John".to_string()).await?.map(|value| value.count()), Some(1));
+
+        Ok(())
+    }
+
+    #[derive(Debug, Clone)]
+    struct ManualClock(Arc<std::sync::Mutex<DateTime<Utc>>>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn time_wheel() -> Result<(), ()> {
+        let now = DateTime::from_timestamp(1_714_585_333, 0).unwrap();
+        let clock = ManualClock(Arc::new(std::sync::Mutex::new(now)));
+        let store = MemStore::new(8, crate::time::Duration::seconds(10))
+            .with_clock(clock.clone())
+            .with_time_wheel(crate::time::Duration::seconds(1));
+        let advance = |seconds: i64| *clock.0.lock().unwrap() += crate::time::Duration::seconds(seconds);
+
+        store.incr("John".to_string()).await?;
+        store.incr_with_ttl("Meg".to_string(), 1, crate::time::Duration::seconds(60)).await?;
+        advance(5);
+        store.incr("Ann".to_string()).await?;
+        assert_eq!(store.inner.lock().await.data.len(), 3);
+
+        // John is removed after its window, the other keys are kept.
+        advance(7);
+        store.incr("Ann".to_string()).await?;
+        assert!(!store.inner.lock().await.data.contains_key("John"));
+        assert_eq!(store.inner.lock().await.data.len(), 2);
+
+        // a key counted again in a new window is kept until the new window ends.
+        store.incr("John".to_string()).await?;
+        advance(5);
+        store.incr("Meg".to_string()).await?;
+        assert_eq!(store.inner.lock().await.data.len(), 2);
+        advance(60);
+        assert_eq!(store.incr("Meg".to_string()).await?.date_count.count, 1);
+        assert_eq!(store.inner.lock().await.data.len(), 1);
+
+        // the grants and metadata are removed when they end, with or without a window.
+        store.grant("Ann".to_string(), 5, crate::time::Duration::seconds(30)).await?;
+        store.set_metadata("Bob".to_string(), Metadata::default(), crate::time::Duration::seconds(5)).await?;
+        advance(20);
+        store.incr("Meg".to_string()).await?;
+        assert!(store.inner.lock().await.metadata.is_empty());
+        assert_eq!(store.inner.lock().await.grants.len(), 1);
+        advance(20);
+        store.incr("Meg".to_string()).await?;
+        assert!(store.inner.lock().await.grants.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hot_keys() -> Result<(), ()> {
+        let store = MemStore::new(8, crate::time::Duration::seconds(100))
+            .with_hot_keys(["global"]);
+
+        let (a, b) = tokio::join!(store.incr("global".to_string()), store.incr("global".to_string()));
+        assert_eq!(a?.count().max(b?.count()), 2);
+        assert_eq!(store.incr_by("global".to_string(), 3).await?.date_count.count, 5);
+        assert_eq!(store.peek("global".to_string()).await?.map(|value| value.count()), Some(5));
+        assert!(store.inner.lock().await.data.is_empty());
+
+        // other keys use the map
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 1);
+
+        store.del("global".to_string()).await?;
+        assert_eq!(store.incr("global".to_string()).await?.date_count.count, 1);
+
+        Ok(())
+    }
 }
@@ -1,13 +1,38 @@
 #![allow(unused_imports)]
 
+pub(crate) mod atomic;
+#[cfg(feature = "test-util")]
+pub mod chaos_store;
+pub mod clock;
+pub mod dual_write_store;
+#[cfg(feature = "chrono")]
+pub mod export;
 pub mod mem_store;
 #[cfg(feature = "redis-store")]
 pub mod redis_store;
+#[cfg(feature = "redis-store")]
+pub mod redis_codec;
+#[cfg(feature = "redis-store")]
+pub mod redis_sliding_store;
+#[cfg(feature = "redis-store")]
+pub mod redis_tenant_policy;
+pub mod replicated_store;
+pub mod schedule;
+pub mod sketch_store;
+pub mod static_store;
+pub(crate) mod time_wheel;
+
+pub use clock::{Clock, CoarseClock, SystemClock};
+pub use schedule::Schedule;
+#[cfg(feature = "chrono")]
+pub use schedule::{aligned_window_start, DailyQuota};
 
-use std::fmt::Debug;
-use std::ops::Deref;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::ops::{Deref, Sub};
 use std::sync::Arc;
-use chrono::{DateTime, Utc};
+use crate::time::{DateTime, Utc};
+use crate::error::ErrorClass;
 
 /// [Store] indicates the location and method of caching,
 /// such as storing in memory ([MemStore]) in the form of a HashMap
@@ -45,6 +70,30 @@ pub trait Store: Clone + Send + Sync {
     /// with val = 1.
     async fn incr(&self, key: Self::Key) -> Result<Self::Value, Self::Error>;
 
+    /// The [incr_with_ttl] function works like [incr_by], but uses `ttl`
+    /// instead of the default TTL of the [Store] when a new window starts,
+    /// so a single [Store] can serve limits with different windows.
+    ///
+    /// The default implementation ignores `ttl` and calls [incr_by], and
+    /// [supports_ttl] returns false, so the middleware refuses the windows it would ignore.
+    async fn incr_with_ttl(&self, key: Self::Key, val: Self::Count, ttl: crate::time::Duration) -> Result<Self::Value, Self::Error> {
+        let _ = ttl;
+        self.incr_by(key, val).await
+    }
+
+    /// The [incr_many] function increases each key by its count, such as when a request
+    /// charges several counters (global, per-tenant and per-endpoint), and returns the
+    /// values in the same order. A [Store] should run them in one round-trip.
+    ///
+    /// The default implementation calls [incr_by] for each key in turn.
+    async fn incr_many(&self, charges: Vec<(Self::Key, Self::Count)>) -> Result<Vec<Self::Value>, Self::Error> {
+        let mut values = Vec::with_capacity(charges.len());
+        for (key, val) in charges {
+            values.push(self.incr_by(key, val).await?);
+        }
+        Ok(values)
+    }
+
     /// The [del] function deletes the storage of
     /// the index [Key] and returns the count result
     /// before deletion.
@@ -58,11 +107,124 @@ pub trait Store: Clone + Send + Sync {
     /// relational database, bulk clearing of data
     /// is slow and unnecessary), the function can do nothing.
     async fn clear(&self) -> Result<(), Self::Error>;
+
+    /// The [del_prefix] function deletes the counters of all keys starting with `prefix`,
+    /// with their grants and metadata, such as all counters of a tenant or of a policy
+    /// namespace at once. Returns the number of deleted counters, or [None] if the
+    /// [Store] cannot delete by prefix.
+    ///
+    /// This function is not mandatory; the default implementation returns [None].
+    async fn del_prefix(&self, prefix: &str) -> Result<Option<usize>, Self::Error> {
+        let _ = prefix;
+        Ok(None)
+    }
+
+    /// The [peek] function returns the value of `key` without increasing it,
+    /// or [None] if the key does not exist.
+    ///
+    /// This function is not mandatory; the default implementation returns [None].
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        let _ = key;
+        Ok(None)
+    }
+
+    /// The [ttl] function returns the time until the window of `key` resets,
+    /// or [None] if the key does not exist, without increasing it,
+    /// such as to show users when their quota resets.
+    ///
+    /// The default implementation uses the [Value::expire_date] of [peek].
+    async fn ttl(&self, key: Self::Key) -> Result<Option<crate::time::Duration>, Self::Error> {
+        let now = Utc::now();
+        Ok(self.peek(key).await?
+            .and_then(|value| value.expire_date())
+            .map(|until| (until - now).max(crate::time::Duration::zero())))
+    }
+
+    /// The [snapshot] function returns the usage of all keys in their current windows,
+    /// such as for billing or analytics (see [spawn_usage_exporter](crate::store::export::spawn_usage_exporter)).
+    /// The counts are not reduced by grants.
+    ///
+    /// This function is not mandatory; the default implementation returns nothing.
+    async fn snapshot(&self) -> Result<Vec<(Self::Key, Self::Value)>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// The [stats] function returns the size of the [Store], such as the number of
+    /// active keys, for capacity planning of the limiter itself
+    /// (see [spawn_stats_exporter](crate::store::export::spawn_stats_exporter)).
+    ///
+    /// The default implementation counts the keys of [snapshot].
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        let active_keys = self.snapshot().await?.len();
+        Ok(StoreStats {
+            active_keys,
+            stored_keys: active_keys,
+            approx_bytes: None,
+        })
+    }
+
+    /// The [set_metadata] function attaches `metadata` to `key` for `ttl`,
+    /// replacing the previous one. The values returned by [incr_by]
+    /// carry the metadata (see [Value::metadata]).
+    ///
+    /// This function is not mandatory; the default implementation does nothing.
+    async fn set_metadata(&self, key: Self::Key, metadata: Metadata, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        let _ = (key, metadata, ttl);
+        Ok(())
+    }
+
+    /// The [grant] function gives `key` `extra` quota for `ttl`, without
+    /// resetting its counter. While the grant is active, the counts returned
+    /// by [incr_by] are reduced by `extra`. Grants on the same key add up.
+    ///
+    /// This function is not mandatory; the default implementation does nothing.
+    /// The stores which implement it are marked with [GrantStore], which the features
+    /// rolling back charges require, such as [RateLimit::with_hierarchy](crate::middleware::RateLimit::with_hierarchy).
+    async fn grant(&self, key: Self::Key, extra: Self::Count, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        let _ = (key, extra, ttl);
+        Ok(())
+    }
+
+    /// The [classify_error] function classifies the errors of this [Store].
+    ///
+    /// The default implementation treats all errors as [ErrorClass::Fatal].
+    fn classify_error(&self, error: &Self::Error) -> ErrorClass {
+        let _ = error;
+        ErrorClass::Fatal
+    }
+
+    /// The [window] function returns the window (TTL) of the keys, such as to validate
+    /// it in [RateLimit::try_new](crate::middleware::RateLimit::try_new).
+    ///
+    /// The default implementation returns [None], as the window is unknown.
+    fn window(&self) -> Option<crate::time::Duration> {
+        None
+    }
+
+    /// The [supports_ttl] function returns true if [incr_with_ttl] starts the new windows
+    /// with its `ttl`, as checked by [RateLimit::validate](crate::middleware::RateLimit::validate)
+    /// for the windows of the policies and of [RateLimit::scoped](crate::middleware::RateLimit::scoped).
+    ///
+    /// The default implementation returns false, as [incr_with_ttl] ignores `ttl`.
+    fn supports_ttl(&self) -> bool {
+        false
+    }
 }
 
+/// [GrantStore] marks the [Store]s which implement [Store::grant], so a charge can be
+/// refunded, such as by [Reservation](crate::reservation::Reservation).
+pub trait GrantStore: Store {}
+
+impl<T: GrantStore> GrantStore for Arc<T> {}
+
+impl<T: GrantStore> GrantStore for &T {}
+
 pub trait Value: Send + Clone + Debug {
     /// [Count] is the type of the counter, such as [u32].
-    type Count: Send + PartialOrd + Clone;
+    ///
+    /// Since 0.3, it is [Display] (to render the quota headers) and [Sub] (to compute
+    /// the remaining count), as the primitive integers are.
+    type Count: Send + PartialOrd + Clone + Display + Sub<Output = Self::Count>;
 
     /// Return the count value from the counter.
     fn count(&self) -> Self::Count;
@@ -72,6 +234,59 @@ pub trait Value: Send + Clone + Debug {
 
     /// Return the expiration time.
     fn expire_date(&self) -> Option<DateTime<Utc>>;
+
+    /// Return the [Metadata] attached to the key (see [Store::set_metadata]),
+    /// so hooks can enrich decisions and logs without another lookup.
+    fn metadata(&self) -> Option<&Metadata> {
+        None
+    }
+
+    /// Return false if the increment which returned this value was not counted by the store,
+    /// such as a request rejected by a token bucket, so the middleware does not refund it.
+    fn is_charged(&self) -> bool {
+        true
+    }
+}
+
+/// [StoreStats] is the size of a [Store], see [Store::stats].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct StoreStats {
+    /// The number of distinct keys in their current windows.
+    pub active_keys: usize,
+    /// The number of keys held by the [Store], including the expired ones
+    /// which are not evicted yet, and the keys of grants and metadata.
+    pub stored_keys: usize,
+    /// The approximate memory used by the keys in bytes, if known.
+    pub approx_bytes: Option<usize>,
+}
+
+/// [Metadata] is attached to a key, such as the tier name,
+/// the first violation time, or the country.
+pub type Metadata = HashMap<String, String>;
+
+/// The key of the operator note in [Metadata], see [RateLimit::annotate](crate::middleware::RateLimit::annotate).
+pub const NOTE_METADATA_KEY: &str = "note";
+
+/// The key of the operator flags in [Metadata], separated by commas,
+/// see [RateLimit::annotate](crate::middleware::RateLimit::annotate).
+pub const FLAGS_METADATA_KEY: &str = "flags";
+
+/// Check if the operator flags of `metadata` contain `flag`, such as `do-not-ban`.
+pub fn has_flag(metadata: &Metadata, flag: &str) -> bool {
+    metadata.get(FLAGS_METADATA_KEY)
+        .is_some_and(|flags| flags.split(',').any(|f| f == flag))
+}
+
+/// [Expiration] decides how the TTL of a key is counted.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum Expiration {
+    /// The TTL is set on the first hit of a window,
+    /// and the count resets when the window ends.
+    #[default]
+    FixedWindow,
+    /// The TTL is refreshed on every hit, so the count resets only
+    /// after a period of inactivity (such as an abuse cool-down).
+    Inactivity,
 }
 
 #[async_trait::async_trait]
@@ -89,6 +304,14 @@ impl<T: Store> Store for Arc<T> {
         self.deref().incr(key).await
     }
 
+    async fn incr_with_ttl(&self, key: Self::Key, val: Self::Count, ttl: crate::time::Duration) -> Result<Self::Value, Self::Error> {
+        self.deref().incr_with_ttl(key, val, ttl).await
+    }
+
+    async fn incr_many(&self, charges: Vec<(Self::Key, Self::Count)>) -> Result<Vec<Self::Value>, Self::Error> {
+        self.deref().incr_many(charges).await
+    }
+
     async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
         self.deref().del(key).await
     }
@@ -96,10 +319,50 @@ impl<T: Store> Store for Arc<T> {
     async fn clear(&self) -> Result<(), Self::Error> {
         self.deref().clear().await
     }
+
+    async fn del_prefix(&self, prefix: &str) -> Result<Option<usize>, Self::Error> {
+        self.deref().del_prefix(prefix).await
+    }
+
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        self.deref().peek(key).await
+    }
+
+    async fn ttl(&self, key: Self::Key) -> Result<Option<crate::time::Duration>, Self::Error> {
+        self.deref().ttl(key).await
+    }
+
+    async fn snapshot(&self) -> Result<Vec<(Self::Key, Self::Value)>, Self::Error> {
+        self.deref().snapshot().await
+    }
+
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        self.deref().stats().await
+    }
+
+    async fn set_metadata(&self, key: Self::Key, metadata: Metadata, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        self.deref().set_metadata(key, metadata, ttl).await
+    }
+
+    async fn grant(&self, key: Self::Key, extra: Self::Count, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        self.deref().grant(key, extra, ttl).await
+    }
+
+    fn classify_error(&self, error: &Self::Error) -> ErrorClass {
+        self.deref().classify_error(error)
+    }
+
+    fn window(&self) -> Option<crate::time::Duration> {
+        self.deref().window()
+    }
+
+    fn supports_ttl(&self) -> bool {
+        self.deref().supports_ttl()
+    }
 }
 
 #[async_trait::async_trait]
-impl<'a, T: Store> Store for &'a T {
+impl<T: Store> Store for &T {
     type Error = T::Error;
     type Key = T::Key;
     type Value = T::Value;
@@ -113,6 +376,14 @@ impl<'a, T: Store> Store for &'a T {
         (*self).incr(key).await
     }
 
+    async fn incr_with_ttl(&self, key: Self::Key, val: Self::Count, ttl: crate::time::Duration) -> Result<Self::Value, Self::Error> {
+        (*self).incr_with_ttl(key, val, ttl).await
+    }
+
+    async fn incr_many(&self, charges: Vec<(Self::Key, Self::Count)>) -> Result<Vec<Self::Value>, Self::Error> {
+        (*self).incr_many(charges).await
+    }
+
     async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
         (*self).del(key).await
     }
@@ -120,4 +391,44 @@ impl<'a, T: Store> Store for &'a T {
     async fn clear(&self) -> Result<(), Self::Error> {
         (*self).clear().await
     }
+
+    async fn del_prefix(&self, prefix: &str) -> Result<Option<usize>, Self::Error> {
+        (*self).del_prefix(prefix).await
+    }
+
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        (*self).peek(key).await
+    }
+
+    async fn ttl(&self, key: Self::Key) -> Result<Option<crate::time::Duration>, Self::Error> {
+        (*self).ttl(key).await
+    }
+
+    async fn snapshot(&self) -> Result<Vec<(Self::Key, Self::Value)>, Self::Error> {
+        (*self).snapshot().await
+    }
+
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        (*self).stats().await
+    }
+
+    async fn set_metadata(&self, key: Self::Key, metadata: Metadata, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        (*self).set_metadata(key, metadata, ttl).await
+    }
+
+    async fn grant(&self, key: Self::Key, extra: Self::Count, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        (*self).grant(key, extra, ttl).await
+    }
+
+    fn classify_error(&self, error: &Self::Error) -> ErrorClass {
+        (*self).classify_error(error)
+    }
+
+    fn window(&self) -> Option<crate::time::Duration> {
+        (*self).window()
+    }
+
+    fn supports_ttl(&self) -> bool {
+        (*self).supports_ttl()
+    }
 }
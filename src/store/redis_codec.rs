@@ -0,0 +1,62 @@
+use redis::{ErrorKind, RedisError, RedisResult};
+
+/// [RedisCodec] encodes and decodes the records stored alongside the counters
+/// of a [RedisStore](crate::store::redis_store::RedisStore), such as JSON or msgpack structs
+/// with the tier, the first-seen time, or the flags of a key.
+///
+/// ```rust
+/// use actix_rl::store::redis_codec::RedisCodec;
+/// use redis::RedisResult;
+///
+/// struct Penalty {
+///     level: u8,
+/// }
+///
+/// struct PenaltyCodec;
+///
+/// impl RedisCodec<Penalty> for PenaltyCodec {
+///     fn encode(&self, value: &Penalty) -> RedisResult<Vec<u8>> {
+///         Ok(vec![value.level])
+///     }
+///
+///     fn decode(&self, bytes: &[u8]) -> RedisResult<Penalty> {
+///         Ok(Penalty { level: bytes.first().copied().unwrap_or_default() })
+///     }
+/// }
+/// ```
+pub trait RedisCodec<T>: Send + Sync {
+    /// Encode `value` into bytes.
+    fn encode(&self, value: &T) -> RedisResult<Vec<u8>>;
+
+    /// Decode the bytes produced by [Self::encode].
+    fn decode(&self, bytes: &[u8]) -> RedisResult<T>;
+}
+
+/// [Utf8Codec] stores [String] as is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8Codec;
+
+impl RedisCodec<String> for Utf8Codec {
+    fn encode(&self, value: &String) -> RedisResult<Vec<u8>> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> RedisResult<String> {
+        String::from_utf8(bytes.to_vec())
+            .map_err(|e| RedisError::from((ErrorKind::TypeError, "invalid utf-8", e.to_string())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_codec() -> RedisResult<()> {
+        let bytes = Utf8Codec.encode(&"gold".to_string())?;
+        assert_eq!(Utf8Codec.decode(&bytes)?, "gold");
+        assert!(Utf8Codec.decode(&[0xff, 0xfe]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::time::{DateTime, Utc};
+use redis::{AsyncCommands, RedisResult, Script};
+use crate::error::ErrorClass;
+use crate::store::redis_store::{RateLimitResult, RedisStore, RedisStoreInner};
+use crate::store::{GrantStore, Metadata, Store, StoreStats};
+
+/// The sliding-log script.
+///
+/// KEYS[1]: the key of the log.
+/// KEYS[2]: the key of the grant.
+/// KEYS[3]: the key of the metadata.
+/// ARGV[1]: the window in milliseconds.
+/// ARGV[2]: the increment.
+/// ARGV[3]: the unique id of this call.
+///
+/// Returns `{count, the score of the oldest entry, grant, metadata}`.
+pub(crate) const SLIDING_LOG_SCRIPT: &str = r#"
+local key = KEYS[1]
+local window = tonumber(ARGV[1])
+local val = tonumber(ARGV[2])
+local id = ARGV[3]
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+redis.call('ZREMRANGEBYSCORE', key, '-inf', now - window)
+for i = 1, val do
+    redis.call('ZADD', key, now, id .. ':' .. i)
+end
+redis.call('PEXPIRE', key, window)
+
+local count = redis.call('ZCARD', key)
+local oldest = redis.call('ZRANGE', key, 0, 0, 'WITHSCORES')
+local oldest_score = now
+if oldest[2] then
+    oldest_score = tonumber(oldest[2])
+end
+
+local grant = tonumber(redis.call('GET', KEYS[2]) or '0')
+
+local metadata = redis.call('HGETALL', KEYS[3])
+
+return {count, oldest_score, grant, metadata}
+"#;
+
+/// [RedisSlidingStore] stores data in redis, using the sliding-log algorithm:
+/// each hit is a member of a sorted set scored by its time, and the count is the
+/// number of hits in the last window. It is precise, at the cost of one
+/// sorted-set member per hit.
+///
+/// All operations run in one Lua script, using the time of the redis server,
+/// or in one `MULTI`/`EXEC` transaction if scripting is disabled (see [Self::from_store]).
+#[derive(Clone)]
+pub struct RedisSlidingStore {
+    pub(crate) inner: Arc<RedisStoreInner>,
+    pub(crate) script: Script,
+}
+
+impl RedisSlidingStore {
+    /// create from a [redis::Client]
+    pub fn from_client<T: ToString>(client: redis::Client, prefix: T, window: crate::time::Duration) -> Self {
+        Self::from_store(RedisStore::from_client(client, prefix, window))
+    }
+
+    /// create from a [RedisStore], using its client, prefix, TTL (as the window), timeouts,
+    /// error classifier and scripting (see [RedisStore::with_scripting]).
+    /// Other options of [RedisStore] (such as the deny cache and batching) are not used.
+    ///
+    /// Without scripting, the commands of the script run in a `MULTI`/`EXEC` transaction,
+    /// which is as atomic, but uses the local time of each instance instead of the time
+    /// of the redis server, so the clock skew between the instances shifts their windows.
+    pub fn from_store(store: RedisStore) -> Self {
+        Self {
+            inner: store.inner,
+            script: Script::new(SLIDING_LOG_SCRIPT),
+        }
+    }
+
+    /// Connect to redis and load the sliding-log script, see [RedisStore::warmup].
+    pub async fn warmup(&self) -> RedisResult<()> {
+        RedisStore { inner: self.inner.clone() }.warmup().await
+    }
+
+    /// Return the transaction of the sliding-log script, at the local time `now` in milliseconds.
+    /// The results are the count, the oldest entry with its score, the grant and the metadata.
+    fn transaction(&self, redis_key: &str, now: i64, val: i32, ttl: crate::time::Duration) -> redis::Pipeline {
+        let window = ttl.num_milliseconds();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        pipe.cmd("ZREMRANGEBYSCORE").arg(redis_key).arg("-inf").arg(now - window).ignore();
+        if val > 0 {
+            let id = Self::unique_id();
+            let cmd = pipe.cmd("ZADD").arg(redis_key);
+            for i in 1..=val {
+                cmd.arg(now).arg(format!("{}:{}", id, i));
+            }
+            cmd.ignore();
+        }
+        pipe.cmd("PEXPIRE").arg(redis_key).arg(window).ignore();
+
+        pipe.cmd("ZCARD").arg(redis_key)
+            .cmd("ZRANGE").arg(redis_key).arg(0).arg(0).arg("WITHSCORES")
+            .cmd("GET").arg(self.inner.grant_key(redis_key))
+            .cmd("HGETALL").arg(self.inner.metadata_key(redis_key));
+        pipe
+    }
+
+    /// Return a unique id for each call.
+    fn unique_id() -> String {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        format!(
+            "{}-{}-{}",
+            std::process::id(),
+            Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            SEQ.fetch_add(1, Ordering::Relaxed),
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for RedisSlidingStore {
+    type Error = redis::RedisError;
+    type Key = String;
+    type Value = RateLimitResult;
+    type Count = i32;
+
+    async fn incr_by(&self, key: Self::Key, val: Self::Count) -> Result<Self::Value, Self::Error> {
+        self.incr_with_ttl(key, val, self.inner.ttl).await
+    }
+
+    async fn incr(&self, key: Self::Key) -> Result<Self::Value, Self::Error> {
+        self.incr_by(key, 1).await
+    }
+
+    async fn incr_with_ttl(&self, key: Self::Key, val: Self::Count, ttl: crate::time::Duration) -> Result<Self::Value, Self::Error> {
+        let redis_key = self.inner.get_key(&key);
+        let mut conn = self.inner.conn().await?;
+
+        let (count, oldest, granted, metadata): (i32, i64, i32, Metadata) = if self.inner.scripting {
+            self.script
+                .key(&redis_key)
+                .key(self.inner.grant_key(&redis_key))
+                .key(self.inner.metadata_key(&redis_key))
+                .arg(ttl.num_milliseconds())
+                .arg(val)
+                .arg(Self::unique_id())
+                .invoke_async(&mut conn)
+                .await?
+        } else {
+            let now = Utc::now().timestamp_millis();
+            let (count, oldest, granted, metadata): (i32, Vec<(String, i64)>, Option<i32>, Metadata) =
+                self.transaction(&redis_key, now, val, ttl)
+                    .query_async(&mut conn)
+                    .await?;
+            let oldest = oldest.first().map_or(now, |(_, score)| *score);
+            (count, oldest, granted.unwrap_or(0), metadata)
+        };
+
+        // the count decreases when the oldest hit leaves the window.
+        let expire_date = DateTime::from_timestamp_millis(oldest)
+            .map(|oldest| oldest + ttl)
+            .unwrap_or_else(|| Utc::now() + ttl);
+
+        Ok(RateLimitResult {
+            count: count.saturating_sub(granted).max(0),
+            expire_date,
+            metadata: (!metadata.is_empty()).then(|| Arc::new(metadata)),
+            charged: true,
+        })
+    }
+
+    async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        let redis_key = self.inner.get_key(key);
+        let mut conn = self.inner.conn().await?;
+        conn.del::<_, ()>(redis_key).await?;
+
+        Ok(None)
+    }
+
+    /// Since we cannot clear all data in redis, here we do nothing.
+    async fn clear(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Scan the keys starting with `prefix` and `UNLINK` them, see [RedisStore](crate::store::redis_store::RedisStore).
+    async fn del_prefix(&self, prefix: &str) -> Result<Option<usize>, Self::Error> {
+        self.inner.del_prefix(prefix).await.map(Some)
+    }
+
+    /// Count the hits in the last window, using the local time.
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        let redis_key = self.inner.get_key(key);
+        let now = Utc::now();
+        let mut conn = self.inner.conn().await?;
+        let min = format!("({}", (now - self.inner.ttl).timestamp_millis());
+        let (count, oldest, granted, metadata): (i32, Vec<(String, i64)>, Option<i32>, Metadata) = redis::pipe()
+            .cmd("ZCOUNT").arg(&redis_key).arg(&min).arg("+inf")
+            .cmd("ZRANGEBYSCORE").arg(&redis_key).arg(&min).arg("+inf").arg("WITHSCORES").arg("LIMIT").arg(0).arg(1)
+            .cmd("GET").arg(self.inner.grant_key(&redis_key))
+            .cmd("HGETALL").arg(self.inner.metadata_key(&redis_key))
+            .query_async(&mut conn)
+            .await?;
+
+        if count == 0 {
+            return Ok(None);
+        }
+
+        let expire_date = oldest.first()
+            .and_then(|(_, oldest)| DateTime::from_timestamp_millis(*oldest))
+            .map(|oldest| oldest + self.inner.ttl)
+            .unwrap_or(now + self.inner.ttl);
+
+        Ok(Some(RateLimitResult {
+            count: count.saturating_sub(granted.unwrap_or(0)).max(0),
+            expire_date,
+            metadata: (!metadata.is_empty()).then(|| Arc::new(metadata)),
+            charged: true,
+        }))
+    }
+
+    /// Scan the keys with the prefix, which may be slow with many keys.
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        self.inner.stats().await
+    }
+
+    async fn set_metadata(&self, key: Self::Key, metadata: Metadata, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        self.inner.set_metadata(&self.inner.get_key(key), metadata, ttl).await
+    }
+
+    async fn grant(&self, key: Self::Key, extra: Self::Count, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        self.inner.grant(&self.inner.get_key(key), extra, ttl).await
+    }
+
+    fn classify_error(&self, error: &Self::Error) -> ErrorClass {
+        (self.inner.classify_error)(error)
+    }
+
+    fn window(&self) -> Option<crate::time::Duration> {
+        Some(self.inner.ttl)
+    }
+
+    fn supports_ttl(&self) -> bool {
+        true
+    }
+}
+
+impl GrantStore for RedisSlidingStore {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transaction() {
+        let store = RedisSlidingStore::from_store(
+            RedisStore::from_url("redis://127.0.0.1", "test", crate::time::Duration::seconds(10)).unwrap()
+                .with_scripting(false)
+        );
+        assert!(!store.inner.scripting);
+
+        let packed = store.transaction("test-John", 1_700_000_000_000, 2, crate::time::Duration::seconds(10))
+            .get_packed_pipeline();
+        let packed = String::from_utf8_lossy(&packed);
+        assert!(packed.starts_with("*1\r\n$5\r\nMULTI\r\n"));
+        assert!(packed.contains("$4\r\nEXEC\r\n"));
+        assert!(packed.contains("1699999990000"));
+        assert_eq!(packed.matches("1700000000000").count(), 2);
+        assert!(!packed.contains("EVAL"));
+
+        let packed = store.transaction("test-John", 1_700_000_000_000, 0, crate::time::Duration::seconds(10))
+            .get_packed_pipeline();
+        assert!(!String::from_utf8_lossy(&packed).contains("ZADD"));
+    }
+}
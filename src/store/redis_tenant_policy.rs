@@ -0,0 +1,180 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use redis::AsyncCommands;
+use crate::policy::Policy;
+use crate::policy_provider::KeyPolicyProvider;
+use crate::store::redis_store::{RedisStore, RedisStoreInner};
+use crate::utils::parse_duration;
+
+/// The max number of cached tenants. Beyond it, the oldest tenant is evicted for each new one.
+const MAX_CACHED_TENANTS: usize = 65536;
+
+/// The time the errors of redis are cached, at most.
+const ERROR_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// [TenantCache] is the cached overrides, with the time they expire,
+/// and the order they were cached in, to evict the oldest first.
+#[derive(Default)]
+struct TenantCache {
+    /// The override of each tenant, the time it expires, and its sequence number.
+    entries: HashMap<String, (Option<Policy>, Instant, u64)>,
+    /// The tenants by sequence number, including those cached again since.
+    order: VecDeque<(String, u64)>,
+    next: u64,
+}
+
+impl TenantCache {
+    fn insert(&mut self, key: String, policy: Option<Policy>, until: Instant) {
+        let seq = self.next;
+        self.next += 1;
+        self.entries.insert(key.clone(), (policy, until, seq));
+        self.order.push_back((key, seq));
+
+        while self.entries.len() > MAX_CACHED_TENANTS {
+            let Some((key, seq)) = self.order.pop_front() else {
+                break;
+            };
+            if self.entries.get(&key).is_some_and(|entry| entry.2 == seq) {
+                self.entries.remove(&key);
+            }
+        }
+
+        // drop the tenants cached again since, once they are half of the order.
+        if self.order.len() > 2 * self.entries.len() {
+            let entries = &self.entries;
+            self.order.retain(|(key, seq)| entries.get(key).is_some_and(|entry| entry.2 == *seq));
+        }
+    }
+}
+
+/// [RedisTenantPolicies] loads the per-tenant overrides stored in redis,
+/// so the limits of a single tenant can be changed with `redis-cli`, without a deploy.
+///
+/// The override of the tenant `{key}` is the hash `{prefix}{key}`, with the fields
+/// `max` and `window` (a humantime-style string, see [parse_duration]).
+/// `window` is optional, the TTL of the [RedisStore] is used if it is missing.
+///
+/// ```text
+/// redis-cli HSET rl:tenant:acme max 1000 window 1m
+/// ```
+///
+/// The overrides (and the tenants without one) are cached locally for `cache_ttl`,
+/// and the invalid ones are ignored. When redis fails, the tenant has no override
+/// for a second (or `cache_ttl` if shorter), so an outage does not cost a connection
+/// per request. Up to 65536 tenants are cached, the oldest is evicted first. Use it with
+/// [with_key_policies](crate::middleware::RateLimit::with_key_policies).
+#[derive(Clone)]
+pub struct RedisTenantPolicies {
+    inner: Arc<RedisStoreInner>,
+    prefix: String,
+    cache_ttl: std::time::Duration,
+    cache: Arc<Mutex<TenantCache>>,
+}
+
+impl RedisTenantPolicies {
+    /// create from a [RedisStore], using its connection and TTL.
+    pub fn from_store<T: ToString>(store: &RedisStore, prefix: T, cache_ttl: std::time::Duration) -> Self {
+        Self {
+            inner: store.inner.clone(),
+            prefix: prefix.to_string(),
+            cache_ttl,
+            cache: Default::default(),
+        }
+    }
+
+    /// Parse the fields of the override hash.
+    fn parse(fields: &HashMap<String, String>, default_window: crate::time::Duration) -> Option<Policy> {
+        let max = fields.get("max")?.trim().parse().ok()?;
+        let window = match fields.get("window") {
+            Some(window) => parse_duration(window).ok()?,
+            None => default_window,
+        };
+
+        let policy = Policy::fixed_window(max, window);
+        policy.validate().ok().map(|_| policy)
+    }
+
+    fn cached(&self, key: &str) -> Option<Option<Policy>> {
+        let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        cache.entries.get(key)
+            .filter(|(_, until, _)| *until > Instant::now())
+            .map(|(policy, _, _)| *policy)
+    }
+
+    fn insert(&self, key: String, policy: Option<Policy>, ttl: std::time::Duration) {
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        cache.insert(key, policy, Instant::now() + ttl);
+    }
+
+    /// Load the override of `key` from redis.
+    async fn load(&self, key: &str) -> redis::RedisResult<Option<Policy>> {
+        let mut conn = self.inner.conn().await?;
+        let fields: HashMap<String, String> = conn.hgetall(format!("{}{}", self.prefix, key)).await?;
+        Ok(Self::parse(&fields, self.inner.ttl))
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyPolicyProvider<String> for RedisTenantPolicies {
+    /// Errors of redis are cached briefly as no override, the policy of the middleware applies.
+    async fn policy(&self, key: String) -> Option<Policy> {
+        if let Some(policy) = self.cached(&key) {
+            return policy;
+        }
+
+        let (policy, ttl) = match self.load(&key).await {
+            Ok(policy) => (policy, self.cache_ttl),
+            Err(_) => (None, self.cache_ttl.min(ERROR_CACHE_TTL)),
+        };
+        self.insert(key, policy, ttl);
+
+        policy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let ttl = crate::time::Duration::seconds(10);
+        let fields = |pairs: &[(&str, &str)]| pairs.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+
+        assert_eq!(
+            RedisTenantPolicies::parse(&fields(&[("max", "1000"), ("window", "1m")]), ttl),
+            Some(Policy::fixed_window(1000, crate::time::Duration::minutes(1))),
+        );
+        assert_eq!(RedisTenantPolicies::parse(&fields(&[("max", "5")]), ttl), Some(Policy::fixed_window(5, ttl)));
+        assert_eq!(RedisTenantPolicies::parse(&fields(&[]), ttl), None);
+        assert_eq!(RedisTenantPolicies::parse(&fields(&[("max", "0")]), ttl), None);
+        assert_eq!(RedisTenantPolicies::parse(&fields(&[("max", "5"), ("window", "1y")]), ttl), None);
+    }
+
+    #[tokio::test]
+    async fn cache() {
+        // nothing listens on port 1, the cached override is used.
+        let store = RedisStore::from_url("redis://127.0.0.1:1", "test", crate::time::Duration::seconds(10)).unwrap();
+        let policies = RedisTenantPolicies::from_store(&store, "rl:tenant:", std::time::Duration::from_secs(60));
+        assert_eq!(policies.policy("acme".to_string()).await, None);
+        // the error is cached briefly.
+        assert!(policies.cached("acme").is_some());
+
+        let policy = Policy::fixed_window(1000, crate::time::Duration::minutes(1));
+        policies.insert("acme".to_string(), Some(policy), std::time::Duration::from_secs(60));
+        assert_eq!(policies.policy("acme".to_string()).await, Some(policy));
+
+        // the cache stays under the cap.
+        for i in 0..MAX_CACHED_TENANTS + 10 {
+            policies.insert(format!("tenant:{}", i), None, std::time::Duration::from_secs(60));
+        }
+        let cache = policies.cache.lock().unwrap();
+        assert_eq!(cache.entries.len(), MAX_CACHED_TENANTS);
+        assert!(!cache.entries.contains_key("tenant:0"));
+        assert!(cache.entries.contains_key(&format!("tenant:{}", MAX_CACHED_TENANTS + 9)));
+        assert!(cache.order.len() <= 2 * MAX_CACHED_TENANTS);
+    }
+}
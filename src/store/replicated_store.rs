@@ -0,0 +1,293 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::mpsc;
+use crate::error::ErrorClass;
+use crate::store::{GrantStore, Metadata, Store, StoreStats};
+
+/// The default number of operations queued for each peer of [ReplicatedStore].
+pub const DEFAULT_MAX_PENDING: usize = 10_000;
+
+/// [ReplicatedStore] replicates the counters across regions.
+///
+/// Each operation runs on the `local` [Store] (such as the [RedisStore](crate::store::redis_store::RedisStore)
+/// of the local region) and its result is returned, while the same operation is queued for
+/// each of the `peers` (the stores of other regions), and applied in order by one background
+/// task per peer. Errors of the peers are ignored.
+///
+/// At most [DEFAULT_MAX_PENDING] operations are queued for each peer (see [Self::with_max_pending]),
+/// so a slow or unreachable peer does not hold more memory or tasks. The operations over it
+/// are dropped, and counted in [Self::dropped].
+///
+/// Since the peers are updated asynchronously, the limits are approximately global:
+/// a burst across regions may exceed `max` by the hits which are not replicated yet,
+/// but requests never wait for cross-region round trips.
+///
+/// Must be used inside a tokio runtime, such as an actix-web server.
+///
+/// ```rust
+/// use actix_rl::store::mem_store::MemStore;
+/// use actix_rl::store::replicated_store::ReplicatedStore;
+///
+/// let local = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let peer = MemStore::new(1024, actix_rl::time::Duration::seconds(60));
+/// let store = ReplicatedStore::new(local, vec![peer]);
+/// ```
+#[derive(Clone)]
+pub struct ReplicatedStore<S: Store> {
+    local: S,
+    peers: Arc<Vec<S>>,
+    max_pending: usize,
+    /// the queues of the peers, created with their tasks on the first operation.
+    queues: Arc<OnceLock<Vec<mpsc::Sender<Operation<S>>>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// [Operation] is an operation of [ReplicatedStore] queued for a peer.
+#[derive(Clone)]
+enum Operation<S: Store> {
+    IncrBy(S::Key, S::Count),
+    Incr(S::Key),
+    IncrWithTtl(S::Key, S::Count, crate::time::Duration),
+    IncrMany(Vec<(S::Key, S::Count)>),
+    Del(S::Key),
+    Clear,
+    DelPrefix(String),
+    SetMetadata(S::Key, Metadata, crate::time::Duration),
+    Grant(S::Key, S::Count, crate::time::Duration),
+}
+
+impl<S: Store> Operation<S> {
+    /// Apply the operation on `peer`, ignoring the result.
+    async fn apply(self, peer: &S) {
+        let _ = match self {
+            Operation::IncrBy(key, val) => peer.incr_by(key, val).await.map(drop),
+            Operation::Incr(key) => peer.incr(key).await.map(drop),
+            Operation::IncrWithTtl(key, val, ttl) => peer.incr_with_ttl(key, val, ttl).await.map(drop),
+            Operation::IncrMany(charges) => peer.incr_many(charges).await.map(drop),
+            Operation::Del(key) => peer.del(key).await.map(drop),
+            Operation::Clear => peer.clear().await,
+            Operation::DelPrefix(prefix) => peer.del_prefix(&prefix).await.map(drop),
+            Operation::SetMetadata(key, metadata, ttl) => peer.set_metadata(key, metadata, ttl).await,
+            Operation::Grant(key, extra, ttl) => peer.grant(key, extra, ttl).await,
+        };
+    }
+}
+
+impl<S: Store> ReplicatedStore<S> {
+    /// create from the `local` store and the stores of the peer regions.
+    pub fn new(local: S, peers: Vec<S>) -> Self {
+        Self {
+            local,
+            peers: Arc::new(peers),
+            max_pending: DEFAULT_MAX_PENDING,
+            queues: Arc::new(OnceLock::new()),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Queue at most `max_pending` operations for each peer, default to [DEFAULT_MAX_PENDING].
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending.max(1);
+        self.queues = Arc::new(OnceLock::new());
+        self
+    }
+
+    /// Return the local store.
+    pub fn local(&self) -> &S {
+        &self.local
+    }
+
+    /// Return the stores of the peer regions.
+    pub fn peers(&self) -> &[S] {
+        &self.peers
+    }
+
+    /// Return the number of operations dropped since the queue of a peer was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<S> ReplicatedStore<S>
+    where
+        S: Store + 'static,
+        S::Key: 'static,
+        S::Count: 'static,
+{
+    /// Queue `operation` for every peer, or drop it for the peers whose queues are full.
+    fn fan_out(&self, operation: Operation<S>) {
+        if self.peers.is_empty() {
+            return;
+        }
+
+        let queues = self.queues.get_or_init(|| self.peers.iter()
+            .map(|peer| {
+                let (tx, mut rx) = mpsc::channel::<Operation<S>>(self.max_pending);
+                let peer = peer.clone();
+                // stops when the store and its clones are dropped.
+                tokio::spawn(async move {
+                    while let Some(operation) = rx.recv().await {
+                        operation.apply(&peer).await;
+                    }
+                });
+                tx
+            })
+            .collect());
+
+        for queue in queues {
+            if queue.try_send(operation.clone()).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Store for ReplicatedStore<S>
+    where
+        S: Store + 'static,
+        S::Key: 'static,
+        S::Count: 'static,
+{
+    type Error = S::Error;
+    type Key = S::Key;
+    type Value = S::Value;
+    type Count = S::Count;
+
+    async fn incr_by(&self, key: Self::Key, val: Self::Count) -> Result<Self::Value, Self::Error> {
+        self.fan_out(Operation::IncrBy(key.clone(), val.clone()));
+
+        self.local.incr_by(key, val).await
+    }
+
+    async fn incr(&self, key: Self::Key) -> Result<Self::Value, Self::Error> {
+        self.fan_out(Operation::Incr(key.clone()));
+
+        self.local.incr(key).await
+    }
+
+    async fn incr_with_ttl(&self, key: Self::Key, val: Self::Count, ttl: crate::time::Duration) -> Result<Self::Value, Self::Error> {
+        self.fan_out(Operation::IncrWithTtl(key.clone(), val.clone(), ttl));
+
+        self.local.incr_with_ttl(key, val, ttl).await
+    }
+
+    async fn incr_many(&self, charges: Vec<(Self::Key, Self::Count)>) -> Result<Vec<Self::Value>, Self::Error> {
+        self.fan_out(Operation::IncrMany(charges.clone()));
+
+        self.local.incr_many(charges).await
+    }
+
+    async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        self.fan_out(Operation::Del(key.clone()));
+
+        self.local.del(key).await
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.fan_out(Operation::Clear);
+
+        self.local.clear().await
+    }
+
+    async fn del_prefix(&self, prefix: &str) -> Result<Option<usize>, Self::Error> {
+        self.fan_out(Operation::DelPrefix(prefix.to_string()));
+
+        self.local.del_prefix(prefix).await
+    }
+
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        self.local.peek(key).await
+    }
+
+    async fn ttl(&self, key: Self::Key) -> Result<Option<crate::time::Duration>, Self::Error> {
+        self.local.ttl(key).await
+    }
+
+    async fn snapshot(&self) -> Result<Vec<(Self::Key, Self::Value)>, Self::Error> {
+        self.local.snapshot().await
+    }
+
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        self.local.stats().await
+    }
+
+    async fn set_metadata(&self, key: Self::Key, metadata: Metadata, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        self.fan_out(Operation::SetMetadata(key.clone(), metadata.clone(), ttl));
+
+        self.local.set_metadata(key, metadata, ttl).await
+    }
+
+    async fn grant(&self, key: Self::Key, extra: Self::Count, ttl: crate::time::Duration) -> Result<(), Self::Error> {
+        self.fan_out(Operation::Grant(key.clone(), extra.clone(), ttl));
+
+        self.local.grant(key, extra, ttl).await
+    }
+
+    fn classify_error(&self, error: &Self::Error) -> ErrorClass {
+        self.local.classify_error(error)
+    }
+
+    fn window(&self) -> Option<crate::time::Duration> {
+        self.local.window()
+    }
+
+    fn supports_ttl(&self) -> bool {
+        self.local.supports_ttl()
+    }
+}
+
+impl<S> GrantStore for ReplicatedStore<S>
+    where
+        S: GrantStore + 'static,
+        S::Key: 'static,
+        S::Count: 'static,
+{}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::mem_store::MemStore;
+    use super::*;
+
+    #[tokio::test]
+    async fn replicate() -> Result<(), ()> {
+        let local = MemStore::new(8, crate::time::Duration::seconds(100));
+        let peer = MemStore::new(8, crate::time::Duration::seconds(100));
+        let store = ReplicatedStore::new(local.clone(), vec![peer.clone()]);
+
+        // the hits of the peer region are counted by the peer only.
+        peer.incr_by("John".to_string(), 5).await?;
+
+        assert_eq!(store.incr("John".to_string()).await?.date_count.count, 1);
+        assert_eq!(store.incr_by("John".to_string(), 2).await?.date_count.count, 3);
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(peer.incr_by("John".to_string(), 0).await?.date_count.count, 8);
+
+        store.del("John".to_string()).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(local.incr_by("John".to_string(), 0).await?.date_count.count, 0);
+        assert_eq!(peer.incr_by("John".to_string(), 0).await?.date_count.count, 0);
+        assert_eq!(store.dropped(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn overflow() -> Result<(), ()> {
+        let local = MemStore::new(8, crate::time::Duration::seconds(100));
+        let peer = MemStore::new(8, crate::time::Duration::seconds(100));
+        let store = ReplicatedStore::new(local, vec![peer.clone()]).with_max_pending(1);
+
+        // the task of the peer does not run until this task yields.
+        for _ in 0..3 {
+            store.incr("John".to_string()).await?;
+        }
+        assert_eq!(store.dropped(), 2);
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(peer.peek("John".to_string()).await?.map(|value| value.date_count.count), Some(1));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt::Debug;
+use crate::time::{DateTime, Utc};
+#[cfg(feature = "chrono")]
+use chrono::{Days, FixedOffset, NaiveTime, TimeZone};
+
+/// [Schedule] decides the bounds of windows which are aligned to
+/// wall-clock time, rather than starting from the first request.
+pub trait Schedule: Debug + Send + Sync {
+    /// Return the start and the end of the window containing `now`.
+    /// `ttl` is the TTL of the [Store](crate::store::Store),
+    /// which may be ignored by schedules with their own window length.
+    fn window(&self, now: DateTime<Utc>, ttl: crate::time::Duration) -> (DateTime<Utc>, DateTime<Utc>);
+}
+
+/// Windows of `ttl`, aligned to boundaries in the timezone (see [aligned_window_start]).
+#[cfg(feature = "chrono")]
+impl Schedule for FixedOffset {
+    fn window(&self, now: DateTime<Utc>, ttl: crate::time::Duration) -> (DateTime<Utc>, DateTime<Utc>) {
+        let start = aligned_window_start(now, ttl, *self);
+        (start, start + ttl)
+    }
+}
+
+/// Return the start of the window containing `now`, with windows aligned to
+/// wall-clock boundaries in the timezone `offset`.
+///
+/// For example, with a `ttl` of 1 hour, the window starts at the top of the hour;
+/// with a `ttl` of 1 day and `offset` of UTC+8, the window starts at midnight in UTC+8.
+#[cfg(feature = "chrono")]
+pub fn aligned_window_start(now: DateTime<Utc>, ttl: crate::time::Duration, offset: FixedOffset) -> DateTime<Utc> {
+    let ttl = ttl.num_milliseconds();
+    if ttl <= 0 {
+        return now;
+    }
+
+    let offset = offset.local_minus_utc() as i64 * 1000;
+    let local = now.timestamp_millis() + offset;
+    let start = local - local.rem_euclid(ttl) - offset;
+
+    DateTime::from_timestamp_millis(start).unwrap_or(now)
+}
+
+/// [DailyQuota] is a [Schedule] of calendar days, which reset at
+/// a local time (`reset_at`) in the timezone `tz`.
+///
+/// The timezone can be any [TimeZone], such as [FixedOffset]
+/// or a timezone from `chrono-tz`. Days with DST changes are
+/// 23 or 25 hours long. If `reset_at` does not exist on a day (skipped by DST),
+/// the window resets at the first instant after the gap.
+///
+/// ```rust
+/// use chrono::{FixedOffset, NaiveTime};
+/// use actix_rl::store::DailyQuota;
+///
+/// // 100 requests per day, which resets at midnight in UTC+8.
+/// let schedule = DailyQuota::new(FixedOffset::east_opt(8 * 3600).unwrap(), NaiveTime::MIN);
+/// let store = actix_rl::store::mem_store::MemStore::new(1024, actix_rl::time::Duration::days(1))
+///     .with_schedule(schedule);
+/// let rate_limiter = actix_rl::middleware::RateLimit::new(store, 100, actix_rl::controller::Controller::default());
+/// ```
+#[cfg(feature = "chrono")]
+#[derive(Debug, Clone)]
+pub struct DailyQuota<Tz: TimeZone> {
+    tz: Tz,
+    reset_at: NaiveTime,
+}
+
+#[cfg(feature = "chrono")]
+impl<Tz: TimeZone> DailyQuota<Tz> {
+    pub fn new(tz: Tz, reset_at: NaiveTime) -> Self {
+        Self {
+            tz,
+            reset_at,
+        }
+    }
+
+    /// Return the reset instant on the local `date`.
+    fn reset_on(&self, date: chrono::NaiveDate) -> DateTime<Utc> {
+        let local = date.and_time(self.reset_at);
+        self.tz.from_local_datetime(&local).earliest()
+            .or_else(|| {
+                // `reset_at` is skipped by DST, try the instants after it.
+                (1..=24)
+                    .map(|i| local + crate::time::Duration::minutes(15 * i))
+                    .find_map(|local| self.tz.from_local_datetime(&local).earliest())
+            })
+            .map(|date| date.with_timezone(&Utc))
+            .unwrap_or_else(|| local.and_utc())
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<Tz> Schedule for DailyQuota<Tz>
+    where
+        Tz: TimeZone + Debug + Send + Sync,
+        Tz::Offset: Send + Sync,
+{
+    fn window(&self, now: DateTime<Utc>, _: crate::time::Duration) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = now.with_timezone(&self.tz).date_naive();
+        let reset = self.reset_on(today);
+
+        if reset <= now {
+            let tomorrow = today.checked_add_days(Days::new(1)).unwrap_or(today);
+            (reset, self.reset_on(tomorrow))
+        } else {
+            let yesterday = today.checked_sub_days(Days::new(1)).unwrap_or(today);
+            (self.reset_on(yesterday), reset)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "chrono"))]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_aligned_window_start() {
+        let now = parse("2024-05-01T17:42:13+00:00");
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let utc8 = FixedOffset::east_opt(8 * 3600).unwrap();
+
+        let start = aligned_window_start(now, crate::time::Duration::hours(1), utc);
+        assert_eq!(start, parse("2024-05-01T17:00:00+00:00"));
+
+        // midnight in UTC+8
+        let start = aligned_window_start(now, crate::time::Duration::days(1), utc8);
+        assert_eq!(start, parse("2024-05-01T16:00:00+00:00"));
+    }
+
+    #[test]
+    fn test_daily_quota() {
+        let utc8 = FixedOffset::east_opt(8 * 3600).unwrap();
+        let quota = DailyQuota::new(utc8, NaiveTime::MIN);
+
+        let (start, end) = quota.window(parse("2024-05-01T17:42:13+00:00"), crate::time::Duration::zero());
+        assert_eq!(start, parse("2024-05-01T16:00:00+00:00"));
+        assert_eq!(end, parse("2024-05-02T16:00:00+00:00"));
+
+        let (start, end) = quota.window(parse("2024-05-01T15:42:13+00:00"), crate::time::Duration::zero());
+        assert_eq!(start, parse("2024-04-30T16:00:00+00:00"));
+        assert_eq!(end, parse("2024-05-01T16:00:00+00:00"));
+    }
+
+    #[test]
+    fn test_daily_quota_dst() {
+        let quota = DailyQuota::new(chrono_tz::America::New_York, NaiveTime::MIN);
+
+        // 2024-03-10 is 23 hours long in New York.
+        let (start, end) = quota.window(parse("2024-03-10T12:00:00-04:00"), crate::time::Duration::zero());
+        assert_eq!(start, parse("2024-03-10T00:00:00-05:00"));
+        assert_eq!(end, parse("2024-03-11T00:00:00-04:00"));
+        assert_eq!(end - start, crate::time::Duration::hours(23));
+
+        // 2024-11-03 is 25 hours long in New York.
+        let (start, end) = quota.window(parse("2024-11-03T12:00:00-05:00"), crate::time::Duration::zero());
+        assert_eq!(end - start, crate::time::Duration::hours(25));
+
+        // 02:30 does not exist on 2024-03-10, resets at 03:00 instead.
+        let quota = DailyQuota::new(chrono_tz::America::New_York, NaiveTime::from_hms_opt(2, 30, 0).unwrap());
+        let (start, _) = quota.window(parse("2024-03-10T12:00:00-04:00"), crate::time::Duration::zero());
+        assert_eq!(start, parse("2024-03-10T03:00:00-04:00"));
+    }
+}
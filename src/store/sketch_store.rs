@@ -0,0 +1,220 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex, PoisonError};
+use crate::store::atomic::window_epoch;
+use crate::store::mem_store::DateCountUntil;
+use crate::store::{Clock, Store, StoreStats, SystemClock};
+
+/// [SketchStore] counts the keys approximately in a count-min sketch, in bounded memory
+/// whatever the number of keys, such as for limits by URL and IP address under a scan.
+/// The sketch is reset when a window starts, with windows aligned to the unix epoch.
+///
+/// The counts are never below the exact ones, so a limit is never missed, but a key may be
+/// counted with the requests of others which share its counters. With `width` counters
+/// per row and `depth` rows, each count is over by at most `e / width` (`e` ≈ 2.718)
+/// times the total count of the window, except with a probability of `e^-depth`.
+/// See [Self::with_error] to size the sketch by these bounds.
+///
+/// The counters are updated conservatively (only those below the new count are raised),
+/// which keeps the error bounds and reduces the over-count in practice. The hashes are
+/// seeded randomly for each store, so the collisions cannot be chosen by clients.
+///
+/// The keys are not stored, so [Store::del], [Store::del_prefix] and [Store::snapshot] are not supported.
+///
+/// ```rust
+/// use actix_rl::store::sketch_store::SketchStore;
+///
+/// // over-count by at most 0.1% of the requests of the minute, except with a probability of 0.1%.
+/// let store = SketchStore::with_error(0.001, 0.001, actix_rl::time::Duration::minutes(1));
+/// let rate_limit = actix_rl::middleware::RateLimit::new(store, 100, actix_rl::controller::Controller::default());
+/// ```
+#[derive(Debug, Clone)]
+pub struct SketchStore {
+    pub(crate) inner: Arc<SketchStoreInner>,
+}
+
+impl SketchStore {
+    /// Create with `width` counters per row, `depth` rows, and the `ttl` of windows.
+    pub fn new(width: usize, depth: usize, ttl: crate::time::Duration) -> Self {
+        let (width, depth) = (width.max(1), depth.max(1));
+        Self {
+            inner: Arc::new(SketchStoreInner {
+                sketch: Mutex::new(Sketch {
+                    epoch: None,
+                    counters: vec![0; width * depth],
+                }),
+                width,
+                depth,
+                hasher: RandomState::new(),
+                ttl,
+                clock: Arc::new(SystemClock),
+            }),
+        }
+    }
+
+    /// Create a sketch whose counts are over by at most `epsilon` times the total count
+    /// of the window, except with a probability of `delta`, such as `0.001` for both
+    /// (2719 counters per row and 7 rows, 76 KB).
+    pub fn with_error(epsilon: f64, delta: f64, ttl: crate::time::Duration) -> Self {
+        let width = (std::f64::consts::E / epsilon).ceil() as usize;
+        let depth = (1.0 / delta).ln().ceil() as usize;
+        Self::new(width, depth, ttl)
+    }
+
+    /// Use a [Clock] for the window math. The default is [SystemClock].
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        Arc::make_mut(&mut self.inner).clock = Arc::new(clock);
+        self
+    }
+
+    /// Return the number of counters per row and the number of rows.
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.inner.width, self.inner.depth)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SketchStoreInner {
+    pub sketch: Mutex<Sketch>,
+    pub width: usize,
+    pub depth: usize,
+    pub hasher: RandomState,
+    pub ttl: crate::time::Duration,
+    pub clock: Arc<dyn Clock>,
+}
+
+// not derived, since the [Mutex] is not [Clone].
+impl Clone for SketchStoreInner {
+    fn clone(&self) -> Self {
+        Self {
+            sketch: Mutex::new(self.sketch.lock().unwrap_or_else(PoisonError::into_inner).clone()),
+            width: self.width,
+            depth: self.depth,
+            hasher: self.hasher.clone(),
+            ttl: self.ttl,
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+/// [Sketch] is the counters of a window, row by row.
+#[derive(Debug, Clone)]
+pub(crate) struct Sketch {
+    /// The epoch of the window of the counters, see [window_epoch].
+    pub epoch: Option<u32>,
+    pub counters: Vec<u32>,
+}
+
+impl SketchStoreInner {
+    /// Return the index of the counter of `key` in each row, by double hashing.
+    fn indexes(&self, key: &str) -> impl Iterator<Item = usize> + '_ {
+        let hash = self.hasher.hash_one(key);
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        (0..self.depth).map(move |row| row * self.width + h1.wrapping_add(row.wrapping_mul(h2)) % self.width)
+    }
+
+    /// Return the counters of the window containing `epoch`, reset if a new window started.
+    fn sketch(&self, epoch: u32) -> std::sync::MutexGuard<'_, Sketch> {
+        let mut sketch = self.sketch.lock().unwrap_or_else(PoisonError::into_inner);
+        if sketch.epoch != Some(epoch) {
+            sketch.counters.fill(0);
+            sketch.epoch = Some(epoch);
+        }
+        sketch
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for SketchStore {
+    type Error = ();
+    type Key = String;
+    type Value = DateCountUntil;
+    type Count = u32;
+
+    async fn incr_by(&self, key: Self::Key, val: Self::Count) -> Result<Self::Value, Self::Error> {
+        let (epoch, start) = window_epoch(self.inner.clock.now(), self.inner.ttl);
+        let indexes: Vec<usize> = self.inner.indexes(&key).collect();
+        let mut sketch = self.inner.sketch(epoch);
+
+        // conservative update: raise the counters below the new estimate only.
+        let count = indexes.iter().map(|index| sketch.counters[*index]).min().unwrap_or(0).saturating_add(val);
+        for index in indexes {
+            let counter = &mut sketch.counters[index];
+            *counter = (*counter).max(count);
+        }
+
+        Ok(DateCountUntil::window(start, count, self.inner.ttl))
+    }
+
+    async fn incr(&self, key: Self::Key) -> Result<Self::Value, Self::Error> {
+        self.incr_by(key, 1).await
+    }
+
+    async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        // the counters are shared with other keys.
+        let _ = key;
+        Ok(None)
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let mut sketch = self.inner.sketch.lock().unwrap_or_else(PoisonError::into_inner);
+        sketch.counters.fill(0);
+        Ok(())
+    }
+
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        let (epoch, start) = window_epoch(self.inner.clock.now(), self.inner.ttl);
+        let sketch = self.inner.sketch(epoch);
+        let count = self.inner.indexes(&key).map(|index| sketch.counters[index]).min().unwrap_or(0);
+        Ok((count > 0).then(|| DateCountUntil::window(start, count, self.inner.ttl)))
+    }
+
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        Ok(StoreStats {
+            active_keys: 0,
+            stored_keys: 0,
+            approx_bytes: Some(self.inner.width * self.inner.depth * std::mem::size_of::<u32>()),
+        })
+    }
+
+    fn window(&self) -> Option<crate::time::Duration> {
+        Some(self.inner.ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::Value;
+    use super::*;
+
+    #[tokio::test]
+    async fn sketch_store() -> Result<(), ()> {
+        let store = SketchStore::with_error(0.01, 0.01, crate::time::Duration::seconds(100));
+        assert_eq!(store.dimensions(), (272, 5));
+
+        assert_eq!(store.incr("John".to_string()).await?.count(), 1);
+        assert_eq!(store.incr_by("John".to_string(), 2).await?.count(), 3);
+        assert_eq!(store.peek("John".to_string()).await?.map(|value| value.count()), Some(3));
+        assert_eq!(store.peek("Meg".to_string()).await?.map(|value| value.count()), None);
+
+        // never under the exact counts, and over by at most 1% of the total.
+        let total = 10_000;
+        for i in 0..total {
+            store.incr(format!("ip:{}", i % 1000)).await?;
+        }
+        for i in 0..1000 {
+            let count = store.peek(format!("ip:{}", i)).await?.map_or(0, |value| value.count());
+            assert!(count >= 10);
+            assert!(count <= 10 + total / 100, "{}", count);
+        }
+        assert_eq!(store.stats().await?.approx_bytes, Some(272 * 5 * 4));
+
+        // the keys are not stored.
+        assert_eq!(store.del_prefix("ip:").await?, None);
+
+        store.clear().await?;
+        assert!(store.peek("John".to_string()).await?.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+use crate::error::UnknownKeyError;
+use crate::store::atomic::{window_epoch, AtomicWindow};
+use crate::store::mem_store::DateCountUntil;
+use crate::store::{Clock, Store, StoreStats, SystemClock};
+
+/// [StaticStore] stores the counters of a fixed set of keys (such as the tenants
+/// of a SaaS) in a contiguous array of atomics, without hashing or locking.
+///
+/// The key is the index of the counter, in `0..len`. Map the request to the index
+/// in [with_find_identifier](crate::controller::Controller::with_find_identifier).
+/// Other keys return [UnknownKeyError].
+///
+/// The windows are aligned to the unix epoch.
+///
+/// ```rust
+/// use actix_rl::store::static_store::StaticStore;
+///
+/// // 200 tenants, 100 hits per minute each.
+/// let store = StaticStore::new(200, actix_rl::time::Duration::minutes(1));
+/// let controller = actix_rl::controller::Controller::<StaticStore>::new()
+///     .with_find_identifier(|req| {
+///         req.headers().get("X-Tenant-Id")
+///             .and_then(|id| id.to_str().ok())
+///             .and_then(|id| id.parse().ok())
+///             .unwrap_or(0)
+///     });
+/// let rate_limit = actix_rl::middleware::RateLimit::new(store, 100, controller);
+/// ```
+#[derive(Debug, Clone)]
+pub struct StaticStore {
+    pub(crate) inner: Arc<StaticStoreInner>,
+}
+
+impl StaticStore {
+    /// create with `len` keys, and the `ttl` of windows.
+    pub fn new(len: usize, ttl: crate::time::Duration) -> Self {
+        Self {
+            inner: Arc::new(StaticStoreInner {
+                counters: (0..len).map(|_| AtomicWindow::default()).collect(),
+                ttl,
+                clock: Arc::new(SystemClock),
+            }),
+        }
+    }
+
+    /// Use a [Clock] for the window math. The default is [SystemClock].
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        Arc::make_mut(&mut self.inner).clock = Arc::new(clock);
+        self
+    }
+
+    /// Return the number of keys.
+    pub fn len(&self) -> usize {
+        self.inner.counters.len()
+    }
+
+    /// Return true if there is no key.
+    pub fn is_empty(&self) -> bool {
+        self.inner.counters.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct StaticStoreInner {
+    pub counters: Box<[AtomicWindow]>,
+    pub ttl: crate::time::Duration,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl StaticStoreInner {
+    fn counter(&self, key: usize) -> Result<&AtomicWindow, UnknownKeyError> {
+        self.counters.get(key).ok_or(UnknownKeyError(key))
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for StaticStore {
+    type Error = UnknownKeyError;
+    type Key = usize;
+    type Value = DateCountUntil;
+    type Count = u32;
+
+    async fn incr_by(&self, key: Self::Key, val: Self::Count) -> Result<Self::Value, Self::Error> {
+        let counter = self.inner.counter(key)?;
+        let (epoch, start) = window_epoch(self.inner.clock.now(), self.inner.ttl);
+        Ok(DateCountUntil::window(start, counter.incr(epoch, val), self.inner.ttl))
+    }
+
+    async fn incr(&self, key: Self::Key) -> Result<Self::Value, Self::Error> {
+        self.incr_by(key, 1).await
+    }
+
+    async fn del(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        self.inner.counter(key)?.reset();
+        Ok(None)
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.inner.counters.iter().for_each(AtomicWindow::reset);
+        Ok(())
+    }
+
+    async fn peek(&self, key: Self::Key) -> Result<Option<Self::Value>, Self::Error> {
+        let counter = self.inner.counter(key)?;
+        let (epoch, start) = window_epoch(self.inner.clock.now(), self.inner.ttl);
+        let count = counter.get(epoch);
+        Ok((count > 0).then(|| DateCountUntil::window(start, count, self.inner.ttl)))
+    }
+
+    async fn snapshot(&self) -> Result<Vec<(Self::Key, Self::Value)>, Self::Error> {
+        let (epoch, start) = window_epoch(self.inner.clock.now(), self.inner.ttl);
+        Ok(self.inner.counters.iter()
+            .map(|counter| counter.get(epoch))
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .map(|(key, count)| (key, DateCountUntil::window(start, count, self.inner.ttl)))
+            .collect())
+    }
+
+    async fn stats(&self) -> Result<StoreStats, Self::Error> {
+        let (epoch, _) = window_epoch(self.inner.clock.now(), self.inner.ttl);
+        Ok(StoreStats {
+            active_keys: self.inner.counters.iter().filter(|counter| counter.get(epoch) > 0).count(),
+            stored_keys: self.len(),
+            approx_bytes: Some(std::mem::size_of_val(&*self.inner.counters)),
+        })
+    }
+
+    fn window(&self) -> Option<crate::time::Duration> {
+        Some(self.inner.ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn static_store() -> Result<(), UnknownKeyError> {
+        let store = StaticStore::new(2, crate::time::Duration::seconds(100));
+
+        assert_eq!(store.incr(0).await?.date_count.count, 1);
+        assert_eq!(store.incr_by(0, 2).await?.date_count.count, 3);
+        assert_eq!(store.incr(1).await?.date_count.count, 1);
+        assert_eq!(store.incr(2).await.unwrap_err(), UnknownKeyError(2));
+
+        assert_eq!(store.peek(0).await?.map(|value| value.date_count.count), Some(3));
+        assert_eq!(store.snapshot().await?.len(), 2);
+        let stats = store.stats().await?;
+        assert_eq!((stats.active_keys, stats.stored_keys), (2, store.len()));
+
+        store.del(0).await?;
+        assert!(store.peek(0).await?.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use crate::time::{DateTime, Utc};
+
+/// The number of bits of the slot index of a level.
+const SLOT_BITS: u32 = 6;
+
+/// The number of slots of a level.
+const SLOTS: usize = 1 << SLOT_BITS;
+
+/// The number of levels. With 64 slots per level, the wheel spans 2^24 ticks,
+/// such as 194 days at a resolution of 1 second.
+const LEVELS: usize = 4;
+
+/// [TimeWheel] is a hierarchical timing wheel, which schedules keys by their expiry,
+/// see [MemStore::with_time_wheel](crate::store::mem_store::MemStore::with_time_wheel).
+///
+/// A key is scheduled in the slot of its tick, on the lowest level where its tick and
+/// the current tick only differ by the index of the slot. When the current tick enters
+/// a slot of a higher level, the keys of the slot are scheduled again on lower levels,
+/// until they are on the first level and their tick is reached. So scheduling a key
+/// costs O(1), and the ticks of empty slots are skipped, so an idle period costs
+/// no more than the slots which hold keys.
+///
+/// The keys are not removed when they are rescheduled or deleted: the caller checks
+/// the keys returned by [Self::advance], and schedules again those still alive.
+/// The deadlines beyond the span of the wheel are returned early, for the same reason.
+#[derive(Debug, Clone)]
+pub(crate) struct TimeWheel {
+    /// The duration of a tick, in milliseconds.
+    resolution: i64,
+    /// The time of the tick 0, set by the first call.
+    origin: Option<DateTime<Utc>>,
+    /// The last tick processed by [Self::advance].
+    current: u64,
+    /// The slots of each level, with the keys and their ticks.
+    levels: Vec<Vec<Vec<(String, u64)>>>,
+    /// The number of scheduled keys.
+    len: usize,
+}
+
+impl TimeWheel {
+    pub fn new(resolution: crate::time::Duration) -> Self {
+        Self {
+            resolution: resolution.num_milliseconds().max(1),
+            origin: None,
+            current: 0,
+            levels: vec![vec![Vec::new(); SLOTS]; LEVELS],
+            len: 0,
+        }
+    }
+
+    /// Remove all keys.
+    pub fn clear(&mut self) {
+        self.levels.iter_mut().flatten().for_each(Vec::clear);
+        self.len = 0;
+    }
+
+    /// Return the tick of `at`, rounded down.
+    fn tick(&mut self, at: DateTime<Utc>) -> u64 {
+        let origin = *self.origin.get_or_insert(at);
+        ((at - origin).num_milliseconds().max(0) / self.resolution) as u64
+    }
+
+    /// Schedule `key`, to be returned by [Self::advance] after `deadline`.
+    pub fn insert(&mut self, key: String, deadline: DateTime<Utc>) {
+        let tick = (self.tick(deadline) + 1).max(self.current + 1);
+        self.place(key, tick);
+    }
+
+    /// Put `key` in the slot of `tick`, which is not before the current tick.
+    fn place(&mut self, key: String, tick: u64) {
+        // the last tick within the span, returned early and scheduled again by the caller.
+        let tick = tick.min(self.current | ((1 << (SLOT_BITS * LEVELS as u32)) - 1));
+        let significant = (tick ^ self.current) | (SLOTS as u64 - 1);
+        let level = ((63 - significant.leading_zeros()) / SLOT_BITS) as usize;
+        let slot = (tick >> (SLOT_BITS * level as u32)) as usize & (SLOTS - 1);
+        self.levels[level][slot].push((key, tick));
+        self.len += 1;
+    }
+
+    /// Process the ticks until `now`, and return the keys due, without duplicates.
+    pub fn advance(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let target = self.tick(now);
+        let mut due = Vec::new();
+
+        while self.current < target {
+            match self.next_event() {
+                Some(tick) if tick <= target => self.current = tick,
+                _ => {
+                    self.current = target;
+                    break;
+                },
+            }
+
+            // move the keys of the entered slots down, from the highest level.
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS * level as u32;
+                if self.current & ((1 << shift) - 1) == 0 {
+                    let slot = (self.current >> shift) as usize & (SLOTS - 1);
+                    for (key, tick) in std::mem::take(&mut self.levels[level][slot]) {
+                        self.len -= 1;
+                        self.place(key, tick);
+                    }
+                }
+            }
+
+            let slot = self.current as usize & (SLOTS - 1);
+            let keys = std::mem::take(&mut self.levels[0][slot]);
+            self.len -= keys.len();
+            due.extend(keys.into_iter().map(|(key, _)| key));
+        }
+
+        due.sort_unstable();
+        due.dedup();
+        due
+    }
+
+    /// Return the next tick which enters a slot holding keys, on any level.
+    fn next_event(&self) -> Option<u64> {
+        (0..LEVELS)
+            .filter_map(|level| {
+                let shift = SLOT_BITS * level as u32;
+                // the keys of a level are after the current slot, in the same slot of the level above.
+                let index = (self.current >> shift) as usize & (SLOTS - 1);
+                let slot = (index + 1..SLOTS).find(|slot| !self.levels[level][*slot].is_empty())?;
+                let base = self.current >> (shift + SLOT_BITS) << (shift + SLOT_BITS);
+                Some(base | (slot as u64) << shift)
+            })
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_wheel() {
+        let origin = DateTime::from_timestamp(1_714_585_333, 0).unwrap();
+        let at = |seconds: i64| origin + crate::time::Duration::seconds(seconds);
+        let mut wheel = TimeWheel::new(crate::time::Duration::seconds(1));
+
+        assert!(wheel.advance(at(0)).is_empty());
+        wheel.insert("a".to_string(), at(3));
+        wheel.insert("b".to_string(), at(100));
+        wheel.insert("c".to_string(), at(5000));
+        wheel.insert("c".to_string(), at(5000));
+        assert_eq!(wheel.len, 4);
+
+        // due strictly after the deadline.
+        assert!(wheel.advance(at(3)).is_empty());
+        assert_eq!(wheel.advance(at(4)), vec!["a"]);
+        assert!(wheel.advance(at(100)).is_empty());
+        assert_eq!(wheel.advance(at(4000)), vec!["b"]);
+        assert_eq!(wheel.advance(at(6000)), vec!["c"]);
+        assert_eq!(wheel.len, 0);
+
+        // beyond the span, the key is returned early.
+        wheel.insert("d".to_string(), at(400 * 24 * 3600));
+        let due = wheel.advance(at(200 * 24 * 3600));
+        assert_eq!(due, vec!["d"]);
+
+        // the empty slots are skipped.
+        wheel.insert("f".to_string(), at(300 * 24 * 3600));
+        assert!(wheel.advance(at(250 * 24 * 3600)).is_empty());
+        assert_eq!(wheel.advance(at(300 * 24 * 3600 + 1)), vec!["f"]);
+
+        wheel.insert("e".to_string(), at(300 * 24 * 3600 + 10));
+        wheel.clear();
+        assert_eq!(wheel.len, 0);
+        assert!(wheel.advance(at(301 * 24 * 3600)).is_empty());
+    }
+}
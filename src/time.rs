@@ -0,0 +1,304 @@
+//! The time types of the crate.
+//!
+//! With the default `chrono` feature, they are the types of [chrono], so the API takes
+//! `chrono::Duration` and `chrono::DateTime<chrono::Utc>`. Without it (the `no-chrono` build,
+//! with `default-features = false`), they are minimal equivalents on [std::time::SystemTime],
+//! and the features working on calendars (the aligned windows, `DailyQuota`, `export`
+//! and `audit`) are not available.
+//!
+//! ```rust
+//! use actix_rl::time::Duration;
+//!
+//! let store = actix_rl::store::mem_store::MemStore::new(1024, Duration::seconds(10));
+//! ```
+
+#[cfg(feature = "chrono")]
+pub use chrono::{DateTime, Duration, Utc};
+
+#[cfg(not(feature = "chrono"))]
+pub use shim::{DateTime, Duration, OutOfRangeError, Utc};
+
+#[cfg(not(feature = "chrono"))]
+mod shim {
+    use std::fmt::{Display, Formatter};
+    use std::marker::PhantomData;
+    use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+    const NANOS_PER_MILLI: i128 = 1_000_000;
+    const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+    /// A signed span of time, with nanosecond precision, like `chrono::Duration`.
+    #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+    pub struct Duration(i128);
+
+    /// Returned by [Duration::to_std] when the duration is negative.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub struct OutOfRangeError;
+
+    impl Display for OutOfRangeError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "duration out of range")
+        }
+    }
+
+    impl std::error::Error for OutOfRangeError {}
+
+    impl Duration {
+        pub const fn zero() -> Self {
+            Self(0)
+        }
+
+        pub const fn days(days: i64) -> Self {
+            Self(days as i128 * 86_400 * NANOS_PER_SEC)
+        }
+
+        pub const fn hours(hours: i64) -> Self {
+            Self(hours as i128 * 3_600 * NANOS_PER_SEC)
+        }
+
+        pub const fn minutes(minutes: i64) -> Self {
+            Self(minutes as i128 * 60 * NANOS_PER_SEC)
+        }
+
+        pub const fn seconds(seconds: i64) -> Self {
+            Self(seconds as i128 * NANOS_PER_SEC)
+        }
+
+        pub const fn milliseconds(milliseconds: i64) -> Self {
+            Self(milliseconds as i128 * NANOS_PER_MILLI)
+        }
+
+        pub const fn try_milliseconds(milliseconds: i64) -> Option<Self> {
+            Some(Self::milliseconds(milliseconds))
+        }
+
+        pub const fn nanoseconds(nanoseconds: i64) -> Self {
+            Self(nanoseconds as i128)
+        }
+
+        pub const fn num_seconds(&self) -> i64 {
+            (self.0 / NANOS_PER_SEC) as i64
+        }
+
+        pub const fn num_milliseconds(&self) -> i64 {
+            (self.0 / NANOS_PER_MILLI) as i64
+        }
+
+        pub fn num_nanoseconds(&self) -> Option<i64> {
+            i64::try_from(self.0).ok()
+        }
+
+        pub const fn abs(&self) -> Self {
+            Self(self.0.abs())
+        }
+
+        pub fn checked_add(&self, rhs: &Self) -> Option<Self> {
+            self.0.checked_add(rhs.0).map(Self)
+        }
+
+        pub fn checked_sub(&self, rhs: &Self) -> Option<Self> {
+            self.0.checked_sub(rhs.0).map(Self)
+        }
+
+        pub fn from_std(duration: std::time::Duration) -> Result<Self, OutOfRangeError> {
+            Ok(Self(duration.as_nanos() as i128))
+        }
+
+        pub fn to_std(&self) -> Result<std::time::Duration, OutOfRangeError> {
+            let nanos = u128::try_from(self.0).map_err(|_| OutOfRangeError)?;
+            let secs = u64::try_from(nanos / NANOS_PER_SEC as u128).map_err(|_| OutOfRangeError)?;
+            Ok(std::time::Duration::new(secs, (nanos % NANOS_PER_SEC as u128) as u32))
+        }
+    }
+
+    /// ISO 8601, such as `PT90S` or `PT0.5S`, like `chrono::Duration`.
+    impl Display for Duration {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            let sign = if self.0 < 0 { "-" } else { "" };
+            let (secs, nanos) = (self.0.abs() / NANOS_PER_SEC, self.0.abs() % NANOS_PER_SEC);
+            match nanos {
+                0 => write!(f, "{}PT{}S", sign, secs),
+                nanos => write!(f, "{}PT{}.{}S", sign, secs, format!("{:09}", nanos).trim_end_matches('0')),
+            }
+        }
+    }
+
+    impl Add for Duration {
+        type Output = Self;
+
+        fn add(self, rhs: Self) -> Self {
+            Self(self.0 + rhs.0)
+        }
+    }
+
+    impl Sub for Duration {
+        type Output = Self;
+
+        fn sub(self, rhs: Self) -> Self {
+            Self(self.0 - rhs.0)
+        }
+    }
+
+    impl AddAssign for Duration {
+        fn add_assign(&mut self, rhs: Self) {
+            self.0 += rhs.0;
+        }
+    }
+
+    impl SubAssign for Duration {
+        fn sub_assign(&mut self, rhs: Self) {
+            self.0 -= rhs.0;
+        }
+    }
+
+    impl Neg for Duration {
+        type Output = Self;
+
+        fn neg(self) -> Self {
+            Self(-self.0)
+        }
+    }
+
+    impl Mul<i32> for Duration {
+        type Output = Self;
+
+        fn mul(self, rhs: i32) -> Self {
+            Self(self.0 * rhs as i128)
+        }
+    }
+
+    impl Div<i32> for Duration {
+        type Output = Self;
+
+        fn div(self, rhs: i32) -> Self {
+            Self(self.0 / rhs as i128)
+        }
+    }
+
+    /// The UTC timezone, like `chrono::Utc`.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+    pub struct Utc;
+
+    impl Utc {
+        /// Return the current time of the system.
+        pub fn now() -> DateTime<Utc> {
+            let nanos = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+                Ok(since) => since.as_nanos() as i128,
+                Err(e) => -(e.duration().as_nanos() as i128),
+            };
+            DateTime(nanos, PhantomData)
+        }
+    }
+
+    /// A time since the unix epoch, with nanosecond precision, like `chrono::DateTime<Utc>`.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+    pub struct DateTime<Tz>(i128, PhantomData<Tz>);
+
+    impl DateTime<Utc> {
+        /// The unix epoch.
+        pub const UNIX_EPOCH: Self = Self(0, PhantomData);
+        /// The latest time, like `chrono::DateTime::<Utc>::MAX_UTC`.
+        pub const MAX_UTC: Self = Self(i64::MAX as i128 * NANOS_PER_SEC, PhantomData);
+        /// The earliest time, like `chrono::DateTime::<Utc>::MIN_UTC`.
+        pub const MIN_UTC: Self = Self(i64::MIN as i128 * NANOS_PER_SEC, PhantomData);
+
+        pub fn from_timestamp(secs: i64, nsecs: u32) -> Option<Self> {
+            (nsecs < NANOS_PER_SEC as u32).then(|| Self(secs as i128 * NANOS_PER_SEC + nsecs as i128, PhantomData))
+        }
+
+        pub fn from_timestamp_millis(millis: i64) -> Option<Self> {
+            Some(Self(millis as i128 * NANOS_PER_MILLI, PhantomData))
+        }
+
+        pub const fn timestamp(&self) -> i64 {
+            self.0.div_euclid(NANOS_PER_SEC) as i64
+        }
+
+        pub const fn timestamp_millis(&self) -> i64 {
+            self.0.div_euclid(NANOS_PER_MILLI) as i64
+        }
+
+        pub fn timestamp_nanos_opt(&self) -> Option<i64> {
+            i64::try_from(self.0).ok()
+        }
+    }
+
+    /// Such as `2024-05-01 17:42:13.250 UTC`, like `chrono::DateTime<Utc>`.
+    impl Display for DateTime<Utc> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            let (days, nanos) = (self.0.div_euclid(86_400 * NANOS_PER_SEC), self.0.rem_euclid(86_400 * NANOS_PER_SEC));
+            // the civil date of the days since the epoch, by Howard Hinnant's algorithm.
+            let z = days + 719_468;
+            let era = z.div_euclid(146_097);
+            let doe = z.rem_euclid(146_097);
+            let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+            let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+            let mp = (5 * doy + 2) / 153;
+            let day = doy - (153 * mp + 2) / 5 + 1;
+            let month = if mp < 10 { mp + 3 } else { mp - 9 };
+            let year = yoe + era * 400 + i128::from(month <= 2);
+
+            let secs = nanos / NANOS_PER_SEC;
+            write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)?;
+            match nanos % NANOS_PER_SEC {
+                0 => write!(f, " UTC"),
+                nanos if nanos % NANOS_PER_MILLI == 0 => write!(f, ".{:03} UTC", nanos / NANOS_PER_MILLI),
+                nanos if nanos % 1000 == 0 => write!(f, ".{:06} UTC", nanos / 1000),
+                nanos => write!(f, ".{:09} UTC", nanos),
+            }
+        }
+    }
+
+    impl Add<Duration> for DateTime<Utc> {
+        type Output = Self;
+
+        fn add(self, rhs: Duration) -> Self {
+            Self(self.0 + rhs.0, PhantomData)
+        }
+    }
+
+    impl Sub<Duration> for DateTime<Utc> {
+        type Output = Self;
+
+        fn sub(self, rhs: Duration) -> Self {
+            Self(self.0 - rhs.0, PhantomData)
+        }
+    }
+
+    impl AddAssign<Duration> for DateTime<Utc> {
+        fn add_assign(&mut self, rhs: Duration) {
+            self.0 += rhs.0;
+        }
+    }
+
+    impl SubAssign<Duration> for DateTime<Utc> {
+        fn sub_assign(&mut self, rhs: Duration) {
+            self.0 -= rhs.0;
+        }
+    }
+
+    impl Sub for DateTime<Utc> {
+        type Output = Duration;
+
+        fn sub(self, rhs: Self) -> Duration {
+            Duration(self.0 - rhs.0)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn shim() {
+            let time = DateTime::from_timestamp(1_714_585_333, 250_000_000).unwrap();
+            assert_eq!(time.to_string(), "2024-05-01 17:42:13.250 UTC");
+            assert_eq!(time.timestamp_millis(), 1_714_585_333_250);
+            assert_eq!((time + Duration::seconds(90) - time).to_string(), "PT90S");
+            assert_eq!(Duration::milliseconds(-1500).to_string(), "-PT1.5S");
+            assert!(Duration::seconds(-1).to_std().is_err());
+            assert_eq!(Duration::milliseconds(1500).to_std(), Ok(std::time::Duration::from_millis(1500)));
+            assert!(Utc::now() > time);
+        }
+    }
+}
@@ -1,26 +1,429 @@
-use actix_web::{HttpMessage, HttpRequest};
-use crate::store::Store;
+use std::collections::HashMap;
+use std::fmt::{Display, Write as _};
+use std::ops::Sub;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use actix_web::middleware::Logger;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use crate::error::ParseDurationError;
+use crate::identifier::IdentifierSource;
+use crate::policy::{PolicyLabels, PolicyVariant};
+use crate::store::{Store, Value};
 
+/// [Outcome] is the decision of the middleware for a request. It is inserted into the
+/// extensions of the request before the hooks of the [Controller](crate::controller::Controller)
+/// are called, for the allowed and the rejected requests, so analytics can tell them apart
+/// with [Outcome::from_request] (or [RateLimitByPass::outcome] for the allowed ones).
+///
+/// With several limiters, a rejection replaces the outcome of the previous limiters,
+/// otherwise the outcome of the first limiter is kept.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default)]
+pub enum Outcome {
+    /// Counted, and within the limit (or its grace margin).
+    Allowed,
+    /// Not counted: skipped by [Controller::with_do_rate_limit](crate::controller::Controller::with_do_rate_limit),
+    /// marked as [RateLimitExempt], frozen, a retry of an idempotency key, the first request
+    /// of a key in a [FirstSeenFilter](crate::prefilter::FirstSeenFilter), or without an identifier.
+    #[default]
+    Bypassed,
+    /// Not counted accurately as the [Store] failed, and let through by the
+    /// [FailurePolicy](crate::controller::FailurePolicy).
+    FailedOpen,
+    /// Rejected: over the limit, over the global budget, with an invalid signature,
+    /// or as the [Store] failed.
+    Rejected,
+    /// Rejected without counting, as the key is hard-limited until the end of its window
+    /// (see [RateLimit::with_rejection_cache](crate::middleware::RateLimit::with_rejection_cache)).
+    Banned,
+}
+
+impl Outcome {
+    /// Return the name of the outcome, such as `failed_open`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Allowed => "allowed",
+            Self::Bypassed => "bypassed",
+            Self::FailedOpen => "failed_open",
+            Self::Rejected => "rejected",
+            Self::Banned => "banned",
+        }
+    }
+
+    /// Check if the request is rejected.
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, Self::Rejected | Self::Banned)
+    }
+
+    /// Return the outcome of `req`, or [None] if it has not reached the middleware.
+    pub fn from_request(req: &HttpRequest) -> Option<Outcome> {
+        req.extensions().get::<Outcome>().copied()
+    }
+
+    /// Record the outcome of `req`: the rejections replace the previous outcome.
+    pub(crate) fn record(req: &HttpRequest, outcome: Outcome) {
+        let mut extensions = req.extensions_mut();
+        if outcome.is_rejected() || !extensions.contains::<Outcome>() {
+            extensions.insert(outcome);
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// [RateLimitByPass] is inserted into the extensions of every request
+/// allowed by the middleware, including the bypassed ones.
 #[derive(Clone, Default)]
 pub struct RateLimitByPass<T: Store + 'static> {
     pub(crate) value: Option<<T as Store>::Value>,
+    pub(crate) outcome: Outcome,
+    pub(crate) bypassed: bool,
+    pub(crate) grace: bool,
+    pub(crate) source: Option<IdentifierSource>,
+    pub(crate) variant: Option<PolicyVariant>,
+    pub(crate) remaining: Option<String>,
+    pub(crate) labels: Option<PolicyLabels>,
 }
 
+/// [NamedByPass] stores the [RateLimitByPass] of each named limiter.
+#[derive(Clone)]
+pub(crate) struct NamedByPass<T: Store + 'static>(HashMap<String, RateLimitByPass<T>>);
+
 impl<T: Store + 'static> RateLimitByPass<T> {
-    pub(crate) fn checked(req: &HttpRequest) -> bool {
-        Self::from_request(req).is_some()
+    /// Check if the request has been checked. If `name` is [None],
+    /// any previous check counts; otherwise only the check from the limiter with the same name.
+    pub(crate) fn checked(req: &HttpRequest, name: Option<&str>) -> bool {
+        match name {
+            None => req.extensions().contains::<RateLimitByPass<T>>(),
+            Some(name) => req.extensions().get::<NamedByPass<T>>()
+                .map(|named| named.0.contains_key(name))
+                .unwrap_or(false),
+        }
     }
 
-    pub(crate) fn check(req: &HttpRequest, value: Option<<T as Store>::Value>) {
-        let rl = RateLimitByPass::<T> { value };
-        req.extensions_mut().insert(rl);
+    /// Record the check. The first record is kept as the unnamed one,
+    /// which is returned by [Self::from_request].
+    pub(crate) fn check(req: &HttpRequest, name: Option<&str>, outcome: Outcome, value: Option<<T as Store>::Value>, grace: bool, remaining: Option<String>) {
+        Outcome::record(req, outcome);
+        let bypassed = value.is_none();
+        let mut extensions = req.extensions_mut();
+        let source = extensions.get::<IdentifierSource>().copied();
+        let variant = extensions.get::<PolicyVariant>().cloned();
+        let labels = extensions.get::<PolicyLabels>().cloned();
+        let rl = RateLimitByPass::<T> { value, outcome, bypassed, grace, source, variant, remaining, labels };
+
+        if let Some(name) = name {
+            if let Some(named) = extensions.get_mut::<NamedByPass<T>>() {
+                named.0.insert(name.to_string(), rl.clone());
+            } else {
+                extensions.insert(NamedByPass(HashMap::from([(name.to_string(), rl.clone())])));
+            }
+        }
+
+        if !extensions.contains::<RateLimitByPass<T>>() {
+            extensions.insert(rl);
+        }
     }
 
+    /// Return the value captured during the check,
+    /// or [None] if the request is bypassed.
     pub fn get_value(&self) -> Option<&<T as Store>::Value> {
         self.value.as_ref()
     }
 
+    /// Return the [Outcome] of the request: [Outcome::Allowed], [Outcome::Bypassed]
+    /// or [Outcome::FailedOpen].
+    pub fn outcome(&self) -> Outcome {
+        self.outcome
+    }
+
+    /// Check if the request is bypassed (not counted), such as skipped by
+    /// [Controller::with_do_rate_limit](crate::controller::Controller::with_do_rate_limit),
+    /// marked as [RateLimitExempt], or without an identifier.
+    pub fn is_bypassed(&self) -> bool {
+        self.bypassed
+    }
+
+    /// Check if the request is over `max`, but allowed within the grace margin
+    /// (see [Controller::with_grace](crate::controller::Controller::with_grace)).
+    pub fn is_grace(&self) -> bool {
+        self.grace
+    }
+
+    /// Return the source of the identifier, if recorded by the identifier extractor,
+    /// such as [find_identifier_by_trusted_proxy](crate::identifier::find_identifier_by_trusted_proxy).
+    pub fn identifier_source(&self) -> Option<IdentifierSource> {
+        self.source
+    }
+
+    /// Return the variant of the [Experiment](crate::policy::Experiment) assigned to the key,
+    /// see [RateLimit::with_experiment](crate::middleware::RateLimit::with_experiment).
+    pub fn variant(&self) -> Option<&PolicyVariant> {
+        self.variant.as_ref()
+    }
+
+    /// Return the labels of the limiter, see [RateLimit::with_labels](crate::middleware::RateLimit::with_labels).
+    pub fn labels(&self) -> Option<&PolicyLabels> {
+        self.labels.as_ref()
+    }
+
+    /// Return the remaining count before reaching the max, or [None] if the request is bypassed.
+    pub fn remaining(&self) -> Option<&str> {
+        self.remaining.as_deref()
+    }
+
     pub fn from_request(req: &HttpRequest) -> Option<RateLimitByPass<T>> {
         req.extensions().get::<RateLimitByPass<T>>().cloned()
     }
+
+    /// Return the [RateLimitByPass] recorded by the limiter with `name`
+    /// (see [Controller::with_name](crate::controller::Controller::with_name)).
+    pub fn from_request_named(req: &HttpRequest, name: &str) -> Option<RateLimitByPass<T>> {
+        req.extensions().get::<NamedByPass<T>>()
+            .and_then(|named| named.0.get(name).cloned())
+    }
+}
+
+/// Register the decisions of the middleware as custom fields of `logger`, so they can
+/// appear in the access logs: `%{rate_limit_decision}xo` (`allowed`, `grace`, `bypassed`,
+/// `failed_open`, `limited` or `banned`, see [Outcome]), `%{rate_limit_count}xo`, `%{rate_limit_remaining}xo` and
+/// `%{rate_limit_labels}xo` (see [PolicyLabels]).
+/// The fields without a value are logged as `-`.
+///
+/// The fields are read from the [Outcome] and the [RateLimitByPass] of the first
+/// (or unnamed) limiter; the rejected requests are `limited` or `banned`.
+///
+/// ```rust
+/// use actix_web::{middleware::Logger, App};
+/// use actix_rl::store::mem_store::MemStore;
+/// use actix_rl::utils::log_rate_limit;
+///
+/// let logger = log_rate_limit::<MemStore>(Logger::new(
+///     r#"%a "%r" %s rl=%{rate_limit_decision}xo remaining=%{rate_limit_remaining}xo"#,
+/// ));
+/// let app = App::new().wrap(logger);
+/// ```
+pub fn log_rate_limit<T: Store + 'static>(logger: Logger) -> Logger {
+    let by_pass = |res: &ServiceResponse| RateLimitByPass::<T>::from_request(res.request());
+    logger
+        .custom_response_replace("rate_limit_decision", move |res| match (Outcome::from_request(res.request()), by_pass(res)) {
+            (Some(Outcome::Rejected), _) => "limited".to_string(),
+            (Some(Outcome::Banned), _) => "banned".to_string(),
+            (_, Some(rl)) if rl.outcome == Outcome::Allowed && rl.grace => "grace".to_string(),
+            (_, Some(rl)) => rl.outcome.to_string(),
+            (_, None) if res.status() == StatusCode::TOO_MANY_REQUESTS => "limited".to_string(),
+            (_, None) => "-".to_string(),
+        })
+        .custom_response_replace("rate_limit_count", move |res| {
+            by_pass(res)
+                .and_then(|rl| rl.value.map(|value| value.count().to_string()))
+                .unwrap_or_else(|| "-".to_string())
+        })
+        .custom_response_replace("rate_limit_remaining", move |res| {
+            by_pass(res)
+                .and_then(|rl| rl.remaining)
+                .unwrap_or_else(|| "-".to_string())
+        })
+        .custom_response_replace("rate_limit_labels", |res| {
+            PolicyLabels::from_request(res.request())
+                .map(|labels| labels.to_string())
+                .filter(|labels| !labels.is_empty())
+                .unwrap_or_else(|| "-".to_string())
+        })
+}
+
+/// [RateLimitExempt] marks a request as exempt from rate limiting.
+///
+/// Other middlewares or guards (such as an auth middleware which has already
+/// validated a service account) can insert it into the request extensions,
+/// and the rate-limit middleware will skip checking the request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimitExempt;
+
+impl RateLimitExempt {
+    /// Mark the request as exempt from rate limiting.
+    pub fn mark(req: &HttpRequest) {
+        req.extensions_mut().insert(RateLimitExempt);
+    }
+
+    /// Check if the request has been marked as exempt.
+    pub fn is_exempt(req: &HttpRequest) -> bool {
+        req.extensions().contains::<RateLimitExempt>()
+    }
+}
+
+/// The default header telling whether a response is served from cache, see [CacheHit].
+pub const DEFAULT_CACHE_STATUS_HEADER: &str = "X-Cache";
+
+/// [CacheHit] marks a response as served from cache, so its charge can be refunded,
+/// see [RateLimit::with_cache_refund](crate::middleware::RateLimit::with_cache_refund).
+///
+/// The handlers (or the caching middlewares inside the rate-limit middleware) can insert
+/// it into the extensions of the response, or set [DEFAULT_CACHE_STATUS_HEADER] to `HIT`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheHit;
+
+impl CacheHit {
+    /// Mark the response as served from cache.
+    pub fn mark<B>(res: &mut HttpResponse<B>) {
+        res.extensions_mut().insert(CacheHit);
+    }
+
+    /// Check if the response has been marked as served from cache,
+    /// or has a [DEFAULT_CACHE_STATUS_HEADER] starting with `HIT` (case-insensitive).
+    pub fn is_cache_hit<B>(res: &HttpResponse<B>) -> bool {
+        res.extensions().contains::<CacheHit>() || res.headers().get(DEFAULT_CACHE_STATUS_HEADER)
+            .is_some_and(|value| value.as_bytes().get(..3).is_some_and(|hit| hit.eq_ignore_ascii_case(b"HIT")))
+    }
+}
+
+/// Insert a header, ignoring invalid names or values.
+pub(crate) fn insert_header(headers: &mut HeaderMap, name: &str, value: String) {
+    if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+        headers.insert(name, value);
+    }
+}
+
+/// Write `value` as a JSON string.
+pub(crate) fn write_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Return the remaining count before reaching `max`, as a string.
+pub(crate) fn remaining<C: PartialOrd + Clone + Display + Sub<Output = C>>(max: &C, count: &C) -> String {
+    if count >= max {
+        "0".to_string()
+    } else {
+        (max.clone() - count.clone()).to_string()
+    }
+}
+
+/// Parse a humantime-style window/TTL string, such as `"10s"`, `"5m"`, `"1h30m"` or `"1d 12h"`.
+///
+/// Supported units: `ms`, `s`, `m`, `h`, `d`, `w`
+/// (and their long forms, such as `secs`, `minutes`, `hours`).
+///
+/// ```rust
+/// let ttl = actix_rl::utils::parse_duration("90s").unwrap();
+/// let store = actix_rl::store::mem_store::MemStore::new(1024, ttl);
+/// ```
+pub fn parse_duration(s: &str) -> Result<crate::time::Duration, ParseDurationError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(ParseDurationError::Empty);
+    }
+
+    let bytes = s.as_bytes();
+    let mut pos = 0;
+    let mut total = crate::time::Duration::zero();
+
+    while pos < bytes.len() {
+        // number part
+        let start = pos;
+        while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(ParseDurationError::NumberExpected(start));
+        }
+        let number: i64 = s[start..pos].parse().map_err(|_| ParseDurationError::Overflow)?;
+
+        // unit part
+        let start = pos;
+        while pos < bytes.len() && bytes[pos].is_ascii_alphabetic() {
+            pos += 1;
+        }
+        let unit_millis: i64 = match &s[start..pos] {
+            "ms" | "msec" | "millis" | "milliseconds" => 1,
+            "s" | "sec" | "secs" | "second" | "seconds" => 1_000,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60_000,
+            "h" | "hr" | "hrs" | "hour" | "hours" => 3_600_000,
+            "d" | "day" | "days" => 86_400_000,
+            "w" | "week" | "weeks" => 604_800_000,
+            unit => return Err(ParseDurationError::UnknownUnit(unit.to_string())),
+        };
+
+        let millis = number.checked_mul(unit_millis).ok_or(ParseDurationError::Overflow)?;
+        let part = crate::time::Duration::try_milliseconds(millis).ok_or(ParseDurationError::Overflow)?;
+        total = total.checked_add(&part).ok_or(ParseDurationError::Overflow)?;
+
+        // skip whitespaces between parts
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+    }
+
+    Ok(total)
+}
+
+/// Format a window/TTL as a humantime-style string, such as `"1h30m"`,
+/// which can be parsed by [parse_duration].
+///
+/// Sub-millisecond parts are dropped, and negative durations are formatted as `"0s"`.
+pub fn format_duration(duration: crate::time::Duration) -> String {
+    let mut millis = duration.num_milliseconds().max(0);
+    if millis == 0 {
+        return "0s".to_string();
+    }
+
+    let mut s = String::new();
+    for (unit, unit_millis) in [("d", 86_400_000), ("h", 3_600_000), ("m", 60_000), ("s", 1_000), ("ms", 1)] {
+        if millis >= unit_millis {
+            s.push_str(&format!("{}{}", millis / unit_millis, unit));
+            millis %= unit_millis;
+        }
+    }
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10s"), Ok(crate::time::Duration::seconds(10)));
+        assert_eq!(parse_duration("5m"), Ok(crate::time::Duration::minutes(5)));
+        assert_eq!(parse_duration("1h"), Ok(crate::time::Duration::hours(1)));
+        assert_eq!(parse_duration(" 1h30m "), Ok(crate::time::Duration::minutes(90)));
+        assert_eq!(parse_duration("1d 12hours"), Ok(crate::time::Duration::hours(36)));
+        assert_eq!(parse_duration("250ms"), Ok(crate::time::Duration::milliseconds(250)));
+
+        assert_eq!(parse_duration(""), Err(ParseDurationError::Empty));
+        assert_eq!(parse_duration("s"), Err(ParseDurationError::NumberExpected(0)));
+        assert_eq!(parse_duration("10"), Err(ParseDurationError::UnknownUnit("".to_string())));
+        assert_eq!(parse_duration("10y"), Err(ParseDurationError::UnknownUnit("y".to_string())));
+        assert_eq!(parse_duration("99999999999999999999s"), Err(ParseDurationError::Overflow));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(crate::time::Duration::seconds(10)), "10s");
+        assert_eq!(format_duration(crate::time::Duration::minutes(90)), "1h30m");
+        assert_eq!(format_duration(crate::time::Duration::milliseconds(86_400_250)), "1d250ms");
+        assert_eq!(format_duration(crate::time::Duration::zero()), "0s");
+        assert_eq!(format_duration(crate::time::Duration::seconds(-1)), "0s");
+
+        let window = crate::time::Duration::milliseconds(123_456_789);
+        assert_eq!(parse_duration(&format_duration(window)), Ok(window));
+    }
 }